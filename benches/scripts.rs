@@ -0,0 +1,49 @@
+// Runs every script of this directory three times and prints the best time.
+// `cargo bench` builds in release mode, `cargo bench -- loop` only runs the
+// scripts whose name contains "loop".
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use max_interpreter::io::CapturedOutput;
+use max_interpreter::vm::VM;
+
+const RUNS: usize = 3;
+
+fn main() {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches");
+    let mut scripts: Vec<_> = std::fs::read_dir(&directory)
+        .expect("benches directory")
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "max"))
+        .collect();
+    scripts.sort();
+
+    for path in scripts {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        let source = std::fs::read_to_string(&path).expect("readable script");
+        let best = (0..RUNS)
+            .map(|_| run(&source, &path))
+            .min()
+            .unwrap_or_default();
+        println!("{:<28} {:.3}s", name, best.as_secs_f64());
+    }
+}
+
+fn run(source: &str, path: &Path) -> Duration {
+    let mut vm = VM::new();
+    let output = CapturedOutput::new();
+    vm.set_output(Box::new(output.clone()));
+    let start = Instant::now();
+    let result = vm.interpret_file(source.to_owned(), &path.to_string_lossy());
+    let elapsed = start.elapsed();
+    assert!(result.is_ok(), "{}: {}", path.display(), output.take());
+    elapsed
+}
@@ -0,0 +1,23 @@
+#![no_main]
+
+// Compiles and runs arbitrary source, run with `cargo fuzz run compile_run`.
+// Errors in the script are expected, a panic of the interpreter is a bug.
+use libfuzzer_sys::fuzz_target;
+use max_interpreter::io::CapturedOutput;
+use max_interpreter::options::SandboxPolicy;
+use max_interpreter::vm::VM;
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    let mut vm = VM::new();
+    vm.set_output(Box::new(CapturedOutput::new()));
+    vm.set_sandbox_policy(SandboxPolicy::deny_all());
+    vm.set_deterministic(0);
+    if vm.interpret(source.to_owned()).is_ok() {
+        vm.run_event_loop();
+    }
+});
@@ -1,6 +1,10 @@
 use core::panic;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::{Rc, Weak};
 
-use crate::value::{print_value, Value};
+use crate::object::ObjFunction;
+use crate::value::Value;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum OpCode {
@@ -32,10 +36,124 @@ pub enum OpCode {
     OpJump,
     OpLoop,
     OpCall,
+    OpBuildList,
+    OpIndex,
+    OpSlice,
+    OpBuildSet,
+    OpBuildMap,
+    OpUnion,
+    OpIntersection,
+    OpIn,
+    OpIs,
+    OpConcat,
+    OpGetIter,
+    OpForIter,
+    OpYield,
+    OpCallNative,
+    OpGetGlobal,
+    OpSetGlobal,
+    OpInvokeBuiltin,
+    OpCallIndirect,
+    OpTestLocals,
+    OpTestLocalConstant,
+    OpDefer,
+    OpRunDeferred,
+    OpEndDefer,
+    OpTry,
+    OpEndTry,
+    OpCaught,
+    OpCatches,
+    OpRaise,
     Number(usize),
 }
 
 impl OpCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::OpConstant => "OP_CONSTANT",
+            OpCode::OpAdd => "OP_ADD",
+            OpCode::OpSubtract => "OP_SUBTRACT",
+            OpCode::OpMultiply => "OP_MULTIPLY",
+            OpCode::OpDivide => "OP_DIVIDE",
+            OpCode::OpNegate => "OP_NEGATE",
+            OpCode::OpNot => "OP_NOT",
+            OpCode::OpTrue => "OP_TRUE",
+            OpCode::OpFalse => "OP_FALSE",
+            OpCode::OpNone => "OP_NONE",
+            OpCode::OpPrint => "OP_PRINT",
+            OpCode::OpEqual => "OP_EQUAL",
+            OpCode::OpNotEqual => "OP_NOT_EQUAL",
+            OpCode::OpGreater => "OP_GREATER",
+            OpCode::OpGreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::OpLess => "OP_LESS",
+            OpCode::OpLessEqual => "OP_LESS_EQUAL",
+            OpCode::OpReturn => "OP_RETURN",
+            OpCode::OpSet => "OP_SET",
+            OpCode::OpGet => "OP_GET",
+            OpCode::OpEol => "OP_EOL",
+            OpCode::OpEof => "OP_EOF",
+            OpCode::OpPop => "OP_POP",
+            OpCode::OpJumpIfTrue => "OP_JUMP_IF_TRUE",
+            OpCode::OpJumpIfFalse => "OP_JUMP_IF_FALSE",
+            OpCode::OpJump => "OP_JUMP",
+            OpCode::OpLoop => "OP_LOOP",
+            OpCode::OpCall => "OP_CALL",
+            OpCode::OpBuildList => "OP_BUILD_LIST",
+            OpCode::OpIndex => "OP_INDEX",
+            OpCode::OpSlice => "OP_SLICE",
+            OpCode::OpBuildSet => "OP_BUILD_SET",
+            OpCode::OpBuildMap => "OP_BUILD_MAP",
+            OpCode::OpUnion => "OP_UNION",
+            OpCode::OpIntersection => "OP_INTERSECTION",
+            OpCode::OpIn => "OP_IN",
+            OpCode::OpIs => "OP_IS",
+            OpCode::OpConcat => "OP_CONCAT",
+            OpCode::OpGetIter => "OP_GET_ITER",
+            OpCode::OpForIter => "OP_FOR_ITER",
+            OpCode::OpYield => "OP_YIELD",
+            OpCode::OpCallNative => "OP_CALL_NATIVE",
+            OpCode::OpGetGlobal => "OP_GET_GLOBAL",
+            OpCode::OpSetGlobal => "OP_SET_GLOBAL",
+            OpCode::OpInvokeBuiltin => "OP_INVOKE_BUILTIN",
+            OpCode::OpCallIndirect => "OP_CALL_INDIRECT",
+            OpCode::OpTestLocals => "OP_TEST_LOCALS",
+            OpCode::OpTestLocalConstant => "OP_TEST_LOCAL_CONSTANT",
+            OpCode::OpDefer => "OP_DEFER",
+            OpCode::OpRunDeferred => "OP_RUN_DEFERRED",
+            OpCode::OpEndDefer => "OP_END_DEFER",
+            OpCode::OpTry => "OP_TRY",
+            OpCode::OpEndTry => "OP_END_TRY",
+            OpCode::OpCaught => "OP_CAUGHT",
+            OpCode::OpCatches => "OP_CATCHES",
+            OpCode::OpRaise => "OP_RAISE",
+            OpCode::Number(_) => "NUMBER",
+        }
+    }
+
+    // How many OpCode::Number operands follow the instruction in the chunk
+    pub fn operand_count(&self) -> usize {
+        match self {
+            OpCode::OpConstant
+            | OpCode::OpSet
+            | OpCode::OpGet
+            | OpCode::OpJumpIfTrue
+            | OpCode::OpJumpIfFalse
+            | OpCode::OpJump
+            | OpCode::OpLoop
+            | OpCode::OpCall
+            | OpCode::OpBuildList
+            | OpCode::OpBuildSet
+            | OpCode::OpBuildMap
+            | OpCode::OpRunDeferred
+            | OpCode::OpCatches => 1,
+            OpCode::OpForIter | OpCode::OpCallNative | OpCode::OpInvokeBuiltin => 2,
+            OpCode::OpGetGlobal | OpCode::OpSetGlobal => 1,
+            OpCode::OpCallIndirect | OpCode::OpDefer | OpCode::OpTry => 2,
+            OpCode::OpTestLocals | OpCode::OpTestLocalConstant => 4,
+            _ => 0,
+        }
+    }
+
     pub fn as_number(&self) -> usize {
         match self {
             OpCode::Number(n) => *n,
@@ -44,12 +162,45 @@ impl OpCode {
     }
 }
 
+// The comparisons a test instruction can make, its third operand is the
+// position of the comparison in this list
+pub const TEST_COMPARISONS: [OpCode; 6] = [
+    OpCode::OpLess,
+    OpCode::OpLessEqual,
+    OpCode::OpGreater,
+    OpCode::OpGreaterEqual,
+    OpCode::OpEqual,
+    OpCode::OpNotEqual,
+];
+
+// Columns of the source an instruction was compiled from, end excluded
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
-    lines: Vec<usize>,
+    pub lines: Vec<usize>,
+    // Run-length encoded like lines, each span with the number of bytes it covers
+    pub spans: Vec<(Span, usize)>,
     pub constants: Vec<Value>,
+    // The function each OP_CALL_INDIRECT called last, its second operand is
+    // the index. Weak so a function calling itself does not keep itself alive.
+    pub call_caches: RefCell<Vec<Weak<ObjFunction>>>,
     pub had_error: bool,
+    // Where the code comes from, the function name is empty for the script.
+    // Shared so every function of a file does not hold its own copy.
+    pub function_name: Rc<str>,
+    pub file: Rc<str>,
+}
+
+impl Default for Chunk {
+    fn default() -> Chunk {
+        Chunk::new()
+    }
 }
 
 impl Chunk {
@@ -57,14 +208,38 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             lines: Vec::new(),
+            spans: Vec::new(),
             constants: Vec::new(),
+            call_caches: RefCell::new(Vec::new()),
             had_error: false,
+            function_name: Rc::from(""),
+            file: Rc::from(""),
+        }
+    }
+
+    // How error messages and the disassembler refer to the chunk, e.g.
+    // `function count (main.max)`
+    pub fn location(&self) -> String {
+        let owner = if self.function_name.is_empty() {
+            "script".to_owned()
+        } else {
+            format!("function {}", self.function_name)
+        };
+        if self.file.is_empty() {
+            owner
+        } else {
+            format!("{} ({})", owner, self.file)
         }
     }
 
-    pub fn write(&mut self, byte: OpCode, line: usize) {
+    pub fn write(&mut self, byte: OpCode, line: usize, span: Span) {
         self.code.push(byte);
 
+        match self.spans.last_mut() {
+            Some((last, count)) if *last == span => *count += 1,
+            _ => self.spans.push((span, 1)),
+        }
+
         let lines_len = self.lines.len();
         if lines_len > 1 && self.lines[lines_len - 2] == line {
             self.lines[lines_len - 1] += 1;
@@ -74,25 +249,61 @@ impl Chunk {
         }
     }
 
+    // Removes the last byte along with its line and span
+    pub fn pop(&mut self) -> Option<OpCode> {
+        let byte = self.code.pop()?;
+        if let Some((_, count)) = self.spans.last_mut() {
+            *count -= 1;
+            if *count == 0 {
+                self.spans.pop();
+            }
+        }
+        let lines_len = self.lines.len();
+        self.lines[lines_len - 1] -= 1;
+        if self.lines[lines_len - 1] == 0 {
+            self.lines.truncate(lines_len - 2);
+        }
+        Some(byte)
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
 
+    pub fn add_call_site(&mut self) -> usize {
+        let caches = self.call_caches.get_mut();
+        caches.push(Weak::new());
+        caches.len() - 1
+    }
+
     pub fn get_line(&self, index: usize) -> usize {
         let mut line = 0;
 
         for i in (0..self.lines.len()).step_by(2) {
             line += self.lines[i + 1];
-            if line - 1 >= index {
+            if line > index {
                 return self.lines[i];
             }
         }
-        panic!("Index out of bounds")
+        // Past the end, e.g. an error on running out of code, is the last line
+        self.lines.iter().rev().nth(1).copied().unwrap_or(0)
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+    pub fn get_span(&self, index: usize) -> Option<Span> {
+        let mut covered = 0;
+        for (span, count) in &self.spans {
+            covered += count;
+            if covered > index {
+                return Some(*span);
+            }
+        }
+        None
+    }
+
+    // Appends the listing of the chunk to the given text
+    pub fn disassemble(&self, listing: &mut String) {
+        let _ = writeln!(listing, "== {} ==", self.location());
 
         let mut skip_next: usize = 0;
         for (index, byte) in self.code.iter().enumerate() {
@@ -100,153 +311,178 @@ impl Chunk {
                 skip_next -= 1;
                 continue;
             }
-            skip_next = self.disassemble_instruction(byte, index);
+            skip_next = self.disassemble_instruction(byte, index, listing);
         }
     }
 
-    pub fn disassemble_instruction(&self, byte: &OpCode, index: usize) -> usize {
-        print!("{:04} ", index);
+    // Returns the number of operands following the instruction
+    pub fn disassemble_instruction(
+        &self,
+        byte: &OpCode,
+        index: usize,
+        listing: &mut String,
+    ) -> usize {
+        let _ = write!(listing, "{:04} ", index);
         let line = self.get_line(index);
         if index > 0 && line == self.get_line(index - 1) {
-            print!("   | ");
+            listing.push_str("   | ");
         } else {
-            print!("{:4} ", line);
+            let _ = write!(listing, "{:4} ", line);
         }
 
-        match byte {
-            OpCode::OpConstant => {
-                self.constant_instruction("OP_CONSTANT", index);
-                1
-            }
-            OpCode::OpAdd => {
-                println!("OP_ADD");
-                0
-            }
-            OpCode::OpSubtract => {
-                println!("OP_SUBTRACT");
-                0
-            }
-            OpCode::OpMultiply => {
-                println!("OP_MULTIPLY");
-                0
-            }
-            OpCode::OpDivide => {
-                println!("OP_DIVIDE");
-                0
-            }
-            OpCode::OpTrue => {
-                println!("OP_TRUE");
-                0
-            }
-            OpCode::OpFalse => {
-                println!("OP_FALSE");
-                0
+        let instruction = match self.instruction_at(index) {
+            Some(instruction) => instruction,
+            None => {
+                let _ = writeln!(listing, "Unknown operand {:?}", byte);
+                return 0;
             }
-            OpCode::OpNone => {
-                println!("OP_NONE");
-                0
-            }
-            OpCode::OpPrint => {
-                println!("OP_PRINT");
-                0
-            }
-            OpCode::OpNot => {
-                println!("OP_NOT");
-                0
-            }
-            OpCode::OpNegate => {
-                println!("OP_NEGATE");
-                0
-            }
-            OpCode::OpEqual => {
-                println!("OP_EQUAL");
-                0
-            }
-            OpCode::OpNotEqual => {
-                println!("OP_NOT_EQUAL");
-                0
-            }
-            OpCode::OpGreater => {
-                println!("OP_GREATER");
-                0
-            }
-            OpCode::OpGreaterEqual => {
-                println!("OP_GREATER_EQUAL");
-                0
-            }
-            OpCode::OpLess => {
-                println!("OP_LESS");
-                0
-            }
-            OpCode::OpLessEqual => {
-                println!("OP_LESS_EQUAL");
-                0
-            }
-            OpCode::OpReturn => {
-                println!("OP_RETURN");
-                0
-            }
-            OpCode::OpSet => {
-                self.byte_instruction("OP_SET", index);
-                1
-            }
-            OpCode::OpGet => {
-                self.byte_instruction("OP_GET", index);
-                1
-            }
-            OpCode::OpEol => {
-                println!("OP_EOL");
-                0
-            }
-            OpCode::OpEof => {
-                println!("OP_EOF");
-                0
-            }
-            OpCode::OpPop => {
-                println!("OP_POP");
-                0
-            }
-            OpCode::OpJumpIfTrue => {
-                self.byte_instruction("OP_JUMP_IF_TRUE", index);
-                1
-            }
-            OpCode::OpJumpIfFalse => {
-                self.byte_instruction("OP_JUMP_IF_FALSE", index);
-                1
-            }
-            OpCode::OpJump => {
-                self.byte_instruction("OP_JUMP", index);
-                1
-            }
-            OpCode::OpLoop => {
-                self.byte_instruction("OP_LOOP", index);
-                1
-            }
-            OpCode::OpCall => {
-                self.byte_instruction("OP_CALL", index);
-                1
-            }
-            _ => panic!(
-                "Unhandled value in chunk: {:?}. Here's the whole sequence: {:?}",
-                byte, self.code
+        };
+        let _ = match (&instruction.constant, instruction.operands.as_slice()) {
+            (Some(value), [_, count]) => writeln!(
+                listing,
+                "{:30}{} {:?}",
+                instruction.name,
+                value,
+                OpCode::Number(*count)
+            ),
+            (Some(value), _) => writeln!(listing, "{:30}{}", instruction.name, value),
+            (None, []) => writeln!(listing, "{}", instruction.name),
+            (None, [operand]) => writeln!(
+                listing,
+                "{:30}{:?}",
+                instruction.name,
+                OpCode::Number(*operand)
             ),
+            (None, operands) => {
+                let operands: Vec<String> = operands
+                    .iter()
+                    .map(|operand| format!("{:?}", OpCode::Number(*operand)))
+                    .collect();
+                writeln!(listing, "{:30}{}", instruction.name, operands.join(" "))
+            }
+        };
+        instruction.operands.len()
+    }
+
+    // None when the offset holds an operand or the operands are cut off
+    pub fn instruction_at(&self, offset: usize) -> Option<Instruction> {
+        let op_code = self.code.get(offset)?;
+        if let OpCode::Number(_) = op_code {
+            return None;
         }
+        let operands = (1..=op_code.operand_count())
+            .map(|i| match self.code.get(offset + i) {
+                Some(OpCode::Number(n)) => Some(*n),
+                _ => None,
+            })
+            .collect::<Option<Vec<usize>>>()?;
+        let constant = match op_code {
+            OpCode::OpConstant
+            | OpCode::OpGetGlobal
+            | OpCode::OpSetGlobal
+            | OpCode::OpInvokeBuiltin => Some(self.constants.get(operands[0])?.clone()),
+            _ => None,
+        };
+        Some(Instruction {
+            offset,
+            name: op_code.name(),
+            operands,
+            constant,
+            line: self.get_line(offset),
+            span: self.get_span(offset),
+        })
     }
 
-    fn byte_instruction(&self, op_code: &str, index: usize) {
-        print!("{:30}", op_code);
-        let slot = self.code[index + 1];
-        println!("{:?}", slot);
+    // Stops at the first offset that does not decode
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while let Some(instruction) = self.instruction_at(offset) {
+            offset += 1 + instruction.operands.len();
+            instructions.push(instruction);
+        }
+        instructions
     }
 
-    fn constant_instruction(&self, op_code: &str, index: usize) {
-        let constant = self.code[index + 1];
-        let value = match constant {
-            OpCode::Number(index) => self.constants[index].clone(),
-            _ => panic!("Expected constant to be a number"),
-        };
-        print!("{:30}", op_code);
-        print_value(value);
-        println!();
+    // The chunk as a JSON object for tools reading bytecode, e.g.
+    // {"function": "", "file": "main.max", "constants": [...], "code": [...]}
+    pub fn to_json(&self) -> String {
+        let constants: Vec<String> = self.constants.iter().map(json_value).collect();
+        let code: Vec<String> = self
+            .instructions()
+            .iter()
+            .map(|instruction| {
+                let operands: Vec<String> = instruction
+                    .operands
+                    .iter()
+                    .map(|operand| operand.to_string())
+                    .collect();
+                let span = match instruction.span {
+                    Some(span) => format!("{{\"start\": {}, \"end\": {}}}", span.start, span.end),
+                    None => "null".to_owned(),
+                };
+                let constant = match &instruction.constant {
+                    Some(value) => json_value(value),
+                    None => "null".to_owned(),
+                };
+                format!(
+                    "{{\"offset\": {}, \"op\": {}, \"operands\": [{}], \"constant\": {}, \"line\": {}, \"span\": {}}}",
+                    instruction.offset,
+                    json_string(instruction.name),
+                    operands.join(", "),
+                    constant,
+                    instruction.line,
+                    span
+                )
+            })
+            .collect();
+        format!(
+            "{{\"function\": {}, \"file\": {}, \"constants\": [{}], \"code\": [{}]}}",
+            json_string(&self.function_name),
+            json_string(&self.file),
+            constants.join(", "),
+            code.join(", ")
+        )
     }
 }
+
+// A decoded instruction, what the disassembler prints
+#[derive(Debug, Clone)]
+pub struct Instruction {
+    pub offset: usize,
+    pub name: &'static str,
+    pub operands: Vec<usize>,
+    // The constant operand, the value of OP_CONSTANT or the name of a global
+    pub constant: Option<Value>,
+    pub line: usize,
+    pub span: Option<Span>,
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Values are written as their type and how they print, which is enough to
+// tell constants apart without a JSON form for every kind of value
+fn json_value(value: &Value) -> String {
+    format!(
+        "{{\"type\": {}, \"value\": {}}}",
+        json_string(&value.type_of()),
+        json_string(&value.to_text())
+    )
+}
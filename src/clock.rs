@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::interrupt;
+
+// Long sleeps wake up this often to see whether Ctrl-C cut them short
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+// Time as seen by a script. The virtual clock only moves when the script
+// sleeps or waits for a timer, so runs in deterministic mode never depend on
+// how fast the machine is.
+#[derive(Clone)]
+pub enum Clock {
+    Real(Instant),
+    Virtual(Duration),
+}
+
+impl Clock {
+    // Without the os feature, and on wasm32-unknown-unknown which has no clock
+    // to read, time is always virtual
+    pub fn real() -> Clock {
+        if cfg!(any(
+            not(feature = "os"),
+            all(target_arch = "wasm32", target_os = "unknown")
+        )) {
+            Clock::Virtual(Duration::ZERO)
+        } else {
+            Clock::Real(Instant::now())
+        }
+    }
+
+    // Time elapsed since the VM started
+    pub fn elapsed(&self) -> Duration {
+        match self {
+            Clock::Real(start) => start.elapsed(),
+            Clock::Virtual(elapsed) => *elapsed,
+        }
+    }
+
+    // Wall clock time, which starts at the epoch for the virtual clock
+    pub fn since_epoch(&self) -> Duration {
+        match self {
+            Clock::Real(_) => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            Clock::Virtual(elapsed) => *elapsed,
+        }
+    }
+
+    pub fn sleep(&mut self, duration: Duration) {
+        match self {
+            Clock::Real(_) => {
+                let end = Instant::now() + duration;
+                loop {
+                    let now = Instant::now();
+                    if now >= end || interrupt::pending() {
+                        break;
+                    }
+                    std::thread::sleep((end - now).min(SLEEP_SLICE));
+                }
+            }
+            Clock::Virtual(elapsed) => *elapsed += duration,
+        }
+    }
+
+    pub fn sleep_until(&mut self, deadline: Duration) {
+        let elapsed = self.elapsed();
+        if deadline > elapsed {
+            self.sleep(deadline - elapsed);
+        }
+    }
+}
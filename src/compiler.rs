@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::ops::{Index, IndexMut};
+use std::rc::Rc;
+use std::sync::Mutex;
 
 use crate::{
-    chunk::{Chunk, OpCode},
+    chunk::{json_string, Chunk, OpCode, Span, TEST_COMPARISONS},
     common::DEBUG_PRINT_CODE,
+    decimal::Decimal,
+    native::{
+        expected_args, find_native, is_method, COMPREHENSION_ADD, NATIVES, STOPWATCH_START,
+        TIME_REPORT, UNPACK, WITH_ENTER,
+    },
     object::{FunctionInfo, ObjFunction},
     scanner::{Scanner, Token, TokenType},
+    thread::{Sendable, SendableFunction},
     value::Value,
+    verify::max_stack,
 };
 
 use num_traits::FromPrimitive;
-use once_cell::sync::Lazy;
 
 #[derive(Clone)]
 pub struct Parser {
@@ -21,6 +31,74 @@ pub struct Parser {
     next_2: Token,
     had_error: bool,
     panic_mode: bool,
+    // Braces opened and not closed yet, to know where a statement ends
+    braces: usize,
+    // Reported errors, written out by the VM once compiling is done
+    errors: Vec<Diagnostic>,
+    // Code that compiles but is likely a mistake, only reported by --analyze
+    warnings: Vec<Diagnostic>,
+}
+
+// A problem found while compiling, with the token it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub warning: bool,
+    pub line: usize,
+    // The token and its columns, None at the end of the source
+    pub at: Option<(String, Span)>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(warning: bool, token: &Token, message: &str) -> Diagnostic {
+        let at = (token.r#type != TokenType::Eof).then(|| {
+            let span = Span {
+                start: token.column,
+                end: token.end_column(),
+            };
+            (token.lexeme.clone(), span)
+        });
+        Diagnostic {
+            warning,
+            line: token.line,
+            at,
+            message: message.to_owned(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let (lexeme, span) = match &self.at {
+            Some((lexeme, span)) => (
+                json_string(lexeme),
+                format!("{{\"start\": {}, \"end\": {}}}", span.start, span.end),
+            ),
+            None => ("null".to_owned(), "null".to_owned()),
+        };
+        format!(
+            "{{\"line\": {}, \"at\": {}, \"span\": {}, \"message\": {}}}",
+            self.line,
+            lexeme,
+            span,
+            json_string(&self.message)
+        )
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.warning { "Warning" } else { "Error" };
+        match &self.at {
+            Some((lexeme, _)) if lexeme.is_empty() => {
+                write!(f, "[line {}] {}: {}", self.line, kind, self.message)
+            }
+            Some((lexeme, _)) => write!(
+                f,
+                "[line {}] {} at '{}': {}",
+                self.line, kind, lexeme, self.message
+            ),
+            None => write!(f, "[line {}] {} at end: {}", self.line, kind, self.message),
+        }
+    }
 }
 
 impl Parser {
@@ -34,6 +112,9 @@ impl Parser {
             next_2: Token::new(TokenType::Empty, 0),
             had_error: false,
             panic_mode: false,
+            braces: 0,
+            errors: Vec::new(),
+            warnings: Vec::new(),
         }
     }
 
@@ -58,17 +139,20 @@ impl Parser {
             return;
         }
         self.panic_mode = true;
-        if token.r#type == TokenType::Eof {
-            eprintln!("[line {}] Error at end: {}", token.line, message);
-        } else {
-            eprintln!(
-                "[line {}] Error at '{}': {}",
-                token.line, token.lexeme, message
-            );
+        // Both passes read the whole source, an error found by the header pass
+        // is not reported again
+        let error = Diagnostic::new(false, token, message);
+        if !self.errors.contains(&error) {
+            self.errors.push(error);
         }
         self.had_error = true;
     }
 
+    fn warn_at_current(&mut self, message: &str) {
+        let warning = Diagnostic::new(true, &self.current, message);
+        self.warnings.push(warning);
+    }
+
     fn consume(&mut self, r#type: TokenType, message: &str) {
         if self.current.r#type == r#type || self.current.r#type == TokenType::Eof {
             self.advance();
@@ -83,6 +167,11 @@ impl Parser {
         self.previous = self.current.clone();
         self.current = self.next.clone();
         self.next = self.next_2.clone();
+        match self.previous.r#type {
+            TokenType::LeftBrace => self.braces += 1,
+            TokenType::RightBrace => self.braces = self.braces.saturating_sub(1),
+            _ => (),
+        }
 
         loop {
             self.next_2 = self.scanner.scan_token();
@@ -95,7 +184,15 @@ impl Parser {
                 break;
             }
 
-            self.error_at_next("Error at next token.");
+            // The message of the scanner is the lexeme of an error token, which
+            // is skipped so parsing goes on after it
+            let error = std::mem::replace(&mut self.current, self.next.clone());
+            self.next = self.next_2.clone();
+            let at = Token {
+                lexeme: String::new(),
+                ..error.clone()
+            };
+            self.error_at(&at, &error.lexeme);
         }
     }
 
@@ -105,7 +202,7 @@ impl Parser {
         }
 
         self.advance();
-        return true;
+        true
     }
 
     fn peek_previous_2(&self) -> Token {
@@ -120,9 +217,9 @@ impl Parser {
         self.next.clone()
     }
 
-    // fn peek_next_2(&self) -> Token {
-    //     self.next_2.clone()
-    // }
+    fn peek_next_2(&self) -> Token {
+        self.next_2.clone()
+    }
 
     fn check(&self, r#type: TokenType) -> bool {
         self.current.r#type == r#type
@@ -135,15 +232,175 @@ impl Parser {
         self.current = Token::new(TokenType::Empty, 0);
         self.next = Token::new(TokenType::Empty, 0);
         self.next_2 = Token::new(TokenType::Empty, 0);
-        self.had_error = false;
         self.panic_mode = false;
+        self.braces = 0;
+    }
+
+    // Starts over on a new source, nothing found by an earlier compile is kept
+    fn start(&mut self, source: String) {
+        self.scanner = Scanner::new(source);
+        self.reset();
+        self.had_error = false;
+        self.errors.clear();
+        self.warnings.clear();
+    }
+
+    fn position(&self) -> Position {
+        Position {
+            scanner: self.scanner.clone(),
+            tokens: [
+                self.previous_2.clone(),
+                self.previous.clone(),
+                self.current.clone(),
+                self.next.clone(),
+                self.next_2.clone(),
+            ],
+            braces: self.braces,
+        }
+    }
+
+    // Goes back to tokens that were already read, what was reported since
+    // stays reported
+    fn rewind(&mut self, position: Position) {
+        let [previous_2, previous, current, next, next_2] = position.tokens;
+        self.scanner = position.scanner;
+        self.previous_2 = previous_2;
+        self.previous = previous;
+        self.current = current;
+        self.next = next;
+        self.next_2 = next_2;
+        self.braces = position.braces;
+    }
+
+    // Moves past the parameters and the body of the function whose name was
+    // just read. False if the body is not closed, the parser is then left
+    // where it was.
+    fn skip_function(&mut self) -> bool {
+        let position = self.position();
+        let braces = self.braces;
+        while !matches!(
+            self.current.r#type,
+            TokenType::LeftBrace | TokenType::Newline | TokenType::Eof
+        ) {
+            self.advance();
+        }
+        if self.current.r#type == TokenType::LeftBrace {
+            self.advance();
+            while self.current.r#type != TokenType::Eof {
+                self.advance();
+                if self.previous.r#type == TokenType::RightBrace && self.braces == braces {
+                    return true;
+                }
+            }
+        }
+        self.rewind(position);
+        false
+    }
+
+    // Whether a `for` follows the first element of a list or braces literal,
+    // making it a comprehension. The tokens are read from a copy of the
+    // scanner, the parser does not move.
+    fn comprehension_ahead(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let tokens = [&self.current, &self.next, &self.next_2]
+            .into_iter()
+            .map(|token| token.r#type)
+            .chain(std::iter::from_fn(|| Some(scanner.scan_token().r#type)));
+
+        let mut depth = 0usize;
+        for r#type in tokens {
+            match r#type {
+                TokenType::LeftParen | TokenType::LeftSquareBracket | TokenType::LeftBrace => {
+                    depth += 1
+                }
+                TokenType::RightParen | TokenType::RightSquareBracket | TokenType::RightBrace
+                    if depth > 0 =>
+                {
+                    depth -= 1
+                }
+                TokenType::For if depth == 0 => return true,
+                TokenType::Empty => (),
+                TokenType::RightParen
+                | TokenType::RightSquareBracket
+                | TokenType::RightBrace
+                | TokenType::Comma
+                | TokenType::Newline
+                | TokenType::Eof
+                | TokenType::Error
+                    if depth == 0 =>
+                {
+                    return false
+                }
+                TokenType::Eof | TokenType::Error => return false,
+                _ => (),
+            }
+        }
+        false
+    }
+
+    // Whether the next item of a block is a statement or declaration rather
+    // than an expression that could be the value of the block. A nested if is
+    // an expression so its branches can give the value.
+    fn starts_statement(&self) -> bool {
+        let current = self.peek_current().r#type;
+        let next = self.peek_next().r#type;
+        matches!(
+            current,
+            TokenType::Print
+                | TokenType::While
+                | TokenType::Loop
+                | TokenType::Do
+                | TokenType::For
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Yield
+                | TokenType::Time
+                | TokenType::Defer
+                | TokenType::With
+                | TokenType::Try
+                | TokenType::Raise
+                | TokenType::At
+                | TokenType::LeftBrace
+        ) || current.is_type()
+            || current == TokenType::Identifier
+                && matches!(next, TokenType::Equal | TokenType::Colon | TokenType::Comma)
+    }
+
+    // Moves past the element of a comprehension, up to its `for`
+    fn skip_comprehension_element(&mut self) {
+        let mut depth = 0usize;
+        while !(self.check(TokenType::Eof) || depth == 0 && self.check(TokenType::For)) {
+            match self.current.r#type {
+                TokenType::LeftParen | TokenType::LeftSquareBracket | TokenType::LeftBrace => {
+                    depth += 1
+                }
+                TokenType::RightParen | TokenType::RightSquareBracket | TokenType::RightBrace => {
+                    depth = depth.saturating_sub(1)
+                }
+                _ => (),
+            }
+            self.advance();
+        }
     }
-}
 
-static mut PARSER: Lazy<Parser> = Lazy::new(|| Parser::new(String::new()));
+    // `outer: while ...` names a loop, a function header never has a loop
+    // keyword right after its colon
+    fn is_loop_label(&self) -> bool {
+        self.peek_current().r#type == TokenType::Identifier
+            && self.peek_next().r#type == TokenType::Colon
+            && matches!(
+                self.peek_next_2().r#type,
+                TokenType::While | TokenType::For | TokenType::Loop | TokenType::Do
+            )
+    }
+}
 
-fn get_parser() -> &'static mut Parser {
-    unsafe { &mut *PARSER }
+// A place in the tokens the parser can go back to
+#[derive(Clone)]
+struct Position {
+    scanner: Scanner,
+    tokens: [Token; 5],
+    braces: usize,
 }
 
 #[derive(Copy, Clone, FromPrimitive, Debug)]
@@ -154,6 +411,8 @@ enum Precedence {
     And,
     Equality,
     Comparison,
+    BitOr,
+    BitAnd,
     Term,
     Factor,
     Unary,
@@ -170,11 +429,195 @@ struct ParseRule {
 #[derive(Clone, Debug)]
 pub struct Local {
     name: Token,
+    // Columns of the name where the variable was declared, on the line of the name
+    span: Span,
     depth: usize,
     type_: TokenType,
     is_initialized: bool,
+    // Set once a slot is pushed for a variable that is not initialized yet
+    has_slot: bool,
+    // Whether every path to the code being compiled assigns the variable, a
+    // variable declared without a value is not until each branch assigns it
+    assigned: bool,
 }
 
+impl Local {
+    fn new(name: Token, depth: usize, type_: TokenType) -> Local {
+        Local {
+            span: Span {
+                start: name.column,
+                end: name.end_column(),
+            },
+            name,
+            depth,
+            type_,
+            is_initialized: false,
+            has_slot: false,
+            assigned: false,
+        }
+    }
+}
+
+// The locals in slot order, with the slots of each name so resolving one
+// does not scan them all. A name declared again in a deeper scope, like a
+// loop variable, is pushed on top of the slots of that name.
+#[derive(Clone, Debug, Default)]
+pub struct Locals {
+    slots: Vec<Local>,
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+impl Locals {
+    // The slot of the innermost local of that name
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name)?.last().copied()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.by_name.contains_key(name)
+    }
+
+    fn push(&mut self, local: Local) -> usize {
+        let slot = self.slots.len();
+        self.by_name
+            .entry(local.name.lexeme.clone())
+            .or_default()
+            .push(slot);
+        self.slots.push(local);
+        slot
+    }
+
+    fn pop(&mut self) -> Option<Local> {
+        let local = self.slots.pop()?;
+        if let Some(slots) = self.by_name.get_mut(&local.name.lexeme) {
+            slots.pop();
+            if slots.is_empty() {
+                self.by_name.remove(&local.name.lexeme);
+            }
+        }
+        Some(local)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        while self.slots.len() > len {
+            self.pop();
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn get(&self, slot: usize) -> Option<&Local> {
+        self.slots.get(slot)
+    }
+
+    fn get_mut(&mut self, slot: usize) -> Option<&mut Local> {
+        self.slots.get_mut(slot)
+    }
+
+    fn last(&self) -> Option<&Local> {
+        self.slots.last()
+    }
+
+    fn iter(&self) -> std::slice::Iter<'_, Local> {
+        self.slots.iter()
+    }
+
+    // Whether each local is assigned, in slot order, to be restored once a
+    // branch is compiled
+    fn assigned(&self) -> Vec<bool> {
+        self.slots.iter().map(|local| local.assigned).collect()
+    }
+
+    fn set_assigned(&mut self, assigned: &[bool]) {
+        for (local, assigned) in self.slots.iter_mut().zip(assigned) {
+            local.assigned = *assigned;
+        }
+    }
+
+    // Nothing runs after a break or continue until the paths join again, the
+    // dead path assigns everything so it does not weaken the join
+    fn assign_all(&mut self) {
+        for local in &mut self.slots {
+            local.assigned = true;
+        }
+    }
+}
+
+// Where paths join, a local is assigned if it is assigned on all of them
+fn join_assigned(paths: &[Vec<bool>]) -> Option<Vec<bool>> {
+    paths.iter().cloned().reduce(|joined, path| {
+        joined
+            .iter()
+            .zip(&path)
+            .map(|(joined, path)| *joined && *path)
+            .collect()
+    })
+}
+
+impl Index<usize> for Locals {
+    type Output = Local;
+
+    fn index(&self, slot: usize) -> &Local {
+        &self.slots[slot]
+    }
+}
+
+impl IndexMut<usize> for Locals {
+    fn index_mut(&mut self, slot: usize) -> &mut Local {
+        &mut self.slots[slot]
+    }
+}
+
+// The jumps of break and continue statements are patched once the loop knows
+// where its body ends
+#[derive(Clone, Debug)]
+struct Loop {
+    label: Option<String>,
+    scope_depth: usize,
+    // How many try blocks were open when the loop began
+    tries: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+    // Which locals are assigned at each break and continue
+    break_assigned: Vec<Vec<bool>>,
+    continue_assigned: Vec<Vec<bool>>,
+}
+
+// A top level function body left for another thread by a parallel compile,
+// read by a copy of the parser standing on the function name
+#[derive(Clone)]
+struct Body {
+    parser: Parser,
+    locals: Locals,
+    // How many constants of the script were there before the function, the
+    // chunk of the body starts with them like in a serial compile
+    constants: usize,
+    // Where the function goes in the constants of the script
+    constant: usize,
+    // Warnings found before the body, its own come after them
+    warnings: usize,
+}
+
+// What every body of a parallel compile is compiled with, taken from the
+// script once its own code is compiled
+struct BodyContext {
+    functions: HashMap<String, FunctionInfo>,
+    globals: HashSet<String>,
+    file: String,
+    functions_count: usize,
+    strict: bool,
+    print_code: bool,
+}
+
+struct CompiledBody {
+    function: SendableFunction,
+    listing: String,
+    warnings: Vec<Diagnostic>,
+}
+
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum FunctionType {
     Function,
@@ -184,26 +627,95 @@ pub enum FunctionType {
 
 #[derive(Clone)]
 pub struct Compiler {
+    parser: Parser,
     function: ObjFunction,
     function_type: FunctionType,
-    locals: Vec<Local>,
-    functions: HashMap<String, FunctionInfo>,
+    locals: Locals,
+    functions: HashMap<String, Rc<FunctionInfo>>,
     values: HashMap<String, Value>,
     scope_depth: usize,
+    strict: bool,
+    // Whether chunks are disassembled once compiled, into the listing
+    print_code: bool,
+    listing: String,
+    loops: Vec<Loop>,
+    // Scope depth of each defer statement in the scopes still open
+    defers: Vec<usize>,
+    // Set while compiling the code of a defer statement
+    in_defer: bool,
+    // Scope depths of the try statements the code being compiled is in
+    tries: Vec<usize>,
+    // Set by a `name:` prefix until the loop it names begins
+    next_label: Option<String>,
+    // Line and column of the first token of the expression being parsed
+    expression_start: Option<(usize, usize)>,
+    // Where the OpPop of the latest top level expression statement of the
+    // script is, its value is returned if nothing follows it
+    last_expression: Option<usize>,
+    // Names of the globals the VM holds, set by the host or by earlier scripts
+    globals: HashSet<String>,
+    // Set for a lone expression, which can read the globals but not assign them
+    read_only: bool,
+    // How many threads compile the top level function bodies, once the code
+    // of the script is. With one they are compiled as they are read.
+    threads: usize,
+    bodies: Vec<Body>,
 }
 
 impl Compiler {
     pub fn new() -> Compiler {
         Compiler {
+            parser: Parser::new(String::new()),
             function: ObjFunction::new(),
             function_type: FunctionType::Script,
-            locals: Vec::new(),
+            locals: Locals::default(),
             functions: HashMap::new(),
             values: HashMap::new(),
             scope_depth: 0,
+            strict: false,
+            print_code: DEBUG_PRINT_CODE,
+            listing: String::new(),
+            loops: Vec::new(),
+            defers: Vec::new(),
+            in_defer: false,
+            tries: Vec::new(),
+            next_label: None,
+            expression_start: None,
+            last_expression: None,
+            globals: HashSet::new(),
+            read_only: false,
+            threads: 1,
+            bodies: Vec::new(),
         }
     }
 
+    // In strict mode conditions must be bool and values of different types
+    // cannot be compared, whatever can be seen at compile time is reported here
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    // Turned off when the bytecode is dumped in another format
+    pub fn set_print_code(&mut self, print_code: bool) {
+        self.print_code = print_code;
+    }
+
+    // Compiles the bodies of top level functions on that many threads, the
+    // bytecode is the same as a serial compile's
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads;
+    }
+
+    // Names the script resolves to globals of the VM when no variable has them
+    pub fn set_globals(&mut self, globals: HashSet<String>) {
+        self.globals = globals;
+    }
+
+    // The file the source was read from, kept on every chunk for error messages
+    pub fn set_file(&mut self, file: &str) {
+        self.current_chunk().file = Rc::from(file);
+    }
+
     pub fn immut_current_chunk(&self) -> &Chunk {
         &self.function.chunk
     }
@@ -212,64 +724,138 @@ impl Compiler {
         &mut self.function.chunk
     }
 
+    // The errors found by the last compile, in the order they were found
+    pub fn take_errors(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.parser.errors)
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.parser.warnings)
+    }
+
+    // The disassembly of every chunk compiled, when printing code is on
+    pub fn take_listing(&mut self) -> String {
+        std::mem::take(&mut self.listing)
+    }
+
     pub fn compile(&mut self, source: String) -> ObjFunction {
-        get_parser().scanner = Scanner::new(source);
+        let serial = (self.threads > 1).then(|| Compiler {
+            threads: 1,
+            ..self.clone()
+        });
+        self.parser.start(source);
 
         self.start_compiler();
 
         // First pass to initialize functions so that their order does not matter
         // Function header analysis is also done here
-        while !get_parser().match_token(TokenType::Eof) {
+        while !self.parser.match_token(TokenType::Eof) {
             self.globals_declaration();
         }
 
-        get_parser().reset();
+        self.parser.reset();
 
-        while !get_parser().match_token(TokenType::Eof) {
+        while !self.parser.match_token(TokenType::Eof) {
             self.declaration();
         }
 
+        if !self.bodies.is_empty() && !self.compile_bodies() {
+            // A body with errors is compiled again with the whole script, the
+            // errors are then reported in the order a serial compile finds them.
+            // So is one after constants that cannot be copied to a thread.
+            if let Some(mut serial) = serial {
+                let function = serial.compile(self.parser.scanner.source());
+                *self = serial;
+                return function;
+            }
+        }
+
         self.end_compiler();
 
-        if get_parser().had_error {
+        if self.parser.had_error {
+            self.current_chunk().had_error = true;
+        }
+        self.function.clone()
+    }
+
+    // A single expression whose value the script returns, anything else in
+    // the source is an error
+    pub fn compile_expression(&mut self, source: String) -> ObjFunction {
+        self.parser.start(source);
+        self.read_only = true;
+
+        self.start_compiler();
+        while self.parser.match_token(TokenType::Newline) {}
+        if self.parser.check(TokenType::Eof) {
+            self.parser.error_at_current("Expect expression.");
+        } else {
+            self.expression();
+        }
+        while self.parser.match_token(TokenType::Newline) {}
+        if !self.parser.check(TokenType::Eof) {
+            self.parser.error_at_current("Expect end of expression.");
+        }
+        self.emit_byte(OpCode::OpReturn);
+
+        if self.print_code && !self.parser.had_error {
+            self.function.chunk.disassemble(&mut self.listing);
+        }
+        self.function.max_stack = max_stack(&self.function);
+        if self.parser.had_error {
             self.current_chunk().had_error = true;
         }
-        return self.function.clone();
+        self.function.clone()
     }
 
     fn globals_declaration(&mut self) {
-        if get_parser().peek_current().r#type == TokenType::Identifier
-            && (get_parser().peek_next().r#type == TokenType::Colon
-                || get_parser().peek_next().r#type == TokenType::LeftBrace)
+        let braces = self.parser.braces;
+        // Only a line can start with a function header, `while flag {` is not one
+        let at_line_start = matches!(
+            self.parser.previous.r#type,
+            TokenType::Newline | TokenType::Empty
+        );
+        if at_line_start
+            && !self.parser.is_loop_label()
+            && self.parser.peek_current().r#type == TokenType::Identifier
+            && (self.parser.peek_next().r#type == TokenType::Colon
+                || self.parser.peek_next().r#type == TokenType::LeftBrace)
         {
             self.function_declaration();
         } else {
-            get_parser().advance();
+            self.parser.advance();
         }
 
-        if get_parser().panic_mode {
-            self.synchronize();
+        if self.parser.panic_mode {
+            self.synchronize(braces);
         }
     }
 
     fn declaration(&mut self) {
-        if get_parser().peek_current().r#type == TokenType::Identifier
-            && (get_parser().peek_next().r#type == TokenType::Equal
-                || get_parser().peek_next().r#type == TokenType::Newline)
-            || get_parser().peek_current().r#type.is_type()
+        let braces = self.parser.braces;
+        if self.parser.check(TokenType::At) {
+            self.annotated_function();
+        } else if self.parser.peek_current().r#type == TokenType::Identifier
+            && (self.parser.peek_next().r#type == TokenType::Equal
+                || self.parser.peek_next().r#type == TokenType::Newline)
+            || self.parser.peek_current().r#type.is_type()
         {
             self.variable_assignment();
-        } else if get_parser().peek_current().r#type == TokenType::Identifier
-            && (get_parser().peek_next().r#type == TokenType::Colon
-                || get_parser().peek_next().r#type == TokenType::LeftBrace)
+        } else if self.parser.peek_current().r#type == TokenType::Identifier
+            && self.parser.peek_next().r#type == TokenType::Comma
+        {
+            self.multiple_assignment();
+        } else if !self.parser.is_loop_label()
+            && self.parser.peek_current().r#type == TokenType::Identifier
+            && (self.parser.peek_next().r#type == TokenType::Colon
+                || self.parser.peek_next().r#type == TokenType::LeftBrace)
         {
             self.function_initialization();
         } else {
             self.statement();
         }
 
-        if get_parser().panic_mode {
-            self.synchronize();
+        if self.parser.panic_mode {
+            self.synchronize(braces);
         }
     }
 
@@ -277,134 +863,589 @@ impl Compiler {
         let var_name_register =
             self.parse_variable("Expect function name.", TokenType::TypeFunction);
         self.locals[var_name_register.as_number()].is_initialized = true;
+        self.locals[var_name_register.as_number()].assigned = true;
 
-        let function_name = get_parser().previous.lexeme.clone();
+        let function_name = self.parser.previous.lexeme.clone();
         let mut function_info = FunctionInfo::new(function_name.clone());
 
-        if get_parser().peek_current().r#type == TokenType::Colon {
-            get_parser().advance();
+        if self.parser.peek_current().r#type == TokenType::Colon {
+            self.parser.advance();
             loop {
-                if !get_parser().peek_current().r#type.is_type() {
-                    get_parser().error_at_current("Expect variable type annotation.");
-                } else if get_parser().peek_next().r#type != TokenType::Identifier {
-                    get_parser().error_at_next("Expect variable name.");
+                if !self.parser.peek_current().r#type.is_type() {
+                    self.parser
+                        .error_at_current("Expect variable type annotation.");
+                } else if self.parser.peek_next().r#type != TokenType::Identifier {
+                    self.parser.error_at_next("Expect variable name.");
                 }
                 function_info
                     .arg_types
-                    .push(get_parser().peek_current().r#type.clone());
+                    .push(self.parser.peek_current().r#type);
                 function_info
                     .arg_names
-                    .push(get_parser().peek_next().lexeme.clone());
-                get_parser().advance();
-                get_parser().advance();
-                if !get_parser().match_token(TokenType::Comma) {
+                    .push(self.parser.peek_next().lexeme.clone());
+                self.parser.advance();
+                self.parser.advance();
+                if !self.parser.match_token(TokenType::Comma) {
                     break;
                 }
             }
         }
 
-        self.functions.insert(function_name, function_info.clone());
+        self.functions.insert(function_name, Rc::new(function_info));
         self.function.functions_count += 1;
     }
 
-    fn function_initialization(&mut self) {
+    fn function_initialization(&mut self) -> OpCode {
         let var_name_register =
             self.parse_variable("Expect function name.", TokenType::TypeFunction);
         self.function(FunctionType::Function);
         self.set_variable(var_name_register);
+        var_name_register
+    }
+
+    // `@name` lines before a function declaration. Each names a native that
+    // is called with the function once it is defined and replaces it with
+    // what it returns, the annotation closest to the function first.
+    fn annotated_function(&mut self) {
+        let mut annotations = Vec::new();
+        while self.parser.match_token(TokenType::At) {
+            self.parser
+                .consume(TokenType::Identifier, "Expect annotation name after '@'.");
+            let name = self.parser.previous.lexeme.clone();
+            match find_native(&name) {
+                Some(index) if NATIVES[index].min_args <= 1 && NATIVES[index].max_args >= 1 => {
+                    annotations.push(index)
+                }
+                Some(_) => self.parser.error_at_previous(&format!(
+                    "Function {} cannot be an annotation, it does not take one argument.",
+                    name
+                )),
+                None => self
+                    .parser
+                    .error_at_previous(&format!("Unknown annotation {}.", name)),
+            }
+            self.parser
+                .consume(TokenType::Newline, "Expect newline after annotation.");
+        }
+
+        if self.parser.peek_current().r#type != TokenType::Identifier
+            || !matches!(
+                self.parser.peek_next().r#type,
+                TokenType::Colon | TokenType::LeftBrace
+            )
+        {
+            self.parser
+                .error_at_current("Expect function declaration after annotation.");
+            return;
+        }
+        let slot = self.function_initialization();
+        for index in annotations.into_iter().rev() {
+            self.emit_2_bytes(OpCode::OpGet, slot);
+            self.emit_2_bytes(OpCode::OpCallNative, OpCode::Number(index));
+            self.emit_byte(OpCode::Number(1));
+            self.emit_2_bytes(OpCode::OpSet, slot);
+            self.emit_byte(OpCode::OpPop);
+        }
     }
 
     fn function(&mut self, function_type: FunctionType) {
+        let name_token = self.parser.previous.clone();
+        // The header pass can miss a function after an earlier error, its body
+        // is then skipped when the parser synchronizes
+        let Some(function_info) = self.functions.get(&name_token.lexeme).cloned() else {
+            self.parser.error_at(
+                &name_token,
+                &format!("Function {} could not be found.", name_token.lexeme),
+            );
+            self.emit_byte(OpCode::OpNone);
+            return;
+        };
+        if self.threads > 1
+            && matches!(self.function_type, FunctionType::Script)
+            && self.scope_depth == 0
+            && !self.parser.had_error
+            && self.defer_body(&function_info)
+        {
+            return;
+        }
+
         let mut compiler = Compiler::new();
+        // The body is read by the same parser, it is handed back once compiled
+        compiler.parser = std::mem::replace(&mut self.parser, Parser::new(String::new()));
         compiler.function_type = function_type;
-        compiler.function.name = get_parser().previous.lexeme.clone();
         compiler.locals = self.locals.clone();
         compiler.function.chunk.constants = self.function.chunk.constants.clone();
+        compiler.function.chunk.file = self.function.chunk.file.clone();
         compiler.function.functions_count = self.function.functions_count;
         compiler.functions = self.functions.clone();
-        compiler.begin_scope();
+        compiler.strict = self.strict;
+        compiler.print_code = self.print_code;
+        compiler.globals = self.globals.clone();
+
+        let func = compiler.function_body(function_info);
+        self.parser = compiler.parser;
+        self.listing.push_str(&compiler.listing);
+        let byte_2 = self.make_constant(Value::ObjFunction(Rc::new(func)));
+        self.emit_2_bytes(OpCode::OpConstant, byte_2);
+    }
+
+    // The parameters and the block of a function, read by a compiler set up
+    // with what the enclosing one knows
+    fn function_body(&mut self, function_info: Rc<FunctionInfo>) -> ObjFunction {
+        self.function.name = function_info.name.clone();
+        self.function.chunk.function_name = Rc::from(self.function.name.as_str());
+        self.function.function_info = function_info;
+        self.begin_scope();
 
-        if get_parser().peek_current().r#type == TokenType::Colon {
-            get_parser().advance();
+        if self.parser.peek_current().r#type == TokenType::Colon {
+            self.parser.advance();
             loop {
-                compiler.variable_assignment();
-                if !get_parser().match_token(TokenType::Comma) {
+                self.parameter();
+                if !self.parser.match_token(TokenType::Comma) {
                     break;
                 }
             }
         }
 
-        compiler.function.function_info =
-            self.functions.get(&compiler.function.name).unwrap().clone();
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
 
-        get_parser().consume(TokenType::LeftBrace, "Expect '{' before function body.");
-        compiler.block();
+        self.end_compiler()
+    }
 
-        let func = compiler.end_compiler();
-        let byte_2 = self.make_constant(Value::ObjFunction(func));
-        self.emit_2_bytes(OpCode::OpConstant, byte_2);
+    // Leaves the body of a top level function to compile_bodies, with a
+    // function of the same name and parameters holding its constant until
+    // then. False if the body is not closed, it is then compiled here.
+    fn defer_body(&mut self, function_info: &Rc<FunctionInfo>) -> bool {
+        let mut parser = self.parser.clone();
+        parser.warnings.clear();
+        if !self.parser.skip_function() {
+            return false;
+        }
+
+        let mut placeholder = ObjFunction::new();
+        placeholder.name = function_info.name.clone();
+        placeholder.chunk.function_name = Rc::from(placeholder.name.as_str());
+        placeholder.chunk.file = self.function.chunk.file.clone();
+        placeholder.function_info = Rc::clone(function_info);
+        let constants = self.function.chunk.constants.len();
+        let constant = self.make_constant(Value::ObjFunction(Rc::new(placeholder)));
+        self.bodies.push(Body {
+            parser,
+            locals: self.locals.clone(),
+            constants,
+            constant: constant.as_number(),
+            warnings: self.parser.warnings.len(),
+        });
+        self.emit_2_bytes(OpCode::OpConstant, constant);
+        true
+    }
+
+    // Compiles the bodies left by a parallel compile and puts each function in
+    // its constant. Values holding Rc cannot cross threads, so every thread
+    // gets its own copy of the constants and functions are sent back as
+    // SendableFunction. False if a body has errors.
+    fn compile_bodies(&mut self) -> bool {
+        let bodies = std::mem::take(&mut self.bodies);
+        let places: Vec<(usize, usize)> = bodies
+            .iter()
+            .map(|body| (body.constant, body.warnings))
+            .collect();
+        // The core built without the os feature starts no thread, the bodies
+        // are then compiled here
+        let threads = if cfg!(feature = "os") {
+            self.threads
+        } else {
+            1
+        };
+        let Ok(constants) = (0..bodies.len().min(threads))
+            .map(|_| {
+                self.function
+                    .chunk
+                    .constants
+                    .iter()
+                    .map(Sendable::from_value)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            return false;
+        };
+        let context = BodyContext {
+            functions: self
+                .functions
+                .iter()
+                .map(|(name, info)| (name.clone(), info.as_ref().clone()))
+                .collect(),
+            globals: self.globals.clone(),
+            file: self.function.chunk.file.to_string(),
+            functions_count: self.function.functions_count,
+            strict: self.strict,
+            print_code: self.print_code,
+        };
+
+        let queue = Mutex::new(bodies.into_iter().enumerate());
+        let mut compiled: Vec<(usize, Option<CompiledBody>)> = if constants.len() > 1 {
+            std::thread::scope(|scope| {
+                let workers: Vec<_> = constants
+                    .into_iter()
+                    .map(|constants| {
+                        scope.spawn(|| Compiler::compile_queued(&queue, &context, constants))
+                    })
+                    .collect();
+                workers
+                    .into_iter()
+                    .flat_map(|worker| worker.join().unwrap())
+                    .collect()
+            })
+        } else {
+            constants
+                .into_iter()
+                .flat_map(|constants| Compiler::compile_queued(&queue, &context, constants))
+                .collect()
+        };
+        compiled.sort_by_key(|(index, _)| *index);
+
+        let mut warnings = std::mem::take(&mut self.parser.warnings);
+        let mut added = 0;
+        for ((_, body), (constant, warnings_before)) in compiled.into_iter().zip(places) {
+            let Some(body) = body else {
+                return false;
+            };
+            self.function.chunk.constants[constant] =
+                Value::ObjFunction(Rc::new(body.function.into_function()));
+            self.listing.push_str(&body.listing);
+            let at = warnings_before + added;
+            added += body.warnings.len();
+            warnings.splice(at..at, body.warnings);
+        }
+        self.parser.warnings = warnings;
+        true
+    }
+
+    // Runs on each thread of a parallel compile, taking bodies until none is left
+    fn compile_queued(
+        queue: &Mutex<impl Iterator<Item = (usize, Body)>>,
+        context: &BodyContext,
+        constants: Vec<Sendable>,
+    ) -> Vec<(usize, Option<CompiledBody>)> {
+        let constants: Vec<Value> = constants.into_iter().map(Sendable::into_value).collect();
+        let functions: HashMap<String, Rc<FunctionInfo>> = context
+            .functions
+            .iter()
+            .map(|(name, info)| (name.clone(), Rc::new(info.clone())))
+            .collect();
+
+        let mut compiled = Vec::new();
+        loop {
+            let next = queue.lock().unwrap().next();
+            let Some((index, body)) = next else {
+                return compiled;
+            };
+            let mut compiler = Compiler::new();
+            compiler.parser = body.parser;
+            compiler.function_type = FunctionType::Function;
+            compiler.locals = body.locals;
+            compiler.function.chunk.constants = constants[..body.constants].to_vec();
+            compiler.function.chunk.file = Rc::from(context.file.as_str());
+            compiler.function.functions_count = context.functions_count;
+            compiler.functions = functions.clone();
+            compiler.strict = context.strict;
+            compiler.print_code = context.print_code;
+            compiler.globals = context.globals.clone();
+
+            let function_info = Rc::clone(&functions[&compiler.parser.previous.lexeme]);
+            let function = compiler.function_body(function_info);
+            let body = (!compiler.parser.had_error)
+                .then(|| SendableFunction::from_function(&function).ok())
+                .flatten()
+                .map(|function| CompiledBody {
+                    function,
+                    listing: compiler.listing,
+                    warnings: compiler.parser.warnings,
+                });
+            compiled.push((index, body));
+        }
     }
 
     fn variable_assignment(&mut self) {
         let mut var_type = TokenType::None;
-        if get_parser().peek_current().r#type.is_type() {
-            var_type = get_parser().current.r#type;
-            get_parser().advance();
+        if self.parser.peek_current().r#type.is_type() {
+            var_type = self.parser.current.r#type;
+            self.parser.advance();
+        } else if self.is_global_assignment() {
+            self.global_assignment();
+            return;
         }
 
         let var_name_register = self.parse_variable("Expect variable name.", var_type);
-
-        if get_parser().match_token(TokenType::Equal) {
+        let slot = var_name_register.as_number();
+        // A new variable lives where its value is pushed, reassigning one
+        // leaves a copy of the value to pop
+        let reassigned = self.locals[slot].is_initialized;
+
+        if self.parser.match_token(TokenType::Equal) {
+            if self.parser.peek_current().r#type == TokenType::Identifier
+                && self.parser.peek_next().r#type == TokenType::Equal
+            {
+                self.chained_assignment(slot);
+                return;
+            }
             self.expression();
             self.set_variable(var_name_register);
+            if reassigned {
+                self.emit_byte(OpCode::OpPop);
+            }
+            self.locals[slot].assigned = true;
+        } else if !reassigned {
+            // Declared without a value, the slot holds none of its type until
+            // the variable is assigned
+            match var_type.get_none_type() {
+                Value::None => self.emit_byte(OpCode::OpNone),
+                none => self.emit_constant(none),
+            }
+            self.locals[slot].has_slot = true;
+        }
+        self.locals[slot].is_initialized = true;
+    }
+
+    // Parameters shadow variables of the enclosing code, their slots hold the
+    // arguments of the call
+    fn parameter(&mut self) {
+        let mut var_type = TokenType::None;
+        if self.parser.peek_current().r#type.is_type() {
+            var_type = self.parser.current.r#type;
+            self.parser.advance();
+        }
+        self.parser
+            .consume(TokenType::Identifier, "Expect variable name.");
+        let name = self.parser.previous.clone();
+        self.locals.push(Local {
+            is_initialized: true,
+            has_slot: true,
+            assigned: true,
+            ..Local::new(name, self.scope_depth, var_type)
+        });
+    }
+
+    // Assigning a global of the VM updates it from anywhere, a block or a
+    // function body included, unless a local of that name is in scope. Only a
+    // declaration with a type makes a new local instead.
+    fn is_global_assignment(&self) -> bool {
+        let name = &self.parser.peek_current().lexeme;
+        self.globals.contains(name) && !self.locals.contains(name)
+    }
+
+    fn global_assignment(&mut self) {
+        self.parser.advance();
+        let name = self.parser.previous.lexeme.clone();
+        if self.parser.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_global(OpCode::OpSetGlobal, name);
+        } else {
+            // A global on its own is read like any other expression statement
+            self.emit_global(OpCode::OpGetGlobal, name);
+            if matches!(self.function_type, FunctionType::Script) && self.scope_depth == 0 {
+                self.last_expression = Some(self.immut_current_chunk().code.len());
+            }
+        }
+        self.emit_byte(OpCode::OpPop);
+    }
+
+    fn emit_global(&mut self, op_code: OpCode, name: String) {
+        let constant = self.current_chunk().add_constant(Value::from(name));
+        self.emit_2_bytes(op_code, OpCode::Number(constant));
+    }
+
+    // `x = y = 0`, every target gets the value of the last expression
+    fn chained_assignment(&mut self, first: usize) {
+        let mut targets = vec![first];
+        while self.parser.peek_current().r#type == TokenType::Identifier
+            && self.parser.peek_next().r#type == TokenType::Equal
+        {
+            self.parser.advance();
+            targets.push(self.declare_variable(TokenType::None));
+            self.parser.advance();
+        }
+
+        self.reserve_slots(&targets);
+        let start = self.current_chunk().code.len();
+        self.expression();
+        let end = self.current_chunk().code.len();
+
+        for &target in &targets {
+            self.store_variable(target, start, end);
+        }
+        self.emit_byte(OpCode::OpPop);
+        self.finish_assignment(&targets);
+    }
+
+    // `a, b = b, a`, all values are computed before any target is stored so
+    // the right-hand side only sees the old values
+    fn multiple_assignment(&mut self) {
+        let mut targets = Vec::new();
+        loop {
+            self.parser
+                .consume(TokenType::Identifier, "Expect variable name.");
+            targets.push(self.declare_variable(TokenType::None));
+            if !self.parser.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.parser
+            .consume(TokenType::Equal, "Expect '=' after variable names.");
+
+        self.reserve_slots(&targets);
+        let mut values = Vec::new();
+        for i in 0..targets.len() {
+            if i > 0 {
+                let message = format!("Expect {} values to assign.", targets.len());
+                self.parser.consume(TokenType::Comma, &message);
+            }
+            let start = self.current_chunk().code.len();
+            self.expression();
+            values.push((start, self.current_chunk().code.len()));
+        }
+        if self.parser.check(TokenType::Comma) {
+            let message = format!("Expect {} values to assign.", targets.len());
+            self.parser.error_at_current(&message);
+        }
+
+        for (&target, &(start, end)) in targets.iter().zip(values.iter()).rev() {
+            self.store_variable(target, start, end);
+            self.emit_byte(OpCode::OpPop);
+        }
+        self.finish_assignment(&targets);
+    }
+
+    // New variables get their slot before the values are computed, the
+    // values are then stored into them like into any existing variable
+    fn reserve_slots(&mut self, targets: &[usize]) {
+        for &target in targets {
+            let local = &mut self.locals[target];
+            if !local.is_initialized && !local.has_slot {
+                local.has_slot = true;
+                self.emit_byte(OpCode::OpNone);
+            }
+        }
+    }
+
+    // Stores the value on top of the stack, checking its type when the code
+    // between start and end is a literal
+    fn store_variable(&mut self, target: usize, start: usize, end: usize) {
+        if let Some(value) = self.literal_between(start, end) {
+            let local = &self.locals[target];
+            if !local.type_.is_value_correct_type(&value) {
+                self.parser.error_at_previous(&format!(
+                    "Variable {} is of type {} but value is of type {}",
+                    local.name.lexeme,
+                    local.type_,
+                    value.type_of()
+                ));
+            }
+            self.set_value(OpCode::Number(target), value);
+        }
+        self.emit_2_bytes(OpCode::OpSet, OpCode::Number(target));
+    }
+
+    fn finish_assignment(&mut self, targets: &[usize]) {
+        for &target in targets {
+            self.locals[target].is_initialized = true;
+            self.locals[target].assigned = true;
         }
-        self.locals[var_name_register.as_number()].is_initialized = true;
     }
 
     fn parse_variable(&mut self, message: &str, var_type: TokenType) -> OpCode {
-        get_parser().consume(TokenType::Identifier, message);
+        self.parser.consume(TokenType::Identifier, message);
 
         let index = self.declare_variable(var_type);
-        return OpCode::Number(index);
+        OpCode::Number(index)
     }
 
     fn declare_variable(&mut self, var_type: TokenType) -> usize {
-        let name = get_parser().previous.clone();
-        return self.add_local(name, var_type);
+        let name = self.parser.previous.clone();
+        self.add_local(name, var_type)
     }
 
+    // Assigning to a name in scope reuses its variable, declaring it again
+    // with another type is an error
     fn add_local(&mut self, name: Token, var_type: TokenType) -> usize {
-        for i in (0..self.locals.len()).rev() {
-            if name.lexeme == self.locals[i].name.lexeme {
-                return i;
+        if let Some(slot) = self.locals.resolve(&name.lexeme) {
+            let local = &self.locals[slot];
+            if var_type.is_type() && local.type_ != var_type {
+                let declared = if local.type_.is_type() {
+                    format!("with type {}", local.type_)
+                } else {
+                    "without a type".to_owned()
+                };
+                self.parser.error_at_previous(&format!(
+                    "Variable {} was declared {} at line {}, columns {}-{}, it cannot be redeclared as {}.",
+                    name.lexeme,
+                    declared,
+                    local.name.line,
+                    local.span.start,
+                    local.span.end.saturating_sub(1).max(local.span.start),
+                    var_type
+                ));
             }
+            return slot;
         }
 
-        let local = Local {
-            name,
-            depth: self.scope_depth,
-            type_: var_type,
-            is_initialized: false,
-        };
-        self.locals.push(local);
-        return self.locals.len() - 1;
+        self.locals
+            .push(Local::new(name, self.scope_depth, var_type))
     }
 
     fn set_variable(&mut self, var_name_register: OpCode) {
-        let local = self.locals[var_name_register.as_number()].clone();
-        let value;
-        match self.immut_current_chunk().constants.last() {
+        // Unresolved names were already reported, the VM refuses their slot
+        let local = match self.locals.get(var_name_register.as_number()) {
+            Some(local) => local.clone(),
             None => {
-                get_parser().error_at_previous("No value found to assign to the variable.");
+                self.emit_2_bytes(OpCode::OpSet, var_name_register);
                 return;
             }
-            Some(v) => {
-                value = v;
+        };
+        // A copy of another variable, whose value is only known by its type
+        let end = self.immut_current_chunk().code.len();
+        if end >= 2 && self.immut_current_chunk().code[end - 2] == OpCode::OpGet {
+            if let Some(type_) = self.declared_type_between(end - 2, end) {
+                if local.type_.is_type() && local.type_ != type_ {
+                    self.parser.error_at_previous(&format!(
+                        "Variable {} is of type {} but value is of type {}",
+                        local.name.lexeme, local.type_, type_
+                    ));
+                }
             }
+            self.emit_2_bytes(OpCode::OpSet, var_name_register);
+            return;
         }
+        // Booleans and none are pushed by their own opcode, not from a constant
+        let pushed = match self.immut_current_chunk().code.last() {
+            Some(OpCode::OpTrue) => Some(Value::True),
+            Some(OpCode::OpFalse) => Some(Value::False),
+            Some(OpCode::OpNone) => Some(Value::None),
+            _ => None,
+        };
+        // The name of a global or a method is a constant but not the value
+        // being assigned
+        let code = &self.immut_current_chunk().code;
+        let reads_name = code.len() >= 2 && code[code.len() - 2] == OpCode::OpGetGlobal
+            || code.len() >= 3 && code[code.len() - 3] == OpCode::OpInvokeBuiltin;
+        let value = match pushed
+            .as_ref()
+            .or(self.function.chunk.constants.last())
+            .filter(|_| !reads_name)
+        {
+            None => {
+                // No constant to infer the type from, e.g. `total = start` in a function
+                self.emit_2_bytes(OpCode::OpSet, var_name_register);
+                return;
+            }
+            Some(v) => v,
+        };
 
         if !local.type_.is_value_correct_type(value) {
-            get_parser().error_at_previous(&format!(
+            self.parser.error_at_previous(&format!(
                 "Variable {} is of type {} but value is of type {}",
                 local.name.lexeme,
                 local.type_,
@@ -422,28 +1463,77 @@ impl Compiler {
             .or_insert(value.clone());
     }
 
-    fn synchronize(&mut self) {
-        get_parser().panic_mode = false;
+    // Skips to the next statement after an error, so one mistake gives one
+    // error. Braces the statement opened are skipped up to their closing one,
+    // otherwise the rest of a block would be compiled out of place.
+    fn synchronize(&mut self, braces: usize) {
+        self.parser.panic_mode = false;
 
-        while get_parser().current.r#type != TokenType::Eof {
-            if get_parser().previous.r#type == TokenType::Newline {
-                return;
+        while self.parser.current.r#type != TokenType::Eof {
+            if self.parser.braces <= braces {
+                if self.parser.previous.r#type == TokenType::Newline {
+                    return;
+                }
+                match self.parser.current.r#type {
+                    TokenType::If
+                    | TokenType::While
+                    | TokenType::For
+                    | TokenType::Loop
+                    | TokenType::Do
+                    | TokenType::Print
+                    | TokenType::Time
+                    | TokenType::Defer
+                    | TokenType::With
+                    | TokenType::Try
+                    | TokenType::Raise
+                    | TokenType::Return
+                    | TokenType::Break
+                    | TokenType::Continue => return,
+                    // A stray brace at the top level has nothing to close
+                    TokenType::RightBrace if self.parser.braces > 0 => return,
+                    _ => (),
+                }
             }
 
-            get_parser().advance();
+            self.parser.advance();
         }
     }
 
     fn statement(&mut self) {
-        if get_parser().match_token(TokenType::Print) {
+        if self.parser.is_loop_label() {
+            self.labeled_statement();
+        } else if self.parser.match_token(TokenType::Print) {
             self.print_statement();
-        } else if get_parser().match_token(TokenType::If) {
+        } else if self.parser.match_token(TokenType::If) {
             self.if_statement();
-        } else if get_parser().match_token(TokenType::While) {
+        } else if self.parser.match_token(TokenType::While) {
             self.while_statement();
-        } else if get_parser().match_token(TokenType::For) {
+        } else if self.parser.match_token(TokenType::Loop) {
+            self.loop_statement();
+        } else if self.parser.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.parser.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.parser.match_token(TokenType::Do) {
+            self.do_while_statement();
+        } else if self.parser.match_token(TokenType::For) {
             self.for_statement();
-        } else if get_parser().match_token(TokenType::LeftBrace) {
+        } else if self.parser.match_token(TokenType::Yield) {
+            self.yield_statement();
+        } else if self.parser.match_token(TokenType::Time) {
+            self.time_statement();
+        } else if self.parser.match_token(TokenType::Defer) {
+            self.defer_statement();
+        } else if self.parser.match_token(TokenType::With) {
+            self.with_statement();
+        } else if self.parser.check(TokenType::Try)
+            && self.parser.peek_next().r#type == TokenType::LeftBrace
+        {
+            self.parser.advance();
+            self.try_statement();
+        } else if self.parser.match_token(TokenType::Raise) {
+            self.raise_statement();
+        } else if self.parser.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
             self.end_scope();
@@ -453,68 +1543,536 @@ impl Compiler {
     }
 
     fn for_statement(&mut self) {
-        todo!("Finish for loops when methods are implemented.");
-        // self.begin_scope();
-        // let loop_start = self.current_chunk().code.len();
+        self.begin_scope();
+
+        let variables = self.loop_variable_names();
+        self.expression();
+        self.emit_byte(OpCode::OpGetIter);
+
+        // The iterator is kept in a hidden local so the loop finds it by slot
+        let iterator = self.hidden_local("iterator", &variables[0]);
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_2_bytes(OpCode::OpForIter, OpCode::Number(iterator));
+        self.emit_byte(OpCode::Number(0));
+        let exit_jump = self.current_chunk().code.len() - 1;
+
+        // The loop variables are in a scope of their own, popped at the end of
+        // each iteration and by a break like the locals of the body
+        let before = self.locals.assigned();
+        self.begin_loop();
+        self.begin_scope();
+        self.loop_variables(variables);
+        self.statement();
+        self.end_scope();
+        self.patch_continues();
+        self.emit_loop(loop_start);
+        self.locals.set_assigned(&before);
+
+        self.patch_jump(exit_jump);
+        // The none pushed in place of the item once the iterator is done
+        self.emit_byte(OpCode::OpPop);
+        self.end_loop();
+        self.end_scope();
+    }
+
+    // `x in` or `k, v in`
+    fn loop_variable_names(&mut self) -> Vec<Token> {
+        let mut variables = Vec::new();
+        loop {
+            self.parser
+                .consume(TokenType::Identifier, "Expect loop variable name.");
+            variables.push(self.parser.previous.clone());
+            if !self.parser.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.parser
+            .consume(TokenType::In, "Expect 'in' after loop variable.");
+        variables
+    }
 
-        // println!("{:?}", get_parser().peek_next_2());
-        // // self.variable_assignment();
-        // get_parser().consume(
-        //     TokenType::In,
-        //     "Expect in after variable declaration in for loop.",
-        // );
+    fn yield_statement(&mut self) {
+        self.yield_expression(false);
+        // Drops the value sent back by the resume
+        self.emit_byte(OpCode::OpPop);
+        self.emit_eol();
+    }
+
+    fn yield_expression(&mut self, _can_assign: bool) {
+        if let FunctionType::Script = self.function_type {
+            self.parser
+                .error_at_previous("Can't yield from top-level code.");
+        }
+        if self.in_defer {
+            self.parser
+                .error_at_previous("Can't yield from deferred code.");
+        }
 
-        // self.statement();
-        // self.emit_loop(loop_start);
-        // self.end_scope();
+        // Any function that yields becomes a generator, calling it only creates the generator
+        self.function.is_generator = true;
+        self.expression();
+        self.emit_byte(OpCode::OpYield);
     }
 
     fn while_statement(&mut self) {
         let loop_start = self.current_chunk().code.len();
         self.expression();
+        self.check_condition(loop_start);
+
+        // A test instruction leaves no condition on the stack to pop
+        let test_jump = self.fuse_test(loop_start);
+        let exit_jump = match test_jump {
+            Some(jump) => jump,
+            None => {
+                let jump = self.emit_jump(OpCode::OpJumpIfFalse);
+                self.emit_byte(OpCode::OpPop);
+                jump
+            }
+        };
+        // The body may not run at all, what it assigns is not assigned after
+        let before = self.locals.assigned();
+        self.begin_loop();
+        self.statement();
+        self.patch_continues();
+        self.emit_loop(loop_start);
+        self.locals.set_assigned(&before);
+
+        self.patch_jump(exit_jump);
+        if test_jump.is_none() {
+            self.emit_byte(OpCode::OpPop);
+        }
+        self.end_loop();
+    }
+
+    // Conditions like `i < n` or `i < 10` run every iteration of a loop, so
+    // instead of pushing both sides, comparing and jumping on the result they
+    // are compiled to a single test instruction. Returns the offset of its
+    // jump operand, to be patched.
+    fn fuse_test(&mut self, start: usize) -> Option<usize> {
+        use OpCode::{Number, OpConstant, OpGet};
+        let chunk = self.immut_current_chunk();
+        let (test, left, right, comparison) = match chunk.code.get(start..)? {
+            [OpGet, Number(left), OpGet, Number(right), comparison] => {
+                (OpCode::OpTestLocals, *left, *right, comparison)
+            }
+            [OpGet, Number(left), OpConstant, Number(right), comparison] => {
+                (OpCode::OpTestLocalConstant, *left, *right, comparison)
+            }
+            _ => return None,
+        };
+        let comparison = TEST_COMPARISONS.iter().position(|op| op == comparison)?;
+        // Unresolved variables are reported by OP_GET at runtime
+        if left == usize::MAX || (test == OpCode::OpTestLocals && right == usize::MAX) {
+            return None;
+        }
+        // Errors point at the whole comparison, like they did before fusing
+        let end = chunk.code.len() - 1;
+        let line = chunk.get_line(end);
+        let span = chunk.get_span(end)?;
+
+        for _ in start..=end {
+            self.current_chunk().pop();
+        }
+        for byte in [
+            test,
+            OpCode::Number(left),
+            OpCode::Number(right),
+            OpCode::Number(comparison),
+            OpCode::Number(0),
+        ] {
+            self.current_chunk().write(byte, line, span);
+        }
+        Some(self.immut_current_chunk().code.len() - 1)
+    }
+
+    // Without a condition, break is the only way out of the loop
+    fn loop_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.begin_loop();
+        self.statement();
+        self.patch_continues();
+        self.emit_loop(loop_start);
+
+        // Only the breaks reach the code after the loop
+        match join_assigned(&self.loops.last().unwrap().break_assigned) {
+            Some(joined) => self.locals.set_assigned(&joined),
+            None => self.locals.assign_all(),
+        }
+        self.end_loop();
+    }
+
+    fn labeled_statement(&mut self) {
+        self.parser.advance();
+        let label = self.parser.previous.lexeme.clone();
+        if self
+            .loops
+            .iter()
+            .any(|enclosing| enclosing.label.as_ref() == Some(&label))
+        {
+            let message = format!("Label '{}' is already used by an enclosing loop.", label);
+            self.parser.error_at_previous(&message);
+        }
+        self.parser.advance();
+
+        self.next_label = Some(label);
+        self.statement();
+    }
+
+    fn begin_loop(&mut self) {
+        self.loops.push(Loop {
+            label: self.next_label.take(),
+            scope_depth: self.scope_depth,
+            tries: self.tries.len(),
+            breaks: Vec::new(),
+            continues: Vec::new(),
+            break_assigned: Vec::new(),
+            continue_assigned: Vec::new(),
+        });
+    }
+
+    fn patch_continues(&mut self) {
+        let continues = std::mem::take(&mut self.loops.last_mut().unwrap().continues);
+        for jump in continues {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().unwrap();
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        if let Some((target, jump)) = self.jump_out_of_body("break") {
+            self.loops[target].breaks.push(jump);
+            self.loops[target]
+                .break_assigned
+                .push(self.locals.assigned());
+        }
+        self.locals.assign_all();
+    }
+
+    fn continue_statement(&mut self) {
+        if let Some((target, jump)) = self.jump_out_of_body("continue") {
+            self.loops[target].continues.push(jump);
+            self.loops[target]
+                .continue_assigned
+                .push(self.locals.assigned());
+        }
+        self.locals.assign_all();
+    }
+
+    // Jumps to the innermost loop, or to the enclosing loop named after the
+    // keyword as in `break outer`. Returns that loop with the jump to patch.
+    fn jump_out_of_body(&mut self, keyword: &str) -> Option<(usize, usize)> {
+        let target = if self.parser.check(TokenType::Identifier) {
+            self.parser.advance();
+            let label = self.parser.previous.lexeme.clone();
+            let target = self
+                .loops
+                .iter()
+                .rposition(|enclosing| enclosing.label.as_ref() == Some(&label));
+            if target.is_none() {
+                let message = format!("No enclosing loop is labeled '{}'.", label);
+                self.parser.error_at_previous(&message);
+            }
+            target
+        } else if self.loops.is_empty() {
+            let message = format!("Can't use '{}' outside of a loop.", keyword);
+            self.parser.error_at_previous(&message);
+            None
+        } else {
+            Some(self.loops.len() - 1)
+        }?;
+
+        // The try blocks jumped out of no longer catch, each runs its finally
+        // code after the code deferred inside it
+        for index in (self.loops[target].tries..self.tries.len()).rev() {
+            self.run_deferred_deeper_than(self.tries[index]);
+            self.emit_byte(OpCode::OpEndTry);
+        }
+        self.run_deferred_deeper_than(self.loops[target].scope_depth);
+        self.pop_locals_deeper_than(self.loops[target].scope_depth);
+        Some((target, self.emit_jump(OpCode::OpJump)))
+    }
+
+    // Code deferred in the scopes being left runs before their locals are
+    // dropped, the most recently deferred first
+    fn run_deferred_deeper_than(&mut self, depth: usize) {
+        if self.defers.iter().any(|deferred| *deferred > depth) {
+            self.emit_2_bytes(OpCode::OpRunDeferred, OpCode::Number(depth + 1));
+        }
+    }
+
+    // Leaving a loop early drops the locals of the scopes it jumps out of,
+    // they stay declared since the rest of the block is still compiled.
+    fn pop_locals_deeper_than(&mut self, depth: usize) {
+        let count = self
+            .locals
+            .iter()
+            .filter(|local| local.depth > depth)
+            .count();
+        for _ in 0..count {
+            self.emit_byte(OpCode::OpPop);
+        }
+    }
+
+    // The body runs once before the condition is first checked
+    fn do_while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.begin_loop();
+        self.statement();
+        self.patch_continues();
+
+        // The condition is reached from the end of the body and from each continue
+        let mut paths = self.loops.last().unwrap().continue_assigned.clone();
+        paths.push(self.locals.assigned());
+        if let Some(joined) = join_assigned(&paths) {
+            self.locals.set_assigned(&joined);
+        }
+
+        self.parser
+            .consume(TokenType::While, "Expect 'while' after do body.");
+        let condition_start = self.current_chunk().code.len();
+        self.expression();
+        self.check_condition(condition_start);
 
         let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
         self.emit_byte(OpCode::OpPop);
-        self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
         self.emit_byte(OpCode::OpPop);
 
-        // Handle break statement
-        if get_parser().match_token(TokenType::Break) {
-            self.emit_jump(OpCode::OpJump);
+        // The code after the loop is reached from the condition and from each break
+        let mut paths = self.loops.last().unwrap().break_assigned.clone();
+        paths.push(self.locals.assigned());
+        if let Some(joined) = join_assigned(&paths) {
+            self.locals.set_assigned(&joined);
         }
+        self.end_loop();
     }
 
     fn emit_loop(&mut self, loop_start: usize) {
         self.emit_byte(OpCode::OpLoop);
-        let offset = self.current_chunk().code.len() - loop_start + 2;
+        // The offset is read after the operand, hence the + 1
+        let offset = self.current_chunk().code.len() - loop_start + 1;
         self.emit_byte(OpCode::Number(offset));
     }
 
     fn if_statement(&mut self) {
+        let condition_start = self.current_chunk().code.len();
         self.expression();
+        self.check_condition(condition_start);
 
         let then_jump = self.emit_jump(OpCode::OpJumpIfFalse);
         self.emit_byte(OpCode::OpPop);
+        let before = self.locals.assigned();
         self.statement();
+        let then_assigned = self.locals.assigned();
+        self.locals.set_assigned(&before);
 
         let else_jump = self.emit_jump(OpCode::OpJump);
 
         self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OpPop);
 
-        if get_parser().match_token(TokenType::Else) {
+        if self.parser.match_token(TokenType::Else) {
             self.statement();
         }
         self.patch_jump(else_jump);
+        self.join_branches(then_assigned);
+    }
+
+    // A variable is assigned after an if when both branches assign it, the
+    // current state is the one at the end of the else branch
+    fn join_branches(&mut self, then_assigned: Vec<bool>) {
+        let else_assigned = self.locals.assigned();
+        if let Some(joined) = join_assigned(&[then_assigned, else_assigned]) {
+            self.locals.set_assigned(&joined);
+        }
+    }
+
+    // An if in expression position, e.g. `x = if cond { 1 } else { 2 }`. Each
+    // branch is a block whose final expression is its value, a missing else
+    // branch gives none.
+    fn if_expression(&mut self, _can_assign: bool) {
+        let pending = self.set_aside_pending();
+
+        let condition_start = self.current_chunk().code.len();
+        self.expression();
+        self.check_condition(condition_start);
+
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop);
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' after condition.");
+        let before = self.locals.assigned();
+        self.block_expression();
+        let then_assigned = self.locals.assigned();
+        self.locals.set_assigned(&before);
+
+        let else_jump = self.emit_jump(OpCode::OpJump);
+        self.patch_jump(then_jump);
         self.emit_byte(OpCode::OpPop);
+
+        if self.parser.match_token(TokenType::Else) {
+            if self.parser.match_token(TokenType::If) {
+                self.if_expression(false);
+            } else {
+                self.parser
+                    .consume(TokenType::LeftBrace, "Expect '{' after else.");
+                self.block_expression();
+            }
+        } else {
+            self.emit_byte(OpCode::OpNone);
+        }
+        self.patch_jump(else_jump);
+        self.join_branches(then_assigned);
+
+        if let Some(local) = pending {
+            self.locals.push(local);
+        }
+    }
+
+    // The variable being declared has no slot until the value is computed, it
+    // is put aside so locals of the expression get the right slots. It is
+    // pushed back once the value is on top.
+    fn set_aside_pending(&mut self) -> Option<Local> {
+        match self.locals.last() {
+            Some(local) if !local.is_initialized && !local.has_slot => self.locals.pop(),
+            _ => None,
+        }
+    }
+
+    // Statements inside the block leave nothing behind and expressions that
+    // are not last are popped, so exactly one value is left on the stack.
+    fn block_expression(&mut self) {
+        self.begin_scope();
+
+        let mut has_value = false;
+        loop {
+            while self.parser.match_token(TokenType::Newline) {}
+            if self.parser.check(TokenType::RightBrace) || self.parser.check(TokenType::Eof) {
+                break;
+            }
+
+            has_value = false;
+            if self.parser.starts_statement() {
+                self.declaration();
+                continue;
+            }
+
+            self.expression();
+            while self.parser.match_token(TokenType::Newline) {}
+            if self.parser.check(TokenType::RightBrace) {
+                has_value = true;
+            } else {
+                self.emit_byte(OpCode::OpPop);
+            }
+        }
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after block");
+
+        if !has_value {
+            self.emit_byte(OpCode::OpNone);
+        }
+        self.end_scope_keeping_value();
+    }
+
+    // The value on top is moved into the slot of the first local of the scope,
+    // which then becomes the top once the other locals are popped.
+    fn end_scope_keeping_value(&mut self) {
+        self.end_deferred();
+        self.scope_depth -= 1;
+
+        let count = self
+            .locals
+            .iter()
+            .filter(|local| local.depth > self.scope_depth)
+            .count();
+        if count == 0 {
+            return;
+        }
+
+        let first_slot = self.locals.len() - count;
+        self.emit_2_bytes(OpCode::OpSet, OpCode::Number(first_slot));
+        for _ in 0..count {
+            self.emit_byte(OpCode::OpPop);
+        }
+        self.locals.truncate(first_slot);
+    }
+
+    // The value pushed when the code between start and end is a lone literal
+    fn literal_between(&self, start: usize, end: usize) -> Option<Value> {
+        let chunk = self.immut_current_chunk();
+        match chunk.code.get(start..end)? {
+            [OpCode::OpConstant, OpCode::Number(index)] => chunk.constants.get(*index).cloned(),
+            [OpCode::OpTrue] => Some(Value::True),
+            [OpCode::OpFalse] => Some(Value::False),
+            [OpCode::OpNone] => Some(Value::None),
+            _ => None,
+        }
+    }
+
+    fn check_condition(&mut self, start: usize) {
+        if !self.strict {
+            return;
+        }
+        let end = self.immut_current_chunk().code.len();
+        // Anything else is only known at runtime, where the VM checks it
+        let found = match self.literal_between(start, end) {
+            Some(Value::True | Value::False) => None,
+            Some(value) => Some(value.type_of()),
+            None => self
+                .declared_type_between(start, end)
+                .filter(|type_| *type_ != TokenType::TypeBool)
+                .map(|type_| type_.to_string()),
+        };
+        if let Some(found) = found {
+            let message = format!("Condition must be of type bool, got {} instead.", found);
+            self.parser.error_at_previous(&message);
+        }
+    }
+
+    // A value of the type the code between start and end pushes, the literal
+    // itself or one of the declared type of the variable it reads
+    fn operand_between(&self, start: usize, end: usize) -> Option<Value> {
+        if let Some(literal) = self.literal_between(start, end) {
+            return Some(literal);
+        }
+        match self.declared_type_between(start, end)? {
+            TokenType::TypeInt => Some(Value::Integer(0)),
+            TokenType::TypeFloat => Some(Value::Float(0.0)),
+            TokenType::TypeDecimal => Decimal::parse("0").ok().map(Value::from),
+            TokenType::TypeString => Some(Value::from("")),
+            TokenType::TypeBool => Some(Value::False),
+            _ => None,
+        }
+    }
+
+    // The declared type of the variable read by the code between start and end
+    fn declared_type_between(&self, start: usize, end: usize) -> Option<TokenType> {
+        let slot = match self.immut_current_chunk().code.get(start..end)? {
+            [OpCode::OpGet, OpCode::Number(slot)] => *slot,
+            _ => return None,
+        };
+        let type_ = self.locals.get(slot)?.type_;
+        type_.is_type().then_some(type_)
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
         self.emit_byte(instruction);
+        self.emit_jump_operand()
+    }
+
+    // A jump offset to patch once the target is known
+    fn emit_jump_operand(&mut self) -> usize {
         self.emit_byte(OpCode::Number(0));
-        return self.current_chunk().code.len() - 1;
+        self.current_chunk().code.len() - 1
     }
 
     fn patch_jump(&mut self, offset: usize) {
@@ -522,107 +2080,507 @@ impl Compiler {
         self.current_chunk().code[offset] = OpCode::Number(jump);
     }
 
-    fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn block(&mut self) {
+        // Set by a break or continue, anything after it in the block never runs
+        let mut jumped_out: Option<String> = None;
+        while !self.parser.check(TokenType::RightBrace) && !self.parser.check(TokenType::Eof) {
+            if !self.parser.check(TokenType::Newline) {
+                if let Some(keyword) = jumped_out.take() {
+                    self.parser
+                        .warn_at_current(&format!("Unreachable code after {}.", keyword));
+                }
+            }
+            let current = &self.parser.current;
+            if matches!(current.r#type, TokenType::Break | TokenType::Continue) {
+                jumped_out = Some(current.lexeme.clone());
+            }
+            self.declaration();
+        }
+
+        self.parser
+            .consume(TokenType::RightBrace, "Expect '}' after block")
+    }
+
+    fn end_scope(&mut self) {
+        self.end_deferred();
+        self.scope_depth -= 1;
+
+        for i in (0..self.locals.len()).rev() {
+            if self.locals[i].depth > self.scope_depth {
+                self.emit_byte(OpCode::OpPop);
+                self.locals.pop();
+            }
+        }
+    }
+
+    // Runs the code deferred in the scope about to end
+    fn end_deferred(&mut self) {
+        self.run_deferred_deeper_than(self.scope_depth - 1);
+        let depth = self.scope_depth;
+        self.defers.retain(|deferred| *deferred < depth);
+    }
+
+    fn expression_statement(&mut self) {
+        // A blank line, or the start of the source, has no value to pop
+        if self.parser.match_token(TokenType::Newline) || self.parser.match_token(TokenType::Empty)
+        {
+            self.emit_eol();
+            return;
+        }
+        self.expression();
+        if matches!(self.function_type, FunctionType::Script) && self.scope_depth == 0 {
+            self.last_expression = Some(self.immut_current_chunk().code.len());
+        }
+        self.emit_byte(OpCode::OpPop);
+        self.emit_eol();
+    }
+
+    fn expression(&mut self) {
+        self.parse_precendence(Precedence::Assignment);
+    }
+
+    // `time { ... }` runs the block and prints how long it took
+    fn time_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.begin_scope();
+
+        // The stopwatch is kept in a hidden local like the iterator of a for loop
+        self.emit_native(STOPWATCH_START, 0);
+        let stopwatch = self.hidden_local("stopwatch", &keyword);
+
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' after time.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        self.emit_2_bytes(OpCode::OpGet, OpCode::Number(stopwatch));
+        self.emit_constant(Value::Integer(keyword.line as i64));
+        self.emit_native(TIME_REPORT, 2);
+        self.emit_byte(OpCode::OpPop);
+        self.end_scope();
+    }
+
+    // `defer statement` runs the statement when the enclosing scope ends,
+    // whether at its closing brace, by a break or continue, or by exit. The
+    // code is compiled in place and jumped over, the VM registers it when
+    // the defer is reached.
+    fn defer_statement(&mut self) {
+        self.deferred(|compiler| {
+            // Jumping out of the deferred code would skip its end, so the
+            // enclosing loops are out of reach while it is compiled
+            let loops = std::mem::take(&mut compiler.loops);
+            let in_defer = std::mem::replace(&mut compiler.in_defer, true);
+            compiler.statement();
+            compiler.in_defer = in_defer;
+            compiler.loops = loops;
+        });
+    }
+
+    // Compiles the code emitted by body as deferred to the end of the scope
+    fn deferred(&mut self, body: impl FnOnce(&mut Compiler)) {
+        let depth = self.scope_depth;
+        self.emit_2_bytes(OpCode::OpDefer, OpCode::Number(depth));
+        let skip = self.emit_jump_operand();
+        body(self);
+        self.emit_byte(OpCode::OpEndDefer);
+        self.patch_jump(skip);
+        self.defers.push(depth);
+    }
+
+    // `with value as name { ... }` calls the close method of the value once
+    // the block is left, by any of the ways a deferred call would run. The
+    // name is optional and shadows like a loop variable.
+    fn with_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.begin_scope();
+
+        self.expression();
+        self.emit_native(WITH_ENTER, 1);
+        // Closed from a hidden local so reassigning the name changes nothing
+        let resource = self.hidden_local("resource", &keyword);
+        if self.parser.match_token(TokenType::As) {
+            self.parser
+                .consume(TokenType::Identifier, "Expect name after 'as'.");
+            let name = self.parser.previous.clone();
+            self.emit_2_bytes(OpCode::OpGet, OpCode::Number(resource));
+            let slot = self.locals.push(Local {
+                is_initialized: true,
+                has_slot: true,
+                assigned: true,
+                ..Local::new(name, self.scope_depth, TokenType::None)
+            });
+            self.emit_2_bytes(OpCode::OpSet, OpCode::Number(slot));
+        }
+        self.deferred(|compiler| {
+            compiler.emit_2_bytes(OpCode::OpGet, OpCode::Number(resource));
+            let close = compiler.current_chunk().add_constant(Value::from("close"));
+            compiler.emit_2_bytes(OpCode::OpInvokeBuiltin, OpCode::Number(close));
+            compiler.emit_byte(OpCode::Number(0));
+            compiler.emit_byte(OpCode::OpPop);
+        });
+
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' after with value.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.end_scope();
+    }
+
+    // `try { ... } catch name { ... }` runs the catch block when an error is
+    // raised in the try block or anything it calls, with the error in the
+    // name. Deferred code of the scopes left on the way runs first. A clause
+    // like `catch ParseError e { ... }` only takes values of that kind or
+    // type, one no clause takes is raised again. A `finally { ... }` block
+    // after them runs however the try and catch blocks are left.
+    fn try_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.emit_byte(OpCode::OpTry);
+        let finally_jump = self.emit_jump_operand();
+        let catch_jump = self.emit_jump_operand();
+        self.parser
+            .consume(TokenType::LeftBrace, "Expect '{' after try.");
+        let before = self.locals.assigned();
+        self.tries.push(self.scope_depth);
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.emit_byte(OpCode::OpEndTry);
+        let mut paths = vec![self.locals.assigned()];
+        self.locals.set_assigned(&before);
+
+        if self.parser.match_token(TokenType::Catch) {
+            let end_jump = self.emit_jump(OpCode::OpJump);
+            // The try block can fail anywhere, so the catch block only relies
+            // on what was assigned before it
+            self.patch_jump(catch_jump);
+            self.catch_clauses(&keyword, &before, &mut paths);
+            self.emit_byte(OpCode::OpEndTry);
+            self.patch_jump(end_jump);
+        } else if !self.parser.check(TokenType::Finally) {
+            self.parser
+                .error_at_previous("Expect 'catch' or 'finally' after try block.");
+        }
+        self.tries.pop();
+
+        let mut finally_assigned = Vec::new();
+        if self.parser.match_token(TokenType::Finally) {
+            let skip = self.emit_jump(OpCode::OpJump);
+            self.patch_jump(finally_jump);
+            // Run like deferred code, which nothing can jump out of
+            let loops = std::mem::take(&mut self.loops);
+            let in_defer = std::mem::replace(&mut self.in_defer, true);
+            self.parser
+                .consume(TokenType::LeftBrace, "Expect '{' after finally.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            self.in_defer = in_defer;
+            self.loops = loops;
+            self.emit_byte(OpCode::OpEndDefer);
+            self.patch_jump(skip);
+            finally_assigned = self.locals.assigned();
+        }
+
+        if let Some(joined) = join_assigned(&paths) {
+            // What the finally block assigns is assigned on every path
+            let joined: Vec<bool> = joined
+                .iter()
+                .zip(finally_assigned.iter().chain(std::iter::repeat(&false)))
+                .map(|(joined, finally)| *joined || *finally)
+                .collect();
+            self.locals.set_assigned(&joined);
+        }
+    }
+
+    // The catch clauses of a try statement, the error is on the stack
+    fn catch_clauses(&mut self, keyword: &Token, before: &[bool], paths: &mut Vec<Vec<bool>>) {
+        self.begin_scope();
+        self.emit_byte(OpCode::OpCaught);
+        let error = self.hidden_local("error", keyword);
+        let mut clause_ends = Vec::new();
+        loop {
+            let skip = self.catch_kind().map(|kind| {
+                self.emit_2_bytes(OpCode::OpGet, OpCode::Number(error));
+                let constant = self.current_chunk().add_constant(Value::from(kind));
+                self.emit_2_bytes(OpCode::OpCatches, OpCode::Number(constant));
+                let skip = self.emit_jump(OpCode::OpJumpIfFalse);
+                self.emit_byte(OpCode::OpPop);
+                skip
+            });
+            self.begin_scope();
+            if self.parser.match_token(TokenType::Identifier) {
+                let name = self.parser.previous.clone();
+                // Like a loop variable the name shadows, it never reassigns
+                self.emit_2_bytes(OpCode::OpGet, OpCode::Number(error));
+                let slot = self.locals.push(Local {
+                    is_initialized: true,
+                    has_slot: true,
+                    assigned: true,
+                    ..Local::new(name, self.scope_depth, TokenType::None)
+                });
+                self.emit_2_bytes(OpCode::OpSet, OpCode::Number(slot));
+            }
+            self.parser
+                .consume(TokenType::LeftBrace, "Expect '{' after catch.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            self.end_scope();
+            paths.push(self.locals.assigned());
+            self.locals.set_assigned(before);
+            clause_ends.push(self.emit_jump(OpCode::OpJump));
+
+            match skip {
+                Some(skip) => {
+                    self.patch_jump(skip);
+                    self.emit_byte(OpCode::OpPop);
+                }
+                None => {
+                    if self.parser.check(TokenType::Catch) {
+                        self.parser
+                            .error_at_current("A catch taking any error must come last.");
+                    }
+                    break;
+                }
+            }
+            if !self.parser.match_token(TokenType::Catch) {
+                // Left for the try blocks further out
+                self.emit_2_bytes(OpCode::OpGet, OpCode::Number(error));
+                self.emit_byte(OpCode::OpRaise);
+                break;
+            }
+        }
+        for jump in clause_ends {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // `try value` gives ok(value), or err(error) with what evaluating the
+    // value raised instead of unwinding, e.g. `try read_file(path)`
+    fn try_expression(&mut self, _can_assign: bool) {
+        self.emit_byte(OpCode::OpTry);
+        // No finally code
+        self.emit_byte(OpCode::Number(0));
+        let catch_jump = self.emit_jump_operand();
+        self.tries.push(self.scope_depth);
+        self.parse_precendence(Precedence::Unary);
+        self.emit_native("ok", 1);
+        self.emit_byte(OpCode::OpEndTry);
+        let end_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(catch_jump);
+        self.emit_byte(OpCode::OpCaught);
+        self.emit_native("err", 1);
+        self.emit_byte(OpCode::OpEndTry);
+        self.patch_jump(end_jump);
+        self.tries.pop();
     }
 
-    fn block(&mut self) {
-        while !get_parser().check(TokenType::RightBrace) && !get_parser().check(TokenType::Eof) {
-            self.declaration();
+    // The kind of error or type a catch clause takes, when a name follows it
+    fn catch_kind(&mut self) -> Option<String> {
+        let kind = self.parser.peek_current().r#type;
+        if (kind != TokenType::Identifier && !kind.is_type())
+            || self.parser.peek_next().r#type != TokenType::Identifier
+        {
+            return None;
         }
-
-        get_parser().consume(TokenType::RightBrace, "Expect '}' after block")
-    }
-
-    fn end_scope(&mut self) {
-        self.scope_depth -= 1;
-
-        for i in (0..self.locals.len()).rev() {
-            if self.locals[i].depth > self.scope_depth {
-                self.emit_byte(OpCode::OpPop);
-                self.locals.pop();
-            }
+        self.parser.advance();
+        match kind {
+            // The only type named differently from its keyword
+            TokenType::TypeChannel => Some("channel".to_owned()),
+            _ => Some(self.parser.previous.lexeme.clone()),
         }
     }
 
-    fn expression_statement(&mut self) {
+    // `raise value` unwinds to the innermost try block whose catch takes the
+    // value, the value being any error or other value
+    fn raise_statement(&mut self) {
         self.expression();
+        if !self.parser.check(TokenType::RightBrace) {
+            self.parser
+                .consume(TokenType::Newline, "Expect newline after value.");
+        }
+        self.emit_byte(OpCode::OpRaise);
         self.emit_eol();
+        self.locals.assign_all();
     }
 
-    fn expression(&mut self) {
-        self.parse_precendence(Precedence::Assignment);
+    // Stores the value on top in a local of the current scope, the space in its
+    // name makes sure no identifier can refer to it
+    fn hidden_local(&mut self, name: &str, at: &Token) -> usize {
+        let token = Token {
+            r#type: TokenType::Identifier,
+            lexeme: format!(" {} {}", name, self.scope_depth),
+            line: at.line,
+            column: at.column,
+        };
+        let slot = self.add_local(token, TokenType::None);
+        self.locals[slot].is_initialized = true;
+        self.locals[slot].assigned = true;
+        self.emit_2_bytes(OpCode::OpSet, OpCode::Number(slot));
+        slot
+    }
+
+    fn emit_native(&mut self, name: &str, arg_count: usize) {
+        match find_native(name) {
+            Some(index) => {
+                self.emit_2_bytes(OpCode::OpCallNative, OpCode::Number(index));
+                self.emit_byte(OpCode::Number(arg_count));
+            }
+            None => self
+                .parser
+                .error_at_previous(&format!("Unknown native {}.", name)),
+        }
     }
 
     fn print_statement(&mut self) {
         self.expression();
-        get_parser().consume(TokenType::Newline, "Expect newline after value.");
+        // The last statement of a one line block needs no separator before the '}'
+        if !self.parser.check(TokenType::RightBrace) {
+            self.parser
+                .consume(TokenType::Newline, "Expect newline after value.");
+        }
         self.emit_byte(OpCode::OpPrint);
         self.emit_eol();
     }
 
     fn parse_precendence(&mut self, precedence: Precedence) {
-        get_parser().advance();
-        let prefix_rule = self.get_rule(get_parser().previous.r#type).prefix;
-        if prefix_rule == Compiler::none
-            && get_parser().previous.r#type != TokenType::Newline
-            && get_parser().current.r#type == TokenType::Newline
-        {
-            get_parser().error_at_previous("Expect expression.");
+        self.parser.advance();
+        let first = &self.parser.previous;
+        let enclosing = self.expression_start.replace((first.line, first.column));
+        self.parse_expression_at(precedence);
+        self.expression_start = enclosing;
+    }
+
+    fn parse_expression_at(&mut self, precedence: Precedence) {
+        let prefix_rule = self.get_rule(self.parser.previous.r#type).prefix;
+        if std::ptr::fn_addr_eq(prefix_rule, Compiler::none as fn(&mut Compiler, bool)) {
+            self.parser.error_at_previous("Expect expression.");
             return;
         }
 
         let can_assign = precedence as u8 <= Precedence::Assignment as u8;
         prefix_rule(self, can_assign);
 
-        while precedence as u8 <= self.get_rule(get_parser().current.r#type).precedence as u8 {
-            get_parser().advance();
-            let infix_rule = self.get_rule(get_parser().previous.r#type).infix;
+        while precedence as u8 <= self.get_rule(self.parser.current.r#type).precedence as u8 {
+            self.parser.advance();
+            let infix_rule = self.get_rule(self.parser.previous.r#type).infix;
             infix_rule(self, can_assign);
         }
 
-        if can_assign && get_parser().match_token(TokenType::Equal) {
-            get_parser().error_at_previous("Invalid assignment target.");
+        if can_assign && self.parser.match_token(TokenType::Equal) {
+            self.parser.error_at_previous("Invalid assignment target.");
         }
     }
 
     fn integer(&mut self, _can_assign: bool) {
-        let value = get_parser().previous.lexeme.parse::<i64>().unwrap();
-        self.emit_constant(Value::Integer(value));
+        let lexeme = self.parser.previous.lexeme.clone();
+        match lexeme.parse::<i64>() {
+            Ok(value) => self.emit_constant(Value::Integer(value)),
+            Err(_) => self.parser.error_at_previous(&format!(
+                "Integer {} is too large, the largest is {}. Use a float or a decimal instead.",
+                lexeme,
+                i64::MAX
+            )),
+        }
     }
 
     fn float(&mut self, _can_assign: bool) {
-        let value = get_parser().previous.lexeme.parse::<f64>().unwrap();
+        let value = self.parser.previous.lexeme.parse::<f64>().unwrap();
         self.emit_constant(Value::Float(value));
     }
 
+    fn decimal(&mut self, _can_assign: bool) {
+        let lexeme = self.parser.previous.lexeme.clone();
+        match Decimal::parse(lexeme.trim_end_matches('d')) {
+            Ok(value) => self.emit_constant(Value::from(value)),
+            Err(message) => self.parser.error_at_previous(&message),
+        }
+    }
+
     fn string(&mut self, _can_assign: bool) {
-        let value = get_parser().previous.lexeme.parse::<String>().unwrap();
-        self.emit_constant(Value::String(value));
+        let value = self.parser.previous.lexeme.parse::<String>().unwrap();
+        self.emit_constant(Value::from(value));
     }
 
     fn grouping(&mut self, _can_assign: bool) {
         self.expression();
-        get_parser().consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
     fn unary(&mut self, _can_assign: bool) {
-        let operator_type = get_parser().previous.r#type;
+        let operator_type = self.parser.previous.r#type;
+        let operand_start = self.current_chunk().code.len();
         self.parse_precendence(Precedence::Unary);
 
         match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::OpNegate),
+            TokenType::Minus => {
+                // Negative number literals are folded into the constant itself
+                let end = self.current_chunk().code.len();
+                let is_number = matches!(
+                    self.literal_between(operand_start, end),
+                    Some(Value::Integer(_) | Value::Float(_) | Value::Decimal(_))
+                );
+                match self.current_chunk().code.last() {
+                    Some(&OpCode::Number(index)) if is_number => {
+                        let chunk = self.current_chunk();
+                        match -chunk.constants[index].clone() {
+                            Ok(value) => chunk.constants[index] = value,
+                            Err(message) => self.parser.error_at_previous(&message),
+                        }
+                    }
+                    _ => self.emit_byte(OpCode::OpNegate),
+                }
+            }
+            TokenType::Plus => (),
             TokenType::Bang => self.emit_byte(OpCode::OpNot),
             _ => panic!("Invalid unary type."),
         }
     }
 
     fn binary(&mut self, _can_assign: bool) {
-        let operator_type = get_parser().previous.r#type;
+        let operator_type = self.parser.previous.r#type;
+        let negate = operator_type == TokenType::Is && self.parser.match_token(TokenType::Not);
         let rule = self.get_rule(operator_type);
-        let precedence = FromPrimitive::from_u8(rule.precedence as u8 + 1).unwrap();
+        let precedence =
+            FromPrimitive::from_u8(rule.precedence as u8 + 1).unwrap_or(Precedence::Primary);
+        let right_start = self.current_chunk().code.len();
         self.parse_precendence(precedence);
 
+        // Only comparisons between literals and typed variables can be checked
+        // at compile time
+        let is_comparison = !matches!(operator_type, TokenType::In | TokenType::Is)
+            && matches!(
+                rule.precedence,
+                Precedence::Equality | Precedence::Comparison
+            );
+        if self.strict && is_comparison {
+            let end = self.immut_current_chunk().code.len();
+            // The left operand is either a lone opcode, a constant with its
+            // index or a variable with its slot
+            let left = (1..=2.min(right_start))
+                .find_map(|size| self.operand_between(right_start - size, right_start));
+            let right = self.operand_between(right_start, end);
+            if let (Some(left), Some(right)) = (left, right) {
+                if !left.is_comparable_with(&right) {
+                    let message = format!(
+                        "Cannot compare {} with {} in strict mode.",
+                        left.type_of(),
+                        right.type_of()
+                    );
+                    self.parser.error_at_previous(&message);
+                }
+            }
+        }
+
         match operator_type {
             TokenType::Plus => self.emit_byte(OpCode::OpAdd),
             TokenType::Minus => self.emit_byte(OpCode::OpSubtract),
@@ -634,49 +2592,134 @@ impl Compiler {
             TokenType::GreaterEqual => self.emit_byte(OpCode::OpGreaterEqual),
             TokenType::Less => self.emit_byte(OpCode::OpLess),
             TokenType::LessEqual => self.emit_byte(OpCode::OpLessEqual),
+            TokenType::Pipe => self.emit_byte(OpCode::OpUnion),
+            TokenType::Ampersand => self.emit_byte(OpCode::OpIntersection),
+            TokenType::In => self.emit_byte(OpCode::OpIn),
+            TokenType::Is => self.emit_byte(OpCode::OpIs),
+            TokenType::DotDot => self.emit_byte(OpCode::OpConcat),
             _ => panic!("Invalid binary type."),
         }
+
+        if negate {
+            self.emit_byte(OpCode::OpNot);
+        }
     }
 
     fn literal(&mut self, _can_assign: bool) {
-        match get_parser().previous.r#type {
-            TokenType::True => self.emit_constant(Value::True),
-            TokenType::False => self.emit_constant(Value::False),
-            TokenType::None => self.emit_constant(Value::None),
+        match self.parser.previous.r#type {
+            TokenType::True => self.emit_byte(OpCode::OpTrue),
+            TokenType::False => self.emit_byte(OpCode::OpFalse),
+            TokenType::None => self.emit_byte(OpCode::OpNone),
+            TokenType::Nan => self.emit_constant(Value::Float(f64::NAN)),
+            TokenType::Inf => self.emit_constant(Value::Float(f64::INFINITY)),
             _ => panic!("Invalid literal type."),
         }
     }
 
     fn variable(&mut self, can_assign: bool) {
-        self.named_variable(get_parser().previous.lexeme.clone(), can_assign);
+        let name = self.parser.previous.lexeme.clone();
+        if self.parser.check(TokenType::LeftParen) && !self.locals.contains(&name) {
+            if let Some(index) = find_native(&name) {
+                self.native_call(index);
+                return;
+            }
+        }
+        self.named_variable(name, can_assign);
+    }
+
+    fn native_call(&mut self, index: usize) {
+        let native = &NATIVES[index];
+        self.parser.advance();
+
+        let arg_count = self.arguments();
+
+        if arg_count < native.min_args || arg_count > native.max_args {
+            self.parser.error_at_previous(&format!(
+                "Function {} expects {} arguments but got {}.",
+                native.name,
+                expected_args(native.min_args, native.max_args),
+                arg_count
+            ));
+        }
+
+        self.emit_2_bytes(OpCode::OpCallNative, OpCode::Number(index));
+        self.emit_byte(OpCode::Number(arg_count));
+    }
+
+    // `receiver.name(args)` calls a builtin method of the receiver's type
+    fn method_call(&mut self, _can_assign: bool) {
+        // The type keyword is also the name of a method, `error.function`
+        if !self.parser.match_token(TokenType::TypeFunction) {
+            self.parser
+                .consume(TokenType::Identifier, "Expect method name after '.'.");
+        }
+        let name = self.parser.previous.lexeme.clone();
+        if !is_method(&name) {
+            self.parser
+                .error_at_previous(&format!("Unknown method {}.", name));
+        }
+        // A method without arguments reads like a field, `error.message`
+        let arg_count = if self.parser.match_token(TokenType::LeftParen) {
+            self.arguments()
+        } else {
+            0
+        };
+
+        let constant = self.current_chunk().add_constant(Value::from(name));
+        self.emit_2_bytes(OpCode::OpInvokeBuiltin, OpCode::Number(constant));
+        self.emit_byte(OpCode::Number(arg_count));
     }
 
     fn named_variable(&mut self, name: String, can_assign: bool) {
+        if self.globals.contains(&name) && !self.locals.contains(&name) {
+            if can_assign && self.parser.match_token(TokenType::Equal) {
+                if self.read_only {
+                    self.parser
+                        .error_at_previous("Globals cannot be assigned in an expression.");
+                }
+                self.expression();
+                self.emit_global(OpCode::OpSetGlobal, name);
+            } else {
+                self.emit_global(OpCode::OpGetGlobal, name);
+            }
+            return;
+        }
         let arg = self.resolve_local(&name);
 
-        if can_assign && get_parser().match_token(TokenType::Equal) {
+        if can_assign && self.parser.match_token(TokenType::Equal) {
             self.expression();
             self.set_variable(arg);
+            if let Some(local) = self.locals.get_mut(arg.as_number()) {
+                local.assigned = true;
+            }
+        } else if self
+            .locals
+            .get(arg.as_number())
+            .is_some_and(|local| local.is_initialized && !local.assigned)
+        {
+            self.parser.error_at_previous(&format!(
+                "Variable {} is possibly uninitialized, not every path assigns it.",
+                name
+            ));
         }
         self.emit_2_bytes(OpCode::OpGet, arg);
     }
 
     fn resolve_local(&mut self, name: &String) -> OpCode {
-        for i in (0..self.locals.len()).rev() {
-            if self.locals[i].name.lexeme == *name {
-                if !self.locals[i].is_initialized {
-                    get_parser().error_at_previous(&format!(
-                        "Variable {} is used before being initialized.",
-                        name
-                    ));
-                }
-                return OpCode::Number(i);
+        if let Some(slot) = self.locals.resolve(name) {
+            if !self.locals[slot].is_initialized {
+                self.parser.error_at_previous(&format!(
+                    "Variable {} is used before being initialized.",
+                    name
+                ));
             }
+            return OpCode::Number(slot);
         }
 
-        get_parser().error_at_previous(&format!("Variable {} could not be found.", name));
+        self.parser
+            .error_at_previous(&format!("Variable {} could not be found.", name));
 
-        return OpCode::Number(usize::MAX);
+        OpCode::Number(usize::MAX)
     }
 
     fn and(&mut self, _can_assign: bool) {
@@ -697,80 +2740,334 @@ impl Compiler {
         self.patch_jump(end_jump);
     }
 
+    // A call to a function known by name is checked here, anything else, like
+    // a function stored in a variable, is checked by the VM when it is called
     fn call(&mut self, _can_assign: bool) {
-        let arg_count = self.argument_list();
-        self.emit_2_bytes(OpCode::OpCall, OpCode::Number(arg_count));
+        let callee = self.parser.peek_previous_2();
+        if callee.r#type == TokenType::Identifier && self.functions.contains_key(&callee.lexeme) {
+            let arg_count = self.argument_list();
+            self.emit_2_bytes(OpCode::OpCall, OpCode::Number(arg_count));
+        } else {
+            let arg_count = self.arguments();
+            let site = self.current_chunk().add_call_site();
+            self.emit_2_bytes(OpCode::OpCallIndirect, OpCode::Number(arg_count));
+            self.emit_byte(OpCode::Number(site));
+        }
+    }
+
+    // Compiles the arguments up to the closing parenthesis and counts them
+    fn arguments(&mut self) -> usize {
+        let mut arg_count = 0;
+        if !self.parser.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                arg_count += 1;
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count
+    }
+
+    fn list(&mut self, _can_assign: bool) {
+        if self.parser.comprehension_ahead() {
+            self.comprehension(OpCode::OpBuildList, TokenType::RightSquareBracket);
+            return;
+        }
+
+        let mut count = 0;
+        if !self.parser.check(TokenType::RightSquareBracket) {
+            loop {
+                self.expression();
+                count += 1;
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.parser.consume(
+            TokenType::RightSquareBracket,
+            "Expect ']' after list elements.",
+        );
+        self.emit_2_bytes(OpCode::OpBuildList, OpCode::Number(count));
+    }
+
+    // Braces hold either a set or a map, the first element decides which one
+    // it is: a ':' after it makes the whole literal a map.
+    fn set(&mut self, _can_assign: bool) {
+        if self.parser.comprehension_ahead() {
+            self.comprehension(OpCode::OpBuildSet, TokenType::RightBrace);
+            return;
+        }
+
+        let mut count = 0;
+        let mut is_map = false;
+        if !self.parser.check(TokenType::RightBrace) {
+            self.expression();
+            is_map = self.parser.match_token(TokenType::Colon);
+            loop {
+                if is_map {
+                    if count > 0 {
+                        self.parser
+                            .consume(TokenType::Colon, "Expect ':' after map key.");
+                    }
+                    self.expression();
+                }
+                count += 1;
+                if !self.parser.match_token(TokenType::Comma) {
+                    break;
+                }
+                self.expression();
+            }
+        }
+
+        if is_map {
+            self.parser
+                .consume(TokenType::RightBrace, "Expect '}' after map entries.");
+            self.emit_2_bytes(OpCode::OpBuildMap, OpCode::Number(count));
+        } else {
+            self.parser
+                .consume(TokenType::RightBrace, "Expect '}' after set elements.");
+            self.emit_2_bytes(OpCode::OpBuildSet, OpCode::Number(count));
+        }
+    }
+
+    // `[x * 2 for x in items if x > 0]`, `{x for x in items}` and
+    // `{k: v for k, v in pairs}`. The loop runs before the element is added
+    // but comes after it in the source, so the element is skipped until the
+    // loop header is compiled, then the parser goes back to it.
+    fn comprehension(&mut self, build: OpCode, closing: TokenType) {
+        let pending = self.set_aside_pending();
+        let element = self.parser.position();
+        self.parser.skip_comprehension_element();
+
+        self.begin_scope();
+        let keyword = self.parser.peek_current();
+        // A ':' after the key turns the set into a map, found once the element
+        // is compiled
+        let build_offset = self.current_chunk().code.len();
+        self.emit_2_bytes(build, OpCode::Number(0));
+        let collection = self.hidden_local("comprehension", &keyword);
+
+        self.parser
+            .consume(TokenType::For, "Expect 'for' after comprehension element.");
+        let variables = self.loop_variable_names();
+        self.expression();
+        self.emit_byte(OpCode::OpGetIter);
+        let iterator = self.hidden_local("iterator", &keyword);
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_2_bytes(OpCode::OpForIter, OpCode::Number(iterator));
+        self.emit_byte(OpCode::Number(0));
+        let exit_jump = self.current_chunk().code.len() - 1;
+
+        let before = self.locals.assigned();
+        self.begin_scope();
+        self.loop_variables(variables);
+
+        let filter_jump = if self.parser.match_token(TokenType::If) {
+            let condition_start = self.current_chunk().code.len();
+            self.expression();
+            self.check_condition(condition_start);
+            let jump = self.emit_jump(OpCode::OpJumpIfFalse);
+            self.emit_byte(OpCode::OpPop);
+            Some(jump)
+        } else {
+            None
+        };
+
+        let end = self.parser.position();
+        self.parser.rewind(element);
+        // The key is held in a local while the value is computed so a
+        // comprehension in the value gets slots above it
+        self.expression();
+        let mut key = None;
+        if build == OpCode::OpBuildSet && self.parser.match_token(TokenType::Colon) {
+            self.current_chunk().code[build_offset] = OpCode::OpBuildMap;
+            let slot = self.hidden_local("key", &keyword);
+            self.expression();
+            self.emit_2_bytes(OpCode::OpGet, OpCode::Number(slot));
+            key = Some(slot);
+        }
+        if !self.parser.check(TokenType::For) {
+            self.parser
+                .error_at_current("Expect 'for' after comprehension element.");
+        }
+        self.emit_2_bytes(OpCode::OpGet, OpCode::Number(collection));
+        self.emit_native(COMPREHENSION_ADD, if key.is_some() { 3 } else { 2 });
+        self.emit_byte(OpCode::OpPop);
+        // The key local is still below the result, popped for each element
+        if let Some(key) = key {
+            self.emit_byte(OpCode::OpPop);
+            self.locals.truncate(key);
+        }
+        self.parser.rewind(end);
+
+        if let Some(filter_jump) = filter_jump {
+            let skip_jump = self.emit_jump(OpCode::OpJump);
+            self.patch_jump(filter_jump);
+            self.emit_byte(OpCode::OpPop);
+            self.patch_jump(skip_jump);
+        }
+        self.end_scope();
+        self.emit_loop(loop_start);
+        self.locals.set_assigned(&before);
+
+        self.patch_jump(exit_jump);
+        // The none pushed in place of the item once the iterator is done,
+        // then the iterator, which leaves the collection on top as the value
+        self.emit_byte(OpCode::OpPop);
+        self.emit_byte(OpCode::OpPop);
+        self.scope_depth -= 1;
+        self.locals.truncate(collection);
+
+        if let Some(local) = pending {
+            self.locals.push(local);
+        }
+        let message = if closing == TokenType::RightBrace {
+            "Expect '}' after comprehension."
+        } else {
+            "Expect ']' after comprehension."
+        };
+        self.parser.consume(closing, message);
+    }
+
+    // The item of an iteration is stored in the loop variable, or split into
+    // several, like `k, v`, from a list with one value per variable
+    fn loop_variables(&mut self, variables: Vec<Token>) {
+        let count = variables.len();
+        let item = if count > 1 {
+            self.emit_constant(Value::Integer(count as i64));
+            self.emit_native(UNPACK, 2);
+            Some(self.hidden_local("item", &variables[0]))
+        } else {
+            None
+        };
+
+        for (index, variable) in variables.into_iter().enumerate() {
+            if let Some(item) = item {
+                self.emit_2_bytes(OpCode::OpGet, OpCode::Number(item));
+                self.emit_constant(Value::Integer(index as i64));
+                self.emit_byte(OpCode::OpIndex);
+            }
+            // Loop variables always shadow, they never reassign an outer variable
+            let slot = self.locals.push(Local {
+                is_initialized: true,
+                has_slot: true,
+                assigned: true,
+                ..Local::new(variable, self.scope_depth, TokenType::None)
+            });
+            self.emit_2_bytes(OpCode::OpSet, OpCode::Number(slot));
+        }
+    }
+
+    fn index(&mut self, _can_assign: bool) {
+        if self.parser.check(TokenType::RightSquareBracket) {
+            self.parser.error_at_current("Expect index or slice.");
+            return;
+        }
+
+        // Every slice bound is optional, a missing one is pushed as none
+        // and resolved by the VM according to the step direction.
+        self.slice_bound();
+        if !self.parser.match_token(TokenType::Colon) {
+            self.parser
+                .consume(TokenType::RightSquareBracket, "Expect ']' after index.");
+            self.emit_byte(OpCode::OpIndex);
+            return;
+        }
+
+        self.slice_bound();
+        if self.parser.match_token(TokenType::Colon) {
+            self.slice_bound();
+        } else {
+            self.emit_byte(OpCode::OpNone);
+        }
+        self.parser
+            .consume(TokenType::RightSquareBracket, "Expect ']' after slice.");
+        self.emit_byte(OpCode::OpSlice);
+    }
+
+    fn slice_bound(&mut self) {
+        if self.parser.check(TokenType::Colon) || self.parser.check(TokenType::RightSquareBracket) {
+            self.emit_byte(OpCode::OpNone);
+        } else {
+            self.expression();
+        }
     }
 
     fn argument_list(&mut self) -> usize {
         let mut args = Vec::new();
-        let function_info = self.function_info(get_parser().peek_previous_2().lexeme.clone());
+        let function_info = self.function_info(self.parser.peek_previous_2().lexeme.clone());
 
-        if !get_parser().check(TokenType::RightParen) {
+        if !self.parser.check(TokenType::RightParen) {
             loop {
-                args.push(get_parser().peek_current());
+                args.push(self.parser.peek_current());
                 self.expression();
-                if !get_parser().match_token(TokenType::Comma) {
+                if !self.parser.match_token(TokenType::Comma) {
                     break;
                 }
             }
         }
 
         if args.len() != function_info.arg_names.len() {
-            let message: String;
-            if function_info.arg_names.len() == 1 {
-                message = format!(
+            let message = if function_info.arg_names.len() == 1 {
+                format!(
                     "Expected {} argument but got {}.",
                     function_info.arg_names.len(),
                     args.len()
-                );
+                )
             } else {
-                message = format!(
+                format!(
                     "Expected {} arguments but got {}.",
                     function_info.arg_names.len(),
                     args.len()
-                );
-            }
-            get_parser().error_at_previous(&message);
+                )
+            };
+            self.parser.error_at_previous(&message);
         }
 
-        for i in 0..args.len() {
-            if !function_info.arg_types[i].is_token_correct_type(&args[i]) {
-                let value;
-                match self.values.get(&args[i].lexeme) {
+        // Extra arguments were reported above and have no type to check
+        for (arg, arg_type) in args.iter().zip(&function_info.arg_types) {
+            if !arg_type.is_token_correct_type(arg) {
+                let value = match self.values.get(&arg.lexeme) {
                     None => {
-                        get_parser().error_at_previous(&format!(
+                        self.parser.error_at_previous(&format!(
                             "Expected argument of type {} but got argument of type {}.",
-                            function_info.arg_types[i],
-                            &args[i].type_of()
+                            arg_type,
+                            arg.type_of()
                         ));
-                        value = Value::None;
-                    }
-                    Some(v) => {
-                        value = v.clone();
+                        Value::None
                     }
-                }
-                if !function_info.arg_types[i].is_value_correct_type(&value) {
-                    get_parser().error_at_previous(&format!(
+                    Some(v) => v.clone(),
+                };
+                if !arg_type.is_value_correct_type(&value) {
+                    self.parser.error_at_previous(&format!(
                         "Expected argument of type {} but got argument of type {}.",
-                        function_info.arg_types[i],
+                        arg_type,
                         &value.type_of()
                     ));
                 }
             }
         }
 
-        get_parser().consume(TokenType::RightParen, "Expect ')' after arguments.");
-        return args.len();
+        self.parser
+            .consume(TokenType::RightParen, "Expect ')' after arguments.");
+        args.len()
     }
 
-    fn function_info(&mut self, name: String) -> FunctionInfo {
+    fn function_info(&mut self, name: String) -> Rc<FunctionInfo> {
         match self.functions.get(&name) {
             None => {
-                get_parser().error_at_previous_2(&format!("Function {} could not be found.", name));
-                return FunctionInfo::new(String::new());
+                self.parser
+                    .error_at_previous_2(&format!("Function {} could not be found.", name));
+                Rc::new(FunctionInfo::new(String::new()))
             }
-            Some(info) => return info.clone(),
+            Some(info) => info.clone(),
         }
     }
 
@@ -788,6 +3085,11 @@ impl Compiler {
                 prefix: Compiler::integer,
                 infix: Compiler::none,
             },
+            TokenType::Decimal => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::decimal,
+                infix: Compiler::none,
+            },
             TokenType::String => ParseRule {
                 precedence: Precedence::None,
                 prefix: Compiler::string,
@@ -808,6 +3110,11 @@ impl Compiler {
                 prefix: Compiler::literal,
                 infix: Compiler::none,
             },
+            TokenType::Nan | TokenType::Inf => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::literal,
+                infix: Compiler::none,
+            },
             TokenType::FloatNone => ParseRule {
                 precedence: Precedence::None,
                 prefix: Compiler::literal,
@@ -838,6 +3145,16 @@ impl Compiler {
                 prefix: Compiler::grouping,
                 infix: Compiler::call,
             },
+            TokenType::LeftBrace => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::set,
+                infix: Compiler::none,
+            },
+            TokenType::LeftSquareBracket => ParseRule {
+                precedence: Precedence::Call,
+                prefix: Compiler::list,
+                infix: Compiler::index,
+            },
             TokenType::Minus => ParseRule {
                 precedence: Precedence::Term,
                 prefix: Compiler::unary,
@@ -845,7 +3162,7 @@ impl Compiler {
             },
             TokenType::Plus => ParseRule {
                 precedence: Precedence::Term,
-                prefix: Compiler::none,
+                prefix: Compiler::unary,
                 infix: Compiler::binary,
             },
             TokenType::Star => ParseRule {
@@ -858,6 +3175,36 @@ impl Compiler {
                 prefix: Compiler::none,
                 infix: Compiler::binary,
             },
+            TokenType::Pipe => ParseRule {
+                precedence: Precedence::BitOr,
+                prefix: Compiler::none,
+                infix: Compiler::binary,
+            },
+            TokenType::Ampersand => ParseRule {
+                precedence: Precedence::BitAnd,
+                prefix: Compiler::none,
+                infix: Compiler::binary,
+            },
+            TokenType::In => ParseRule {
+                precedence: Precedence::Comparison,
+                prefix: Compiler::none,
+                infix: Compiler::binary,
+            },
+            TokenType::Dot => ParseRule {
+                precedence: Precedence::Call,
+                prefix: Compiler::none,
+                infix: Compiler::method_call,
+            },
+            TokenType::DotDot => ParseRule {
+                precedence: Precedence::Term,
+                prefix: Compiler::none,
+                infix: Compiler::binary,
+            },
+            TokenType::Is => ParseRule {
+                precedence: Precedence::Equality,
+                prefix: Compiler::none,
+                infix: Compiler::binary,
+            },
             TokenType::And => ParseRule {
                 precedence: Precedence::And,
                 prefix: Compiler::none,
@@ -903,11 +3250,26 @@ impl Compiler {
                 prefix: Compiler::none,
                 infix: Compiler::binary,
             },
+            TokenType::Yield => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::yield_expression,
+                infix: Compiler::none,
+            },
             TokenType::Identifier => ParseRule {
                 precedence: Precedence::None,
                 prefix: Compiler::variable,
                 infix: Compiler::none,
             },
+            TokenType::If => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::if_expression,
+                infix: Compiler::none,
+            },
+            TokenType::Try => ParseRule {
+                precedence: Precedence::None,
+                prefix: Compiler::try_expression,
+                infix: Compiler::none,
+            },
             _ => ParseRule {
                 precedence: Precedence::None,
                 prefix: Compiler::none,
@@ -929,34 +3291,88 @@ impl Compiler {
 
     fn emit_return(&mut self) {
         self.emit_byte(OpCode::OpNone);
+        self.emit_return_value();
+    }
+
+    // Code deferred in the outermost scope of the function runs on the way out
+    fn emit_return_value(&mut self) {
+        if !self.defers.is_empty() {
+            self.emit_2_bytes(OpCode::OpRunDeferred, OpCode::Number(0));
+        }
         self.emit_byte(OpCode::OpReturn);
     }
 
+    // A script ending with an expression returns its value instead of none,
+    // which lets hosts use scripts to evaluate expressions
+    fn return_last_expression(&mut self) -> bool {
+        let pop = match self.last_expression {
+            Some(pop) => pop,
+            None => return false,
+        };
+        let chunk = self.current_chunk();
+        if chunk.code[pop + 1..]
+            .iter()
+            .any(|byte| *byte != OpCode::OpEol)
+        {
+            return false;
+        }
+        while chunk.code.len() > pop {
+            chunk.pop();
+        }
+        self.emit_return_value();
+        true
+    }
+
     fn emit_eol(&mut self) {
         self.emit_byte(OpCode::OpEol);
     }
 
     fn start_compiler(&mut self) {
-        get_parser().advance();
+        self.parser.advance();
     }
 
     fn end_compiler(&mut self) -> ObjFunction {
-        self.emit_return();
-        if DEBUG_PRINT_CODE && !self.current_chunk().had_error {
-            let func_name = format!("{}", &self.function);
-            self.immut_current_chunk()
-                .disassemble(if self.function.name == "" {
-                    "<script>"
-                } else {
-                    &func_name
-                });
+        if !self.return_last_expression() {
+            self.emit_return();
+        }
+        if matches!(self.function_type, FunctionType::Script) {
+            self.function.exports = self
+                .locals
+                .iter()
+                .enumerate()
+                .filter(|(_, local)| {
+                    local.depth == 0
+                        && local.is_initialized
+                        && local.type_ != TokenType::TypeFunction
+                })
+                .map(|(slot, local)| (local.name.lexeme.clone(), slot, local.type_))
+                .collect();
         }
-        return self.function.clone();
+        if self.print_code && !self.current_chunk().had_error {
+            self.function.chunk.disassemble(&mut self.listing);
+        }
+        self.function.max_stack = max_stack(&self.function);
+        self.function.clone()
     }
 
     fn emit_byte(&mut self, byte: OpCode) {
-        let line = get_parser().previous.line;
-        self.current_chunk().write(byte, line);
+        let line = self.parser.previous.line;
+        let span = self.current_span();
+        self.current_chunk().write(byte, line, span);
+    }
+
+    // Bytes emitted for an operator or a call cover the whole expression up to
+    // the last token read, when it fits on one line
+    fn current_span(&self) -> Span {
+        let previous = &self.parser.previous;
+        let end = previous.end_column();
+        match self.expression_start {
+            Some((line, start)) if line == previous.line && start <= end => Span { start, end },
+            _ => Span {
+                start: previous.column,
+                end,
+            },
+        }
     }
 
     fn emit_2_bytes(&mut self, byte1: OpCode, byte2: OpCode) {
@@ -964,3 +3380,208 @@ impl Compiler {
         self.emit_byte(byte2);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(false);
+        compiler.compile(source.to_owned());
+        compiler
+            .take_errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn compiles_two_scripts_in_a_row() {
+        let source = "f {\n    print 1\n}\nf()\n";
+        assert_eq!(compile(source), Vec::<String>::new());
+        assert_eq!(compile(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn errors_are_not_kept_by_the_next_compile() {
+        assert!(!compile("print (").is_empty());
+        assert_eq!(compile("print 1"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn function_missing_from_the_header_pass_is_an_error() {
+        assert_eq!(
+            compile("x = 1 f {\n    print 1\n}\nprint (\n"),
+            [
+                "[line 1] Error at 'f': Function f could not be found.",
+                "[line 5] Error at end: Expect expression.",
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_collections_and_try_blocks_are_errors() {
+        for (source, error) in [
+            (
+                "m = {\"a\": }\n",
+                "[line 1] Error at '}': Expect expression.",
+            ),
+            (
+                "x = [y for y in]\n",
+                "[line 1] Error at ']': Expect expression.",
+            ),
+            (
+                "print [x for x in [1] if]\n",
+                "[line 1] Error at ']': Expect expression.",
+            ),
+            (
+                "try {\n    print 1\n}\n",
+                "[line 3] Error at '}': Expect 'catch' or 'finally' after try block.",
+            ),
+        ] {
+            assert_eq!(compile(source), [error], "{}", source);
+        }
+    }
+
+    #[test]
+    fn compiles_on_several_threads_at_once() {
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                std::thread::spawn(move || {
+                    let source = format!("f{n}: int x {{\n    print x\n}}\nf{n}({n})\n");
+                    (0..50).all(|_| compile(&source).is_empty())
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn literals_are_opcodes_not_constants() {
+        for (source, literal) in [
+            ("true", OpCode::OpTrue),
+            ("false", OpCode::OpFalse),
+            ("none", OpCode::OpNone),
+        ] {
+            let mut compiler = Compiler::new();
+            compiler.set_print_code(false);
+            let script = compiler.compile_expression(source.to_owned());
+            assert!(compiler.take_errors().is_empty());
+            assert_eq!(script.chunk.code, [literal, OpCode::OpReturn]);
+            assert!(script.chunk.constants.is_empty());
+        }
+
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(false);
+        let script = compiler.compile("x = [true, false, none]\n".to_owned());
+        assert!(compiler.take_errors().is_empty());
+        assert!(script.chunk.constants.is_empty());
+    }
+
+    #[test]
+    fn unexpected_characters_are_skipped() {
+        assert_eq!(
+            compile("print 3 % 2\nprint (\n"),
+            [
+                "[line 1] Error: Unexpected character.",
+                "[line 3] Error at end: Expect expression.",
+            ]
+        );
+        assert_eq!(
+            compile("x = \"abc"),
+            ["[line 1] Error: Unterminated string."]
+        );
+    }
+
+    #[test]
+    fn errors_of_the_header_pass_fail_the_compile() {
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(false);
+        let script = compiler.compile("f: x {\n    print 1\n}\nprint 2\n".to_owned());
+        assert!(script.had_error());
+        assert_eq!(compiler.take_errors().len(), 1);
+    }
+
+    #[test]
+    fn a_missing_operand_is_a_compile_error() {
+        assert_eq!(
+            compile("x = ] string\n"),
+            ["[line 1] Error at ']': Expect expression."]
+        );
+        assert_eq!(
+            compile("loop ] x = 1\n"),
+            ["[line 1] Error at ']': Expect expression."]
+        );
+    }
+
+    fn compile_strict(source: &str) -> Vec<String> {
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(false);
+        compiler.set_strict(true);
+        compiler.compile(source.to_owned());
+        compiler
+            .take_errors()
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn strict_mode_compares_typed_variables_by_their_type() {
+        assert_eq!(
+            compile_strict("int k = 0\nwhile k < \"x\" {\n    k = k + 1\n}\n"),
+            ["[line 2] Error at 'x': Cannot compare int with string in strict mode."]
+        );
+        assert_eq!(
+            compile_strict("string s = \"a\"\nint k = 1\nprint s == k\n"),
+            ["[line 3] Error at 'k': Cannot compare string with int in strict mode."]
+        );
+        assert_eq!(
+            compile_strict("int k = 0\nfloat f = 1.5\nwhile k < f {\n    k = k + 1\n}\n"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            compile("int k = 0\nprint k < \"x\"\n"),
+            Vec::<String>::new()
+        );
+    }
+
+    fn compile_on(threads: usize, source: &str) -> (String, Vec<String>, Vec<String>) {
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(true);
+        compiler.set_threads(threads);
+        compiler.compile(source.to_owned());
+        let messages =
+            |diagnostics: Vec<Diagnostic>| diagnostics.iter().map(|d| d.to_string()).collect();
+        (
+            compiler.take_listing(),
+            messages(compiler.take_errors()),
+            messages(compiler.take_warnings()),
+        )
+    }
+
+    #[test]
+    fn parallel_compiles_match_serial_compiles() {
+        let body = "    total = 0\n    for i in range(0, a) {\n        total = total + i\n    }\n    print \"{total}\"\n";
+        let mut source = String::from("x = 1.5\n");
+        for n in 0..12 {
+            source.push_str(&format!(
+                "f{n}: int a {{\n{body}    g{n}: int b {{\n        print b\n    }}\n}}\n"
+            ));
+        }
+        source.push_str("w {\n    while true {\n        break\n        print 1\n    }\n}\nf3(4)\n");
+        let serial = compile_on(1, &source);
+        assert!(serial.1.is_empty(), "{:?}", serial.1);
+        assert_eq!(serial.2.len(), 1);
+        assert_eq!(compile_on(4, &source), serial);
+
+        // A body with errors is compiled again serially
+        let source = format!("{source}h {{\n    y = ]\n}}\nprint z\n");
+        let serial = compile_on(1, &source);
+        assert_eq!(serial.1.len(), 2);
+        assert_eq!(compile_on(4, &source), serial);
+    }
+}
@@ -0,0 +1,608 @@
+use std::fmt::Display;
+
+const MILLIS_PER_SECOND: i64 = 1000;
+const MILLIS_PER_MINUTE: i64 = 60_000;
+const MILLIS_PER_HOUR: i64 = 3_600_000;
+const MILLIS_PER_DAY: i64 = 86_400_000;
+
+// Longer units first so that "ms" is tried before "m"
+const DURATION_UNITS: [(&str, i64); 6] = [
+    ("w", 7 * MILLIS_PER_DAY),
+    ("d", MILLIS_PER_DAY),
+    ("h", MILLIS_PER_HOUR),
+    ("ms", 1),
+    ("m", MILLIS_PER_MINUTE),
+    ("s", MILLIS_PER_SECOND),
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+// A moment in UTC as milliseconds since the Unix epoch, earlier moments are
+// negative. Only years 0 to 9999 can be built or parsed so that every date
+// prints with four digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    millis: i64,
+}
+
+// A length of time in milliseconds, negative when going back in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    millis: i64,
+}
+
+// A date and time as written on a calendar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parts {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millisecond: u32,
+}
+
+impl Default for Parts {
+    fn default() -> Parts {
+        Parts {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            millisecond: 0,
+        }
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar, from
+// Howard Hinnant's date algorithms
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+impl DateTime {
+    pub fn from_millis(millis: i64) -> DateTime {
+        DateTime { millis }
+    }
+
+    pub fn millis(&self) -> i64 {
+        self.millis
+    }
+
+    pub fn from_parts(parts: Parts) -> Result<DateTime, String> {
+        if !(0..=9999).contains(&parts.year) {
+            return Err(format!(
+                "Year {} is out of range, expected 0 to 9999",
+                parts.year
+            ));
+        }
+        if !(1..=12).contains(&parts.month) {
+            return Err(format!(
+                "Month {} is out of range, expected 1 to 12",
+                parts.month
+            ));
+        }
+        let last_day = days_in_month(parts.year, parts.month);
+        if !(1..=last_day).contains(&parts.day) {
+            return Err(format!(
+                "Day {} is out of range, {} {} has {} days",
+                parts.day,
+                MONTHS[parts.month as usize - 1],
+                parts.year,
+                last_day
+            ));
+        }
+        if parts.hour > 23 || parts.minute > 59 || parts.second > 59 || parts.millisecond > 999 {
+            return Err(format!(
+                "Time {:02}:{:02}:{:02}.{:03} is out of range",
+                parts.hour, parts.minute, parts.second, parts.millisecond
+            ));
+        }
+
+        let days = days_from_civil(parts.year, parts.month, parts.day);
+        let seconds = (parts.hour * 3600 + parts.minute * 60 + parts.second) as i64;
+        Ok(DateTime {
+            millis: days * MILLIS_PER_DAY + seconds * MILLIS_PER_SECOND + parts.millisecond as i64,
+        })
+    }
+
+    pub fn parts(&self) -> Parts {
+        let days = self.millis.div_euclid(MILLIS_PER_DAY);
+        let time = self.millis.rem_euclid(MILLIS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        let seconds = (time / MILLIS_PER_SECOND) as u32;
+        Parts {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+            millisecond: (time % MILLIS_PER_SECOND) as u32,
+        }
+    }
+
+    // 1 for Monday up to 7 for Sunday, the epoch was a Thursday
+    pub fn weekday(&self) -> u32 {
+        (self.millis.div_euclid(MILLIS_PER_DAY) + 3).rem_euclid(7) as u32 + 1
+    }
+
+    pub fn add_millis(&self, millis: i64) -> Result<DateTime, String> {
+        self.millis
+            .checked_add(millis)
+            .map(DateTime::from_millis)
+            .ok_or_else(|| "Date out of range".to_owned())
+    }
+
+    pub fn add_days(&self, days: i64) -> Result<DateTime, String> {
+        days.checked_mul(MILLIS_PER_DAY)
+            .ok_or_else(|| "Date out of range".to_owned())
+            .and_then(|millis| self.add_millis(millis))
+    }
+
+    pub fn since(&self, earlier: DateTime) -> Result<Duration, String> {
+        self.millis
+            .checked_sub(earlier.millis)
+            .map(Duration::from_millis)
+            .ok_or_else(|| "Duration out of range".to_owned())
+    }
+
+    // Patterns use strftime directives: %Y %m %d %H %M %S, %f for the
+    // milliseconds, %y, %b and %B for the month name, %a and %A for the day
+    // name, %j for the day of the year, %I with %p for 12 hour clocks, %%
+    pub fn format(&self, pattern: &str) -> Result<String, String> {
+        let parts = self.parts();
+        let mut formatted = String::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                formatted.push(c);
+                continue;
+            }
+            let directive = chars.next().ok_or("Pattern ends with a lone %")?;
+            let text = match directive {
+                'Y' => format!("{:04}", parts.year),
+                'y' => format!("{:02}", parts.year.rem_euclid(100)),
+                'm' => format!("{:02}", parts.month),
+                'd' => format!("{:02}", parts.day),
+                'H' => format!("{:02}", parts.hour),
+                'I' => format!("{:02}", (parts.hour + 11) % 12 + 1),
+                'p' => (if parts.hour < 12 { "AM" } else { "PM" }).to_owned(),
+                'M' => format!("{:02}", parts.minute),
+                'S' => format!("{:02}", parts.second),
+                'f' => format!("{:03}", parts.millisecond),
+                'b' => MONTHS[parts.month as usize - 1][..3].to_owned(),
+                'B' => MONTHS[parts.month as usize - 1].to_owned(),
+                'a' => WEEKDAYS[self.weekday() as usize - 1][..3].to_owned(),
+                'A' => WEEKDAYS[self.weekday() as usize - 1].to_owned(),
+                'j' => {
+                    let first = days_from_civil(parts.year, 1, 1);
+                    let day = self.millis.div_euclid(MILLIS_PER_DAY) - first + 1;
+                    format!("{:03}", day)
+                }
+                '%' => "%".to_owned(),
+                directive => return Err(format!("Unknown directive %{} in pattern", directive)),
+            };
+            formatted.push_str(&text);
+        }
+        Ok(formatted)
+    }
+
+    // Reads a date written following a pattern of the same directives as
+    // format. Parts missing from the pattern default to 1970-01-01 00:00:00.
+    pub fn parse(pattern: &str, text: &str) -> Result<DateTime, String> {
+        let mismatch = || format!("\"{}\" does not match the pattern \"{}\"", text, pattern);
+        let mut parts = Parts::default();
+        let mut afternoon = None;
+        let mut input = text;
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                input = input.strip_prefix(c).ok_or_else(mismatch)?;
+                continue;
+            }
+            let directive = chars.next().ok_or("Pattern ends with a lone %")?;
+            match directive {
+                'Y' => parts.year = take_number(&mut input, 4).ok_or_else(mismatch)?,
+                // Two digit years are from 1969 to 2068 like POSIX reads them
+                'y' => {
+                    let year = take_number(&mut input, 2).ok_or_else(mismatch)?;
+                    parts.year = if year < 69 { 2000 + year } else { 1900 + year };
+                }
+                'm' => parts.month = take_number(&mut input, 2).ok_or_else(mismatch)? as u32,
+                'd' => parts.day = take_number(&mut input, 2).ok_or_else(mismatch)? as u32,
+                'H' | 'I' => parts.hour = take_number(&mut input, 2).ok_or_else(mismatch)? as u32,
+                'M' => parts.minute = take_number(&mut input, 2).ok_or_else(mismatch)? as u32,
+                'S' => parts.second = take_number(&mut input, 2).ok_or_else(mismatch)? as u32,
+                'f' => parts.millisecond = take_number(&mut input, 3).ok_or_else(mismatch)? as u32,
+                'p' => {
+                    let marker = input.get(..2).ok_or_else(mismatch)?.to_uppercase();
+                    afternoon = match marker.as_str() {
+                        "AM" => Some(false),
+                        "PM" => Some(true),
+                        _ => return Err(mismatch()),
+                    };
+                    input = &input[2..];
+                }
+                'b' | 'B' => {
+                    let month = MONTHS
+                        .iter()
+                        .position(|name| {
+                            let name = if directive == 'b' { &name[..3] } else { name };
+                            input
+                                .get(..name.len())
+                                .is_some_and(|start| start.eq_ignore_ascii_case(name))
+                        })
+                        .ok_or_else(mismatch)?;
+                    let length = if directive == 'b' {
+                        3
+                    } else {
+                        MONTHS[month].len()
+                    };
+                    parts.month = month as u32 + 1;
+                    input = &input[length..];
+                }
+                'a' | 'A' => {
+                    let length = WEEKDAYS
+                        .iter()
+                        .map(|name| if directive == 'a' { &name[..3] } else { name })
+                        .find(|name| {
+                            input
+                                .get(..name.len())
+                                .is_some_and(|start| start.eq_ignore_ascii_case(name))
+                        })
+                        .ok_or_else(mismatch)?
+                        .len();
+                    input = &input[length..];
+                }
+                '%' => input = input.strip_prefix('%').ok_or_else(mismatch)?,
+                directive => return Err(format!("Unknown directive %{} in pattern", directive)),
+            }
+        }
+        if !input.is_empty() {
+            return Err(mismatch());
+        }
+        match afternoon {
+            Some(_) if !(1..=12).contains(&parts.hour) => {
+                return Err(format!(
+                    "Hour {} is out of range, expected 1 to 12",
+                    parts.hour
+                ))
+            }
+            Some(afternoon) => parts.hour = parts.hour % 12 + if afternoon { 12 } else { 0 },
+            None => (),
+        }
+        DateTime::from_parts(parts)
+    }
+}
+
+impl Duration {
+    pub fn from_millis(millis: i64) -> Duration {
+        Duration { millis }
+    }
+
+    // Fractions of a millisecond are rounded away
+    pub fn from_float_millis(millis: f64) -> Result<Duration, String> {
+        let millis = millis.round();
+        if !millis.is_finite() || millis.abs() >= i64::MAX as f64 {
+            return Err("Duration out of range".to_owned());
+        }
+        Ok(Duration::from_millis(millis as i64))
+    }
+
+    pub fn millis(&self) -> i64 {
+        self.millis
+    }
+
+    pub fn checked_add(&self, other: Duration) -> Result<Duration, String> {
+        self.millis
+            .checked_add(other.millis)
+            .map(Duration::from_millis)
+            .ok_or_else(|| "Duration out of range".to_owned())
+    }
+
+    pub fn checked_neg(&self) -> Result<Duration, String> {
+        self.millis
+            .checked_neg()
+            .map(Duration::from_millis)
+            .ok_or_else(|| "Duration out of range".to_owned())
+    }
+
+    // Numbers followed by a unit, like 1h30m, 1.5d or 250ms. The units are w,
+    // d, h, m, s and ms, a leading - makes the whole duration negative.
+    pub fn parse(text: &str) -> Result<Duration, String> {
+        let invalid = || {
+            format!(
+                "Invalid duration \"{}\", expected numbers followed by w, d, h, m, s or ms like 1h30m",
+                text
+            )
+        };
+        let (negative, mut input) = match text.trim().strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.trim()),
+        };
+        if input.is_empty() {
+            return Err(invalid());
+        }
+        let mut millis = 0.0;
+        while !input.is_empty() {
+            let length = input
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(input.len());
+            let number: f64 = input[..length].parse().map_err(|_| invalid())?;
+            input = &input[length..];
+            let (unit, size) = DURATION_UNITS
+                .iter()
+                .find(|(unit, _)| input.starts_with(unit))
+                .ok_or_else(invalid)?;
+            millis += number * *size as f64;
+            input = input[unit.len()..].trim_start();
+        }
+        Duration::from_float_millis(if negative { -millis } else { millis })
+    }
+
+    // Patterns use %d for days, %H hours, %M minutes, %S seconds and %f
+    // milliseconds. Each only counts what the larger units of the pattern
+    // leave, so 90 minutes are 01:30 with "%H:%M" and 90 with "%M".
+    pub fn format(&self, pattern: &str) -> Result<String, String> {
+        let units = [
+            ('d', MILLIS_PER_DAY, 1),
+            ('H', MILLIS_PER_HOUR, 2),
+            ('M', MILLIS_PER_MINUTE, 2),
+            ('S', MILLIS_PER_SECOND, 2),
+            ('f', 1, 3),
+        ];
+        let mut chars = pattern.chars();
+        let mut used = Vec::new();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                continue;
+            }
+            match chars.next().ok_or("Pattern ends with a lone %")? {
+                '%' => (),
+                directive if units.iter().any(|(unit, _, _)| *unit == directive) => {
+                    used.push(directive)
+                }
+                directive => return Err(format!("Unknown directive %{} in pattern", directive)),
+            }
+        }
+
+        let mut rest = self.millis.unsigned_abs();
+        let mut amounts = Vec::new();
+        for (unit, size, width) in units {
+            if used.contains(&unit) {
+                amounts.push((unit, rest / size as u64, width));
+                rest %= size as u64;
+            }
+        }
+
+        let mut formatted = String::new();
+        if self.millis < 0 {
+            formatted.push('-');
+        }
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                formatted.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => formatted.push('%'),
+                directive => {
+                    let (_, amount, width) = amounts
+                        .iter()
+                        .find(|(unit, _, _)| Some(*unit) == directive)
+                        .expect("directives were checked");
+                    formatted.push_str(&format!("{:0width$}", amount, width = *width));
+                }
+            }
+        }
+        Ok(formatted)
+    }
+}
+
+// Like 1d2h30m, the same way durations are parsed, or 0s when empty
+impl Display for Duration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.millis == 0 {
+            return write!(f, "0s");
+        }
+        if self.millis < 0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.millis.unsigned_abs();
+        let units = [
+            ("d", MILLIS_PER_DAY),
+            ("h", MILLIS_PER_HOUR),
+            ("m", MILLIS_PER_MINUTE),
+            ("s", MILLIS_PER_SECOND),
+            ("ms", 1),
+        ];
+        for (unit, size) in units {
+            let amount = rest / size as u64;
+            rest %= size as u64;
+            if amount != 0 {
+                write!(f, "{}{}", amount, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Up to width digits from the start of the input
+fn take_number(input: &mut &str, width: usize) -> Option<i64> {
+    let length = input
+        .bytes()
+        .take(width)
+        .take_while(u8::is_ascii_digit)
+        .count();
+    if length == 0 {
+        return None;
+    }
+    let number = input[..length].parse().ok()?;
+    *input = &input[length..];
+    Some(number)
+}
+
+// Like 2024-03-01 14:30:00, the milliseconds are only written when there are some
+impl Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = self.parts();
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            parts.year, parts.month, parts.day, parts.hour, parts.minute, parts.second
+        )?;
+        if parts.millisecond != 0 {
+            write!(f, ".{:03}", parts.millisecond)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i64, month: u32, day: u32) -> DateTime {
+        DateTime::from_parts(Parts {
+            year,
+            month,
+            day,
+            ..Parts::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn calendar_dates_round_trip() {
+        assert_eq!(date(1970, 1, 1).millis(), 0);
+        assert_eq!(date(1969, 12, 31).millis(), -MILLIS_PER_DAY);
+        let leap_day = date(2024, 2, 29);
+        assert_eq!(leap_day.to_string(), "2024-02-29 00:00:00");
+        assert_eq!(leap_day.weekday(), 4);
+        assert_eq!(
+            leap_day.add_days(1).unwrap().to_string(),
+            "2024-03-01 00:00:00"
+        );
+        assert_eq!(
+            DateTime::from_millis(leap_day.millis() + 1)
+                .parts()
+                .millisecond,
+            1
+        );
+        assert!(DateTime::from_parts(Parts {
+            year: 2023,
+            month: 2,
+            day: 29,
+            ..Parts::default()
+        })
+        .is_err());
+        assert!(DateTime::from_parts(Parts {
+            hour: 24,
+            ..Parts::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn dates_are_formatted_and_parsed_with_patterns() {
+        let moment = DateTime::parse("%Y-%m-%d %H:%M:%S", "2024-03-01 13:05:09").unwrap();
+        assert_eq!(
+            moment.format("%a %d %b %Y, %I:%M %p, day %j").unwrap(),
+            "Fri 01 Mar 2024, 01:05 PM, day 061"
+        );
+        assert_eq!(
+            DateTime::parse("%d/%m/%y %I%p", "01/03/24 1PM").unwrap(),
+            DateTime::parse("%Y-%m-%d %H", "2024-03-01 13").unwrap()
+        );
+        assert!(DateTime::parse("%Y-%m-%d", "2024-13-01").is_err());
+        assert!(DateTime::parse("%Y-%m-%d", "March").is_err());
+        assert!(moment.format("%Q").is_err());
+    }
+
+    #[test]
+    fn durations_are_parsed_and_formatted() {
+        let duration = Duration::parse("1h30m").unwrap();
+        assert_eq!(duration.millis(), 90 * MILLIS_PER_MINUTE);
+        assert_eq!(duration.to_string(), "1h30m");
+        assert_eq!(duration.format("%H:%M").unwrap(), "01:30");
+        assert_eq!(duration.format("%M").unwrap(), "90");
+        assert_eq!(Duration::parse("-1.5d").unwrap().to_string(), "-1d12h");
+        assert_eq!(Duration::parse("250ms").unwrap().millis(), 250);
+        for text in ["", "5", "1x", "h"] {
+            assert!(Duration::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(
+            date(2024, 3, 1)
+                .since(date(2024, 2, 1))
+                .unwrap()
+                .to_string(),
+            "29d"
+        );
+    }
+}
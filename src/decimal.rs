@@ -0,0 +1,212 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+// Divisions that do not terminate are rounded to this many decimal places
+const DIVISION_SCALE: u32 = 28;
+
+// An exact decimal number worth units / 10^scale. The scale is kept as written
+// so 1.10 prints as 1.10, and results of arithmetic keep the largest scale of
+// their operands like they would on paper.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+fn overflow() -> String {
+    "Decimal overflow".to_owned()
+}
+
+fn power_of_ten(exponent: u32) -> Result<i128, String> {
+    10i128.checked_pow(exponent).ok_or_else(overflow)
+}
+
+impl Decimal {
+    pub fn from_integer(value: i64) -> Decimal {
+        Decimal {
+            units: value as i128,
+            scale: 0,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Decimal, String> {
+        let invalid = || format!("Invalid decimal \"{}\"", text);
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let mut units: i128 = 0;
+        for c in whole.chars().chain(fraction.chars()) {
+            units = units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(c.to_digit(10).unwrap() as i128))
+                .ok_or_else(overflow)?;
+        }
+        Ok(Decimal {
+            units: if negative { -units } else { units },
+            scale: fraction.len() as u32,
+        })
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.units == 0
+    }
+
+    fn rescale(&self, scale: u32) -> Result<i128, String> {
+        self.units
+            .checked_mul(power_of_ten(scale - self.scale)?)
+            .ok_or_else(overflow)
+    }
+
+    // Both operands expressed with the same, largest, scale
+    fn aligned(&self, other: &Decimal) -> Result<(i128, i128, u32), String> {
+        let scale = self.scale.max(other.scale);
+        Ok((self.rescale(scale)?, other.rescale(scale)?, scale))
+    }
+
+    pub fn add(&self, other: &Decimal) -> Result<Decimal, String> {
+        let (a, b, scale) = self.aligned(other)?;
+        let units = a.checked_add(b).ok_or_else(overflow)?;
+        Ok(Decimal { units, scale })
+    }
+
+    pub fn sub(&self, other: &Decimal) -> Result<Decimal, String> {
+        let (a, b, scale) = self.aligned(other)?;
+        let units = a.checked_sub(b).ok_or_else(overflow)?;
+        Ok(Decimal { units, scale })
+    }
+
+    pub fn mul(&self, other: &Decimal) -> Result<Decimal, String> {
+        let units = self.units.checked_mul(other.units).ok_or_else(overflow)?;
+        Ok(Decimal {
+            units,
+            scale: self.scale + other.scale,
+        }
+        .normalize(self.scale.max(other.scale)))
+    }
+
+    // Rounds half away from zero when the quotient does not terminate. Large
+    // dividends get fewer decimal places rather than overflowing.
+    pub fn div(&self, other: &Decimal) -> Result<Decimal, String> {
+        if other.is_zero() {
+            return Err("Division by zero".to_owned());
+        }
+        let min_scale = self.scale.max(other.scale);
+        let (dividend, scale) = (min_scale..=DIVISION_SCALE.max(min_scale))
+            .rev()
+            .find_map(|scale| {
+                let factor = 10i128.checked_pow(scale + other.scale - self.scale)?;
+                Some((self.units.checked_mul(factor)?, scale))
+            })
+            .ok_or_else(overflow)?;
+
+        let quotient = dividend / other.units;
+        let remainder = dividend % other.units;
+        let units = if remainder.unsigned_abs() * 2 >= other.units.unsigned_abs() {
+            quotient + dividend.signum() * other.units.signum()
+        } else {
+            quotient
+        };
+        Ok(Decimal { units, scale }.normalize(min_scale))
+    }
+
+    pub fn neg(&self) -> Decimal {
+        Decimal {
+            units: -self.units,
+            scale: self.scale,
+        }
+    }
+
+    // Drops trailing zeros beyond the given scale
+    fn normalize(mut self, min_scale: u32) -> Decimal {
+        while self.scale > min_scale && self.units % 10 == 0 {
+            self.units /= 10;
+            self.scale -= 1;
+        }
+        self
+    }
+
+    // Units and scale without trailing zeros, the same for equal decimals
+    pub fn parts(&self) -> (i128, u32) {
+        let normalized = self.normalize(0);
+        (normalized.units, normalized.scale)
+    }
+
+    pub fn compare(&self, other: &Decimal) -> Option<Ordering> {
+        let (a, b, _) = self.aligned(other).ok()?;
+        Some(a.cmp(&b))
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let digits = self.units.unsigned_abs().to_string();
+        let sign = if self.units < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        Decimal::parse(text).unwrap()
+    }
+
+    #[test]
+    fn arithmetic_is_exact_and_keeps_the_scale() {
+        let sum = decimal("0.1").add(&decimal("0.2")).unwrap();
+        assert_eq!(sum.to_string(), "0.3");
+        assert_eq!(sum.compare(&decimal("0.30")), Some(Ordering::Equal));
+        assert_eq!(
+            decimal("1.10").add(&decimal("2")).unwrap().to_string(),
+            "3.10"
+        );
+        assert_eq!(
+            decimal("1.5").sub(&decimal("2.25")).unwrap().to_string(),
+            "-0.75"
+        );
+        assert_eq!(
+            decimal("1.5").mul(&decimal("-2.25")).unwrap().to_string(),
+            "-3.375"
+        );
+        assert_eq!(decimal("1").div(&decimal("4")).unwrap().to_string(), "0.25");
+        assert_eq!(
+            decimal("2").div(&decimal("3")).unwrap().to_string(),
+            "0.6666666666666666666666666667"
+        );
+        assert!(decimal("1").div(&decimal("0.00")).is_err());
+    }
+
+    #[test]
+    fn invalid_text_and_overflows_are_errors() {
+        for text in ["", ".", "-", "1.2.3", "1e5", "12a", " 1"] {
+            assert!(Decimal::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(decimal("+.5").to_string(), "0.5");
+        assert_eq!(decimal("-0012.50").to_string(), "-12.50");
+
+        let big = decimal(&"9".repeat(38));
+        assert!(Decimal::parse(&"9".repeat(40)).is_err());
+        assert!(big.mul(&big).is_err());
+        assert!(big.add(&big).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::value::Value;
+
+pub type Items = Rc<RefCell<Vec<Value>>>;
+pub type Elements = Rc<RefCell<IndexSet<Value>>>;
+pub type Entries = Rc<RefCell<IndexMap<Value, Value>>>;
+
+// What the heap of the current thread holds, for scripts through gc_stats()
+// and for embedders through VM::gc_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcStats {
+    // Lists, sets and maps created since the thread started
+    pub allocations: usize,
+    // The ones still alive
+    pub live: usize,
+    pub collections: usize,
+    // Memory taken by the live containers and their items, not counting
+    // what the items point to such as the text of strings
+    pub bytes: usize,
+}
+
+// Lists hold items, sets hold elements and maps hold entries
+enum Tracked {
+    Items(Weak<RefCell<Vec<Value>>>),
+    Elements(Weak<RefCell<IndexSet<Value>>>),
+    Entries(Weak<RefCell<IndexMap<Value, Value>>>),
+}
+
+enum Node {
+    Items(Items),
+    Elements(Elements),
+    Entries(Entries),
+}
+
+impl Node {
+    fn address(&self) -> usize {
+        match self {
+            Node::Items(items) => Rc::as_ptr(items) as usize,
+            Node::Elements(elements) => Rc::as_ptr(elements) as usize,
+            Node::Entries(entries) => Rc::as_ptr(entries) as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Items(items) => Rc::strong_count(items),
+            Node::Elements(elements) => Rc::strong_count(elements),
+            Node::Entries(entries) => Rc::strong_count(entries),
+        }
+    }
+
+    // None while the container is borrowed, e.g. by a native running a callback
+    fn children(&self) -> Option<Vec<usize>> {
+        let mut children = Vec::new();
+        match self {
+            Node::Items(items) => {
+                for item in items.try_borrow().ok()?.iter() {
+                    children.extend(address(item));
+                }
+            }
+            // Elements are hashable, none of them is a container
+            Node::Elements(elements) => {
+                elements.try_borrow().ok()?;
+            }
+            Node::Entries(entries) => {
+                for (key, value) in entries.try_borrow().ok()?.iter() {
+                    children.extend(address(key));
+                    children.extend(address(value));
+                }
+            }
+        }
+        Some(children)
+    }
+
+    // Dropping the contents breaks the cycles the container is part of
+    fn clear(&self) {
+        match self {
+            Node::Items(items) => {
+                if let Ok(mut items) = items.try_borrow_mut() {
+                    drop(std::mem::take(&mut *items));
+                }
+            }
+            Node::Elements(elements) => {
+                if let Ok(mut elements) = elements.try_borrow_mut() {
+                    drop(std::mem::take(&mut *elements));
+                }
+            }
+            Node::Entries(entries) => {
+                if let Ok(mut entries) = entries.try_borrow_mut() {
+                    drop(std::mem::take(&mut *entries));
+                }
+            }
+        }
+    }
+}
+
+fn address(value: &Value) -> Option<usize> {
+    match value {
+        Value::List(items) => Some(Rc::as_ptr(items) as usize),
+        Value::Set(elements) => Some(Rc::as_ptr(elements) as usize),
+        Value::Map(entries) => Some(Rc::as_ptr(entries) as usize),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Heap {
+    tracked: RefCell<Vec<Tracked>>,
+    // Containers created since the last collection
+    pending: Cell<usize>,
+    allocations: Cell<usize>,
+    collections: Cell<usize>,
+}
+
+// Every thread runs its own VM, so each one has its own heap
+thread_local! {
+    static HEAP: Heap = Heap::default();
+}
+
+fn track(tracked: Tracked) {
+    HEAP.with(|heap| {
+        heap.tracked.borrow_mut().push(tracked);
+        heap.pending.set(heap.pending.get() + 1);
+        heap.allocations.set(heap.allocations.get() + 1);
+    });
+}
+
+// The backing store of a new list
+pub fn items(items: Vec<Value>) -> Items {
+    let items = Rc::new(RefCell::new(items));
+    track(Tracked::Items(Rc::downgrade(&items)));
+    items
+}
+
+// The backing store of a new set, Value::new_set checks the elements
+pub fn elements(elements: IndexSet<Value>) -> Elements {
+    let elements = Rc::new(RefCell::new(elements));
+    track(Tracked::Elements(Rc::downgrade(&elements)));
+    elements
+}
+
+// The backing store of a new map, Value::new_map checks the keys
+pub fn entries(entries: IndexMap<Value, Value>) -> Entries {
+    let entries = Rc::new(RefCell::new(entries));
+    track(Tracked::Entries(Rc::downgrade(&entries)));
+    entries
+}
+
+pub fn pending() -> usize {
+    HEAP.with(|heap| heap.pending.get())
+}
+
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| {
+        let mut stats = GcStats {
+            allocations: heap.allocations.get(),
+            collections: heap.collections.get(),
+            ..GcStats::default()
+        };
+        for tracked in heap.tracked.borrow().iter() {
+            // A container borrowed for writing is counted without its items
+            let bytes = match tracked {
+                Tracked::Items(items) => items.upgrade().map(|items| {
+                    let capacity = items.try_borrow().map_or(0, |items| items.capacity());
+                    size_of_val(&*items) + capacity * size_of::<Value>()
+                }),
+                // Each element also takes a slot of the hash index
+                Tracked::Elements(elements) => elements.upgrade().map(|elements| {
+                    let capacity = elements
+                        .try_borrow()
+                        .map_or(0, |elements| elements.capacity());
+                    size_of_val(&*elements) + capacity * (size_of::<Value>() + size_of::<usize>())
+                }),
+                Tracked::Entries(entries) => entries.upgrade().map(|entries| {
+                    let capacity = entries.try_borrow().map_or(0, |entries| entries.capacity());
+                    size_of_val(&*entries)
+                        + capacity * (size_of::<(Value, Value)>() + size_of::<usize>())
+                }),
+            };
+            if let Some(bytes) = bytes {
+                stats.live += 1;
+                // The two reference counts kept next to the container
+                stats.bytes += bytes + 2 * size_of::<usize>();
+            }
+        }
+        stats
+    })
+}
+
+// Reference counting frees a container as soon as nothing points to it, but
+// not containers pointing to each other, like a list holding itself.
+//
+// Anything referencing a container from outside the heap is a root: the
+// stack, frames, globals, timers and values held by natives or the embedder.
+// Subtracting the references containers hold to each other from their
+// reference counts leaves the ones referenced from outside. Everything
+// reachable from those is live, the rest is only kept alive by cycles and is
+// emptied so reference counting frees it. Returns how many were freed.
+pub fn collect() -> usize {
+    let nodes: Vec<Node> = HEAP.with(|heap| {
+        heap.pending.set(0);
+        heap.collections.set(heap.collections.get() + 1);
+        let mut tracked = heap.tracked.borrow_mut();
+        tracked.retain(|tracked| match tracked {
+            Tracked::Items(items) => items.strong_count() > 0,
+            Tracked::Elements(elements) => elements.strong_count() > 0,
+            Tracked::Entries(entries) => entries.strong_count() > 0,
+        });
+        tracked
+            .iter()
+            .filter_map(|tracked| match tracked {
+                Tracked::Items(items) => items.upgrade().map(Node::Items),
+                Tracked::Elements(elements) => elements.upgrade().map(Node::Elements),
+                Tracked::Entries(entries) => entries.upgrade().map(Node::Entries),
+            })
+            .collect()
+    });
+
+    let index: HashMap<usize, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.address(), i))
+        .collect();
+    // One reference of each is the one upgraded above
+    let mut outside: Vec<usize> = nodes.iter().map(|node| node.strong_count() - 1).collect();
+    let children: Vec<Option<Vec<usize>>> = nodes
+        .iter()
+        .map(|node| {
+            node.children().map(|children| {
+                children
+                    .iter()
+                    .filter_map(|address| index.get(address).copied())
+                    .collect()
+            })
+        })
+        .collect();
+    for child in children.iter().flatten().flatten() {
+        outside[*child] = outside[*child].saturating_sub(1);
+    }
+
+    // A borrowed container is in use and counts as a root
+    let mut live = vec![false; nodes.len()];
+    let mut pending: Vec<usize> = (0..nodes.len())
+        .filter(|i| outside[*i] > 0 || children[*i].is_none())
+        .collect();
+    while let Some(i) = pending.pop() {
+        if live[i] {
+            continue;
+        }
+        live[i] = true;
+        if let Some(children) = &children[i] {
+            pending.extend(children.iter().filter(|child| !live[**child]));
+        }
+    }
+
+    let garbage: Vec<&Node> = nodes
+        .iter()
+        .zip(&live)
+        .filter(|(_, live)| !**live)
+        .map(|(node, _)| node)
+        .collect();
+    for node in &garbage {
+        node.clear();
+    }
+    garbage.len()
+}
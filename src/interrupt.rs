@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by the signal handler and taken by the VM at its next check point, the
+// handler itself cannot safely do anything more than that
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod signal {
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_sigint(_signum: i32) {
+        super::INTERRUPTED.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn catch() {
+        let handler: extern "C" fn(i32) = on_sigint;
+        unsafe {
+            signal(SIGINT, handler as usize);
+        }
+    }
+
+    pub fn release() {
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod signal {
+    pub fn catch() {}
+
+    pub fn release() {}
+}
+
+// Ctrl-C only sets a flag from now on instead of killing the process
+pub fn catch_ctrl_c() {
+    signal::catch();
+}
+
+// Ctrl-C kills the process again, and an interrupt not taken yet is dropped
+pub fn release_ctrl_c() {
+    signal::release();
+    INTERRUPTED.store(false, Ordering::SeqCst);
+}
+
+// Interrupts the running script as Ctrl-C would, for hosts with their own
+// way of stopping it
+pub fn request() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Whether an interrupt is waiting to be taken, for waits that should end early
+pub fn pending() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Whether an interrupt came since the last call
+pub fn take() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
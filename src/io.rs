@@ -0,0 +1,135 @@
+use std::cell::RefCell;
+use std::io::{IsTerminal, Write};
+use std::rc::Rc;
+use std::sync::Arc;
+
+// Where a VM writes what scripts print and the errors it reports. Hosts
+// without a terminal, like a browser playground, capture it instead.
+pub trait Output {
+    fn write_out(&mut self, text: &str);
+    fn write_err(&mut self, text: &str);
+
+    // Whether what is written out is shown on a terminal, escape sequences
+    // for colors and the cursor are only written when it is
+    fn is_terminal(&self) -> bool {
+        false
+    }
+
+    // Columns of the terminal, if the size is known
+    fn width(&self) -> Option<usize> {
+        None
+    }
+
+    // Called before waiting on the user, so a prompt is shown in full
+    fn flush(&mut self) {}
+}
+
+// Makes an output for the VM of each thread a script spawns, since an Output
+// stays on the thread it was made on
+pub type OutputFactory = Arc<dyn Fn() -> Box<dyn Output> + Send + Sync>;
+
+pub struct StdOutput;
+
+impl Output for StdOutput {
+    fn write_out(&mut self, text: &str) {
+        print!("{}", text);
+    }
+
+    fn write_err(&mut self, text: &str) {
+        eprint!("{}", text);
+    }
+
+    fn is_terminal(&self) -> bool {
+        std::io::stdout().is_terminal()
+    }
+
+    fn width(&self) -> Option<usize> {
+        if !self.is_terminal() {
+            return None;
+        }
+        terminal_width().or_else(|| env_var("COLUMNS")?.parse().ok())
+    }
+
+    fn flush(&mut self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+// A variable of the environment the interpreter runs in, which the core built
+// without the os feature does not read
+#[cfg(feature = "os")]
+pub(crate) fn env_var(name: &str) -> Option<String> {
+    std::env::var_os(name).map(|value| value.to_string_lossy().into_owned())
+}
+
+#[cfg(not(feature = "os"))]
+pub(crate) fn env_var(_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn terminal_width() -> Option<usize> {
+    #[repr(C)]
+    struct WinSize {
+        rows: u16,
+        columns: u16,
+        x_pixels: u16,
+        y_pixels: u16,
+    }
+
+    extern "C" {
+        fn ioctl(fd: i32, request: std::ffi::c_ulong, ...) -> i32;
+    }
+
+    #[cfg(target_os = "linux")]
+    const TIOCGWINSZ: std::ffi::c_ulong = 0x5413;
+    #[cfg(target_os = "macos")]
+    const TIOCGWINSZ: std::ffi::c_ulong = 0x40087468;
+
+    let mut size = WinSize {
+        rows: 0,
+        columns: 0,
+        x_pixels: 0,
+        y_pixels: 0,
+    };
+    // Stdout is file descriptor 1, the call only fills in the struct
+    let result = unsafe { ioctl(1, TIOCGWINSZ, &mut size as *mut WinSize) };
+    if result == 0 && size.columns > 0 {
+        Some(size.columns as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+// Both streams in the order they were written, the way a terminal shows them.
+// Clones share the same text so the host keeps one to read it back.
+#[derive(Clone, Default)]
+pub struct CapturedOutput {
+    text: Rc<RefCell<String>>,
+}
+
+impl CapturedOutput {
+    pub fn new() -> CapturedOutput {
+        CapturedOutput::default()
+    }
+
+    // Returns everything written since the last call
+    pub fn take(&self) -> String {
+        std::mem::take(&mut self.text.borrow_mut())
+    }
+}
+
+impl Output for CapturedOutput {
+    fn write_out(&mut self, text: &str) {
+        self.text.borrow_mut().push_str(text);
+    }
+
+    fn write_err(&mut self, text: &str) {
+        self.text.borrow_mut().push_str(text);
+    }
+}
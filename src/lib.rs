@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate num_derive;
+extern crate num_traits;
+
+pub mod chunk;
+mod clock;
+mod common;
+mod compiler;
+pub mod datetime;
+pub mod decimal;
+mod gc;
+pub mod interrupt;
+pub mod io;
+mod native;
+pub mod object;
+pub mod options;
+pub mod program;
+mod scanner;
+mod thread;
+pub mod trace;
+pub mod value;
+#[cfg(feature = "serde")]
+mod value_serde;
+mod verify;
+pub mod vm;
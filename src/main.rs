@@ -1,32 +1,153 @@
-#[macro_use]
-extern crate num_derive;
-extern crate num_traits;
-
-mod chunk;
-mod common;
-mod compiler;
-mod object;
-mod scanner;
-mod value;
-mod vm;
+use max_interpreter::interrupt;
+use max_interpreter::options::{Capability, SandboxPolicy};
+use max_interpreter::trace::Trace;
+use max_interpreter::value::Value;
+use max_interpreter::vm::{InterpretError, InterpretResult, VM};
 use std::env;
 use std::io::Write;
 use std::process::exit;
-use vm::InterpretResult;
-use vm::VM;
+
+const USAGE: &str = "Usage: rlox [--sandbox[=capabilities]] [--strict] [--verify-bytecode] [--parallel-compile[=threads]] [--stress-gc] [--dump-bytecode=json] [--analyze] [--deterministic[=seed]] [--trace] [--trace-out=file] [--trace-function=name] [--trace-stack=n] [script [arguments]]";
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
-    let args: Vec<String> = env::args().collect();
+    // Options of the interpreter come before the script, whatever follows it
+    // is left for the script to read
+    let mut args = env::args().skip(1);
+    let mut flags = Vec::new();
+    let mut script = None;
+    for arg in args.by_ref() {
+        if arg.starts_with("--") {
+            flags.push(arg);
+        } else {
+            script = Some(arg);
+            break;
+        }
+    }
     let mut vm = VM::new();
+    // One answer per line for the prompt natives, for running interactive
+    // scripts unattended
+    if let Ok(answers) = env::var("MAX_ANSWERS") {
+        vm.set_answers(answers.lines().map(str::to_owned).collect());
+    }
+    let mut trace = false;
+    let mut trace_out = None;
+    let mut trace_functions = Vec::new();
+    let mut trace_stack = None;
 
-    if args.len() == 1 {
-        repl(&mut vm);
-    } else if args.len() == 2 {
-        run_file(&mut vm, &args[1]);
-    } else {
-        println!("Usage: rlox [script]");
-        exit(64);
+    for flag in flags {
+        if let Some(path) = flag.strip_prefix("--trace-out=") {
+            trace_out = Some(path.to_owned());
+            continue;
+        }
+        if let Some(name) = flag.strip_prefix("--trace-function=") {
+            trace_functions.push(name.to_owned());
+            continue;
+        }
+        // Sandbox mode allowing only the listed capabilities, e.g. --sandbox=network
+        if let Some(names) = flag.strip_prefix("--sandbox=") {
+            let mut policy = SandboxPolicy::deny_all();
+            for name in names.split(',').filter(|name| !name.is_empty()) {
+                match Capability::from_name(name) {
+                    Some(capability) => policy.allow(capability),
+                    None => {
+                        println!(
+                            "Unknown capability {}, expected filesystem, network, process, env or eval",
+                            name
+                        );
+                        exit(64);
+                    }
+                }
+            }
+            vm.set_sandbox_policy(policy);
+            continue;
+        }
+        if let Some(seed) = flag.strip_prefix("--deterministic=") {
+            match seed.parse::<u64>() {
+                Ok(seed) => vm.set_deterministic(seed),
+                Err(_) => {
+                    println!(
+                        "Expected a number as seed for --deterministic, got {}",
+                        seed
+                    );
+                    exit(64);
+                }
+            }
+            continue;
+        }
+        if let Some(threads) = flag.strip_prefix("--parallel-compile=") {
+            match threads.parse::<usize>() {
+                Ok(threads) if threads > 0 => vm.set_compile_threads(threads),
+                _ => {
+                    println!(
+                        "Expected a number of threads for --parallel-compile, got {}",
+                        threads
+                    );
+                    exit(64);
+                }
+            }
+            continue;
+        }
+        if let Some(depth) = flag.strip_prefix("--trace-stack=") {
+            match depth.parse::<usize>() {
+                Ok(depth) => trace_stack = Some(depth),
+                Err(_) => {
+                    println!(
+                        "Expected a number of values for --trace-stack, got {}",
+                        depth
+                    );
+                    exit(64);
+                }
+            }
+            continue;
+        }
+        match flag.as_str() {
+            "--sandbox" => vm.set_sandboxed(true),
+            "--strict" => vm.set_strict(true),
+            "--verify-bytecode" => vm.set_verify_bytecode(true),
+            "--parallel-compile" => vm.set_compile_threads(
+                std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            ),
+            "--stress-gc" => vm.set_gc_threshold(1),
+            "--dump-bytecode=json" => vm.set_dump_bytecode(true),
+            "--analyze" => vm.set_analyze(true),
+            "--trace" => trace = true,
+            "--deterministic" => vm.set_deterministic(0),
+            _ => {
+                println!("Unknown option {}", flag);
+                println!("{}", USAGE);
+                exit(64);
+            }
+        }
+    }
+
+    // Any of the trace options turns tracing on, to stderr unless a file is given
+    if trace || trace_out.is_some() || !trace_functions.is_empty() || trace_stack.is_some() {
+        let mut trace = match &trace_out {
+            Some(path) => Trace::to_file(path).unwrap_or_else(|message| {
+                println!("{}", message);
+                exit(74);
+            }),
+            None => Trace::stderr(),
+        };
+        for name in &trace_functions {
+            trace.filter_function(name);
+        }
+        if let Some(depth) = trace_stack {
+            trace.set_stack_depth(depth);
+        }
+        vm.set_trace(Some(trace));
+    }
+
+    // Ctrl-C stops the script, or the current line of the REPL, rather than
+    // killing the interpreter
+    vm.set_interruptible(true);
+    match script {
+        Some(script) => {
+            vm.set_script_args(args.collect());
+            run_file(&mut vm, &script);
+        }
+        None => repl(&mut vm),
     }
 }
 
@@ -39,21 +160,47 @@ fn repl(vm: &mut VM) {
         let mut line = String::new();
         std::io::stdin().read_line(&mut line).unwrap();
 
-        if line.is_empty() {
+        // Only the end of input, :quit or a call to exit leave the REPL
+        if line.is_empty() || line.trim() == ":quit" {
             break;
         }
+        if line.trim().is_empty() {
+            continue;
+        }
+        // A Ctrl-C pressed at the prompt is not meant for the next line
+        interrupt::take();
 
-        vm.interpret(line);
+        // A line holding an expression echoes its value, a failed line is
+        // rolled back so it leaves no frames or timers behind
+        let snapshot = vm.snapshot();
+        match vm.interpret(line) {
+            Ok(value) => {
+                if !matches!(value, Value::None) {
+                    println!("{}", value);
+                }
+                if let InterpretResult::Exit(status) = vm.run_event_loop() {
+                    exit(status);
+                }
+            }
+            Err(InterpretError::Exit(status)) => exit(status),
+            Err(_) => vm.restore(snapshot),
+        }
     }
 }
 
 fn run_file(vm: &mut VM, file: &str) {
     let source = std::fs::read_to_string(file).unwrap();
-    let result = vm.interpret(source);
+    let result = match vm.interpret_file(source, file) {
+        Ok(_) => vm.run_event_loop(),
+        Err(InterpretError::Compile) => InterpretResult::CompileError,
+        Err(InterpretError::Runtime) => InterpretResult::RuntimeError,
+        Err(InterpretError::Exit(status)) => InterpretResult::Exit(status),
+    };
 
     match result {
         InterpretResult::Ok => (),
         InterpretResult::CompileError => exit(65),
         InterpretResult::RuntimeError => exit(70),
+        InterpretResult::Exit(status) => exit(status),
     }
 }
@@ -0,0 +1,284 @@
+use indexmap::IndexMap;
+
+use crate::value::Value;
+use crate::vm::VM;
+
+// The arguments given after the script on the command line
+pub fn args(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    let args: Vec<Value> = vm
+        .script_args()
+        .iter()
+        .map(|arg| Value::from(arg.as_str()))
+        .collect();
+    Ok(Value::from(args))
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Bool,
+    Int,
+    Float,
+    String,
+}
+
+impl Kind {
+    fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "bool" => Some(Kind::Bool),
+            "int" => Some(Kind::Int),
+            "float" => Some(Kind::Float),
+            "string" => Some(Kind::String),
+            _ => None,
+        }
+    }
+
+    fn of(value: &Value) -> Option<Kind> {
+        match value {
+            Value::True | Value::False | Value::BoolNone => Some(Kind::Bool),
+            Value::Integer(_) | Value::IntegerNone => Some(Kind::Int),
+            Value::Float(_) | Value::FloatNone => Some(Kind::Float),
+            Value::String(_) | Value::StringNone => Some(Kind::String),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Bool => "bool",
+            Kind::Int => "int",
+            Kind::Float => "float",
+            Kind::String => "string",
+        }
+    }
+
+    // What an option that is neither given nor defaulted is set to, a flag
+    // that is not given is off
+    fn unset(self) -> Value {
+        match self {
+            Kind::Bool => Value::False,
+            Kind::Int => Value::IntegerNone,
+            Kind::Float => Value::FloatNone,
+            Kind::String => Value::StringNone,
+        }
+    }
+
+    fn parse(self, text: &str) -> Option<Value> {
+        match self {
+            Kind::Bool => match text {
+                "true" => Some(Value::True),
+                "false" => Some(Value::False),
+                _ => None,
+            },
+            Kind::Int => text.parse::<i64>().ok().map(Value::from),
+            Kind::Float => text.parse::<f64>().ok().map(Value::from),
+            Kind::String => Some(Value::from(text)),
+        }
+    }
+}
+
+struct Flag {
+    name: String,
+    kind: Kind,
+    default: Option<Value>,
+    required: bool,
+    help: String,
+}
+
+fn spec_entry(entries: &IndexMap<Value, Value>, name: &str) -> Option<Value> {
+    entries.get(&Value::from(name)).cloned()
+}
+
+// Every option of the spec is either given as its default value, its type
+// coming from it, or as a map with the type, default, required and help keys
+fn parse_spec(spec: &Value) -> Result<Vec<Flag>, String> {
+    let entries = match spec {
+        Value::Map(entries) => entries.borrow(),
+        value => {
+            return Err(format!(
+                "Expected a map of options, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let mut flags = Vec::new();
+    for (name, option) in entries.iter() {
+        let name = match name {
+            Value::String(name) => name.to_string(),
+            name => {
+                return Err(format!(
+                    "Option names must be strings, got {} instead",
+                    name.type_of()
+                ))
+            }
+        };
+        let (kind, default, required, help) = match option {
+            Value::Map(option) => {
+                let option = option.borrow();
+                let default = spec_entry(&option, "default");
+                let kind = match spec_entry(&option, "type") {
+                    Some(Value::String(kind)) => Kind::from_name(&kind).ok_or_else(|| {
+                        format!(
+                            "Unknown type {} for option {}, expected bool, int, float or string",
+                            kind, name
+                        )
+                    })?,
+                    Some(kind) => {
+                        return Err(format!(
+                            "The type of option {} must be a string, got {} instead",
+                            name,
+                            kind.type_of()
+                        ))
+                    }
+                    None => default.as_ref().and_then(Kind::of).unwrap_or(Kind::String),
+                };
+                let required = spec_entry(&option, "required").is_some_and(|r| r.is_truthy());
+                let help = spec_entry(&option, "help").map_or(String::new(), |h| h.to_text());
+                (kind, default, required, help)
+            }
+            value => {
+                let kind = Kind::of(value).ok_or_else(|| {
+                    format!(
+                        "Option {} has a default of type {}, expected bool, int, float or string",
+                        name,
+                        value.type_of()
+                    )
+                })?;
+                (kind, Some(value.clone()), false, String::new())
+            }
+        };
+        if let Some(default) = &default {
+            if Kind::of(default).map(Kind::name) != Some(kind.name()) {
+                return Err(format!(
+                    "Option {} is of type {}, its default cannot be of type {}",
+                    name,
+                    kind.name(),
+                    default.type_of()
+                ));
+            }
+        }
+        flags.push(Flag {
+            name,
+            kind,
+            default,
+            required,
+            help,
+        });
+    }
+    Ok(flags)
+}
+
+fn usage(flags: &[Flag]) -> String {
+    let names: Vec<String> = flags
+        .iter()
+        .map(|flag| match flag.kind {
+            Kind::Bool => format!("--{}", flag.name),
+            kind => format!("--{} {}", flag.name, kind.name()),
+        })
+        .collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0);
+
+    let mut usage = String::from("Options:");
+    for (flag, name) in flags.iter().zip(names) {
+        let mut description = flag.help.clone();
+        if flag.required {
+            description.push_str(" (required)");
+        } else if let Some(default) = &flag.default {
+            description.push_str(&format!(" (default {})", default));
+        }
+        let line = format!(
+            "\n  {:width$}  {}",
+            name,
+            description.trim_start(),
+            width = width
+        );
+        usage.push_str(line.trim_end());
+    }
+    usage
+}
+
+fn parse(flags: &[Flag], arguments: &[String]) -> Result<Value, String> {
+    let mut values: Vec<Option<Value>> = vec![None; flags.len()];
+    let mut positional = Vec::new();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        let option = match argument.strip_prefix("--") {
+            // Everything after a lone -- is positional
+            Some("") => {
+                positional.extend(arguments.by_ref().map(|arg| Value::from(arg.as_str())));
+                break;
+            }
+            Some(option) => option,
+            None => {
+                positional.push(Value::from(argument.as_str()));
+                continue;
+            }
+        };
+        let (name, inline) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let index = flags
+            .iter()
+            .position(|flag| flag.name == name)
+            .ok_or_else(|| format!("Unknown option --{}", name))?;
+        let kind = flags[index].kind;
+        let text = match (inline, kind) {
+            (Some(text), _) => text,
+            (None, Kind::Bool) => "true",
+            (None, _) => arguments
+                .next()
+                .ok_or_else(|| format!("Option --{} expects a value", name))?,
+        };
+        let value = kind.parse(text).ok_or_else(|| {
+            format!(
+                "Option --{} expects a value of type {}, got {}",
+                name,
+                kind.name(),
+                text
+            )
+        })?;
+        values[index] = Some(value);
+    }
+
+    let mut entries = Vec::new();
+    for (flag, value) in flags.iter().zip(values) {
+        let value = match value.or_else(|| flag.default.clone()) {
+            Some(value) => value,
+            None if flag.required => return Err(format!("Missing option --{}", flag.name)),
+            None => flag.kind.unset(),
+        };
+        entries.push((Value::from(flag.name.as_str()), value));
+    }
+    entries.push((Value::from("_"), Value::from(positional)));
+    Value::new_map(entries)
+}
+
+// Parses the script arguments, or the given list of strings, against a map of
+// options. The result maps every option name to its value and `_` to the
+// positional arguments. A wrong command line fails with the usage.
+pub fn parse_args(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let flags = parse_spec(&args[0])?;
+    let arguments = match args.get(1) {
+        Some(Value::List(items)) => items
+            .borrow()
+            .iter()
+            .map(|item| match item {
+                Value::String(item) => Ok(item.to_string()),
+                item => Err(format!(
+                    "Arguments must be strings, got {} instead",
+                    item.type_of()
+                )),
+            })
+            .collect::<Result<Vec<String>, String>>()?,
+        Some(value) => {
+            return Err(format!(
+                "Expected a list of arguments, got {} instead",
+                value.type_of()
+            ))
+        }
+        None => vm.script_args().to_vec(),
+    };
+    parse(&flags, &arguments).map_err(|message| format!("{}\n{}", message, usage(&flags)))
+}
@@ -0,0 +1,54 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+// Adds the element of one iteration to the collection a comprehension builds,
+// which comes last. A map gets the value then the key. Sets and maps keep
+// their entries unique like their literals do.
+pub fn comprehension_add(_vm: &mut VM, mut args: Vec<Value>) -> Result<Value, String> {
+    let collection = args.pop().unwrap_or(Value::None);
+    let value = args.remove(0);
+    match (&collection, args.first()) {
+        (Value::List(items), None) => items.borrow_mut().push(value),
+        (Value::Set(elements), None) => {
+            if !value.is_hashable() {
+                return Err(format!("Unhashable type {} in set", value.type_of()));
+            }
+            elements.borrow_mut().insert(value);
+        }
+        (Value::Map(entries), Some(key)) => {
+            if !key.is_hashable() {
+                return Err(format!("Unhashable type {} as map key", key.type_of()));
+            }
+            entries.borrow_mut().insert(key.clone(), value);
+        }
+        (collection, _) => {
+            return Err(format!(
+                "Cannot add to a comprehension of type {}",
+                collection.type_of()
+            ))
+        }
+    }
+    Ok(Value::None)
+}
+
+// Checks that a value given to several loop variables, like `k, v`, is a list
+// with one item per variable. The list is returned for the variables to index.
+pub fn unpack(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let expected = match args[1] {
+        Value::Integer(expected) => expected as usize,
+        _ => return Err("Expected the number of values to unpack".to_owned()),
+    };
+    match &args[0] {
+        Value::List(items) if items.borrow().len() == expected => Ok(args[0].clone()),
+        Value::List(items) => Err(format!(
+            "Expected {} values to unpack, got {}",
+            expected,
+            items.borrow().len()
+        )),
+        value => Err(format!(
+            "Expected a list of {} values to unpack, got {} instead",
+            expected,
+            value.type_of()
+        )),
+    }
+}
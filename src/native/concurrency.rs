@@ -0,0 +1,138 @@
+#[cfg(feature = "os")]
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::object::ObjChannel;
+use crate::thread::Sendable;
+#[cfg(feature = "os")]
+use crate::thread::SendableFunction;
+use crate::value::Value;
+#[cfg(feature = "os")]
+use crate::vm::InterpretResult;
+use crate::vm::VM;
+
+// The function runs on its own thread with a fresh VM. Its arguments and the
+// functions it can call are copied over, nothing is shared but channels.
+#[cfg(feature = "os")]
+pub fn spawn(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut args = args.into_iter();
+    let function = match args.next().unwrap() {
+        Value::ObjFunction(function) => function.as_ref().clone(),
+        value => {
+            return Err(format!(
+                "Expected a function to spawn, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let args: Vec<Value> = args.collect();
+    let arg_count = function.function_info.arg_names.len();
+    if args.len() != arg_count {
+        return Err(format!(
+            "Function {} expects {} arguments but got {}",
+            function.name,
+            arg_count,
+            args.len()
+        ));
+    }
+    function.function_info.check_argument_types(&args)?;
+
+    let slots = vm
+        .function_slots()
+        .iter()
+        .chain(function.bound.iter())
+        .chain(args.iter())
+        .map(Sendable::from_value)
+        .collect::<Result<Vec<_>, _>>()?;
+    let function = SendableFunction::from_function(&function)?;
+
+    let options = vm.options();
+    let output_factory = vm.output_factory();
+    // A deterministic thread gets its own seed, drawn from the spawning VM,
+    // and a virtual clock starting from the time it was spawned at
+    let deterministic = if vm.is_deterministic() {
+        Some((vm.rng().next_u64(), vm.clock().elapsed()))
+    } else {
+        None
+    };
+    let handle = std::thread::spawn(move || {
+        let slots = slots.into_iter().map(Sendable::into_value).collect();
+        let mut vm = VM::with_options(options);
+        if let Some(factory) = output_factory {
+            vm.set_output_factory(factory);
+        }
+        if let Some((seed, elapsed)) = deterministic {
+            vm.set_deterministic(seed);
+            vm.clock().sleep(elapsed);
+        }
+        matches!(
+            vm.run_function(Rc::new(function.into_function()), slots),
+            InterpretResult::Ok
+        )
+    });
+    Ok(Value::Thread(Rc::new(RefCell::new(Some(handle)))))
+}
+
+pub fn join(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let handle = match &args[0] {
+        Value::Thread(handle) => handle.borrow_mut().take(),
+        value => {
+            return Err(format!(
+                "Expected a thread to join, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    match handle.map(|handle| handle.join()) {
+        Some(Ok(true)) => Ok(Value::None),
+        Some(Ok(false)) => Err("Joined thread stopped with an error".to_owned()),
+        Some(Err(_)) => Err("Joined thread panicked".to_owned()),
+        None => Err("Thread was already joined".to_owned()),
+    }
+}
+
+pub fn channel(_vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Channel(Rc::new(ObjChannel::new())))
+}
+
+pub fn send(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let channel = match &args[0] {
+        Value::Channel(channel) => channel,
+        value => {
+            return Err(format!(
+                "Expected a channel to send to, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let message = Sendable::from_value(&args[1])?;
+    match channel.sender.send(message) {
+        Ok(()) => Ok(Value::None),
+        Err(_) => Err("Channel is closed".to_owned()),
+    }
+}
+
+// Blocks until a value is sent on the channel
+pub fn recv(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let channel = match &args[0] {
+        Value::Channel(channel) => channel,
+        value => {
+            return Err(format!(
+                "Expected a channel to receive from, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let receiver = match channel.receiver.lock() {
+        Ok(receiver) => receiver,
+        Err(_) => return Err("Channel is unusable after a thread panicked".to_owned()),
+    };
+    match receiver.recv() {
+        Ok(message) => Ok(message.into_value()),
+        Err(_) => Err("Channel is closed".to_owned()),
+    }
+}
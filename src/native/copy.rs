@@ -0,0 +1,10 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+pub fn copy(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(args[0].shallow_copy())
+}
+
+pub fn deep_copy(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(args[0].deep_copy())
+}
@@ -0,0 +1,74 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::object::ObjGenerator;
+use crate::value::Value;
+use crate::vm::{Resumed, VM};
+
+// Any function can run as a coroutine, its argument if it takes one is the
+// value given to the first resume.
+pub fn coroutine(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let function = match &args[0] {
+        Value::ObjFunction(function) => function.clone(),
+        value => {
+            return Err(format!(
+                "Expected a function to create a coroutine, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let arg_count = function.function_info.arg_names.len();
+    if arg_count > 1 {
+        return Err(format!(
+            "Coroutine functions take at most one argument, {} takes {}",
+            function.name, arg_count
+        ));
+    }
+
+    let mut slots = vm.function_slots();
+    slots.extend_from_slice(&function.bound);
+    let mut generator = ObjGenerator::new(function, slots);
+    generator.awaiting_argument = arg_count == 1;
+    Ok(Value::Generator(Rc::new(RefCell::new(generator))))
+}
+
+pub fn resume(vm: &mut VM, mut args: Vec<Value>) -> Result<Value, String> {
+    let sent = if args.len() > 1 {
+        args.pop().unwrap()
+    } else {
+        Value::None
+    };
+
+    let generator = match &args[0] {
+        Value::Generator(generator) => generator.clone(),
+        value => {
+            return Err(format!(
+                "Can only resume coroutines, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    if generator.borrow().done {
+        return Err("Cannot resume a finished coroutine".to_owned());
+    }
+
+    match vm.resume(generator, sent) {
+        Ok(Resumed::Yielded(value)) | Ok(Resumed::Returned(value)) => Ok(value),
+        Err(_) => Err("Error raised while running the coroutine".to_owned()),
+    }
+}
+
+pub fn is_done(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Generator(generator) => Ok(if generator.borrow().done {
+            Value::True
+        } else {
+            Value::False
+        }),
+        value => Err(format!(
+            "Expected a coroutine, got {} instead",
+            value.type_of()
+        )),
+    }
+}
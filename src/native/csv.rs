@@ -0,0 +1,155 @@
+use crate::gc;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn new_list(items: Vec<Value>) -> Value {
+    Value::List(gc::items(items))
+}
+
+// Follows RFC 4180: fields containing a separator, a quote or a line break
+// are wrapped in quotes and quotes inside them are doubled.
+fn parse_rows(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err("Unterminated quoted field in csv".to_owned());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+// With the header option, the first row names the fields and every other row
+// becomes a map from those names to its values.
+pub fn csv_parse(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::String(text) => text,
+        value => {
+            return Err(format!(
+                "Expected csv text as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let header = args.get(1).is_some_and(Value::is_truthy);
+
+    let mut rows = parse_rows(text)?.into_iter();
+    if !header {
+        return Ok(new_list(
+            rows.map(|row| new_list(row.into_iter().map(Value::from).collect()))
+                .collect(),
+        ));
+    }
+
+    let names: Vec<Value> = match rows.next() {
+        Some(names) => names.into_iter().map(Value::from).collect(),
+        None => return Ok(new_list(Vec::new())),
+    };
+    let mut records = Vec::new();
+    for (line, row) in rows.enumerate() {
+        if row.len() != names.len() {
+            return Err(format!(
+                "Csv row {} has {} fields, expected {}",
+                line + 2,
+                row.len(),
+                names.len()
+            ));
+        }
+        let entries = names
+            .iter()
+            .cloned()
+            .zip(row.into_iter().map(Value::from))
+            .collect();
+        records.push(Value::new_map(entries)?);
+    }
+    Ok(new_list(records))
+}
+
+fn field(value: &Value) -> String {
+    let text = value.to_text();
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+fn join_fields<'a>(fields: impl Iterator<Item = &'a Value>) -> String {
+    fields.map(field).collect::<Vec<_>>().join(",") + "\n"
+}
+
+// Rows are either lists of fields or maps, in which case the keys of the first
+// map are written as the header row.
+pub fn csv_stringify(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let rows = match &args[0] {
+        Value::List(rows) => rows.borrow(),
+        value => {
+            return Err(format!(
+                "Expected a list of rows, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let mut text = String::new();
+    let mut names: Option<Vec<Value>> = None;
+    for row in rows.iter() {
+        match row {
+            Value::List(fields) => text.push_str(&join_fields(fields.borrow().iter())),
+            Value::Map(entries) => {
+                let names = names.get_or_insert_with(|| {
+                    let names: Vec<Value> = entries
+                        .borrow()
+                        .iter()
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    text.push_str(&join_fields(names.iter()));
+                    names
+                });
+                let mut fields = Vec::new();
+                for name in names.iter() {
+                    fields.push(row.index(name.clone())?);
+                }
+                text.push_str(&join_fields(fields.iter()));
+            }
+            value => {
+                return Err(format!(
+                    "Csv rows must be lists or maps, got {} instead",
+                    value.type_of()
+                ))
+            }
+        }
+    }
+    Ok(Value::from(text))
+}
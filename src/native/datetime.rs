@@ -0,0 +1,155 @@
+use crate::datetime::{DateTime, Duration, Parts};
+use crate::value::Value;
+use crate::vm::VM;
+
+fn part(value: &Value, name: &str) -> Result<i64, String> {
+    match value {
+        Value::Integer(part) => Ok(*part),
+        value => Err(format!(
+            "Expected the {} as an int, got {} instead",
+            name,
+            value.type_of()
+        )),
+    }
+}
+
+// Parts other than the year are checked against their range by the datetime
+fn small_part(value: &Value, name: &str) -> Result<u32, String> {
+    let number = part(value, name)?;
+    u32::try_from(number).map_err(|_| format!("The {} cannot be {}", name, number))
+}
+
+fn text<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    match value {
+        Value::String(text) => Ok(text),
+        value => Err(format!(
+            "Expected the {} as a string, got {} instead",
+            name,
+            value.type_of()
+        )),
+    }
+}
+
+fn receiver(value: &Value) -> Result<DateTime, String> {
+    match value {
+        Value::DateTime(datetime) => Ok(*datetime),
+        value => Err(format!(
+            "Expected a datetime, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// `datetime()` is the current time, `datetime(ms)` the time given in
+// milliseconds since the epoch like now() returns, and
+// `datetime(year, month, day[, hour, minute, second])` a date in UTC
+pub fn datetime(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let datetime = match args.len() {
+        0 => DateTime::from_millis(vm.clock().since_epoch().as_millis() as i64),
+        1 => DateTime::from_millis(part(&args[0], "timestamp")?),
+        2 => return Err("Expected a timestamp or at least a year, month and day".to_owned()),
+        _ => {
+            let names = ["hour", "minute", "second"];
+            let mut time = [0; 3];
+            for (i, value) in args[3..].iter().enumerate() {
+                time[i] = small_part(value, names[i])?;
+            }
+            DateTime::from_parts(Parts {
+                year: part(&args[0], "year")?,
+                month: small_part(&args[1], "month")?,
+                day: small_part(&args[2], "day")?,
+                hour: time[0],
+                minute: time[1],
+                second: time[2],
+                millisecond: 0,
+            })?
+        }
+    };
+    Ok(Value::from(datetime))
+}
+
+// `parse_datetime("%Y-%m-%d", "2024-03-01")`
+pub fn parse_datetime(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let pattern = text(&args[0], "pattern")?;
+    let string = text(&args[1], "date")?;
+    Ok(Value::from(DateTime::parse(pattern, string)?))
+}
+
+// `parse_duration("1h30m")`
+pub fn parse_duration(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let string = text(&args[0], "duration")?;
+    Ok(Value::from(Duration::parse(string)?))
+}
+
+pub fn add_days(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let days = part(&args[1], "number of days")?;
+    Ok(Value::from(receiver(&args[0])?.add_days(days)?))
+}
+
+// Seconds from the other datetime to this one, negative when it is later
+pub fn diff(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let millis = receiver(&args[0])?.millis() as i128 - receiver(&args[1])?.millis() as i128;
+    Ok(Value::Float(millis as f64 / 1000.0))
+}
+
+pub fn datetime_format(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let pattern = text(&args[1], "pattern")?;
+    Ok(Value::from(receiver(&args[0])?.format(pattern)?))
+}
+
+pub fn year(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().year))
+}
+
+pub fn month(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().month as i64))
+}
+
+pub fn day(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().day as i64))
+}
+
+pub fn hour(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().hour as i64))
+}
+
+pub fn minute(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().minute as i64))
+}
+
+pub fn second(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.parts().second as i64))
+}
+
+// 1 for Monday up to 7 for Sunday
+pub fn weekday(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.weekday() as i64))
+}
+
+// Milliseconds since the epoch, like now() returns
+pub fn timestamp(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(receiver(&args[0])?.millis()))
+}
+
+fn duration(value: &Value) -> Result<Duration, String> {
+    match value {
+        Value::Duration(duration) => Ok(*duration),
+        value => Err(format!(
+            "Expected a duration, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+pub fn duration_format(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let pattern = text(&args[1], "pattern")?;
+    Ok(Value::from(duration(&args[0])?.format(pattern)?))
+}
+
+pub fn total_seconds(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Float(duration(&args[0])?.millis() as f64 / 1000.0))
+}
+
+pub fn milliseconds(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(duration(&args[0])?.millis()))
+}
@@ -0,0 +1,90 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn string(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        value => Err(format!(
+            "Expected a string, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// Decoded bytes become a string again, so they must be valid UTF-8
+fn decoded(bytes: Vec<u8>) -> Result<Value, String> {
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(Value::from(s)),
+        Err(_) => Err("Decoded data is not valid UTF-8".to_owned()),
+    }
+}
+
+pub fn base64_encode(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let bytes = string(&args[0])?.as_bytes();
+    let mut encoded = String::new();
+    for group in bytes.chunks(3) {
+        let buffer = [
+            group[0],
+            *group.get(1).unwrap_or(&0),
+            *group.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, buffer[0], buffer[1], buffer[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                let index = (bits >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    Ok(Value::from(encoded))
+}
+
+pub fn base64_decode(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let encoded = string(&args[0])?.trim_end_matches('=');
+    let mut bytes = Vec::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in encoded.chars() {
+        let value = match BASE64_ALPHABET.iter().position(|&b| b as char == c) {
+            Some(value) => value as u32,
+            None => return Err(format!("Invalid base64 character '{}'", c)),
+        };
+        bits = (bits << 6) | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    decoded(bytes)
+}
+
+pub fn hex_encode(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let bytes = string(&args[0])?.as_bytes();
+    Ok(Value::from(
+        bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>(),
+    ))
+}
+
+pub fn hex_decode(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let encoded = string(&args[0])?;
+    if encoded.len() % 2 != 0 || !encoded.is_ascii() {
+        return Err(format!("Invalid hex string \"{}\"", encoded));
+    }
+
+    let bytes = (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&encoded[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| format!("Invalid hex string \"{}\"", encoded))?;
+    decoded(bytes)
+}
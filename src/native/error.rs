@@ -0,0 +1,70 @@
+use std::rc::Rc;
+
+use crate::object::ObjError;
+use crate::value::Value;
+use crate::vm::{RUNTIME_ERROR, VM};
+
+// A new error to raise, `error("ParseError", "unexpected end")` is caught by
+// `catch ParseError e`. Its trace is where it was made.
+pub fn error(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let (kind, message) = match args.len() {
+        1 => (RUNTIME_ERROR, &args[0]),
+        _ => (text(&args[0], "kind")?, &args[1]),
+    };
+    let message = text(message, "message")?;
+    Ok(Value::Error(Rc::new(vm.error_value(kind, message))))
+}
+
+fn text<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    match value {
+        Value::String(text) => Ok(text),
+        value => Err(format!(
+            "Expected the error {} as a string, got {} instead",
+            name,
+            value.type_of()
+        )),
+    }
+}
+
+fn as_error(value: &Value) -> Result<&Rc<ObjError>, String> {
+    match value {
+        Value::Error(error) => Ok(error),
+        value => Err(format!(
+            "Expected an error, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+pub fn error_kind(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(&*as_error(&args[0])?.kind))
+}
+
+pub fn error_message(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(as_error(&args[0])?.message.as_str()))
+}
+
+pub fn error_line(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(as_error(&args[0])?.line as i64))
+}
+
+pub fn error_function(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(&*as_error(&args[0])?.function))
+}
+
+// One map per call that led to the error, the script first and the function
+// that raised it last
+pub fn error_trace(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let frames = as_error(&args[0])?
+        .trace
+        .iter()
+        .map(|frame| {
+            Value::new_map(vec![
+                (Value::from("function"), Value::from(&*frame.function)),
+                (Value::from("file"), Value::from(&*frame.file)),
+                (Value::from("line"), Value::Integer(frame.line as i64)),
+            ])
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::from(frames))
+}
@@ -0,0 +1,21 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+// Stops the script and hands the status to the host, the interpreter exits
+// with it. Without a status the script ends as if it had succeeded.
+pub fn exit(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let status = match args.first() {
+        None => 0,
+        Some(Value::Integer(status)) => {
+            i32::try_from(*status).map_err(|_| format!("Exit status {} is out of range", status))?
+        }
+        Some(value) => {
+            return Err(format!(
+                "Expected an integer exit status, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    vm.request_exit(status);
+    Ok(Value::None)
+}
@@ -0,0 +1,384 @@
+use crate::io::env_var;
+use crate::value::{format_float, Value};
+use crate::vm::VM;
+
+// A subset of the Python format specification:
+// [[fill]align][sign][0][width][grouping][.precision][type]
+#[derive(Default)]
+struct Spec {
+    fill: Option<char>,
+    align: Option<char>,
+    sign: Option<char>,
+    zero: bool,
+    width: usize,
+    // ',' or '_' between groups of digits
+    grouping: Option<char>,
+    precision: Option<usize>,
+    kind: Option<char>,
+}
+
+// Widths, precisions and decimals come from scripts, anything larger is refused
+// instead of allocating the text
+const MAX_LENGTH: usize = 1000;
+
+fn parse_spec(spec: &str) -> Result<Spec, String> {
+    let invalid = || format!("Invalid format specification \"{}\"", spec);
+    let chars: Vec<char> = spec.chars().collect();
+    let mut parsed = Spec::default();
+    let mut i = 0;
+
+    let is_align = |c: &char| matches!(c, '<' | '>' | '^');
+    if chars.get(1).is_some_and(is_align) {
+        parsed.fill = Some(chars[0]);
+        parsed.align = Some(chars[1]);
+        i = 2;
+    } else if chars.first().is_some_and(is_align) {
+        parsed.align = Some(chars[0]);
+        i = 1;
+    }
+
+    if let Some(&sign @ ('+' | '-' | ' ')) = chars.get(i) {
+        parsed.sign = Some(sign);
+        i += 1;
+    }
+    if chars.get(i) == Some(&'0') {
+        parsed.zero = true;
+        i += 1;
+    }
+
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while chars.get(*i).is_some_and(char::is_ascii_digit) {
+            *i += 1;
+        }
+        chars[start..*i].iter().collect::<String>()
+    };
+    let width = digits(&mut i);
+    if !width.is_empty() {
+        parsed.width = width.parse().map_err(|_| invalid())?;
+        if parsed.width > MAX_LENGTH {
+            return Err(format!("Format width cannot be more than {}", MAX_LENGTH));
+        }
+    }
+    if let Some(&grouping @ (',' | '_')) = chars.get(i) {
+        parsed.grouping = Some(grouping);
+        i += 1;
+    }
+    if chars.get(i) == Some(&'.') {
+        i += 1;
+        let precision = digits(&mut i).parse().map_err(|_| invalid())?;
+        if precision > MAX_LENGTH {
+            return Err(format!(
+                "Format precision cannot be more than {}",
+                MAX_LENGTH
+            ));
+        }
+        parsed.precision = Some(precision);
+    }
+
+    match chars.get(i) {
+        Some(kind @ ('f' | 'e' | '%' | 'd' | 'x' | 'X' | 'b' | 'o' | 's')) => {
+            parsed.kind = Some(*kind);
+            i += 1;
+        }
+        Some(_) => return Err(invalid()),
+        None => (),
+    }
+    if i != chars.len() {
+        return Err(invalid());
+    }
+    Ok(parsed)
+}
+
+// Separates the digits in groups of size from the right, like 1,234,567
+fn group_digits(digits: &str, separator: char, size: usize) -> String {
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(size) {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+// Ints are passed along as such so their digits never go through a float
+fn format_with_spec(value: f64, integer: Option<i64>, spec: &Spec) -> Result<String, String> {
+    let radix = matches!(spec.kind, Some('x' | 'X' | 'b' | 'o'));
+    if let (Some(','), true) = (spec.grouping, radix) {
+        return Err(format!(
+            "Cannot use ',' with format type '{}'",
+            spec.kind.unwrap_or_default()
+        ));
+    }
+
+    let magnitude = value.abs();
+    let digits = match (spec.kind, spec.precision, integer) {
+        _ if !value.is_finite() => format_float(magnitude),
+        (Some('f'), precision, _) => format!("{:.*}", precision.unwrap_or(6), magnitude),
+        (Some('e'), precision, _) => {
+            // Exponents are written like C and Python do, as in 1.5e+03
+            let digits = format!("{:.*e}", precision.unwrap_or(6), magnitude);
+            let invalid = || format!("Cannot write {} in exponent notation", value);
+            let (mantissa, exponent) = digits.split_once('e').ok_or_else(invalid)?;
+            let exponent: i32 = exponent.parse().map_err(|_| invalid())?;
+            format!(
+                "{}e{}{:02}",
+                mantissa,
+                if exponent < 0 { '-' } else { '+' },
+                exponent.abs()
+            )
+        }
+        (Some('%'), precision, _) => {
+            format!("{:.*}%", precision.unwrap_or(6), magnitude * 100.0)
+        }
+        (Some('d'), _, Some(i)) => format!("{}", i.unsigned_abs()),
+        (Some('x'), _, Some(i)) => format!("{:x}", i.unsigned_abs()),
+        (Some('X'), _, Some(i)) => format!("{:X}", i.unsigned_abs()),
+        (Some('b'), _, Some(i)) => format!("{:b}", i.unsigned_abs()),
+        (Some('o'), _, Some(i)) => format!("{:o}", i.unsigned_abs()),
+        (Some('s'), _, _) => return Err("Format type 's' requires a string".to_owned()),
+        (Some(kind), _, _) => return Err(format!("Format type '{}' requires an int", kind)),
+        (None, Some(precision), _) => format!("{:.*}", precision, magnitude),
+        (None, None, Some(i)) => format!("{}", i.unsigned_abs()),
+        (None, None, None) => format_float(magnitude),
+    };
+
+    let sign = if value.is_sign_negative() && value != 0.0 {
+        "-"
+    } else {
+        match spec.sign {
+            Some('+') => "+",
+            Some(' ') => " ",
+            _ => "",
+        }
+    };
+
+    // Digits in other bases are grouped by 4, the exponent notation has a
+    // single digit before the point
+    let grouping = spec
+        .grouping
+        .filter(|_| value.is_finite() && spec.kind != Some('e'))
+        .map(|separator| (separator, if radix { 4 } else { 3 }));
+    let Some((separator, size)) = grouping else {
+        // Zero padding goes between the sign and the digits
+        if spec.zero && spec.align.is_none() {
+            let width = spec.width.saturating_sub(sign.len());
+            return Ok(format!("{}{:0>width$}", sign, digits, width = width));
+        }
+        return Ok(format!("{}{}", sign, digits));
+    };
+
+    let end = digits
+        .find(|c: char| !c.is_ascii_hexdigit())
+        .unwrap_or(digits.len());
+    let (mut integer, rest) = (digits[..end].to_owned(), &digits[end..]);
+    let mut grouped = group_digits(&integer, separator, size);
+    // Zeros added for the padding are grouped too, as in 0,001,234
+    if spec.zero && spec.align.is_none() {
+        let width = spec.width.saturating_sub(sign.len() + rest.chars().count());
+        while grouped.chars().count() < width {
+            integer.insert(0, '0');
+            grouped = group_digits(&integer, separator, size);
+        }
+    }
+    Ok(format!("{}{}{}", sign, grouped, rest))
+}
+
+fn pad(text: String, spec: &Spec, default_align: char) -> String {
+    let len = text.chars().count();
+    if len >= spec.width {
+        return text;
+    }
+    let fill = spec.fill.unwrap_or(' ').to_string();
+    let missing = spec.width - len;
+    let (left, right) = match spec.align.unwrap_or(default_align) {
+        '<' => (0, missing),
+        '^' => (missing / 2, missing - missing / 2),
+        _ => (missing, 0),
+    };
+    format!("{}{}{}", fill.repeat(left), text, fill.repeat(right))
+}
+
+pub fn format(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let spec = match &args[1] {
+        Value::String(spec) => parse_spec(spec)?,
+        value => {
+            return Err(format!(
+                "Expected the format specification as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let formatted = match &args[0] {
+        Value::Integer(i) => pad(format_with_spec(*i as f64, Some(*i), &spec)?, &spec, '>'),
+        Value::Float(f) => pad(format_with_spec(*f, None, &spec)?, &spec, '>'),
+        value => {
+            if !matches!(spec.kind, None | Some('s')) || spec.sign.is_some() {
+                return Err(format!(
+                    "Cannot use a numeric format on type {}",
+                    value.type_of()
+                ));
+            }
+            let mut text = value.to_text();
+            if let Some(precision) = spec.precision {
+                text = text.chars().take(precision).collect();
+            }
+            pad(text, &spec, '<')
+        }
+    };
+    Ok(Value::from(formatted))
+}
+
+// The separators of the locale set in the environment, between thousands and
+// before the decimals. Locales that are not known write numbers like English.
+// A deterministic VM ignores the environment and uses the C locale, which
+// does not group digits.
+fn locale_separators(vm: &VM) -> (Option<char>, char) {
+    if vm.is_deterministic() {
+        return (None, '.');
+    }
+    let locale = ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|name| env_var(name))
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_default();
+    let language = locale
+        .split(['_', '-', '.', '@'])
+        .next()
+        .unwrap_or_default();
+    match language {
+        "da" | "de" | "el" | "es" | "id" | "it" | "nl" | "pt" | "tr" => (Some('.'), ','),
+        "cs" | "fi" | "fr" | "hu" | "nb" | "no" | "pl" | "ru" | "sk" | "sv" | "uk" => {
+            (Some('\u{a0}'), ',')
+        }
+        _ => (Some(','), '.'),
+    }
+}
+
+// `format_number(1234.5)` gives "1,234.50", or "1.234,50" in a German locale.
+// Grouping can be turned off and the number of decimals changed.
+pub fn format_number(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let grouping = match args.get(1) {
+        None | Some(Value::True) => true,
+        Some(Value::False) => false,
+        Some(value) => {
+            return Err(format!(
+                "Expected the grouping as a bool, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let decimals = match args.get(2) {
+        None => 2,
+        Some(Value::Integer(decimals)) if (0..=MAX_LENGTH as i64).contains(decimals) => {
+            *decimals as usize
+        }
+        Some(Value::Integer(decimals)) if *decimals < 0 => {
+            return Err("The number of decimals cannot be negative".to_owned())
+        }
+        Some(Value::Integer(_)) => {
+            return Err(format!(
+                "The number of decimals cannot be more than {}",
+                MAX_LENGTH
+            ))
+        }
+        Some(value) => {
+            return Err(format!(
+                "Expected the number of decimals as an int, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let (negative, digits) = match &args[0] {
+        Value::Integer(i) if decimals == 0 => (*i < 0, i.unsigned_abs().to_string()),
+        Value::Integer(i) => (
+            *i < 0,
+            format!("{}.{}", i.unsigned_abs(), "0".repeat(decimals)),
+        ),
+        Value::Float(f) if !f.is_finite() => return Ok(Value::from(format_float(*f))),
+        Value::Float(f) => (f.is_sign_negative(), format!("{:.*}", decimals, f.abs())),
+        value => {
+            return Err(format!(
+                "Expected a number of type int or float, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let (thousands, decimal) = locale_separators(vm);
+    let (integer, fraction) = match digits.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (digits.as_str(), None),
+    };
+    // A number rounded to zero loses its sign
+    let sign = if negative && digits.bytes().any(|digit| matches!(digit, b'1'..=b'9')) {
+        "-"
+    } else {
+        ""
+    };
+    let mut formatted = match thousands.filter(|_| grouping) {
+        Some(thousands) => format!("{}{}", sign, group_digits(integer, thousands, 3)),
+        None => format!("{}{}", sign, integer),
+    };
+    if let Some(fraction) = fraction {
+        formatted.push(decimal);
+        formatted.push_str(fraction);
+    }
+    Ok(Value::from(formatted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(value: f64, spec: &str) -> Result<String, String> {
+        format_with_spec(value, None, &parse_spec(spec)?)
+    }
+
+    #[test]
+    fn precision_up_to_the_limit() {
+        assert_eq!(format(1.5, ".2f"), Ok("1.50".to_owned()));
+        assert_eq!(format(1500.0, ".1e"), Ok("1.5e+03".to_owned()));
+        assert_eq!(format(1.0, ".1000f").map(|text| text.len()), Ok(1002));
+    }
+
+    #[test]
+    fn precision_and_width_over_the_limit_are_errors() {
+        assert!(format(1.0, ".1001f").is_err());
+        assert!(format(1.0, ".1001e").is_err());
+        assert!(format(1.0, ".99999999999999999999999f").is_err());
+        assert!(format(1.0, "1001").is_err());
+        assert!(format(1.0, "99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn decimals_of_format_number_are_bounded() {
+        let mut vm = VM::new();
+        let number = |vm: &mut VM, decimals: i64| {
+            format_number(
+                vm,
+                vec![Value::Float(1.5), Value::False, Value::Integer(decimals)],
+            )
+        };
+        assert!(number(&mut vm, 1000).is_ok());
+        assert!(number(&mut vm, 1001).is_err());
+        assert!(number(&mut vm, i64::MAX).is_err());
+        assert!(number(&mut vm, -1).is_err());
+    }
+
+    #[cfg(feature = "os")]
+    #[test]
+    fn deterministic_numbers_ignore_the_locale() {
+        std::env::set_var("LANG", "de_DE.UTF-8");
+        std::env::remove_var("LC_ALL");
+        std::env::remove_var("LC_NUMERIC");
+        let mut vm = VM::new();
+        let number = |vm: &mut VM| format_number(vm, vec![Value::Float(1234567.5)]);
+        assert_eq!(number(&mut vm), Ok(Value::from("1.234.567,50")));
+        vm.set_deterministic(1);
+        assert_eq!(number(&mut vm), Ok(Value::from("1234567.50")));
+    }
+}
@@ -0,0 +1,84 @@
+#[cfg(feature = "os")]
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "os")]
+use crate::gc;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn path(value: &Value) -> Result<&Path, String> {
+    match value {
+        Value::String(path) => Ok(Path::new(path.as_str())),
+        value => Err(format!(
+            "Expected a path as a string, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+fn path_value(path: &Path) -> Value {
+    Value::from(path.to_string_lossy().into_owned())
+}
+
+// Paths are joined with the separator of the platform running the script
+pub fn path_join(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut joined = PathBuf::new();
+    for arg in &args {
+        joined.push(path(arg)?);
+    }
+    Ok(path_value(&joined))
+}
+
+pub fn dirname(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let parent = path(&args[0])?.parent().unwrap_or(Path::new(""));
+    Ok(path_value(parent))
+}
+
+pub fn basename(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let name = path(&args[0])?.file_name().unwrap_or_default();
+    Ok(Value::from(name.to_string_lossy().into_owned()))
+}
+
+// Entries are sorted so iterating over a directory is the same on every platform
+#[cfg(feature = "os")]
+pub fn list_dir(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let path = path(&args[0])?;
+    let error = |error: std::io::Error| format!("Could not list {}: {}", path.display(), error);
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path).map_err(error)? {
+        let entry = entry.map_err(error)?;
+        entries.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    entries.sort();
+
+    Ok(Value::List(gc::items(
+        entries.into_iter().map(Value::from).collect(),
+    )))
+}
+
+// Missing parent directories are created as well
+#[cfg(feature = "os")]
+pub fn mkdir(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let path = path(&args[0])?;
+    match fs::create_dir_all(path) {
+        Ok(()) => Ok(Value::None),
+        Err(error) => Err(format!("Could not create {}: {}", path.display(), error)),
+    }
+}
+
+// Directories are only removed when empty, there is no recursive delete
+#[cfg(feature = "os")]
+pub fn remove(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let path = path(&args[0])?;
+    let result = if path.is_dir() {
+        fs::remove_dir(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Ok(()) => Ok(Value::None),
+        Err(error) => Err(format!("Could not remove {}: {}", path.display(), error)),
+    }
+}
@@ -0,0 +1,274 @@
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::value::Value;
+use crate::vm::VM;
+
+// How long connecting, and then each read or write, may take before the
+// request fails instead of hanging the script
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Url<'a> {
+    // Sent as the Host header, an IPv6 address keeps its brackets
+    authority: &'a str,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+// A line break in what goes into the request line or a header would let a
+// script inject headers, or a whole second request
+fn check_line(kind: &str, text: &str) -> Result<(), String> {
+    if text.contains(['\r', '\n']) {
+        return Err(format!(
+            "Invalid {} {:?}: line breaks are not allowed",
+            kind, text
+        ));
+    }
+    Ok(())
+}
+
+// Only plain http is supported, talking https would need a TLS implementation.
+fn parse_url(value: &Value) -> Result<Url<'_>, String> {
+    let url = match value {
+        Value::String(url) => url.as_str(),
+        value => {
+            return Err(format!(
+                "Expected an url such as \"http://localhost:8080/\", got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None if url.starts_with("https://") => {
+            return Err(format!("Could not request {}: https is not supported", url))
+        }
+        None => {
+            return Err(format!(
+                "Could not request {}: expected an http:// url",
+                url
+            ))
+        }
+    };
+
+    check_line("url", url)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    // An IPv6 address is written in brackets, `[::1]:8080`, as its own
+    // colons would be taken for the port
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => return Err(format!("Invalid host {} in {}", authority, url)),
+            },
+            None => return Err(format!("Invalid host {} in {}", authority, url)),
+        },
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => match port.parse() {
+            Ok(port) => port,
+            Err(_) => return Err(format!("Invalid port {} in {}", port, url)),
+        },
+        None => 80,
+    };
+
+    Ok(Url {
+        authority,
+        host,
+        port,
+        path,
+    })
+}
+
+fn headers(value: Option<&Value>) -> Result<Vec<(String, String)>, String> {
+    let map = match value {
+        None => return Ok(Vec::new()),
+        Some(Value::Map(map)) => map.clone(),
+        Some(value) => {
+            return Err(format!(
+                "Expected the headers as a map, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let map = map.borrow();
+    map.iter()
+        .map(|entry| match entry {
+            (Value::String(name), Value::String(value)) => {
+                check_line("header name", name)?;
+                check_line("header value", value)?;
+                Ok((name.to_string(), value.to_string()))
+            }
+            (name, value) => Err(format!(
+                "Headers must map string to string, got {} to {} instead",
+                name.type_of(),
+                value.type_of()
+            )),
+        })
+        .collect()
+}
+
+// The request is sent as HTTP/1.0 so the server answers without chunked
+// encoding and closes the connection once the whole body was sent.
+fn request(
+    method: &str,
+    url: &Value,
+    body: &str,
+    headers: Vec<(String, String)>,
+) -> Result<Value, String> {
+    let url = parse_url(url)?;
+    let error = |error: std::io::Error| format!("Could not request {}: {}", url.host, error);
+
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n",
+        method, url.path, url.authority
+    );
+    if method == "POST" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let mut stream = connect(&url).map_err(error)?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(error)?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(error)?;
+    stream.write_all(request.as_bytes()).map_err(error)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).map_err(error)?;
+
+    parse_response(&String::from_utf8_lossy(&response))
+}
+
+// Tries every address the host resolves to, like TcpStream::connect, each
+// within the timeout
+fn connect(url: &Url) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (url.host, url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no address found for the host",
+        )
+    }))
+}
+
+fn parse_response(response: &str) -> Result<Value, String> {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| "Malformed HTTP response".to_owned())?;
+
+    // Header names are case insensitive, they are lowercased so scripts can
+    // look them up reliably
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| {
+            (
+                Value::from(name.trim().to_lowercase()),
+                Value::from(value.trim().to_owned()),
+            )
+        })
+        .collect();
+
+    Value::new_map(vec![
+        (Value::from("status"), Value::Integer(status)),
+        (Value::from("headers"), Value::new_map(headers)?),
+        (Value::from("body"), Value::from(body.to_owned())),
+    ])
+}
+
+pub fn http_get(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    request("GET", &args[0], "", headers(args.get(1))?)
+}
+
+pub fn http_post(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let body = match &args[1] {
+        Value::String(body) => body,
+        value => {
+            return Err(format!(
+                "Expected the body as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    request("POST", &args[0], body, headers(args.get(2))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Result<(String, String, u16, String), String> {
+        let value = Value::from(url);
+        let url = parse_url(&value)?;
+        Ok((
+            url.authority.to_owned(),
+            url.host.to_owned(),
+            url.port,
+            url.path.to_owned(),
+        ))
+    }
+
+    #[test]
+    fn parses_hosts_and_ports() {
+        assert_eq!(
+            url("http://localhost:8080/a?b=1"),
+            Ok((
+                "localhost:8080".into(),
+                "localhost".into(),
+                8080,
+                "/a?b=1".into()
+            ))
+        );
+        assert_eq!(
+            url("http://example.com"),
+            Ok(("example.com".into(), "example.com".into(), 80, "/".into()))
+        );
+        assert_eq!(
+            url("http://[::1]:8080/"),
+            Ok(("[::1]:8080".into(), "::1".into(), 8080, "/".into()))
+        );
+        assert_eq!(
+            url("http://[fe80::1]/x"),
+            Ok(("[fe80::1]".into(), "fe80::1".into(), 80, "/x".into()))
+        );
+        assert!(url("http://[::1/").is_err());
+        assert!(url("http://[::1]8080/").is_err());
+        assert!(url("http://localhost:http/").is_err());
+    }
+
+    #[test]
+    fn rejects_line_breaks() {
+        assert!(url("http://localhost/a\r\nX-Injected: 1").is_err());
+        let map = |name: &str, value: &str| {
+            Value::new_map(vec![(Value::from(name), Value::from(value))]).unwrap()
+        };
+        assert!(headers(Some(&map("X-Name", "value"))).is_ok());
+        assert!(headers(Some(&map("X-Name\r\nHost", "value"))).is_err());
+        assert!(headers(Some(&map("X-Name", "value\nHost: other"))).is_err());
+    }
+}
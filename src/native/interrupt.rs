@@ -0,0 +1,30 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+// The function runs at the next instruction boundary after each Ctrl-C, and
+// the script carries on afterwards. None unregisters it.
+pub fn on_interrupt(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let function = match &args[0] {
+        Value::ObjFunction(function) => function.clone(),
+        Value::None => {
+            vm.set_interrupt_handler(None);
+            return Ok(Value::None);
+        }
+        value => {
+            return Err(format!(
+                "Expected a function to run on interrupt, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    if !function.function_info.arg_names.is_empty() {
+        return Err(format!(
+            "Interrupt handlers cannot take arguments, {} takes {}",
+            function.name,
+            function.function_info.arg_names.len()
+        ));
+    }
+
+    vm.set_interrupt_handler(Some(function));
+    Ok(Value::None)
+}
@@ -0,0 +1,118 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::object::ObjIterator;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn iterator(iterator: ObjIterator) -> Value {
+    Value::Iterator(Rc::new(RefCell::new(iterator)))
+}
+
+// `for i, item in enumerate(items)`, the values are only taken from the
+// sequence as the loop asks for them
+pub fn enumerate(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(iterator(ObjIterator::Enumerate {
+        source: args[0].iter()?,
+        index: 0,
+    }))
+}
+
+// Pairs up the values of every sequence, stopping with the shortest one
+pub fn zip(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let sources = args
+        .iter()
+        .map(Value::iter)
+        .collect::<Result<Vec<Value>, String>>()?;
+    Ok(iterator(ObjIterator::Zip { sources }))
+}
+
+fn bound(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(bound) => Ok(*bound),
+        value => Err(format!(
+            "Expected range bounds of type int, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// `range(stop)`, `range(start, stop)` or `range(start, stop, step)`, stop
+// itself is never reached
+pub fn range(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let (start, stop) = match args.len() {
+        1 => (0, bound(&args[0])?),
+        _ => (bound(&args[0])?, bound(&args[1])?),
+    };
+    let step = match args.get(2) {
+        Some(step) => bound(step)?,
+        None => 1,
+    };
+    if step == 0 {
+        return Err("The step of a range cannot be 0".to_owned());
+    }
+    Ok(iterator(ObjIterator::Range {
+        next: start,
+        stop,
+        step,
+    }))
+}
+
+// The values of a range left to go through, from the last one
+fn reversed_range(next: i64, stop: i64, step: i64) -> ObjIterator {
+    let (next, stop, step) = (next as i128, stop as i128, step as i128);
+    let count = if step > 0 {
+        (stop - next + step - 1) / step
+    } else {
+        (next - stop - step - 1) / -step
+    }
+    .max(0);
+    if count == 0 {
+        return ObjIterator::Range {
+            next: 0,
+            stop: 0,
+            step: 1,
+        };
+    }
+    let last = next + (count - 1) * step;
+    // One step past the first value, which may be out of the int range
+    let stop = (next - step).clamp(i64::MIN as i128, i64::MAX as i128);
+    ObjIterator::Range {
+        next: last as i64,
+        stop: stop as i64,
+        step: -step as i64,
+    }
+}
+
+// Lists are gone through from their end without copying them, a string, the
+// elements of a set or the keys of a map are copied like when looping over them
+pub fn reversed(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let reversed = match &args[0] {
+        Value::List(items) => ObjIterator::Backwards {
+            items: items.clone(),
+            remaining: items.borrow().len(),
+        },
+        Value::String(string) => ObjIterator::Chars {
+            chars: string.chars().rev().collect(),
+            position: 0,
+        },
+        Value::Set(elements) => ObjIterator::Sequence {
+            items: Rc::new(RefCell::new(
+                elements.borrow().iter().rev().cloned().collect(),
+            )),
+            position: 0,
+        },
+        Value::Map(entries) => ObjIterator::Sequence {
+            items: Rc::new(RefCell::new(
+                entries.borrow().keys().rev().cloned().collect(),
+            )),
+            position: 0,
+        },
+        Value::Iterator(iterator) => match &*iterator.borrow() {
+            ObjIterator::Range { next, stop, step } => reversed_range(*next, *stop, *step),
+            _ => return Err("Only ranges can be reversed among iterators".to_owned()),
+        },
+        value => return Err(format!("Type {} cannot be reversed", value.type_of())),
+    };
+    Ok(iterator(reversed))
+}
@@ -0,0 +1,35 @@
+use crate::decimal::Decimal;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn number(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Integer(i) => Ok(*i as f64),
+        Value::Float(f) => Ok(*f),
+        value => Err(format!(
+            "Expected a number, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+pub fn is_nan(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(number(&args[0])?.is_nan()))
+}
+
+pub fn is_finite(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(number(&args[0])?.is_finite()))
+}
+
+// Floats are converted through their shortest representation, so
+// to_decimal(0.1) is exactly 0.1
+pub fn to_decimal(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let decimal = match &args[0] {
+        Value::Decimal(d) => **d,
+        Value::Integer(i) => Decimal::from_integer(*i),
+        Value::Float(f) if f.is_finite() => Decimal::parse(&f.to_string())?,
+        Value::String(s) => Decimal::parse(s.trim())?,
+        value => return Err(format!("Cannot convert {} to decimal", value)),
+    };
+    Ok(Value::from(decimal))
+}
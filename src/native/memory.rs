@@ -0,0 +1,24 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+// A map, so more figures can be added without breaking scripts
+pub fn gc_stats(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    let stats = vm.gc_stats();
+    let figures = [
+        ("allocations", stats.allocations),
+        ("live", stats.live),
+        ("collections", stats.collections),
+        ("bytes", stats.bytes),
+    ];
+    Value::new_map(
+        figures
+            .into_iter()
+            .map(|(name, figure)| (Value::from(name), Value::Integer(figure as i64)))
+            .collect(),
+    )
+}
+
+// Returns how many lists, sets and maps were freed
+pub fn gc_collect(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(vm.gc_collect() as i64))
+}
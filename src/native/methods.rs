@@ -0,0 +1,474 @@
+use crate::gc;
+use crate::native::datetime::{
+    add_days, datetime_format, day, diff, duration_format, hour, milliseconds, minute, month,
+    second, timestamp, total_seconds, weekday, year,
+};
+use crate::native::error::{error_function, error_kind, error_line, error_message, error_trace};
+use crate::native::{db_close, tcp_close, wait, NativeFn};
+use crate::value::Value;
+use crate::vm::VM;
+
+// A method called with dot syntax, `"a,b".split(",")`. The receiver is passed
+// as the first argument and is not counted in min_args and max_args.
+pub struct BuiltinMethod {
+    pub receiver: &'static str,
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub function: NativeFn,
+}
+
+pub static METHODS: &[BuiltinMethod] = &[
+    BuiltinMethod {
+        receiver: "string",
+        name: "len",
+        min_args: 0,
+        max_args: 0,
+        function: string_len,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "upper",
+        min_args: 0,
+        max_args: 0,
+        function: upper,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "lower",
+        min_args: 0,
+        max_args: 0,
+        function: lower,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "trim",
+        min_args: 0,
+        max_args: 0,
+        function: trim,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "split",
+        min_args: 1,
+        max_args: 1,
+        function: split,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "contains",
+        min_args: 1,
+        max_args: 1,
+        function: string_contains,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "starts_with",
+        min_args: 1,
+        max_args: 1,
+        function: starts_with,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "ends_with",
+        min_args: 1,
+        max_args: 1,
+        function: ends_with,
+    },
+    BuiltinMethod {
+        receiver: "string",
+        name: "replace",
+        min_args: 2,
+        max_args: 2,
+        function: replace,
+    },
+    BuiltinMethod {
+        receiver: "list",
+        name: "len",
+        min_args: 0,
+        max_args: 0,
+        function: items_len,
+    },
+    BuiltinMethod {
+        receiver: "list",
+        name: "push",
+        min_args: 1,
+        max_args: 1,
+        function: push,
+    },
+    BuiltinMethod {
+        receiver: "list",
+        name: "pop",
+        min_args: 0,
+        max_args: 0,
+        function: pop,
+    },
+    BuiltinMethod {
+        receiver: "list",
+        name: "contains",
+        min_args: 1,
+        max_args: 1,
+        function: items_contains,
+    },
+    BuiltinMethod {
+        receiver: "list",
+        name: "join",
+        min_args: 1,
+        max_args: 1,
+        function: join,
+    },
+    BuiltinMethod {
+        receiver: "set",
+        name: "len",
+        min_args: 0,
+        max_args: 0,
+        function: set_len,
+    },
+    BuiltinMethod {
+        receiver: "set",
+        name: "contains",
+        min_args: 1,
+        max_args: 1,
+        function: set_contains,
+    },
+    BuiltinMethod {
+        receiver: "map",
+        name: "len",
+        min_args: 0,
+        max_args: 0,
+        function: map_len,
+    },
+    BuiltinMethod {
+        receiver: "map",
+        name: "keys",
+        min_args: 0,
+        max_args: 0,
+        function: keys,
+    },
+    BuiltinMethod {
+        receiver: "map",
+        name: "values",
+        min_args: 0,
+        max_args: 0,
+        function: values,
+    },
+    BuiltinMethod {
+        receiver: "map",
+        name: "contains",
+        min_args: 1,
+        max_args: 1,
+        function: map_contains,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "add_days",
+        min_args: 1,
+        max_args: 1,
+        function: add_days,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "diff",
+        min_args: 1,
+        max_args: 1,
+        function: diff,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "format",
+        min_args: 1,
+        max_args: 1,
+        function: datetime_format,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "year",
+        min_args: 0,
+        max_args: 0,
+        function: year,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "month",
+        min_args: 0,
+        max_args: 0,
+        function: month,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "day",
+        min_args: 0,
+        max_args: 0,
+        function: day,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "hour",
+        min_args: 0,
+        max_args: 0,
+        function: hour,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "minute",
+        min_args: 0,
+        max_args: 0,
+        function: minute,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "second",
+        min_args: 0,
+        max_args: 0,
+        function: second,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "weekday",
+        min_args: 0,
+        max_args: 0,
+        function: weekday,
+    },
+    BuiltinMethod {
+        receiver: "datetime",
+        name: "timestamp",
+        min_args: 0,
+        max_args: 0,
+        function: timestamp,
+    },
+    BuiltinMethod {
+        receiver: "duration",
+        name: "format",
+        min_args: 1,
+        max_args: 1,
+        function: duration_format,
+    },
+    BuiltinMethod {
+        receiver: "duration",
+        name: "total_seconds",
+        min_args: 0,
+        max_args: 0,
+        function: total_seconds,
+    },
+    BuiltinMethod {
+        receiver: "duration",
+        name: "milliseconds",
+        min_args: 0,
+        max_args: 0,
+        function: milliseconds,
+    },
+    // Resources a with statement can close
+    BuiltinMethod {
+        receiver: "socket",
+        name: "close",
+        min_args: 0,
+        max_args: 0,
+        function: tcp_close,
+    },
+    BuiltinMethod {
+        receiver: "database",
+        name: "close",
+        min_args: 0,
+        max_args: 0,
+        function: db_close,
+    },
+    BuiltinMethod {
+        receiver: "process",
+        name: "close",
+        min_args: 0,
+        max_args: 0,
+        function: wait,
+    },
+    BuiltinMethod {
+        receiver: "error",
+        name: "kind",
+        min_args: 0,
+        max_args: 0,
+        function: error_kind,
+    },
+    BuiltinMethod {
+        receiver: "error",
+        name: "message",
+        min_args: 0,
+        max_args: 0,
+        function: error_message,
+    },
+    BuiltinMethod {
+        receiver: "error",
+        name: "line",
+        min_args: 0,
+        max_args: 0,
+        function: error_line,
+    },
+    BuiltinMethod {
+        receiver: "error",
+        name: "function",
+        min_args: 0,
+        max_args: 0,
+        function: error_function,
+    },
+    BuiltinMethod {
+        receiver: "error",
+        name: "trace",
+        min_args: 0,
+        max_args: 0,
+        function: error_trace,
+    },
+];
+
+pub fn find_method(receiver: &str, name: &str) -> Option<&'static BuiltinMethod> {
+    METHODS
+        .iter()
+        .find(|method| method.receiver == receiver && method.name == name)
+}
+
+// Whether any type has the method, the receiver is only known at runtime
+pub fn is_method(name: &str) -> bool {
+    METHODS.iter().any(|method| method.name == name)
+}
+
+fn new_list(items: Vec<Value>) -> Value {
+    Value::List(gc::items(items))
+}
+
+fn text(args: &[Value], index: usize) -> Result<&str, String> {
+    match &args[index] {
+        Value::String(text) => Ok(text),
+        value => Err(format!(
+            "Expected a string, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+fn string_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(text(&args, 0)?.chars().count() as i64))
+}
+
+fn upper(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.to_uppercase()))
+}
+
+fn lower(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.to_lowercase()))
+}
+
+fn trim(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.trim().to_owned()))
+}
+
+// An empty separator splits the string into its characters
+fn split(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let string = text(&args, 0)?;
+    let separator = text(&args, 1)?;
+    let parts = if separator.is_empty() {
+        string.chars().map(|c| Value::from(c.to_string())).collect()
+    } else {
+        string
+            .split(separator)
+            .map(|part| Value::from(part.to_owned()))
+            .collect()
+    };
+    Ok(new_list(parts))
+}
+
+fn string_contains(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.contains(text(&args, 1)?)))
+}
+
+fn starts_with(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.starts_with(text(&args, 1)?)))
+}
+
+fn ends_with(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(text(&args, 0)?.ends_with(text(&args, 1)?)))
+}
+
+fn replace(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let string = text(&args, 0)?;
+    Ok(Value::from(
+        string.replace(text(&args, 1)?, text(&args, 2)?),
+    ))
+}
+
+fn items(value: &Value) -> Result<&gc::Items, String> {
+    match value {
+        Value::List(items) => Ok(items),
+        value => Err(format!("Expected a list, got {} instead", value.type_of())),
+    }
+}
+
+fn elements(value: &Value) -> Result<&gc::Elements, String> {
+    match value {
+        Value::Set(elements) => Ok(elements),
+        value => Err(format!("Expected a set, got {} instead", value.type_of())),
+    }
+}
+
+fn entries(value: &Value) -> Result<&gc::Entries, String> {
+    match value {
+        Value::Map(entries) => Ok(entries),
+        value => Err(format!("Expected a map, got {} instead", value.type_of())),
+    }
+}
+
+fn items_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(items(&args[0])?.borrow().len() as i64))
+}
+
+// Adds to the list in place and returns none
+fn push(_vm: &mut VM, mut args: Vec<Value>) -> Result<Value, String> {
+    let item = args.pop().unwrap_or(Value::None);
+    items(&args[0])?.borrow_mut().push(item);
+    Ok(Value::None)
+}
+
+fn pop(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match items(&args[0])?.borrow_mut().pop() {
+        Some(item) => Ok(item),
+        None => Err("Cannot pop from an empty list".to_owned()),
+    }
+}
+
+fn items_contains(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(items(&args[0])?.borrow().contains(&args[1])))
+}
+
+// Items that are not strings are joined as they would be printed
+fn join(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let separator = text(&args, 1)?;
+    let parts: Vec<String> = items(&args[0])?
+        .borrow()
+        .iter()
+        .map(Value::to_text)
+        .collect();
+    Ok(Value::from(parts.join(separator)))
+}
+
+fn set_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(elements(&args[0])?.borrow().len() as i64))
+}
+
+fn set_contains(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(elements(&args[0])?.borrow().contains(&args[1])))
+}
+
+fn map_len(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(entries(&args[0])?.borrow().len() as i64))
+}
+
+fn keys(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let keys = entries(&args[0])?.borrow().keys().cloned().collect();
+    Ok(new_list(keys))
+}
+
+fn values(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let values = entries(&args[0])?.borrow().values().cloned().collect();
+    Ok(new_list(values))
+}
+
+fn map_contains(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(
+        entries(&args[0])?.borrow().contains_key(&args[1]),
+    ))
+}
@@ -0,0 +1,852 @@
+mod args;
+mod comprehension;
+mod concurrency;
+mod copy;
+mod coroutine;
+mod csv;
+mod datetime;
+mod encoding;
+mod error;
+mod exit;
+mod format;
+mod fs;
+mod hash;
+#[cfg(feature = "os")]
+mod http;
+mod interrupt;
+mod iter;
+mod math;
+mod memory;
+mod methods;
+#[cfg(feature = "os")]
+mod net;
+#[cfg(feature = "os")]
+mod process;
+mod prompt;
+mod random;
+mod result;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod terminal;
+mod time;
+#[cfg(feature = "config")]
+mod toml;
+#[cfg(any(not(feature = "os"), not(feature = "config"), not(feature = "sqlite")))]
+mod unavailable;
+mod with;
+mod wrap;
+mod xml;
+#[cfg(feature = "config")]
+mod yaml;
+
+use crate::options::Capability;
+use crate::value::Value;
+use crate::vm::VM;
+
+use args::{args, parse_args};
+use comprehension::{comprehension_add, unpack};
+#[cfg(feature = "os")]
+use concurrency::spawn;
+use concurrency::{channel, join, recv, send};
+use copy::{copy, deep_copy};
+use coroutine::{coroutine, is_done, resume};
+use csv::{csv_parse, csv_stringify};
+use datetime::{datetime, parse_datetime, parse_duration};
+use encoding::{base64_decode, base64_encode, hex_decode, hex_encode};
+use error::error;
+use exit::exit;
+use format::{format, format_number};
+use fs::{basename, dirname, path_join};
+#[cfg(feature = "os")]
+use fs::{list_dir, mkdir, remove};
+use hash::{md5, sha256};
+#[cfg(feature = "os")]
+use http::{http_get, http_post};
+use interrupt::on_interrupt;
+use iter::{enumerate, range, reversed, zip};
+use math::{is_finite, is_nan, to_decimal};
+use memory::{gc_collect, gc_stats};
+pub use methods::{find_method, is_method};
+#[cfg(feature = "os")]
+use net::{
+    tcp_accept, tcp_close, tcp_connect, tcp_listen, tcp_read, tcp_read_bytes, tcp_read_line,
+    tcp_write,
+};
+#[cfg(feature = "os")]
+use process::{
+    process_close_stdin, process_kill, process_read_line, process_write, spawn_process, wait,
+};
+use prompt::{choose, confirm, password};
+pub use random::Rng;
+use random::{random, random_int};
+use result::{err, is_err, ok, unwrap_or};
+#[cfg(feature = "sqlite")]
+use sqlite::{db_close, db_exec, db_open, db_query};
+use terminal::{clear_screen, color, is_tty, terminal_width};
+use time::{clear_timeout, clock, elapsed, now, set_timeout, sleep, stopwatch_start, time_report};
+#[cfg(feature = "config")]
+use toml::toml_parse;
+#[cfg(not(feature = "sqlite"))]
+use unavailable::{db_close, db_exec, db_open, db_query};
+#[cfg(not(feature = "os"))]
+use unavailable::{
+    http_get, http_post, list_dir, mkdir, process_close_stdin, process_kill, process_read_line,
+    process_write, remove, spawn, spawn_process, tcp_accept, tcp_close, tcp_connect, tcp_listen,
+    tcp_read, tcp_read_bytes, tcp_read_line, tcp_write, wait,
+};
+#[cfg(not(feature = "config"))]
+use unavailable::{toml_parse, yaml_parse};
+use with::with_enter;
+use wrap::{bind, memoize, trace};
+use xml::{xml_parse, xml_select};
+#[cfg(feature = "config")]
+use yaml::yaml_parse;
+
+pub type NativeFn = fn(&mut VM, Vec<Value>) -> Result<Value, String>;
+
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub function: NativeFn,
+    // Natives reaching outside of the script are refused unless the sandbox
+    // policy of the VM allows what they need
+    pub capability: Option<Capability>,
+}
+
+// Called by the code compiled for a time statement, the space keeps the report
+// out of reach of scripts
+pub const STOPWATCH_START: &str = "stopwatch_start";
+pub const TIME_REPORT: &str = " time_report";
+
+// Called by the code compiled for comprehensions, out of reach of scripts too
+pub const COMPREHENSION_ADD: &str = " comprehension_add";
+pub const UNPACK: &str = " unpack";
+
+// Called by the code compiled for a with statement
+pub const WITH_ENTER: &str = " with_enter";
+
+// Natives an expression evaluated by the host can call. They only compute a
+// value from their arguments, anything prompting, waiting, scheduling or
+// exiting is left out, like the natives reading the environment.
+const EXPRESSION_NATIVES: &[&str] = &[
+    COMPREHENSION_ADD,
+    UNPACK,
+    "copy",
+    "deep_copy",
+    "bind",
+    "enumerate",
+    "zip",
+    "range",
+    "reversed",
+    "format",
+    "is_nan",
+    "is_finite",
+    "to_decimal",
+    "datetime",
+    "parse_datetime",
+    "parse_duration",
+    "path_join",
+    "dirname",
+    "basename",
+    "sha256",
+    "md5",
+    "base64_encode",
+    "base64_decode",
+    "hex_encode",
+    "hex_decode",
+    "csv_parse",
+    "csv_stringify",
+    "toml_parse",
+    "yaml_parse",
+    "xml_parse",
+    "xml_select",
+    "error",
+    "ok",
+    "err",
+    "is_err",
+    "unwrap_or",
+];
+
+pub static NATIVES: &[NativeFunction] = &[
+    NativeFunction {
+        name: "coroutine",
+        min_args: 1,
+        max_args: 1,
+        function: coroutine,
+        capability: None,
+    },
+    NativeFunction {
+        name: "resume",
+        min_args: 1,
+        max_args: 2,
+        function: resume,
+        capability: None,
+    },
+    NativeFunction {
+        name: "is_done",
+        min_args: 1,
+        max_args: 1,
+        function: is_done,
+        capability: None,
+    },
+    NativeFunction {
+        name: "spawn",
+        min_args: 1,
+        max_args: usize::MAX,
+        function: spawn,
+        capability: None,
+    },
+    NativeFunction {
+        name: "join",
+        min_args: 1,
+        max_args: 1,
+        function: join,
+        capability: None,
+    },
+    NativeFunction {
+        name: "channel",
+        min_args: 0,
+        max_args: 0,
+        function: channel,
+        capability: None,
+    },
+    NativeFunction {
+        name: "send",
+        min_args: 2,
+        max_args: 2,
+        function: send,
+        capability: None,
+    },
+    NativeFunction {
+        name: "recv",
+        min_args: 1,
+        max_args: 1,
+        function: recv,
+        capability: None,
+    },
+    NativeFunction {
+        name: "sleep",
+        min_args: 1,
+        max_args: 1,
+        function: sleep,
+        capability: None,
+    },
+    NativeFunction {
+        name: "clock",
+        min_args: 0,
+        max_args: 0,
+        function: clock,
+        capability: None,
+    },
+    NativeFunction {
+        name: "now",
+        min_args: 0,
+        max_args: 0,
+        function: now,
+        capability: None,
+    },
+    NativeFunction {
+        name: "random",
+        min_args: 0,
+        max_args: 0,
+        function: random,
+        capability: None,
+    },
+    NativeFunction {
+        name: "random_int",
+        min_args: 2,
+        max_args: 2,
+        function: random_int,
+        capability: None,
+    },
+    NativeFunction {
+        name: STOPWATCH_START,
+        min_args: 0,
+        max_args: 0,
+        function: stopwatch_start,
+        capability: None,
+    },
+    NativeFunction {
+        name: "elapsed",
+        min_args: 1,
+        max_args: 1,
+        function: elapsed,
+        capability: None,
+    },
+    NativeFunction {
+        name: TIME_REPORT,
+        min_args: 2,
+        max_args: 2,
+        function: time_report,
+        capability: None,
+    },
+    NativeFunction {
+        name: COMPREHENSION_ADD,
+        min_args: 2,
+        max_args: 3,
+        function: comprehension_add,
+        capability: None,
+    },
+    NativeFunction {
+        name: UNPACK,
+        min_args: 2,
+        max_args: 2,
+        function: unpack,
+        capability: None,
+    },
+    NativeFunction {
+        name: "datetime",
+        min_args: 0,
+        max_args: 6,
+        function: datetime,
+        capability: None,
+    },
+    NativeFunction {
+        name: "parse_datetime",
+        min_args: 2,
+        max_args: 2,
+        function: parse_datetime,
+        capability: None,
+    },
+    NativeFunction {
+        name: "parse_duration",
+        min_args: 1,
+        max_args: 1,
+        function: parse_duration,
+        capability: None,
+    },
+    NativeFunction {
+        name: "set_timeout",
+        min_args: 2,
+        max_args: 2,
+        function: set_timeout,
+        capability: None,
+    },
+    NativeFunction {
+        name: "clear_timeout",
+        min_args: 1,
+        max_args: 1,
+        function: clear_timeout,
+        capability: None,
+    },
+    NativeFunction {
+        name: "tcp_connect",
+        min_args: 1,
+        max_args: 1,
+        function: tcp_connect,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_listen",
+        min_args: 1,
+        max_args: 1,
+        function: tcp_listen,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_accept",
+        min_args: 1,
+        max_args: 1,
+        function: tcp_accept,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_read",
+        min_args: 1,
+        max_args: 2,
+        function: tcp_read,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_read_bytes",
+        min_args: 2,
+        max_args: 2,
+        function: tcp_read_bytes,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_read_line",
+        min_args: 1,
+        max_args: 1,
+        function: tcp_read_line,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_write",
+        min_args: 2,
+        max_args: 2,
+        function: tcp_write,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "tcp_close",
+        min_args: 1,
+        max_args: 1,
+        function: tcp_close,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "http_get",
+        min_args: 1,
+        max_args: 2,
+        function: http_get,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "http_post",
+        min_args: 2,
+        max_args: 3,
+        function: http_post,
+        capability: Some(Capability::Network),
+    },
+    NativeFunction {
+        name: "path_join",
+        min_args: 1,
+        max_args: usize::MAX,
+        function: path_join,
+        capability: None,
+    },
+    NativeFunction {
+        name: "dirname",
+        min_args: 1,
+        max_args: 1,
+        function: dirname,
+        capability: None,
+    },
+    NativeFunction {
+        name: "basename",
+        min_args: 1,
+        max_args: 1,
+        function: basename,
+        capability: None,
+    },
+    NativeFunction {
+        name: "list_dir",
+        min_args: 1,
+        max_args: 1,
+        function: list_dir,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "mkdir",
+        min_args: 1,
+        max_args: 1,
+        function: mkdir,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "remove",
+        min_args: 1,
+        max_args: 1,
+        function: remove,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "sha256",
+        min_args: 1,
+        max_args: 1,
+        function: sha256,
+        capability: None,
+    },
+    NativeFunction {
+        name: "md5",
+        min_args: 1,
+        max_args: 1,
+        function: md5,
+        capability: None,
+    },
+    NativeFunction {
+        name: "base64_encode",
+        min_args: 1,
+        max_args: 1,
+        function: base64_encode,
+        capability: None,
+    },
+    NativeFunction {
+        name: "base64_decode",
+        min_args: 1,
+        max_args: 1,
+        function: base64_decode,
+        capability: None,
+    },
+    NativeFunction {
+        name: "hex_encode",
+        min_args: 1,
+        max_args: 1,
+        function: hex_encode,
+        capability: None,
+    },
+    NativeFunction {
+        name: "hex_decode",
+        min_args: 1,
+        max_args: 1,
+        function: hex_decode,
+        capability: None,
+    },
+    NativeFunction {
+        name: "csv_parse",
+        min_args: 1,
+        max_args: 2,
+        function: csv_parse,
+        capability: None,
+    },
+    NativeFunction {
+        name: "csv_stringify",
+        min_args: 1,
+        max_args: 1,
+        function: csv_stringify,
+        capability: None,
+    },
+    NativeFunction {
+        name: "toml_parse",
+        min_args: 1,
+        max_args: 1,
+        function: toml_parse,
+        capability: None,
+    },
+    NativeFunction {
+        name: "yaml_parse",
+        min_args: 1,
+        max_args: 1,
+        function: yaml_parse,
+        capability: None,
+    },
+    NativeFunction {
+        name: "xml_parse",
+        min_args: 1,
+        max_args: 1,
+        function: xml_parse,
+        capability: None,
+    },
+    NativeFunction {
+        name: "xml_select",
+        min_args: 2,
+        max_args: 2,
+        function: xml_select,
+        capability: None,
+    },
+    NativeFunction {
+        name: "db_open",
+        min_args: 1,
+        max_args: 1,
+        function: db_open,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "db_query",
+        min_args: 2,
+        max_args: 3,
+        function: db_query,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "db_exec",
+        min_args: 2,
+        max_args: 3,
+        function: db_exec,
+        capability: Some(Capability::Filesystem),
+    },
+    NativeFunction {
+        name: "db_close",
+        min_args: 1,
+        max_args: 1,
+        function: db_close,
+        capability: None,
+    },
+    NativeFunction {
+        name: "spawn_process",
+        min_args: 1,
+        max_args: 1,
+        function: spawn_process,
+        capability: Some(Capability::Process),
+    },
+    NativeFunction {
+        name: "process_write",
+        min_args: 2,
+        max_args: 2,
+        function: process_write,
+        capability: None,
+    },
+    NativeFunction {
+        name: "process_close_stdin",
+        min_args: 1,
+        max_args: 1,
+        function: process_close_stdin,
+        capability: None,
+    },
+    NativeFunction {
+        name: "process_read_line",
+        min_args: 1,
+        max_args: 2,
+        function: process_read_line,
+        capability: None,
+    },
+    NativeFunction {
+        name: "process_kill",
+        min_args: 1,
+        max_args: 1,
+        function: process_kill,
+        capability: None,
+    },
+    NativeFunction {
+        name: "wait",
+        min_args: 1,
+        max_args: 1,
+        function: wait,
+        capability: None,
+    },
+    NativeFunction {
+        name: "copy",
+        min_args: 1,
+        max_args: 1,
+        function: copy,
+        capability: None,
+    },
+    NativeFunction {
+        name: "deep_copy",
+        min_args: 1,
+        max_args: 1,
+        function: deep_copy,
+        capability: None,
+    },
+    NativeFunction {
+        name: "memoize",
+        min_args: 1,
+        max_args: 1,
+        function: memoize,
+        capability: None,
+    },
+    NativeFunction {
+        name: "trace",
+        min_args: 1,
+        max_args: 1,
+        function: trace,
+        capability: None,
+    },
+    NativeFunction {
+        name: "bind",
+        min_args: 1,
+        max_args: usize::MAX,
+        function: bind,
+        capability: None,
+    },
+    NativeFunction {
+        name: "enumerate",
+        min_args: 1,
+        max_args: 1,
+        function: enumerate,
+        capability: None,
+    },
+    NativeFunction {
+        name: "zip",
+        min_args: 2,
+        max_args: usize::MAX,
+        function: zip,
+        capability: None,
+    },
+    NativeFunction {
+        name: "range",
+        min_args: 1,
+        max_args: 3,
+        function: range,
+        capability: None,
+    },
+    NativeFunction {
+        name: "reversed",
+        min_args: 1,
+        max_args: 1,
+        function: reversed,
+        capability: None,
+    },
+    NativeFunction {
+        name: "format",
+        min_args: 2,
+        max_args: 2,
+        function: format,
+        capability: None,
+    },
+    NativeFunction {
+        name: "format_number",
+        min_args: 1,
+        max_args: 3,
+        function: format_number,
+        // The separators come from the locale variables
+        capability: Some(Capability::Env),
+    },
+    NativeFunction {
+        name: "args",
+        min_args: 0,
+        max_args: 0,
+        function: args,
+        capability: None,
+    },
+    NativeFunction {
+        name: "parse_args",
+        min_args: 1,
+        max_args: 2,
+        function: parse_args,
+        capability: None,
+    },
+    NativeFunction {
+        name: "color",
+        min_args: 2,
+        max_args: 2,
+        function: color,
+        // NO_COLOR turns the colors off
+        capability: Some(Capability::Env),
+    },
+    NativeFunction {
+        name: "is_tty",
+        min_args: 0,
+        max_args: 0,
+        function: is_tty,
+        capability: None,
+    },
+    NativeFunction {
+        name: "terminal_width",
+        min_args: 0,
+        max_args: 0,
+        function: terminal_width,
+        // COLUMNS is the width when the terminal cannot be asked
+        capability: Some(Capability::Env),
+    },
+    NativeFunction {
+        name: "clear_screen",
+        min_args: 0,
+        max_args: 0,
+        function: clear_screen,
+        capability: None,
+    },
+    NativeFunction {
+        name: "confirm",
+        min_args: 1,
+        max_args: 1,
+        function: confirm,
+        capability: None,
+    },
+    NativeFunction {
+        name: "choose",
+        min_args: 2,
+        max_args: 2,
+        function: choose,
+        capability: None,
+    },
+    NativeFunction {
+        name: "password",
+        min_args: 1,
+        max_args: 1,
+        function: password,
+        capability: None,
+    },
+    NativeFunction {
+        name: "is_nan",
+        min_args: 1,
+        max_args: 1,
+        function: is_nan,
+        capability: None,
+    },
+    NativeFunction {
+        name: "is_finite",
+        min_args: 1,
+        max_args: 1,
+        function: is_finite,
+        capability: None,
+    },
+    NativeFunction {
+        name: "to_decimal",
+        min_args: 1,
+        max_args: 1,
+        function: to_decimal,
+        capability: None,
+    },
+    NativeFunction {
+        name: "gc_stats",
+        min_args: 0,
+        max_args: 0,
+        function: gc_stats,
+        capability: None,
+    },
+    NativeFunction {
+        name: "gc_collect",
+        min_args: 0,
+        max_args: 0,
+        function: gc_collect,
+        capability: None,
+    },
+    NativeFunction {
+        name: "exit",
+        min_args: 0,
+        max_args: 1,
+        function: exit,
+        capability: None,
+    },
+    NativeFunction {
+        name: "on_interrupt",
+        min_args: 1,
+        max_args: 1,
+        function: on_interrupt,
+        capability: None,
+    },
+    NativeFunction {
+        name: WITH_ENTER,
+        min_args: 1,
+        max_args: 1,
+        function: with_enter,
+        capability: None,
+    },
+    NativeFunction {
+        name: "error",
+        min_args: 1,
+        max_args: 2,
+        function: error,
+        capability: None,
+    },
+    NativeFunction {
+        name: "ok",
+        min_args: 1,
+        max_args: 1,
+        function: ok,
+        capability: None,
+    },
+    NativeFunction {
+        name: "err",
+        min_args: 1,
+        max_args: 1,
+        function: err,
+        capability: None,
+    },
+    NativeFunction {
+        name: "is_err",
+        min_args: 1,
+        max_args: 1,
+        function: is_err,
+        capability: None,
+    },
+    NativeFunction {
+        name: "unwrap_or",
+        min_args: 2,
+        max_args: 2,
+        function: unwrap_or,
+        capability: None,
+    },
+];
+
+// How many arguments a function accepts, as written in arity errors
+pub fn expected_args(min_args: usize, max_args: usize) -> String {
+    if min_args == max_args {
+        min_args.to_string()
+    } else if max_args == usize::MAX {
+        format!("at least {}", min_args)
+    } else {
+        format!("{} to {}", min_args, max_args)
+    }
+}
+
+pub fn find_native(name: &str) -> Option<usize> {
+    NATIVES.iter().position(|native| native.name == name)
+}
+
+pub fn allowed_in_expression(native: &NativeFunction) -> bool {
+    EXPRESSION_NATIVES.contains(&native.name)
+}
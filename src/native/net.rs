@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+
+use crate::gc;
+use crate::object::ObjSocket;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn address(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(address) => Ok(address),
+        value => Err(format!(
+            "Expected an address such as \"localhost:8080\", got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+fn socket(value: &Value) -> Result<Rc<RefCell<Option<ObjSocket>>>, String> {
+    match value {
+        Value::Socket(socket) => Ok(socket.clone()),
+        value => Err(format!(
+            "Expected a socket, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+fn with_socket<T>(
+    value: &Value,
+    action: impl FnOnce(&mut ObjSocket) -> std::io::Result<T>,
+) -> Result<T, String> {
+    let socket = socket(value)?;
+    let mut socket = socket.borrow_mut();
+    match socket.as_mut() {
+        Some(socket) => action(socket).map_err(|error| format!("Socket error: {}", error)),
+        None => Err("Socket is closed".to_owned()),
+    }
+}
+
+fn new_socket(stream: TcpStream) -> Value {
+    Value::Socket(Rc::new(RefCell::new(Some(ObjSocket {
+        reader: BufReader::new(stream),
+    }))))
+}
+
+pub fn tcp_connect(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let address = address(&args[0])?;
+    match TcpStream::connect(address) {
+        Ok(stream) => Ok(new_socket(stream)),
+        Err(error) => Err(format!("Could not connect to {}: {}", address, error)),
+    }
+}
+
+pub fn tcp_listen(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let address = address(&args[0])?;
+    match TcpListener::bind(address) {
+        Ok(listener) => Ok(Value::Listener(Rc::new(RefCell::new(Some(listener))))),
+        Err(error) => Err(format!("Could not listen on {}: {}", address, error)),
+    }
+}
+
+// Blocks until a client connects
+pub fn tcp_accept(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let listener = match &args[0] {
+        Value::Listener(listener) => listener.clone(),
+        value => {
+            return Err(format!(
+                "Expected a listener, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let listener = listener.borrow();
+    match listener.as_ref().map(|listener| listener.accept()) {
+        Some(Ok((stream, _))) => Ok(new_socket(stream)),
+        Some(Err(error)) => Err(format!("Could not accept a connection: {}", error)),
+        None => Err("Listener is closed".to_owned()),
+    }
+}
+
+fn byte_count(args: &[Value], index: usize, default: usize) -> Result<usize, String> {
+    match args.get(index) {
+        None => Ok(default),
+        Some(Value::Integer(count)) if *count > 0 => Ok(*count as usize),
+        Some(value) => Err(format!(
+            "Expected a positive byte count, got {} instead",
+            value
+        )),
+    }
+}
+
+// Reads whatever is available up to the given size, an empty string means the peer closed
+pub fn tcp_read(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut buffer = vec![0; byte_count(&args, 1, 4096)?];
+    let read = with_socket(&args[0], |socket| socket.reader.read(&mut buffer))?;
+    Ok(Value::from(
+        String::from_utf8_lossy(&buffer[..read]).into_owned(),
+    ))
+}
+
+// Reads exactly the given amount of bytes, returned as a list of ints
+pub fn tcp_read_bytes(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut buffer = vec![0; byte_count(&args, 1, 0)?];
+    with_socket(&args[0], |socket| socket.reader.read_exact(&mut buffer))?;
+    let bytes = buffer
+        .into_iter()
+        .map(|byte| Value::Integer(byte as i64))
+        .collect();
+    Ok(Value::List(gc::items(bytes)))
+}
+
+// Returns the line without its line ending, or none once the peer closed
+pub fn tcp_read_line(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut line = String::new();
+    let read = with_socket(&args[0], |socket| socket.reader.read_line(&mut line))?;
+    if read == 0 {
+        return Ok(Value::None);
+    }
+
+    let trimmed = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(trimmed);
+    Ok(Value::from(line))
+}
+
+// Writes a string or a list of bytes and returns the amount of bytes written
+pub fn tcp_write(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let bytes = match &args[1] {
+        Value::String(data) => data.as_bytes().to_vec(),
+        Value::List(data) => data
+            .borrow()
+            .iter()
+            .map(|byte| match byte {
+                Value::Integer(byte) if (0..=255).contains(byte) => Ok(*byte as u8),
+                value => Err(format!("Expected a byte between 0 and 255, got {}", value)),
+            })
+            .collect::<Result<_, _>>()?,
+        value => {
+            return Err(format!(
+                "Can only write strings or lists of bytes, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    with_socket(&args[0], |socket| socket.reader.get_mut().write_all(&bytes))?;
+    Ok(Value::Integer(bytes.len() as i64))
+}
+
+pub fn tcp_close(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Socket(socket) => {
+            socket.borrow_mut().take();
+        }
+        Value::Listener(listener) => {
+            listener.borrow_mut().take();
+        }
+        value => {
+            return Err(format!(
+                "Expected a socket or a listener, got {} instead",
+                value.type_of()
+            ))
+        }
+    }
+    Ok(Value::None)
+}
@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+
+use crate::object::ObjProcess;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn process(value: &Value) -> Result<Rc<RefCell<ObjProcess>>, String> {
+    match value {
+        Value::Process(process) => Ok(process.clone()),
+        value => Err(format!(
+            "Expected a process from spawn_process, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// Sends the lines of a pipe without their line ending until it is closed
+fn read_lines(pipe: impl Read + Send + 'static) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        let mut line = Vec::new();
+        while reader
+            .read_until(b'\n', &mut line)
+            .is_ok_and(|read| read > 0)
+        {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\r', '\n']);
+            if sender.send(text.to_owned()).is_err() {
+                return;
+            }
+            line.clear();
+        }
+    });
+    receiver
+}
+
+fn command(value: &Value) -> Result<Command, String> {
+    match value {
+        // A string is a command line for the shell
+        Value::String(line) => {
+            let mut command = if cfg!(windows) {
+                Command::new("cmd")
+            } else {
+                Command::new("sh")
+            };
+            command.arg(if cfg!(windows) { "/C" } else { "-c" });
+            command.arg(line.as_str());
+            Ok(command)
+        }
+        // A list is the program followed by its arguments, with no shell
+        Value::List(items) => {
+            let items = items.borrow();
+            let mut words = items.iter().map(|item| match item {
+                Value::String(word) => Ok(word.as_str()),
+                value => Err(format!(
+                    "Expected the program and its arguments as strings, got {} instead",
+                    value.type_of()
+                )),
+            });
+            let mut command = match words.next() {
+                Some(program) => Command::new(program?),
+                None => return Err("Expected at least the program to run".to_owned()),
+            };
+            for word in words {
+                command.arg(word?);
+            }
+            Ok(command)
+        }
+        value => Err(format!(
+            "Expected a command as a string or a list, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// `spawn_process("python3 -i")` starts a child process without waiting for
+// it, its input and output are then driven through the returned handle
+pub fn spawn_process(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut command = command(&args[0])?;
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("Could not start {}: {}", args[0].to_text(), error))?;
+    let stdin = child.stdin.take();
+    let stdout = read_lines(child.stdout.take().unwrap());
+    let stderr = read_lines(child.stderr.take().unwrap());
+    Ok(Value::Process(Rc::new(RefCell::new(ObjProcess {
+        child,
+        stdin,
+        stdout,
+        stderr,
+    }))))
+}
+
+// The text is written as is, add a line break to send a whole line
+pub fn process_write(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let process = process(&args[0])?;
+    let mut process = process.borrow_mut();
+    let stdin = process
+        .stdin
+        .as_mut()
+        .ok_or("The input of the process is closed")?;
+    stdin
+        .write_all(args[1].to_text().as_bytes())
+        .and_then(|_| stdin.flush())
+        .map_err(|error| format!("Could not write to the process: {}", error))?;
+    Ok(Value::None)
+}
+
+// Lets the process know no more input is coming, like the end of a file
+pub fn process_close_stdin(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    process(&args[0])?.borrow_mut().stdin.take();
+    Ok(Value::None)
+}
+
+// Waits for the next line the process writes to stdout, or to stderr when
+// asked. Returns the line without its line ending like tcp_read_line, or none
+// once the process closed the stream, usually when it exited. An empty line
+// is an empty string, so a loop reads until none.
+pub fn process_read_line(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let process = process(&args[0])?;
+    let process = process.borrow();
+    let lines = match args.get(1) {
+        None => &process.stdout,
+        Some(Value::String(stream)) if stream.as_str() == "stdout" => &process.stdout,
+        Some(Value::String(stream)) if stream.as_str() == "stderr" => &process.stderr,
+        Some(value) => {
+            return Err(format!(
+                "Expected \"stdout\" or \"stderr\" as the stream, got {}",
+                value
+            ))
+        }
+    };
+    Ok(lines.recv().map_or(Value::None, Value::from))
+}
+
+pub fn process_kill(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let process = process(&args[0])?;
+    let mut process = process.borrow_mut();
+    // A process that already exited cannot be killed and needs not be
+    if process
+        .child
+        .try_wait()
+        .is_ok_and(|status| status.is_none())
+    {
+        process
+            .child
+            .kill()
+            .map_err(|error| format!("Could not kill the process: {}", error))?;
+    }
+    Ok(Value::None)
+}
+
+// Closes the input of the process and waits for it to exit, giving its exit
+// code, or none when a signal stopped it. Output it left can still be read.
+pub fn wait(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let process = process(&args[0])?;
+    let mut process = process.borrow_mut();
+    process.stdin.take();
+    let status = process
+        .child
+        .wait()
+        .map_err(|error| format!("Could not wait for the process: {}", error))?;
+    Ok(status
+        .code()
+        .map_or(Value::None, |code| Value::Integer(code as i64)))
+}
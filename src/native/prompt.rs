@@ -0,0 +1,126 @@
+use std::io::IsTerminal;
+
+use crate::value::Value;
+use crate::vm::VM;
+
+fn prompt_text(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(prompt) => Ok(prompt.to_string()),
+        value => Err(format!(
+            "Expected the prompt as a string, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+#[cfg(unix)]
+fn set_echo(on: bool) -> bool {
+    std::process::Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(not(unix))]
+fn set_echo(_on: bool) -> bool {
+    false
+}
+
+// Answers that were not typed are written after the prompt as if they were,
+// keeping the output readable
+fn echo_answer(vm: &mut VM, answer: &str, secret: bool) {
+    let shown = if secret { "" } else { answer };
+    vm.output().write_out(&format!("{}\n", shown));
+}
+
+// The answer to a prompt along with whether the user typed it, in which case
+// an invalid answer is asked again instead of failing. Answers set on the VM
+// come first, then lines of stdin.
+fn ask(vm: &mut VM, prompt: &str, secret: bool) -> Result<(String, bool), String> {
+    vm.output().write_out(prompt);
+    if let Some(answer) = vm.next_answer() {
+        echo_answer(vm, &answer, secret);
+        return Ok((answer, false));
+    }
+    vm.output().flush();
+
+    let stdin = std::io::stdin();
+    let from_terminal = stdin.is_terminal();
+    let hidden = secret && from_terminal && set_echo(false);
+    let mut line = String::new();
+    let read = stdin.read_line(&mut line);
+    if hidden {
+        set_echo(true);
+        vm.output().write_out("\n");
+    }
+    match read {
+        Ok(0) => Err(format!("No answer was given to \"{}\"", prompt.trim_end())),
+        Ok(_) => {
+            let answer = line.trim_end_matches(['\r', '\n']).to_owned();
+            if !from_terminal {
+                echo_answer(vm, &answer, secret);
+            }
+            Ok((answer, from_terminal))
+        }
+        Err(error) => Err(format!("Could not read the answer: {}", error)),
+    }
+}
+
+// `confirm("Continue?")` asks until the answer is yes or no
+pub fn confirm(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let prompt = format!("{} [y/n] ", prompt_text(&args[0])?);
+    loop {
+        let (answer, from_terminal) = ask(vm, &prompt, false)?;
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(Value::True),
+            "n" | "no" => return Ok(Value::False),
+            _ if from_terminal => vm.output().write_out("Please answer y or n.\n"),
+            _ => return Err(format!("Expected y or n as the answer, got \"{}\"", answer)),
+        }
+    }
+}
+
+// The options are listed with a number, either the number or the option
+// itself picks it. The chosen option is returned.
+pub fn choose(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let prompt = prompt_text(&args[0])?;
+    let options = match &args[1] {
+        Value::List(options) if !options.borrow().is_empty() => options.borrow().clone(),
+        Value::List(_) => return Err("Expected at least one option to choose from".to_owned()),
+        value => {
+            return Err(format!(
+                "Expected a list of options, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+
+    let mut listing = format!("{}\n", prompt);
+    for (number, option) in options.iter().enumerate() {
+        listing.push_str(&format!("  {}) {}\n", number + 1, option.to_text()));
+    }
+    vm.output().write_out(&listing);
+
+    let prompt = format!("Choose 1-{}: ", options.len());
+    loop {
+        let (answer, from_terminal) = ask(vm, &prompt, false)?;
+        let answer = answer.trim();
+        let chosen = match answer.parse::<usize>() {
+            Ok(number) if (1..=options.len()).contains(&number) => Some(&options[number - 1]),
+            _ => options.iter().find(|option| option.to_text() == answer),
+        };
+        match chosen {
+            Some(option) => return Ok(option.clone()),
+            None if from_terminal => vm.output().write_out("Please pick one of the options.\n"),
+            None => return Err(format!("\"{}\" is not one of the options", answer)),
+        }
+    }
+}
+
+// Like reading a line, without echoing what is typed when stdin is a terminal
+pub fn password(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let prompt = prompt_text(&args[0])?;
+    let (answer, _) = ask(vm, &prompt, true)?;
+    Ok(Value::from(answer))
+}
@@ -0,0 +1,71 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::value::Value;
+use crate::vm::VM;
+
+// SplitMix64, small and good enough for scripts. It is not suitable for
+// anything security related.
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    // Seeded from the time, for runs that are not meant to be reproducible.
+    // Without the os feature, or on wasm32-unknown-unknown which has no time
+    // to read, it always starts from 0.
+    pub fn from_time() -> Rng {
+        if cfg!(any(
+            not(feature = "os"),
+            all(target_arch = "wasm32", target_os = "unknown")
+        )) {
+            return Rng::new(0);
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        Rng::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1), from the top 53 bits
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub fn random(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Float(vm.rng().next_f64()))
+}
+
+// Both bounds are included, like a die roll with random_int(1, 6)
+pub fn random_int(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let (min, max) = match (&args[0], &args[1]) {
+        (Value::Integer(min), Value::Integer(max)) => (*min, *max),
+        (min, max) => {
+            return Err(format!(
+                "Expected integer bounds, got {} and {} instead",
+                min.type_of(),
+                max.type_of()
+            ))
+        }
+    };
+    if min > max {
+        return Err(format!("Empty range from {} to {}", min, max));
+    }
+    let range = (max as i128 - min as i128 + 1) as u128;
+    let offset = (vm.rng().next_u64() as u128 % range) as i128;
+    Ok(Value::Integer((min as i128 + offset) as i64))
+}
@@ -0,0 +1,61 @@
+use std::rc::Rc;
+
+use crate::value::Value;
+use crate::vm::VM;
+
+fn result(value: &Value) -> Result<&Rc<Result<Value, Value>>, String> {
+    match value {
+        Value::Result(result) => Ok(result),
+        value => Err(format!(
+            "Expected a result, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+pub fn ok(_vm: &mut VM, mut args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Result(Rc::new(Ok(args.remove(0)))))
+}
+
+// Usually given a message, though any value will do, like the error a
+// `try value` caught
+pub fn err(_vm: &mut VM, mut args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Result(Rc::new(Err(args.remove(0)))))
+}
+
+pub fn is_err(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(result(&args[0])?.is_err()))
+}
+
+pub fn unwrap_or(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &**result(&args[0])? {
+        Ok(value) => Ok(value.clone()),
+        Err(_) => Ok(args[1].clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_hold_a_value_or_an_error() {
+        let mut vm = VM::new();
+        let good = ok(&mut vm, vec![Value::Integer(1)]).unwrap();
+        let bad = err(&mut vm, vec![Value::from("failed")]).unwrap();
+        assert_eq!(is_err(&mut vm, vec![good.clone()]), Ok(Value::False));
+        assert_eq!(is_err(&mut vm, vec![bad.clone()]), Ok(Value::True));
+        assert_eq!(
+            unwrap_or(&mut vm, vec![good, Value::Integer(0)]),
+            Ok(Value::Integer(1))
+        );
+        assert_eq!(
+            unwrap_or(&mut vm, vec![bad, Value::Integer(0)]),
+            Ok(Value::Integer(0))
+        );
+        assert_eq!(
+            is_err(&mut vm, vec![Value::Integer(1)]),
+            Err("Expected a result, got int instead".to_owned())
+        );
+    }
+}
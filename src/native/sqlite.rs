@@ -0,0 +1,389 @@
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::rc::Rc;
+
+use crate::gc;
+use crate::object::ObjDatabase;
+use crate::value::Value;
+use crate::vm::VM;
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_OPEN_READWRITE: c_int = 0x2;
+const SQLITE_OPEN_CREATE: c_int = 0x4;
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_TEXT: c_int = 3;
+const SQLITE_BLOB: c_int = 4;
+// Makes SQLite copy bound text and blobs before the call returns
+const SQLITE_TRANSIENT: isize = -1;
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(
+        filename: *const c_char,
+        db: *mut *mut c_void,
+        flags: c_int,
+        vfs: *const c_char,
+    ) -> c_int;
+    fn sqlite3_close_v2(db: *mut c_void) -> c_int;
+    fn sqlite3_errmsg(db: *mut c_void) -> *const c_char;
+    fn sqlite3_changes(db: *mut c_void) -> c_int;
+    fn sqlite3_total_changes(db: *mut c_void) -> c_int;
+    fn sqlite3_prepare_v2(
+        db: *mut c_void,
+        sql: *const c_char,
+        length: c_int,
+        statement: *mut *mut c_void,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_finalize(statement: *mut c_void) -> c_int;
+    fn sqlite3_step(statement: *mut c_void) -> c_int;
+    fn sqlite3_bind_parameter_count(statement: *mut c_void) -> c_int;
+    fn sqlite3_bind_parameter_index(statement: *mut c_void, name: *const c_char) -> c_int;
+    fn sqlite3_bind_null(statement: *mut c_void, index: c_int) -> c_int;
+    fn sqlite3_bind_int64(statement: *mut c_void, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_double(statement: *mut c_void, index: c_int, value: f64) -> c_int;
+    fn sqlite3_bind_text(
+        statement: *mut c_void,
+        index: c_int,
+        text: *const c_char,
+        length: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_bind_blob(
+        statement: *mut c_void,
+        index: c_int,
+        blob: *const c_void,
+        length: c_int,
+        destructor: isize,
+    ) -> c_int;
+    fn sqlite3_column_count(statement: *mut c_void) -> c_int;
+    fn sqlite3_column_name(statement: *mut c_void, column: c_int) -> *const c_char;
+    fn sqlite3_column_type(statement: *mut c_void, column: c_int) -> c_int;
+    fn sqlite3_column_int64(statement: *mut c_void, column: c_int) -> i64;
+    fn sqlite3_column_double(statement: *mut c_void, column: c_int) -> f64;
+    fn sqlite3_column_text(statement: *mut c_void, column: c_int) -> *const u8;
+    fn sqlite3_column_blob(statement: *mut c_void, column: c_int) -> *const c_void;
+    fn sqlite3_column_bytes(statement: *mut c_void, column: c_int) -> c_int;
+}
+
+impl Drop for ObjDatabase {
+    fn drop(&mut self) {
+        // Statements never outlive a native call, so nothing keeps it open
+        unsafe { sqlite3_close_v2(self.connection) };
+    }
+}
+
+fn last_error(connection: *mut c_void) -> String {
+    unsafe { CStr::from_ptr(sqlite3_errmsg(connection)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn check(connection: *mut c_void, code: c_int) -> Result<(), String> {
+    if code == SQLITE_OK {
+        Ok(())
+    } else {
+        Err(format!("Database error: {}", last_error(connection)))
+    }
+}
+
+// A prepared statement, finalized when dropped
+struct Statement {
+    handle: *mut c_void,
+    connection: *mut c_void,
+}
+
+impl Drop for Statement {
+    fn drop(&mut self) {
+        unsafe { sqlite3_finalize(self.handle) };
+    }
+}
+
+impl Statement {
+    fn bind_value(&self, index: c_int, value: &Value) -> Result<(), String> {
+        let code = match value {
+            Value::None
+            | Value::IntegerNone
+            | Value::FloatNone
+            | Value::StringNone
+            | Value::BoolNone => unsafe { sqlite3_bind_null(self.handle, index) },
+            Value::Integer(n) => unsafe { sqlite3_bind_int64(self.handle, index, *n) },
+            Value::Float(n) => unsafe { sqlite3_bind_double(self.handle, index, *n) },
+            Value::True | Value::False => unsafe {
+                sqlite3_bind_int64(self.handle, index, value.is_truthy() as i64)
+            },
+            Value::String(_) | Value::Decimal(_) | Value::DateTime(_) => {
+                let text = value.to_text();
+                unsafe {
+                    sqlite3_bind_text(
+                        self.handle,
+                        index,
+                        text.as_ptr() as *const c_char,
+                        text.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    )
+                }
+            }
+            // Lists of bytes, like the ones tcp_read_bytes returns, are blobs
+            Value::List(items) => {
+                let bytes = items
+                    .borrow()
+                    .iter()
+                    .map(|item| match item {
+                        Value::Integer(byte) => u8::try_from(*byte).ok(),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or("Only lists of bytes can be stored in a database")?;
+                unsafe {
+                    sqlite3_bind_blob(
+                        self.handle,
+                        index,
+                        bytes.as_ptr() as *const c_void,
+                        bytes.len() as c_int,
+                        SQLITE_TRANSIENT,
+                    )
+                }
+            }
+            value => {
+                return Err(format!(
+                    "Cannot store type {} in a database",
+                    value.type_of()
+                ))
+            }
+        };
+        check(self.connection, code)
+    }
+
+    // A list fills the ? placeholders in order, a map the named ones like
+    // :name, its keys can leave out the colon
+    fn bind(&self, params: &Value) -> Result<(), String> {
+        let count = unsafe { sqlite3_bind_parameter_count(self.handle) } as usize;
+        match params {
+            Value::List(items) => {
+                let items = items.borrow();
+                if items.len() != count {
+                    return Err(format!(
+                        "The statement takes {} parameters, {} were given",
+                        count,
+                        items.len()
+                    ));
+                }
+                for (index, item) in items.iter().enumerate() {
+                    self.bind_value(index as c_int + 1, item)?;
+                }
+            }
+            Value::Map(entries) => {
+                for (key, value) in entries.borrow().iter() {
+                    let key = key.to_text();
+                    let name = if key.starts_with([':', '@', '$']) {
+                        key.clone()
+                    } else {
+                        format!(":{}", key)
+                    };
+                    let name = CString::new(name).map_err(|_| "Invalid parameter name")?;
+                    let index = unsafe { sqlite3_bind_parameter_index(self.handle, name.as_ptr()) };
+                    if index == 0 {
+                        return Err(format!("The statement has no parameter {}", key));
+                    }
+                    self.bind_value(index, value)?;
+                }
+            }
+            value => {
+                return Err(format!(
+                    "Expected the parameters as a list or a map, got {} instead",
+                    value.type_of()
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn column(&self, column: c_int) -> Value {
+        unsafe {
+            match sqlite3_column_type(self.handle, column) {
+                SQLITE_INTEGER => Value::Integer(sqlite3_column_int64(self.handle, column)),
+                SQLITE_FLOAT => Value::Float(sqlite3_column_double(self.handle, column)),
+                SQLITE_TEXT => {
+                    let text = sqlite3_column_text(self.handle, column);
+                    let length = sqlite3_column_bytes(self.handle, column) as usize;
+                    let bytes = std::slice::from_raw_parts(text, length);
+                    Value::from(String::from_utf8_lossy(bytes).into_owned())
+                }
+                SQLITE_BLOB => {
+                    let blob = sqlite3_column_blob(self.handle, column) as *const u8;
+                    let length = sqlite3_column_bytes(self.handle, column) as usize;
+                    let bytes = if length == 0 {
+                        Vec::new()
+                    } else {
+                        std::slice::from_raw_parts(blob, length)
+                            .iter()
+                            .map(|byte| Value::Integer(*byte as i64))
+                            .collect()
+                    };
+                    Value::List(gc::items(bytes))
+                }
+                _ => Value::None,
+            }
+        }
+    }
+
+    // Runs the statement to the end, keeping the rows it returns as maps
+    // from the column names to their values
+    fn run(&self) -> Result<Vec<Value>, String> {
+        let columns = unsafe { sqlite3_column_count(self.handle) };
+        let names: Vec<Value> = (0..columns)
+            .map(|column| {
+                let name = unsafe { CStr::from_ptr(sqlite3_column_name(self.handle, column)) };
+                Value::from(name.to_string_lossy().into_owned())
+            })
+            .collect();
+        let mut rows = Vec::new();
+        loop {
+            match unsafe { sqlite3_step(self.handle) } {
+                SQLITE_ROW => {
+                    let entries = (0..columns)
+                        .map(|column| (names[column as usize].clone(), self.column(column)))
+                        .collect();
+                    rows.push(Value::new_map(entries)?);
+                }
+                SQLITE_DONE => return Ok(rows),
+                _ => return Err(format!("Database error: {}", last_error(self.connection))),
+            }
+        }
+    }
+}
+
+// Prepares the first statement of the sql, returning it along with the rest
+// of the sql. There is no statement when only spaces or comments are left.
+fn prepare(connection: *mut c_void, sql: &CStr) -> Result<(Option<Statement>, &CStr), String> {
+    let mut handle = std::ptr::null_mut();
+    let mut tail = std::ptr::null();
+    let code = unsafe { sqlite3_prepare_v2(connection, sql.as_ptr(), -1, &mut handle, &mut tail) };
+    check(connection, code)?;
+    let rest = unsafe { CStr::from_ptr(tail) };
+    let statement = (!handle.is_null()).then_some(Statement { handle, connection });
+    Ok((statement, rest))
+}
+
+fn database(value: &Value) -> Result<Rc<RefCell<Option<ObjDatabase>>>, String> {
+    match value {
+        Value::Database(database) => Ok(database.clone()),
+        value => Err(format!(
+            "Expected a database from db_open, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+fn sql(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(sql) => Ok(sql),
+        value => Err(format!(
+            "Expected the sql as a string, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// Runs the statements of the sql one after the other, as each can depend on
+// the ones before. Parameters are only allowed with a single statement. The
+// rows returned by the last statement and the number of rows it changed are
+// given back.
+fn execute(args: &[Value]) -> Result<(Vec<Value>, i64), String> {
+    let database = database(&args[0])?;
+    let database = database.borrow();
+    let connection = database.as_ref().ok_or("Database is closed")?.connection;
+    let sql = CString::new(sql(&args[1])?).map_err(|_| "Sql cannot contain a null character")?;
+
+    let mut rest = sql.as_c_str();
+    let mut rows = Vec::new();
+    let mut changes = 0;
+    loop {
+        let (statement, tail) = prepare(connection, rest)?;
+        let Some(statement) = statement else {
+            return Ok((rows, changes));
+        };
+        if let Some(params) = args.get(2) {
+            let more = !tail
+                .to_string_lossy()
+                .trim()
+                .trim_matches(';')
+                .trim()
+                .is_empty();
+            if more {
+                return Err("Parameters can only be given with a single statement".to_owned());
+            }
+            statement.bind(params)?;
+        }
+        // sqlite3_changes keeps the count of the last insert, update or
+        // delete, so a statement changing nothing, like a create table, is
+        // told apart by the total staying the same
+        let total = unsafe { sqlite3_total_changes(connection) };
+        rows = statement.run()?;
+        changes = unsafe {
+            if sqlite3_total_changes(connection) == total {
+                0
+            } else {
+                sqlite3_changes(connection) as i64
+            }
+        };
+        rest = tail;
+    }
+}
+
+// The file is created when missing, ":memory:" opens a database that only
+// lives as long as the connection
+pub fn db_open(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let path = match &args[0] {
+        Value::String(path) => path.as_str(),
+        value => {
+            return Err(format!(
+                "Expected a path as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let filename = CString::new(path).map_err(|_| "Paths cannot contain a null character")?;
+    let mut connection = std::ptr::null_mut();
+    let code = unsafe {
+        sqlite3_open_v2(
+            filename.as_ptr(),
+            &mut connection,
+            SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE,
+            std::ptr::null(),
+        )
+    };
+    // A connection is returned even when opening failed, for its message
+    let database = ObjDatabase { connection };
+    if code != SQLITE_OK {
+        return Err(format!(
+            "Could not open database {}: {}",
+            path,
+            last_error(database.connection)
+        ));
+    }
+    Ok(Value::Database(Rc::new(RefCell::new(Some(database)))))
+}
+
+// `db_query(db, "select * from users where age > ?", [18])` lists the rows
+// as maps from the column names to their values
+pub fn db_query(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let (rows, _) = execute(&args)?;
+    Ok(Value::List(gc::items(rows)))
+}
+
+// Runs statements that return no rows, giving the number of rows the last
+// one inserted, updated or deleted, 0 for any other statement
+pub fn db_exec(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let (_, changes) = execute(&args)?;
+    Ok(Value::Integer(changes))
+}
+
+pub fn db_close(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    database(&args[0])?.borrow_mut().take();
+    Ok(Value::None)
+}
@@ -0,0 +1,81 @@
+use crate::io::env_var;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Assumed when the output is not a terminal or its size cannot be read
+const DEFAULT_WIDTH: i64 = 80;
+
+fn style_code(name: &str) -> Option<&'static str> {
+    let code = match name {
+        "bold" => "1",
+        "dim" => "2",
+        "italic" => "3",
+        "underline" => "4",
+        "black" => "30",
+        "red" => "31",
+        "green" => "32",
+        "yellow" => "33",
+        "blue" => "34",
+        "magenta" => "35",
+        "cyan" => "36",
+        "white" => "37",
+        "gray" => "90",
+        _ => return None,
+    };
+    Some(code)
+}
+
+// Escape sequences are only worth writing to a terminal, and never when the
+// user asked for no colors through NO_COLOR
+fn styled_output(vm: &mut VM) -> bool {
+    vm.output().is_terminal() && env_var("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+// `color(text, "red")` or `color(text, "bold red")`, the text is returned as
+// is when the output is redirected
+pub fn color(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let text = args[0].to_text();
+    let styles = match &args[1] {
+        Value::String(styles) => styles,
+        value => {
+            return Err(format!(
+                "Expected the color as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let codes = styles
+        .split_whitespace()
+        .map(|name| style_code(name).ok_or_else(|| format!("Unknown color {}", name)))
+        .collect::<Result<Vec<&str>, String>>()?;
+
+    if codes.is_empty() || !styled_output(vm) {
+        return Ok(Value::from(text));
+    }
+    Ok(Value::from(format!(
+        "\x1b[{}m{}\x1b[0m",
+        codes.join(";"),
+        text
+    )))
+}
+
+pub fn is_tty(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::from(vm.output().is_terminal()))
+}
+
+pub fn terminal_width(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    let width = vm
+        .output()
+        .width()
+        .map_or(DEFAULT_WIDTH, |width| width as i64);
+    Ok(Value::from(width))
+}
+
+// Clears the terminal and moves the cursor to its top left corner, nothing is
+// written when the output is redirected
+pub fn clear_screen(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    if vm.output().is_terminal() {
+        vm.output().write_out("\x1b[2J\x1b[H");
+    }
+    Ok(Value::None)
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use crate::interrupt;
+use crate::value::Value;
+use crate::vm::VM;
+
+fn milliseconds(value: &Value) -> Result<Duration, String> {
+    match value {
+        Value::Integer(ms) if *ms >= 0 => Ok(Duration::from_millis(*ms as u64)),
+        Value::Float(ms) if *ms >= 0.0 => Ok(Duration::from_secs_f64(*ms / 1000.0)),
+        Value::Duration(d) if d.millis() >= 0 => Ok(Duration::from_millis(d.millis() as u64)),
+        Value::Integer(_) | Value::Float(_) | Value::Duration(_) => {
+            Err("Delays cannot be negative".to_owned())
+        }
+        value => Err(format!(
+            "Expected a delay in milliseconds or a duration, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+pub fn sleep(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    vm.clock().sleep(milliseconds(&args[0])?);
+    if interrupt::pending() {
+        vm.check_interrupt_soon();
+    }
+    Ok(Value::None)
+}
+
+// Seconds since the script started, for measuring how long something takes
+pub fn clock(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Float(vm.clock().elapsed().as_secs_f64()))
+}
+
+// A stopwatch is the time it was started at, in nanoseconds since the script started
+pub fn stopwatch_start(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(vm.clock().elapsed().as_nanos() as i64))
+}
+
+fn stopwatch(vm: &mut VM, value: &Value) -> Result<Duration, String> {
+    match value {
+        Value::Integer(start) if *start >= 0 => Ok(vm
+            .clock()
+            .elapsed()
+            .saturating_sub(Duration::from_nanos(*start as u64))),
+        value => Err(format!(
+            "Expected a stopwatch from stopwatch_start, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// Seconds since the stopwatch was started
+pub fn elapsed(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Float(stopwatch(vm, &args[0])?.as_secs_f64()))
+}
+
+// Prints the time taken by the body of a time statement, along with its line
+pub fn time_report(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let elapsed = stopwatch(vm, &args[0])?.as_secs_f64();
+    let duration = if elapsed < 0.001 {
+        format!("{:.3} µs", elapsed * 1e6)
+    } else if elapsed < 1.0 {
+        format!("{:.3} ms", elapsed * 1e3)
+    } else {
+        format!("{:.3} s", elapsed)
+    };
+    vm.output()
+        .write_out(&format!("time at line {}: {}\n", args[1], duration));
+    Ok(Value::None)
+}
+
+// Milliseconds since the Unix epoch
+pub fn now(vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Integer(vm.clock().since_epoch().as_millis() as i64))
+}
+
+// The callback runs from the event loop once the script is done and its delay elapsed
+pub fn set_timeout(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let function = match &args[0] {
+        Value::ObjFunction(function) => function.clone(),
+        value => {
+            return Err(format!(
+                "Expected a function to schedule, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    if !function.function_info.arg_names.is_empty() {
+        return Err(format!(
+            "Scheduled functions cannot take arguments, {} takes {}",
+            function.name,
+            function.function_info.arg_names.len()
+        ));
+    }
+
+    let id = vm.add_timer(function, milliseconds(&args[1])?);
+    Ok(Value::Integer(id as i64))
+}
+
+pub fn clear_timeout(vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    match &args[0] {
+        Value::Integer(id) if *id >= 0 => Ok(Value::from(vm.cancel_timer(*id as usize))),
+        value => Err(format!(
+            "Expected a timer id, got {} instead",
+            value.type_of()
+        )),
+    }
+}
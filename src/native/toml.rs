@@ -0,0 +1,629 @@
+use crate::datetime::{DateTime, Parts};
+use crate::gc;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Tables stay open while the document is read, as later headers and dotted
+// keys can still add to them. Inline tables and arrays are complete once
+// written, so they are plain values.
+#[derive(Default)]
+struct Table {
+    entries: Vec<(String, Node)>,
+    // Set by a [header], tables only created along the way can get one later
+    header: bool,
+}
+
+enum Node {
+    Value(Value),
+    Table(Table),
+    Tables(Vec<Table>),
+}
+
+impl Table {
+    fn get_mut(&mut self, key: &str) -> Option<&mut Node> {
+        self.entries
+            .iter_mut()
+            .find(|(existing, _)| existing == key)
+            .map(|(_, node)| node)
+    }
+
+    // The table under the keys, creating the missing ones. An array of tables
+    // stands for its last table.
+    fn descend(&mut self, keys: &[String]) -> Result<&mut Table, String> {
+        let Some((key, rest)) = keys.split_first() else {
+            return Ok(self);
+        };
+        if self.get_mut(key).is_none() {
+            self.entries
+                .push((key.clone(), Node::Table(Table::default())));
+        }
+        match self.get_mut(key) {
+            Some(Node::Table(table)) => table.descend(rest),
+            Some(Node::Tables(tables)) => tables.last_mut().unwrap().descend(rest),
+            _ => Err(format!("key {} is already defined as a value", key)),
+        }
+    }
+
+    fn into_value(self) -> Value {
+        let entries = self
+            .entries
+            .into_iter()
+            .map(|(key, node)| {
+                let value = match node {
+                    Node::Value(value) => value,
+                    Node::Table(table) => table.into_value(),
+                    Node::Tables(tables) => Value::List(gc::items(
+                        tables.into_iter().map(Table::into_value).collect(),
+                    )),
+                };
+                (Value::from(key), value)
+            })
+            .collect();
+        Value::Map(gc::entries(entries))
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(offset, c)| self.peek_at(offset) == Some(c))
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("Invalid toml at line {}: {}", self.line, message)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.advance();
+                Ok(())
+            }
+            Some(c) => Err(self.error(&format!("expected '{}', found '{}'", expected, c))),
+            None => Err(self.error(&format!("expected '{}' before the end", expected))),
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.advance();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.advance();
+            }
+        }
+    }
+
+    // Spaces, comments and line breaks, which arrays allow between values
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n') => {
+                    self.advance();
+                }
+                Some('\r') if self.peek_at(1) == Some('\n') => {
+                    self.advance();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        if self.peek() == Some('\r') {
+            self.advance();
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.advance();
+                Ok(())
+            }
+            Some(c) => Err(self.error(&format!("expected the end of the line, found '{}'", c))),
+        }
+    }
+
+    fn document(&mut self) -> Result<Table, String> {
+        let mut root = Table::default();
+        let mut current: Vec<String> = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => return Ok(root),
+                Some('[') if self.peek_at(1) == Some('[') => {
+                    self.position += 2;
+                    let keys = self.keys()?;
+                    self.expect(']')?;
+                    self.expect(']')?;
+                    self.array_table(&mut root, &keys)?;
+                    current = keys;
+                }
+                Some('[') => {
+                    self.advance();
+                    let keys = self.keys()?;
+                    self.expect(']')?;
+                    self.header_table(&mut root, &keys)?;
+                    current = keys;
+                }
+                Some(_) => {
+                    let keys = self.keys()?;
+                    self.expect('=')?;
+                    self.skip_spaces();
+                    let value = self.value()?;
+                    let (key, parents) = keys.split_last().unwrap();
+                    let path = [current.as_slice(), parents].concat();
+                    let table = root
+                        .descend(&path)
+                        .map_err(|message| self.error(&message))?;
+                    if table.get_mut(key).is_some() {
+                        return Err(self.error(&format!("key {} is defined twice", key)));
+                    }
+                    table.entries.push((key.clone(), Node::Value(value)));
+                }
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    fn header_table(&self, root: &mut Table, keys: &[String]) -> Result<(), String> {
+        let (key, parents) = keys.split_last().unwrap();
+        let parent = root
+            .descend(parents)
+            .map_err(|message| self.error(&message))?;
+        match parent.get_mut(key) {
+            None => parent.entries.push((
+                key.clone(),
+                Node::Table(Table {
+                    entries: Vec::new(),
+                    header: true,
+                }),
+            )),
+            Some(Node::Table(table)) if !table.header => table.header = true,
+            Some(_) => {
+                return Err(self.error(&format!("table {} is defined twice", keys.join("."))))
+            }
+        }
+        Ok(())
+    }
+
+    fn array_table(&self, root: &mut Table, keys: &[String]) -> Result<(), String> {
+        let (key, parents) = keys.split_last().unwrap();
+        let parent = root
+            .descend(parents)
+            .map_err(|message| self.error(&message))?;
+        match parent.get_mut(key) {
+            None => parent
+                .entries
+                .push((key.clone(), Node::Tables(vec![Table::default()]))),
+            Some(Node::Tables(tables)) => tables.push(Table::default()),
+            Some(_) => {
+                return Err(self.error(&format!(
+                    "{} is already defined, it cannot be an array of tables",
+                    keys.join(".")
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    // A key made of bare or quoted parts separated by dots, like a."b.c".d
+    fn keys(&mut self) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        loop {
+            self.skip_spaces();
+            let key = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.position;
+                    while self
+                        .peek()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.advance();
+                    }
+                    if start == self.position {
+                        return Err(self.error("expected a key"));
+                    }
+                    self.chars[start..self.position].iter().collect()
+                }
+            };
+            keys.push(key);
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                return Ok(keys);
+            }
+            self.advance();
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"') if self.starts_with("\"\"\"") => {
+                self.multiline_basic_string().map(Value::from)
+            }
+            Some('"') => self.basic_string().map(Value::from),
+            Some('\'') if self.starts_with("'''") => {
+                self.multiline_literal_string().map(Value::from)
+            }
+            Some('\'') => self.literal_string().map(Value::from),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(_) => self.scalar(),
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let escaped = match self.advance() {
+            Some('b') => '\u{8}',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('f') => '\u{c}',
+            Some('r') => '\r',
+            Some('e') => '\u{1b}',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(marker @ ('u' | 'U')) => {
+                let length = if marker == 'u' { 4 } else { 8 };
+                let digits: String = (0..length).filter_map(|_| self.advance()).collect();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() == length)
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| {
+                        self.error(&format!("invalid unicode escape \\{}{}", marker, digits))
+                    })?
+            }
+            Some(c) => return Err(self.error(&format!("invalid escape \\{}", c))),
+            None => return Err(self.error("unterminated string")),
+        };
+        Ok(escaped)
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some('"') => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some('\\') => {
+                    self.advance();
+                    text.push(self.escape()?);
+                }
+                Some(c) => {
+                    self.advance();
+                    text.push(c);
+                }
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.advance();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('\n') | None => return Err(self.error("unterminated string")),
+                Some('\'') => {
+                    self.advance();
+                    return Ok(text);
+                }
+                Some(c) => {
+                    self.advance();
+                    text.push(c);
+                }
+            }
+        }
+    }
+
+    // The delimiter is three quotes, up to two more quotes right before it
+    // belong to the string
+    fn closes(&self, quote: char) -> bool {
+        (0..3).all(|offset| self.peek_at(offset) == Some(quote))
+            && !(0..3).all(|offset| self.peek_at(offset + 3) == Some(quote))
+    }
+
+    fn open_multiline(&mut self) {
+        self.position += 3;
+        if self.peek() == Some('\n') {
+            self.advance();
+        } else if self.starts_with("\r\n") {
+            self.position += 1;
+            self.advance();
+        }
+    }
+
+    fn multiline_basic_string(&mut self) -> Result<String, String> {
+        self.open_multiline();
+        let mut text = String::new();
+        loop {
+            if self.closes('"') {
+                self.position += 3;
+                return Ok(text);
+            }
+            match self.advance() {
+                // A backslash ending a line joins it with the next text
+                Some('\\') if matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) => {
+                    while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+                        self.advance();
+                    }
+                }
+                Some('\\') => text.push(self.escape()?),
+                Some(c) => text.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn multiline_literal_string(&mut self) -> Result<String, String> {
+        self.open_multiline();
+        let mut text = String::new();
+        loop {
+            if self.closes('\'') {
+                self.position += 3;
+                return Ok(text);
+            }
+            match self.advance() {
+                Some(c) => text.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.advance();
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.advance();
+                return Ok(Value::List(gc::items(items)));
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            match self.peek() {
+                Some(',') => {
+                    self.advance();
+                }
+                Some(']') => (),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Value, String> {
+        self.advance();
+        let mut table = Table::default();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.advance();
+            return Ok(table.into_value());
+        }
+        loop {
+            let keys = self.keys()?;
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            let (key, parents) = keys.split_last().unwrap();
+            let parent = table
+                .descend(parents)
+                .map_err(|message| self.error(&message))?;
+            if parent.get_mut(key).is_some() {
+                return Err(self.error(&format!("key {} is defined twice", key)));
+            }
+            parent.entries.push((key.clone(), Node::Value(value)));
+            self.skip_spaces();
+            match self.advance() {
+                Some(',') => (),
+                Some('}') => return Ok(table.into_value()),
+                _ => return Err(self.error("expected ',' or '}' in inline table")),
+            }
+        }
+    }
+
+    // Booleans, numbers and dates, which are written without quotes
+    fn scalar(&mut self) -> Result<Value, String> {
+        let start = self.position;
+        let bare = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-' | '.' | ':');
+        while self.peek().is_some_and(bare) {
+            self.advance();
+        }
+        // A space can separate the date from the time
+        let is_date = |text: &[char]| text.len() == 10 && text[4] == '-' && text[7] == '-';
+        if is_date(&self.chars[start..self.position])
+            && self.peek() == Some(' ')
+            && self.peek_at(1).is_some_and(|c| c.is_ascii_digit())
+        {
+            self.advance();
+            while self.peek().is_some_and(bare) {
+                self.advance();
+            }
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        match text.as_str() {
+            "" => Err(self.error(&format!("unexpected '{}'", self.peek().unwrap_or(' ')))),
+            "true" => Ok(Value::True),
+            "false" => Ok(Value::False),
+            "inf" | "+inf" => Ok(Value::Float(f64::INFINITY)),
+            "-inf" => Ok(Value::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => Ok(Value::Float(f64::NAN)),
+            _ if is_date(&self.chars[start..start + text.len().min(10)]) => self.datetime(&text),
+            // Times of day without a date have no datetime value to map to
+            _ if text.len() >= 8 && text.as_bytes()[2] == b':' => Ok(Value::from(text)),
+            _ => self.number(&text),
+        }
+    }
+
+    fn number(&self, text: &str) -> Result<Value, String> {
+        let invalid = || self.error(&format!("invalid value {}", text));
+        let digits = text.replace('_', "");
+        if text.starts_with('_') || text.ends_with('_') || text.contains("__") {
+            return Err(invalid());
+        }
+        for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+            if let Some(digits) = digits.strip_prefix(prefix) {
+                return i64::from_str_radix(digits, radix)
+                    .map(Value::Integer)
+                    .map_err(|_| invalid());
+            }
+        }
+        let unsigned = digits.trim_start_matches(['+', '-']);
+        if unsigned.len() > 1
+            && unsigned.starts_with('0')
+            && unsigned.as_bytes()[1].is_ascii_digit()
+        {
+            return Err(self.error(&format!("leading zeros are not allowed in {}", text)));
+        }
+        if digits.contains(['.', 'e', 'E']) {
+            return match digits.parse::<f64>() {
+                Ok(number) if !unsigned.starts_with('.') && !unsigned.ends_with('.') => {
+                    Ok(Value::Float(number))
+                }
+                _ => Err(invalid()),
+            };
+        }
+        digits.parse().map(Value::Integer).map_err(|_| invalid())
+    }
+
+    // Local dates are midnight and local times are read as UTC, only times
+    // with an offset are moved to UTC
+    fn datetime(&self, text: &str) -> Result<Value, String> {
+        let invalid = || self.error(&format!("invalid date {}", text));
+        let number = |range: std::ops::Range<usize>| -> Result<i64, String> {
+            text.get(range)
+                .filter(|digits| digits.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|digits| digits.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let mut parts = Parts {
+            year: number(0..4)?,
+            month: number(5..7)? as u32,
+            day: number(8..10)? as u32,
+            ..Parts::default()
+        };
+        let mut offset = 0;
+        if text.len() > 10 {
+            if !matches!(text.as_bytes()[10], b'T' | b't' | b' ')
+                || text.get(13..14) != Some(":")
+                || text.get(16..17) != Some(":")
+            {
+                return Err(invalid());
+            }
+            parts.hour = number(11..13)? as u32;
+            parts.minute = number(14..16)? as u32;
+            parts.second = number(17..19)? as u32;
+            let mut rest = &text[19..];
+            if let Some(fraction) = rest.strip_prefix('.') {
+                let length = fraction.bytes().take_while(u8::is_ascii_digit).count();
+                if length == 0 {
+                    return Err(invalid());
+                }
+                let millis = format!("{:0<3}", &fraction[..length.min(3)]);
+                parts.millisecond = millis.parse().map_err(|_| invalid())?;
+                rest = &fraction[length..];
+            }
+            match rest {
+                "" | "Z" | "z" => (),
+                _ if rest.len() == 6 && rest.as_bytes()[3] == b':' => {
+                    let hours: i64 = rest[1..3].parse().map_err(|_| invalid())?;
+                    let minutes: i64 = rest[4..6].parse().map_err(|_| invalid())?;
+                    offset = (hours * 60 + minutes) * 60_000;
+                    match rest.as_bytes()[0] {
+                        b'+' => (),
+                        b'-' => offset = -offset,
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        let datetime = DateTime::from_parts(parts).map_err(|message| self.error(&message))?;
+        Ok(Value::from(datetime.add_millis(-offset)?))
+    }
+}
+
+// Tables become maps in the order their keys were written, arrays of tables
+// lists of maps and dates datetimes
+pub fn toml_parse(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::String(text) => text,
+        value => {
+            return Err(format!(
+                "Expected toml text as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+    Ok(parser.document()?.into_value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<String, String> {
+        toml_parse(&mut VM::new(), vec![Value::from(text)]).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn tables_arrays_and_values_are_read() {
+        let text = "title = \"app\"\nratio = 0.5\n\n[server]\nport = 8080\nhosts = [\"a\", \"b\"]\nsecure = true\n\n[[users]]\nname = \"ann\"\n\n[[users]]\nname = \"bob\"\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            "{\"title\": \"app\", \"ratio\": 0.5, \"server\": {\"port\": 8080, \"hosts\": [\"a\", \"b\"], \"secure\": true}, \"users\": [{\"name\": \"ann\"}, {\"name\": \"bob\"}]}"
+        );
+        assert_eq!(
+            parse("point = { x = 1, y = -2 }\n").unwrap(),
+            "{\"point\": {\"x\": 1, \"y\": -2}}"
+        );
+    }
+
+    #[test]
+    fn invalid_documents_are_errors() {
+        assert!(parse("x = \n").is_err());
+        assert!(parse("x = 1\nx = 2\n").is_err());
+        assert!(parse("[server\n").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::value::Value;
+use crate::vm::VM;
+
+// Builds without a feature keep its natives in the table, so compiled
+// bytecode refers to the same indices, but calling them is an error
+macro_rules! unavailable {
+    ($feature:literal: $($name:ident),* $(,)?) => {
+        $(
+            pub fn $name(_vm: &mut VM, _args: Vec<Value>) -> Result<Value, String> {
+                Err(format!(
+                    "Function {} is not available, the interpreter was built without the {} feature",
+                    stringify!($name),
+                    $feature
+                ))
+            }
+        )*
+    };
+}
+
+#[cfg(not(feature = "os"))]
+unavailable!(
+    "os":
+    list_dir,
+    mkdir,
+    remove,
+    http_get,
+    http_post,
+    tcp_connect,
+    tcp_listen,
+    tcp_accept,
+    tcp_read,
+    tcp_read_bytes,
+    tcp_read_line,
+    tcp_write,
+    tcp_close,
+    spawn_process,
+    process_write,
+    process_close_stdin,
+    process_read_line,
+    process_kill,
+    wait,
+    spawn,
+);
+
+#[cfg(not(feature = "config"))]
+unavailable!("config": toml_parse, yaml_parse);
+
+#[cfg(not(feature = "sqlite"))]
+unavailable!("sqlite": db_open, db_query, db_exec, db_close);
+
+// Only built by `cargo test --no-default-features`, which keeps the core
+// compiling and running on its own
+#[cfg(all(test, not(feature = "os")))]
+mod tests {
+    use super::*;
+    use crate::io::CapturedOutput;
+    use crate::vm::InterpretError;
+
+    #[test]
+    fn os_natives_fail_at_runtime() {
+        let mut vm = VM::new();
+        let output = CapturedOutput::new();
+        vm.set_output(Box::new(output.clone()));
+        assert!(matches!(
+            vm.interpret("list_dir(\".\")\n".to_owned()),
+            Err(InterpretError::Runtime)
+        ));
+        assert!(output.take().contains(
+            "Function list_dir is not available, the interpreter was built without the os feature"
+        ));
+        vm.reset();
+        assert!(matches!(
+            vm.interpret("worker {\n    print 1\n}\nspawn(worker)\n".to_owned()),
+            Err(InterpretError::Runtime)
+        ));
+        assert!(output.take().contains("Function spawn is not available"));
+        vm.reset();
+        assert!(matches!(
+            vm.interpret("6 * 7\n".to_owned()),
+            Ok(Value::Integer(42))
+        ));
+    }
+
+    #[test]
+    fn the_environment_and_the_clock_are_not_read() {
+        std::env::set_var("LANG", "de_DE.UTF-8");
+        let mut vm = VM::new();
+        vm.set_output(Box::new(CapturedOutput::new()));
+        assert!(matches!(
+            vm.interpret("format_number(1234.5)\n".to_owned()),
+            Ok(Value::String(text)) if &*text == "1,234.50"
+        ));
+        assert!(matches!(
+            vm.interpret("now()\n".to_owned()),
+            Ok(Value::Integer(0))
+        ));
+    }
+}
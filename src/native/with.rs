@@ -0,0 +1,16 @@
+use crate::native::find_method;
+use crate::value::Value;
+use crate::vm::VM;
+
+// The value of a with statement is checked when the block starts, rather than
+// once it is over and the close method turns out to be missing
+pub fn with_enter(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let value = args.into_iter().next().unwrap_or(Value::None);
+    if find_method(&value.type_of(), "close").is_none() {
+        return Err(format!(
+            "Cannot use {} in a with statement, it has no close method",
+            value.type_of()
+        ));
+    }
+    Ok(value)
+}
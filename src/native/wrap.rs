@@ -0,0 +1,80 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::object::{FunctionInfo, ObjFunction};
+use crate::value::Value;
+use crate::vm::VM;
+
+fn function_arg<'a>(value: &'a Value, action: &str) -> Result<&'a Rc<ObjFunction>, String> {
+    match value {
+        Value::ObjFunction(function) => Ok(function),
+        value => Err(format!(
+            "Expected a function to {}, got {} instead",
+            action,
+            value.type_of()
+        )),
+    }
+}
+
+// A copy of the function that remembers what it returned for every set of
+// arguments, calling it again with them gives the stored result back
+pub fn memoize(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let function = function_arg(&args[0], "memoize")?;
+    if function.is_generator {
+        return Err(format!(
+            "Generator function {} cannot be memoized",
+            function.name
+        ));
+    }
+
+    let mut memoized = function.as_ref().clone();
+    memoized.memo = Some(Rc::new(RefCell::new(HashMap::new())));
+    Ok(Value::ObjFunction(Rc::new(memoized)))
+}
+
+// A copy of the function reporting every call and its arguments on stderr
+pub fn trace(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let mut traced = function_arg(&args[0], "trace")?.as_ref().clone();
+    traced.traced = true;
+    Ok(Value::ObjFunction(Rc::new(traced)))
+}
+
+// `bind(f, a, b)` is f with its first arguments set to a and b, calling it
+// takes the arguments that are left
+pub fn bind(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let function = function_arg(&args[0], "bind")?;
+    let values = &args[1..];
+    let info = &function.function_info;
+    if values.len() > info.arg_names.len() {
+        return Err(format!(
+            "Function {} takes {} arguments, {} cannot be bound",
+            info.name,
+            info.arg_names.len(),
+            values.len()
+        ));
+    }
+    let mismatch = values
+        .iter()
+        .zip(&info.arg_types)
+        .zip(&info.arg_names)
+        .find(|((value, type_), _)| !type_.is_value_correct_type(value));
+    if let Some(((value, type_), name)) = mismatch {
+        return Err(format!(
+            "Argument {} of function {} must be of type {}, got {} instead",
+            name,
+            info.name,
+            type_,
+            value.type_of()
+        ));
+    }
+
+    let mut bound = function.as_ref().clone();
+    bound.bound.extend_from_slice(values);
+    bound.function_info = Rc::new(FunctionInfo {
+        name: info.name.clone(),
+        arg_names: info.arg_names[values.len()..].to_vec(),
+        arg_types: info.arg_types[values.len()..].to_vec(),
+    });
+    Ok(Value::ObjFunction(Rc::new(bound)))
+}
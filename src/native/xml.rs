@@ -0,0 +1,600 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use indexmap::IndexMap;
+
+use crate::gc;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Html elements that never have content, so they need no closing tag
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Html elements whose content is text even when it contains tags
+const RAW_TEXT_ELEMENTS: [&str; 2] = ["script", "style"];
+
+struct Element {
+    tag: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Node>,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn new(tag: String, attrs: Vec<(String, String)>) -> Element {
+        Element {
+            tag,
+            attrs,
+            children: Vec::new(),
+        }
+    }
+
+    fn into_value(self) -> Value {
+        let attrs = self
+            .attrs
+            .into_iter()
+            .map(|(name, value)| (Value::from(name), Value::from(value)))
+            .collect();
+        let children = self
+            .children
+            .into_iter()
+            .map(|child| match child {
+                Node::Element(element) => element.into_value(),
+                Node::Text(text) => Value::from(text),
+            })
+            .collect();
+        Value::Map(gc::entries(IndexMap::from([
+            (Value::from("tag"), Value::from(self.tag)),
+            (Value::from("attrs"), Value::Map(gc::entries(attrs))),
+            (Value::from("children"), Value::List(gc::items(children))),
+        ])))
+    }
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "amp" => Some('&'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let number = entity.strip_prefix('#')?;
+            let code = match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => number.parse().ok()?,
+            };
+            char::from_u32(code)
+        }
+    }
+}
+
+// Unknown entities are kept as written, like browsers do
+fn decode(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| Some((end, decode_entity(&rest[1..end + 1])?)));
+        match entity {
+            Some((end, c)) => {
+                decoded.push(c);
+                rest = &rest[end + 2..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars()
+            .enumerate()
+            .all(|(offset, c)| self.chars.get(self.position + offset) == Some(&c))
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("Invalid xml at line {}: {}", self.line, message)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.advance();
+        }
+    }
+
+    // Everything up to the end marker, which is skipped too
+    fn until(&mut self, end: &str, what: &str) -> Result<String, String> {
+        let mut text = String::new();
+        while !self.starts_with(end) {
+            text.push(
+                self.advance()
+                    .ok_or_else(|| self.error(&format!("unterminated {}", what)))?,
+            );
+        }
+        self.position += end.chars().count();
+        Ok(text)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let start = self.position;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.'))
+        {
+            self.advance();
+        }
+        if start == self.position {
+            return Err(self.error("expected a name"));
+        }
+        Ok(self.chars[start..self.position].iter().collect())
+    }
+
+    // Html allows attributes without a value and values without quotes
+    fn attrs(&mut self) -> Result<Vec<(String, String)>, String> {
+        let mut attrs: Vec<(String, String)> = Vec::new();
+        loop {
+            self.skip_spaces();
+            if matches!(self.peek(), Some('>' | '/' | '?') | None) {
+                return Ok(attrs);
+            }
+            let name = self.name()?;
+            self.skip_spaces();
+            let value = if self.peek() == Some('=') {
+                self.advance();
+                self.skip_spaces();
+                match self.peek() {
+                    Some(quote @ ('"' | '\'')) => {
+                        self.advance();
+                        decode(&self.until(&quote.to_string(), "attribute value")?)
+                    }
+                    _ => {
+                        let start = self.position;
+                        while self.peek().is_some_and(|c| !c.is_whitespace() && c != '>') {
+                            self.advance();
+                        }
+                        decode(&self.chars[start..self.position].iter().collect::<String>())
+                    }
+                }
+            } else {
+                String::new()
+            };
+            if attrs.iter().any(|(existing, _)| *existing == name) {
+                return Err(self.error(&format!("attribute {} is defined twice", name)));
+            }
+            attrs.push((name, value));
+        }
+    }
+
+    fn document(&mut self) -> Result<Vec<Node>, String> {
+        // The open elements, the document itself comes first
+        let mut stack = vec![Element::new(String::new(), Vec::new())];
+        while self.peek().is_some() {
+            if self.starts_with("<!--") {
+                self.position += 4;
+                self.until("-->", "comment")?;
+            } else if self.starts_with("<![CDATA[") {
+                self.position += 9;
+                let text = self.until("]]>", "cdata section")?;
+                stack.last_mut().unwrap().children.push(Node::Text(text));
+            } else if self.starts_with("<?") || self.starts_with("<!") {
+                self.until(">", "declaration")?;
+            } else if self.starts_with("</") {
+                self.position += 2;
+                let tag = self.name()?;
+                self.skip_spaces();
+                if self.advance() != Some('>') {
+                    return Err(self.error(&format!("expected '>' to close </{}", tag)));
+                }
+                // Elements left open inside are closed along with it
+                let open = stack[1..]
+                    .iter()
+                    .rposition(|element| element.tag == tag)
+                    .ok_or_else(|| self.error(&format!("unexpected closing tag </{}>", tag)))?;
+                while stack.len() > open + 1 {
+                    let element = stack.pop().unwrap();
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .children
+                        .push(Node::Element(element));
+                }
+            } else if self.starts_with("<")
+                && self
+                    .chars
+                    .get(self.position + 1)
+                    .is_some_and(|c| c.is_alphabetic() || *c == '_')
+            {
+                self.advance();
+                let tag = self.name()?;
+                let attrs = self.attrs()?;
+                let closed = self.peek() == Some('/');
+                if closed {
+                    self.advance();
+                }
+                if self.advance() != Some('>') {
+                    return Err(self.error(&format!("expected '>' to end <{}", tag)));
+                }
+                let lowercase = tag.to_lowercase();
+                let mut element = Element::new(tag, attrs);
+                if closed || VOID_ELEMENTS.contains(&lowercase.as_str()) {
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .children
+                        .push(Node::Element(element));
+                } else if RAW_TEXT_ELEMENTS.contains(&lowercase.as_str()) {
+                    let end = format!("</{}", element.tag);
+                    let text = self.until(&end, &format!("<{}>", element.tag))?;
+                    self.until(">", &format!("</{}", element.tag))?;
+                    if !text.is_empty() {
+                        element.children.push(Node::Text(text));
+                    }
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .children
+                        .push(Node::Element(element));
+                } else {
+                    stack.push(element);
+                }
+            } else {
+                let start = self.position;
+                self.advance();
+                while self.peek().is_some_and(|c| c != '<') {
+                    self.advance();
+                }
+                let text: String = self.chars[start..self.position].iter().collect();
+                if !text.trim().is_empty() {
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .children
+                        .push(Node::Text(decode(&text)));
+                }
+            }
+        }
+        while stack.len() > 1 {
+            let element = stack.pop().unwrap();
+            stack
+                .last_mut()
+                .unwrap()
+                .children
+                .push(Node::Element(element));
+        }
+        Ok(stack.pop().unwrap().children)
+    }
+}
+
+// Elements become maps with their tag, a map of their attributes and a list
+// of children, which are elements or strings of text. Text made only of
+// whitespace is left out. A document with several top level nodes is
+// returned as the children of an element without a tag.
+pub fn xml_parse(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::String(text) => text,
+        value => {
+            return Err(format!(
+                "Expected xml text as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0,
+        line: 1,
+    };
+    let mut nodes = parser.document()?;
+    match nodes.pop() {
+        Some(Node::Element(element)) if nodes.is_empty() => Ok(element.into_value()),
+        Some(node) => {
+            nodes.push(node);
+            let mut document = Element::new(String::new(), Vec::new());
+            document.children = nodes;
+            Ok(document.into_value())
+        }
+        None => Err("Invalid xml: the document is empty".to_owned()),
+    }
+}
+
+// One element of a selector, like div.item[href]
+#[derive(Default)]
+struct Compound {
+    tag: Option<String>,
+    id: Option<String>,
+    classes: Vec<String>,
+    attrs: Vec<(String, Option<String>)>,
+}
+
+#[derive(PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+fn is_name(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | ':')
+}
+
+fn take_name(chars: &mut Peekable<Chars>) -> String {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| is_name(*c)) {
+        name.push(c);
+    }
+    name
+}
+
+fn parse_selector(selector: &str) -> Result<Vec<Vec<(Combinator, Compound)>>, String> {
+    let invalid = || format!("Invalid selector \"{}\"", selector);
+    let mut groups = Vec::new();
+    for group in selector.split(',') {
+        let mut chain = Vec::new();
+        let mut combinator = Combinator::Descendant;
+        let mut chars = group.trim().chars().peekable();
+        if chars.peek().is_none() {
+            return Err(invalid());
+        }
+        while chars.peek().is_some() {
+            let mut compound = Compound::default();
+            let mut empty = true;
+            loop {
+                match chars.peek() {
+                    Some('*') => {
+                        chars.next();
+                    }
+                    Some('.') | Some('#') => {
+                        let marker = chars.next();
+                        let name = take_name(&mut chars);
+                        if name.is_empty() {
+                            return Err(invalid());
+                        }
+                        if marker == Some('.') {
+                            compound.classes.push(name);
+                        } else {
+                            compound.id = Some(name);
+                        }
+                    }
+                    Some('[') => {
+                        chars.next();
+                        let name = take_name(&mut chars);
+                        let value = match chars.next() {
+                            Some(']') => None,
+                            Some('=') => {
+                                let mut value: String =
+                                    chars.by_ref().take_while(|c| *c != ']').collect();
+                                if let Some(quoted) = value
+                                    .strip_prefix(['"', '\''])
+                                    .and_then(|value| value.strip_suffix(['"', '\'']))
+                                {
+                                    value = quoted.to_owned();
+                                }
+                                Some(value)
+                            }
+                            _ => return Err(invalid()),
+                        };
+                        if name.is_empty() {
+                            return Err(invalid());
+                        }
+                        compound.attrs.push((name, value));
+                    }
+                    Some(c) if is_name(*c) && empty => compound.tag = Some(take_name(&mut chars)),
+                    _ => break,
+                }
+                empty = false;
+            }
+            if empty {
+                return Err(invalid());
+            }
+            chain.push((combinator, compound));
+
+            let mut spaced = false;
+            while chars.next_if(|c| c.is_whitespace()).is_some() {
+                spaced = true;
+            }
+            combinator = if chars.next_if_eq(&'>').is_some() {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                Combinator::Child
+            } else if spaced {
+                Combinator::Descendant
+            } else if chars.peek().is_some() {
+                return Err(invalid());
+            } else {
+                break;
+            };
+            if chars.peek().is_none() {
+                return Err(invalid());
+            }
+        }
+        groups.push(chain);
+    }
+    Ok(groups)
+}
+
+// The tag, attributes and children of an element from xml_parse
+fn element_parts(value: &Value) -> Option<(Value, Value, Value)> {
+    let Value::Map(entries) = value else {
+        return None;
+    };
+    let field = |name: &str| entries.borrow().get(&Value::from(name)).cloned();
+    Some((field("tag")?, field("attrs")?, field("children")?))
+}
+
+fn attr(attrs: &Value, name: &str) -> Option<String> {
+    let Value::Map(entries) = attrs else {
+        return None;
+    };
+    entries.borrow().get(&Value::from(name)).map(Value::to_text)
+}
+
+fn matches_compound(compound: &Compound, element: &Value) -> bool {
+    let Some((tag, attrs, _)) = element_parts(element) else {
+        return false;
+    };
+    if compound
+        .tag
+        .as_ref()
+        .is_some_and(|name| tag.to_text() != *name)
+    {
+        return false;
+    }
+    if compound.id.is_some() && attr(&attrs, "id") != compound.id {
+        return false;
+    }
+    let classes = attr(&attrs, "class").unwrap_or_default();
+    if !compound
+        .classes
+        .iter()
+        .all(|class| classes.split_whitespace().any(|existing| existing == class))
+    {
+        return false;
+    }
+    compound
+        .attrs
+        .iter()
+        .all(|(name, expected)| match attr(&attrs, name) {
+            Some(value) => expected.as_ref().is_none_or(|expected| value == *expected),
+            None => false,
+        })
+}
+
+// Matches from the last compound of the chain back to the first, through
+// the ancestors of the element
+fn matches_chain(chain: &[(Combinator, Compound)], element: &Value, ancestors: &[Value]) -> bool {
+    let Some(((combinator, compound), rest)) = chain.split_last() else {
+        return true;
+    };
+    if !matches_compound(compound, element) {
+        return false;
+    }
+    if rest.is_empty() {
+        return true;
+    }
+    match combinator {
+        Combinator::Child => ancestors
+            .split_last()
+            .is_some_and(|(parent, above)| matches_chain(rest, parent, above)),
+        Combinator::Descendant => (0..ancestors.len())
+            .rev()
+            .any(|i| matches_chain(rest, &ancestors[i], &ancestors[..i])),
+    }
+}
+
+fn select(
+    groups: &[Vec<(Combinator, Compound)>],
+    element: &Value,
+    ancestors: &mut Vec<Value>,
+    found: &mut Vec<Value>,
+) {
+    let Some((_, _, Value::List(children))) = element_parts(element) else {
+        return;
+    };
+    if groups
+        .iter()
+        .any(|chain| matches_chain(chain, element, ancestors))
+    {
+        found.push(element.clone());
+    }
+    ancestors.push(element.clone());
+    for child in children.borrow().iter() {
+        select(groups, child, ancestors, found);
+    }
+    ancestors.pop();
+}
+
+// `xml_select(tree, "ul.menu > li a[href]")` lists the elements of the tree
+// matching a css selector, in document order. Selectors are made of tags,
+// *, .class, #id, [attr] and [attr=value], combined with spaces for
+// descendants, > for children and commas for alternatives.
+pub fn xml_select(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    if element_parts(&args[0]).is_none() {
+        return Err(format!(
+            "Expected an element from xml_parse, got {} instead",
+            args[0].type_of()
+        ));
+    }
+    let groups = match &args[1] {
+        Value::String(selector) => parse_selector(selector)?,
+        value => {
+            return Err(format!(
+                "Expected the selector as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let mut found = Vec::new();
+    select(&groups, &args[0], &mut Vec::new(), &mut found);
+    Ok(Value::List(gc::items(found)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elements_are_parsed_and_selected() {
+        let mut vm = VM::new();
+        let text = "<ul class=\"menu\">\n  <li id=\"first\"><a href=\"/\">Home</a></li>\n  <li><a>Plain</a></li>\n</ul>";
+        let tree = xml_parse(&mut vm, vec![Value::from(text)]).unwrap();
+        let select = |vm: &mut VM, selector: &str| {
+            xml_select(vm, vec![tree.clone(), Value::from(selector)])
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            select(&mut vm, "ul.menu > li a[href]"),
+            "[{\"tag\": \"a\", \"attrs\": {\"href\": \"/\"}, \"children\": [\"Home\"]}]"
+        );
+        assert!(select(&mut vm, "#first")
+            .starts_with("[{\"tag\": \"li\", \"attrs\": {\"id\": \"first\"}"));
+        let found = xml_select(&mut vm, vec![tree.clone(), Value::from("li, a")]).unwrap();
+        assert!(matches!(found, Value::List(items) if items.borrow().len() == 4));
+        assert_eq!(select(&mut vm, "table"), "[]");
+    }
+
+    #[test]
+    fn malformed_documents_are_errors() {
+        let mut vm = VM::new();
+        assert!(xml_parse(&mut vm, vec![Value::from("<a></b>")]).is_err());
+        assert!(xml_parse(&mut vm, vec![Value::from("<a x=\"1\" x=\"2\"/>")]).is_err());
+        assert!(xml_parse(&mut vm, vec![Value::from("<a href=\"x></a>")]).is_err());
+        let tree = xml_parse(&mut vm, vec![Value::from("<br>")]).unwrap();
+        assert!(xml_select(&mut vm, vec![tree, Value::from("a[")]).is_err());
+    }
+}
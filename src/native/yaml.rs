@@ -0,0 +1,601 @@
+use crate::gc;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Block collections are read by indentation, so the text is split in lines
+// first. A line starting a sequence item is split again where the item
+// starts, which then reads like any other line indented that far.
+#[derive(Clone)]
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+fn error(line: usize, message: &str) -> String {
+    format!("Invalid yaml at line {}: {}", line, message)
+}
+
+// A comment starts with a # at the start of the line or after a space, and
+// not inside quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some('"') if c == '\\' && previous == '\\' => {
+                previous = ' ';
+                continue;
+            }
+            Some(q) if c == q && !(q == '"' && previous == '\\') => quote = None,
+            Some(_) => (),
+            None if c == '#' && (previous == ' ' || previous == '\t') => return &text[..i],
+            None if (c == '"' || c == '\'') && matches!(previous, ' ' | '[' | '{' | ',' | ':') => {
+                quote = Some(c)
+            }
+            None => (),
+        }
+        previous = c;
+    }
+    text
+}
+
+fn split_lines(text: &str) -> Result<Vec<Line>, String> {
+    let mut lines = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let number = index + 1;
+        let content = raw.trim_start_matches(' ');
+        if content.starts_with('\t') {
+            return Err(error(number, "tabs cannot be used for indentation"));
+        }
+        lines.push(Line {
+            number,
+            indent: raw.len() - content.len(),
+            text: content.trim_end().to_owned(),
+        });
+    }
+    Ok(lines)
+}
+
+// Where the colon of a `key: value` line is, outside of quotes and brackets
+fn key_colon(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    if let Some(quote @ (b'"' | b'\'')) = bytes.first() {
+        index = 1;
+        while index < bytes.len() {
+            if bytes[index] == b'\\' && *quote == b'"' {
+                index += 1;
+            } else if bytes[index] == *quote {
+                break;
+            }
+            index += 1;
+        }
+        index += 1;
+    } else if matches!(bytes.first(), Some(b'[' | b'{')) {
+        return None;
+    }
+    while index < bytes.len() {
+        if bytes[index] == b':' && matches!(bytes.get(index + 1), None | Some(b' ')) {
+            return Some(index);
+        }
+        index += 1;
+    }
+    None
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    current: usize,
+}
+
+impl Parser {
+    fn skip_blank(&mut self) {
+        while self
+            .lines
+            .get(self.current)
+            .is_some_and(|line| strip_comment(&line.text).trim().is_empty())
+        {
+            self.current += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<Line> {
+        self.skip_blank();
+        self.lines.get(self.current).cloned()
+    }
+
+    // The node whose first line is indented by more than the parent
+    fn node(&mut self, parent_indent: Option<usize>) -> Result<Value, String> {
+        let line = match self.peek() {
+            Some(line) if parent_indent.is_none_or(|parent| line.indent > parent) => line,
+            _ => return Ok(Value::None),
+        };
+        let text = strip_comment(&line.text).trim_end();
+        if is_item(text) {
+            self.sequence(line.indent)
+        } else if key_colon(text).is_some() {
+            self.mapping(line.indent)
+        } else {
+            self.current += 1;
+            let value = self.inline(text, line.number)?;
+            match self.peek() {
+                Some(next) if parent_indent.is_none_or(|parent| next.indent > parent) => {
+                    Err(error(next.number, "unexpected content after a value"))
+                }
+                _ => Ok(value),
+            }
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Value, String> {
+        let mut items = Vec::new();
+        while let Some(line) = self.peek() {
+            let text = strip_comment(&line.text).trim_end().to_owned();
+            if line.indent != indent || !is_item(&text) {
+                if line.indent > indent {
+                    return Err(error(line.number, "bad indentation in sequence"));
+                }
+                break;
+            }
+            if text[1..].trim_start().is_empty() {
+                self.current += 1;
+                items.push(self.node(Some(indent))?);
+                continue;
+            }
+            // The item continues on this line, it is read as if it started
+            // a line of its own
+            let offset = line.text.len() - line.text[1..].trim_start().len();
+            self.lines[self.current] = Line {
+                number: line.number,
+                indent: indent + offset,
+                text: line.text[offset..].to_owned(),
+            };
+            items.push(self.node(Some(indent))?);
+        }
+        Ok(Value::List(gc::items(items)))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Value, String> {
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        while let Some(line) = self.peek() {
+            if line.indent < indent {
+                break;
+            }
+            let text = strip_comment(&line.text).trim_end().to_owned();
+            let colon = match key_colon(&text) {
+                Some(colon) if line.indent == indent => colon,
+                _ if line.indent == indent && is_item(&text) => break,
+                _ => return Err(error(line.number, "expected a key in mapping")),
+            };
+            let key = self.inline(text[..colon].trim_end(), line.number)?;
+            if !key.is_hashable() {
+                return Err(error(line.number, "keys must be plain values"));
+            }
+            if entries.iter().any(|(existing, _)| *existing == key) {
+                return Err(error(line.number, &format!("key {} is defined twice", key)));
+            }
+            self.current += 1;
+
+            let rest = text[colon + 1..].trim();
+            let value = if rest.is_empty() {
+                // A sequence can be indented as much as its key
+                match self.peek() {
+                    Some(next) if next.indent == indent && is_item(&next.text) => {
+                        self.sequence(indent)?
+                    }
+                    _ => self.node(Some(indent))?,
+                }
+            } else if rest.starts_with('|') || rest.starts_with('>') {
+                self.block_scalar(rest, line.number, indent)?
+            } else {
+                let value = self.continued(rest, indent);
+                self.inline(&value, line.number)?
+            };
+            entries.push((key, value));
+        }
+        Ok(Value::Map(gc::entries(entries.into_iter().collect())))
+    }
+
+    // Plain values and flow collections can go on over the lines indented
+    // more than their key, they are joined with spaces
+    fn continued(&mut self, first: &str, indent: usize) -> String {
+        let mut text = first.to_owned();
+        while let Some(line) = self.peek() {
+            if line.indent <= indent {
+                break;
+            }
+            text.push(' ');
+            text.push_str(strip_comment(&line.text).trim());
+            self.current += 1;
+        }
+        text
+    }
+
+    // `|` keeps the line breaks and `>` folds lines into one. The final line
+    // break is kept once, removed with `-` and every trailing one kept with `+`.
+    fn block_scalar(
+        &mut self,
+        header: &str,
+        number: usize,
+        indent: usize,
+    ) -> Result<Value, String> {
+        let header = strip_comment(header).trim();
+        let literal = header.starts_with('|');
+        let chomping = match &header[1..] {
+            "" => None,
+            "-" => Some(false),
+            "+" => Some(true),
+            _ => {
+                return Err(error(
+                    number,
+                    &format!("unsupported block header {}", header),
+                ))
+            }
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+        let mut block_indent = None;
+        while let Some(line) = self.lines.get(self.current) {
+            if line.text.is_empty() {
+                lines.push(String::new());
+                self.current += 1;
+                continue;
+            }
+            let expected = *block_indent.get_or_insert(line.indent);
+            if line.indent <= indent || line.indent < expected {
+                break;
+            }
+            lines.push(format!(
+                "{}{}",
+                " ".repeat(line.indent - expected),
+                line.text
+            ));
+            self.current += 1;
+        }
+        let content = lines
+            .iter()
+            .rposition(|line| !line.is_empty())
+            .map_or(0, |i| i + 1);
+        let trailing = lines.len() - content;
+        // Blank lines after the block belong to whatever follows
+        if chomping != Some(true) {
+            self.current -= trailing;
+        }
+        lines.truncate(content);
+
+        let mut text = if literal {
+            lines.join("\n")
+        } else {
+            let mut folded = String::new();
+            for (i, line) in lines.iter().enumerate() {
+                // Blank lines stand for the line breaks, lines indented
+                // further keep theirs
+                if i > 0 {
+                    let previous = &lines[i - 1];
+                    if line.is_empty() || line.starts_with(' ') || previous.starts_with(' ') {
+                        folded.push('\n');
+                    } else if !previous.is_empty() {
+                        folded.push(' ');
+                    }
+                }
+                folded.push_str(line);
+            }
+            folded
+        };
+        match chomping {
+            _ if content == 0 => (),
+            Some(false) => (),
+            Some(true) => text.push_str(&"\n".repeat(trailing + 1)),
+            None => text.push('\n'),
+        }
+        Ok(Value::from(text))
+    }
+
+    fn inline(&self, text: &str, number: usize) -> Result<Value, String> {
+        let mut flow = Flow {
+            chars: text.chars().collect(),
+            position: 0,
+            line: number,
+        };
+        let value = flow.value(false)?;
+        flow.skip_spaces();
+        if flow.position < flow.chars.len() {
+            return Err(error(number, &format!("unexpected text after {}", text)));
+        }
+        Ok(value)
+    }
+}
+
+// Values written on one line: scalars and the bracketed [a, b] and {a: b}
+struct Flow {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+}
+
+impl Flow {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek() == Some(' ') {
+            self.position += 1;
+        }
+    }
+
+    fn value(&mut self, in_flow: bool) -> Result<Value, String> {
+        self.skip_spaces();
+        match self.peek() {
+            Some('[') => self.sequence(),
+            Some('{') => self.mapping(),
+            Some('"') => self.double_quoted().map(Value::from),
+            Some('\'') => self.single_quoted().map(Value::from),
+            Some('&' | '*' | '!') => Err(error(
+                self.line,
+                "anchors, aliases and tags are not supported",
+            )),
+            _ => {
+                let start = self.position;
+                while let Some(c) = self.peek() {
+                    let ends_key =
+                        c == ':' && self.chars.get(self.position + 1).is_none_or(|c| *c == ' ');
+                    if in_flow && (matches!(c, ',' | ']' | '}') || ends_key) {
+                        break;
+                    }
+                    self.position += 1;
+                }
+                let text: String = self.chars[start..self.position].iter().collect();
+                Ok(plain(text.trim()))
+            }
+        }
+    }
+
+    fn sequence(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.peek() == Some(']') {
+                self.position += 1;
+                return Ok(Value::List(gc::items(items)));
+            }
+            items.push(self.value(true)?);
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some(']') => (),
+                _ => return Err(error(self.line, "expected ',' or ']' in sequence")),
+            }
+        }
+    }
+
+    fn mapping(&mut self) -> Result<Value, String> {
+        self.position += 1;
+        let mut entries: Vec<(Value, Value)> = Vec::new();
+        loop {
+            self.skip_spaces();
+            if self.peek() == Some('}') {
+                self.position += 1;
+                return Ok(Value::Map(gc::entries(entries.into_iter().collect())));
+            }
+            let key = self.value(true)?;
+            if !key.is_hashable() {
+                return Err(error(self.line, "keys must be plain values"));
+            }
+            self.skip_spaces();
+            let value = if self.peek() == Some(':') {
+                self.position += 1;
+                self.value(true)?
+            } else {
+                Value::None
+            };
+            if entries.iter().any(|(existing, _)| *existing == key) {
+                return Err(error(self.line, &format!("key {} is defined twice", key)));
+            }
+            entries.push((key, value));
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.position += 1,
+                Some('}') => (),
+                _ => return Err(error(self.line, "expected ',' or '}' in mapping")),
+            }
+        }
+    }
+
+    fn double_quoted(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            let c = self
+                .peek()
+                .ok_or_else(|| error(self.line, "unterminated string"))?;
+            self.position += 1;
+            match c {
+                '"' => return Ok(text),
+                '\\' => {
+                    let escaped = self
+                        .peek()
+                        .ok_or_else(|| error(self.line, "unterminated string"))?;
+                    self.position += 1;
+                    let length = match escaped {
+                        'x' => 2,
+                        'u' => 4,
+                        'U' => 8,
+                        _ => 0,
+                    };
+                    text.push(match escaped {
+                        '0' => '\0',
+                        'a' => '\u{7}',
+                        'b' => '\u{8}',
+                        't' => '\t',
+                        'n' => '\n',
+                        'v' => '\u{b}',
+                        'f' => '\u{c}',
+                        'r' => '\r',
+                        'e' => '\u{1b}',
+                        ' ' | '"' | '/' | '\\' => escaped,
+                        'x' | 'u' | 'U' => {
+                            let digits: String =
+                                self.chars.iter().skip(self.position).take(length).collect();
+                            self.position += digits.chars().count();
+                            u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .filter(|_| digits.len() == length)
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| {
+                                    error(
+                                        self.line,
+                                        &format!("invalid escape \\{}{}", escaped, digits),
+                                    )
+                                })?
+                        }
+                        c => return Err(error(self.line, &format!("invalid escape \\{}", c))),
+                    });
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn single_quoted(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                Some('\'') if self.chars.get(self.position + 1) == Some(&'\'') => {
+                    self.position += 2;
+                    text.push('\'');
+                }
+                Some('\'') => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(c) => {
+                    self.position += 1;
+                    text.push(c);
+                }
+                None => return Err(error(self.line, "unterminated string")),
+            }
+        }
+    }
+}
+
+// Unquoted values are read with the YAML 1.2 core schema
+fn plain(text: &str) -> Value {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => return Value::None,
+        "true" | "True" | "TRUE" => return Value::True,
+        "false" | "False" | "FALSE" => return Value::False,
+        ".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" => {
+            return Value::Float(f64::INFINITY)
+        }
+        "-.inf" | "-.Inf" | "-.INF" => return Value::Float(f64::NEG_INFINITY),
+        ".nan" | ".NaN" | ".NAN" => return Value::Float(f64::NAN),
+        _ => (),
+    }
+    let unsigned = text.trim_start_matches(['+', '-']);
+    if unsigned.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        if let Some(hex) = text.strip_prefix("0x") {
+            if let Ok(number) = i64::from_str_radix(hex, 16) {
+                return Value::Integer(number);
+            }
+        }
+        if let Some(octal) = text.strip_prefix("0o") {
+            if let Ok(number) = i64::from_str_radix(octal, 8) {
+                return Value::Integer(number);
+            }
+        }
+        if unsigned.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(number) = text.parse() {
+                return Value::Integer(number);
+            }
+        }
+        let digits = unsigned.trim_start_matches('.');
+        if digits.starts_with(|c: char| c.is_ascii_digit()) {
+            if let Ok(number) = text.parse() {
+                return Value::Float(number);
+            }
+        }
+    }
+    Value::from(text)
+}
+
+// Mappings become maps and sequences lists. Only one document is read,
+// anchors, aliases and tags are not supported.
+pub fn yaml_parse(_vm: &mut VM, args: Vec<Value>) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::String(text) => text,
+        value => {
+            return Err(format!(
+                "Expected yaml text as a string, got {} instead",
+                value.type_of()
+            ))
+        }
+    };
+    let mut lines = split_lines(text)?;
+    // The document markers are optional when there is a single document
+    let start = lines
+        .iter()
+        .position(|line| !strip_comment(&line.text).trim().is_empty());
+    if let Some(start) = start {
+        let first = &lines[start];
+        if first.indent == 0 && (first.text.starts_with('%') || first.text == "---") {
+            let marker = lines
+                .iter()
+                .position(|line| line.indent == 0 && line.text == "---")
+                .ok_or_else(|| error(first.number, "expected --- after the directives"))?;
+            lines.drain(..=marker);
+        }
+    }
+    if let Some(end) = lines
+        .iter()
+        .position(|line| line.indent == 0 && (line.text == "---" || line.text == "..."))
+    {
+        if lines[end].text == "---" {
+            return Err(error(lines[end].number, "only one document can be read"));
+        }
+        lines.truncate(end);
+    }
+
+    let mut parser = Parser { lines, current: 0 };
+    let value = parser.node(None)?;
+    match parser.peek() {
+        Some(line) => Err(error(line.number, "unexpected content after the document")),
+        None => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<String, String> {
+        yaml_parse(&mut VM::new(), vec![Value::from(text)]).map(|value| value.to_string())
+    }
+
+    #[test]
+    fn mappings_sequences_and_scalars_are_read() {
+        let text = "name: app\nports:\n  - 80\n  - 443\nnested:\n  enabled: true\n  ratio: 1.5\n  missing: null\ntags: [a, b]\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            "{\"name\": \"app\", \"ports\": [80, 443], \"nested\": {\"enabled\": true, \"ratio\": 1.5, \"missing\": none}, \"tags\": [\"a\", \"b\"]}"
+        );
+        assert_eq!(
+            parse("- name: ann\n  age: 30\n- name: bob\n").unwrap(),
+            "[{\"name\": \"ann\", \"age\": 30}, {\"name\": \"bob\"}]"
+        );
+    }
+
+    #[test]
+    fn invalid_documents_are_errors() {
+        assert!(parse("a: 1\na: 2\n").is_err());
+        assert!(parse("a:\n\t- 1\n").is_err());
+        assert!(parse("key: [1, 2\n").is_err());
+    }
+}
@@ -1,13 +1,41 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::process::{Child, ChildStdin};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 
-use crate::{chunk::Chunk, scanner::TokenType};
+use crate::{chunk::Chunk, scanner::TokenType, thread::Sendable, value::Value};
 
 #[derive(Debug, Clone)]
 pub struct ObjFunction {
     pub name: String,
     pub chunk: Chunk,
-    pub function_info: FunctionInfo,
+    // Shared with the compiler and every copy of the function
+    pub function_info: Rc<FunctionInfo>,
     pub functions_count: usize,
+    // Most values the frame holds at once, its slots are allocated that large
+    pub max_stack: usize,
+    pub is_generator: bool,
+    // Top level variables of a script with their slot and declared type,
+    // kept as globals of the VM once the script is done
+    pub exports: Vec<(String, usize, TokenType)>,
+    // Results of earlier calls by their arguments, only set on the copy
+    // memoize returns
+    pub memo: Option<Rc<RefCell<HashMap<String, Value>>>>,
+    // Set on the copy trace returns, every call is reported with its arguments
+    pub traced: bool,
+    // Leading arguments set by bind, the function info only lists the others
+    pub bound: Vec<Value>,
+}
+
+impl Default for ObjFunction {
+    fn default() -> ObjFunction {
+        ObjFunction::new()
+    }
 }
 
 impl ObjFunction {
@@ -15,14 +43,44 @@ impl ObjFunction {
         ObjFunction {
             name: String::new(),
             chunk: Chunk::new(),
-            function_info: FunctionInfo::new(String::new()),
+            function_info: Rc::new(FunctionInfo::new(String::new())),
             functions_count: 0,
+            max_stack: 0,
+            is_generator: false,
+            exports: Vec::new(),
+            memo: None,
+            traced: false,
+            bound: Vec::new(),
         }
     }
 
     pub fn had_error(&self) -> bool {
         self.chunk.had_error
     }
+
+    // This function followed by every function defined in it, each once even
+    // though function constants are copied into the chunks compiled after them
+    pub fn with_nested(&self) -> Vec<ObjFunction> {
+        let mut functions = vec![self.clone()];
+        let mut seen = HashSet::new();
+        let mut index = 0;
+        while index < functions.len() {
+            let nested: Vec<ObjFunction> = functions[index]
+                .chunk
+                .constants
+                .iter()
+                .filter_map(|constant| match constant {
+                    Value::ObjFunction(function) if seen.insert(function.name.clone()) => {
+                        Some(function.as_ref().clone())
+                    }
+                    _ => None,
+                })
+                .collect();
+            functions.extend(nested);
+            index += 1;
+        }
+        functions
+    }
 }
 
 impl Display for ObjFunction {
@@ -51,4 +109,240 @@ impl FunctionInfo {
             arg_types: Vec::new(),
         }
     }
+
+    // The first argument whose value does not match the declared type, as
+    // the error a call with them fails with
+    pub fn check_argument_types(&self, args: &[Value]) -> Result<(), String> {
+        let mismatch = args
+            .iter()
+            .zip(&self.arg_types)
+            .zip(&self.arg_names)
+            .find(|((value, type_), _)| !type_.is_value_correct_type(value));
+        match mismatch {
+            Some(((value, type_), name)) => Err(format!(
+                "Argument {} of function {} must be of type {}, got {} instead",
+                name,
+                self.name,
+                type_,
+                value.type_of()
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ObjIterator {
+    Sequence {
+        items: Rc<RefCell<Vec<Value>>>,
+        position: usize,
+    },
+    Chars {
+        chars: Vec<char>,
+        position: usize,
+    },
+    // Goes through the items from the last one, without copying them
+    Backwards {
+        items: Rc<RefCell<Vec<Value>>>,
+        remaining: usize,
+    },
+    // Integers from next up to stop, excluded, going by step which is never 0
+    Range {
+        next: i64,
+        stop: i64,
+        step: i64,
+    },
+    // The values of another iterator along with their position
+    Enumerate {
+        source: Value,
+        index: i64,
+    },
+    // One value of each iterator at a time, until one of them is done
+    Zip {
+        sources: Vec<Value>,
+    },
+}
+
+// What taking the next value of an iterator needs, the iterators that pull
+// from others leave those to the VM since they can be generators to resume
+pub enum Advance {
+    Value(Option<Value>),
+    Enumerate(Value, i64),
+    Zip(Vec<Value>),
+}
+
+impl ObjIterator {
+    pub fn advance(&mut self) -> Advance {
+        match self {
+            ObjIterator::Sequence { items, position } => {
+                let item = items.borrow().get(*position).cloned();
+                *position += 1;
+                Advance::Value(item)
+            }
+            ObjIterator::Chars { chars, position } => {
+                let item = chars.get(*position).map(|c| Value::from(c.to_string()));
+                *position += 1;
+                Advance::Value(item)
+            }
+            ObjIterator::Backwards { items, remaining } => {
+                // Items removed from the list meanwhile are skipped
+                *remaining = (*remaining).min(items.borrow().len());
+                if *remaining == 0 {
+                    return Advance::Value(None);
+                }
+                *remaining -= 1;
+                Advance::Value(items.borrow().get(*remaining).cloned())
+            }
+            ObjIterator::Range { next, stop, step } => {
+                let more = if *step > 0 { next < stop } else { next > stop };
+                if !more {
+                    return Advance::Value(None);
+                }
+                let value = *next;
+                *next = next.checked_add(*step).unwrap_or(*stop);
+                Advance::Value(Some(Value::Integer(value)))
+            }
+            ObjIterator::Enumerate { source, index } => {
+                *index += 1;
+                Advance::Enumerate(source.clone(), *index - 1)
+            }
+            ObjIterator::Zip { sources } => Advance::Zip(sources.clone()),
+        }
+    }
+}
+
+// Code registered by a defer statement, run once the scope holding it ends
+#[derive(Clone, Debug)]
+pub struct Deferred {
+    // Where the deferred code starts in the chunk
+    pub ip: usize,
+    // How many values the frame held at the defer, the code runs on that stack
+    pub height: usize,
+    // Scope depth of the defer statement
+    pub depth: usize,
+}
+
+// Where a try block catches errors, set when the block starts
+#[derive(Clone, Debug)]
+pub struct TryHandler {
+    // Start of the catch block, taken once it catches an error. The handler
+    // stays until the catch block ends, to run the finally code.
+    pub catch_ip: Option<usize>,
+    // Start of the finally code, run like deferred code when the try or catch
+    // block is left
+    pub finally_ip: Option<usize>,
+    // Values the frame held at the try, the catch block starts from there
+    pub height: usize,
+    // Deferred code registered before the try, which the catch block still
+    // runs in the scopes of
+    pub deferred: usize,
+    // Deferred code already running at the try
+    pub running: usize,
+}
+
+// A runtime error as a catch block sees it
+#[derive(Debug)]
+pub struct ObjError {
+    // What went wrong, `RuntimeError` for the errors of the VM itself
+    pub kind: Rc<str>,
+    pub message: String,
+    // Where it was raised
+    pub line: usize,
+    pub function: Rc<str>,
+    pub file: Rc<str>,
+    // Every call leading to it, the outermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    pub function: Rc<str>,
+    pub file: Rc<str>,
+    pub line: usize,
+}
+
+impl Display for ObjError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<error: {}>", self.message)
+    }
+}
+
+// A suspended generator call, the VM rebuilds a call frame from it on every resume.
+#[derive(Debug)]
+pub struct ObjGenerator {
+    pub function: Rc<ObjFunction>,
+    pub ip: usize,
+    pub slots: Vec<Value>,
+    // Kept while suspended, the scopes that deferred it are still open
+    pub deferred: Vec<Deferred>,
+    pub handlers: Vec<TryHandler>,
+    pub started: bool,
+    pub awaiting_argument: bool,
+    pub done: bool,
+}
+
+impl ObjGenerator {
+    pub fn new(function: Rc<ObjFunction>, slots: Vec<Value>) -> ObjGenerator {
+        ObjGenerator {
+            function,
+            ip: 0,
+            slots,
+            deferred: Vec::new(),
+            handlers: Vec::new(),
+            started: false,
+            awaiting_argument: false,
+            done: false,
+        }
+    }
+}
+
+impl Display for ObjGenerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<generator {}>", self.function.name)
+    }
+}
+
+// Both ends of the channel travel together so any thread holding it can send and receive.
+#[derive(Debug, Clone)]
+pub struct ObjChannel {
+    pub sender: Sender<Sendable>,
+    pub receiver: Arc<Mutex<Receiver<Sendable>>>,
+}
+
+impl Default for ObjChannel {
+    fn default() -> ObjChannel {
+        ObjChannel::new()
+    }
+}
+
+impl ObjChannel {
+    pub fn new() -> ObjChannel {
+        let (sender, receiver) = mpsc::channel();
+        ObjChannel {
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+}
+
+// Writes go straight to the stream behind the reader
+#[derive(Debug)]
+pub struct ObjSocket {
+    pub reader: BufReader<TcpStream>,
+}
+
+// A child process, its output is read by threads of its own so that a full
+// pipe never blocks the child while the script waits on the other one
+#[derive(Debug)]
+pub struct ObjProcess {
+    pub child: Child,
+    pub stdin: Option<ChildStdin>,
+    pub stdout: Receiver<String>,
+    pub stderr: Receiver<String>,
+}
+
+// An open SQLite connection, closed by the sqlite natives when dropped
+#[derive(Debug)]
+pub struct ObjDatabase {
+    pub connection: *mut std::ffi::c_void,
 }
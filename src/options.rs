@@ -0,0 +1,121 @@
+// What natives may reach outside of the script. Each native needing one of
+// these is refused at dispatch unless the policy allows it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    Filesystem,
+    Network,
+    Process,
+    Env,
+    Eval,
+}
+
+impl Capability {
+    pub fn from_name(name: &str) -> Option<Capability> {
+        match name {
+            "filesystem" => Some(Capability::Filesystem),
+            "network" => Some(Capability::Network),
+            "process" => Some(Capability::Process),
+            "env" => Some(Capability::Env),
+            "eval" => Some(Capability::Eval),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::Filesystem => "filesystem",
+            Capability::Network => "network",
+            Capability::Process => "process",
+            Capability::Env => "env",
+            Capability::Eval => "eval",
+        }
+    }
+}
+
+// Everything is allowed by default, embedders running untrusted scripts
+// start from deny_all and turn on what they trust the script with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SandboxPolicy {
+    pub filesystem: bool,
+    pub network: bool,
+    pub process: bool,
+    pub env: bool,
+    pub eval: bool,
+}
+
+impl SandboxPolicy {
+    pub fn allow_all() -> SandboxPolicy {
+        SandboxPolicy {
+            filesystem: true,
+            network: true,
+            process: true,
+            env: true,
+            eval: true,
+        }
+    }
+
+    pub fn deny_all() -> SandboxPolicy {
+        SandboxPolicy {
+            filesystem: false,
+            network: false,
+            process: false,
+            env: false,
+            eval: false,
+        }
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Filesystem => self.filesystem,
+            Capability::Network => self.network,
+            Capability::Process => self.process,
+            Capability::Env => self.env,
+            Capability::Eval => self.eval,
+        }
+    }
+
+    pub fn allow(&mut self, capability: Capability) {
+        match capability {
+            Capability::Filesystem => self.filesystem = true,
+            Capability::Network => self.network = true,
+            Capability::Process => self.process = true,
+            Capability::Env => self.env = true,
+            Capability::Eval => self.eval = true,
+        }
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> SandboxPolicy {
+        SandboxPolicy::allow_all()
+    }
+}
+
+// How a VM compiles and runs scripts, set once by the host with
+// VM::with_options or piece by piece with the VM setters
+#[derive(Clone, Copy, Debug)]
+pub struct VmOptions {
+    pub sandbox: SandboxPolicy,
+    // Conditions must be bool and values of unrelated types cannot be compared
+    pub strict: bool,
+    // Checks the stack usage of compiled code before running it
+    pub verify_bytecode: bool,
+    // How many threads compile the top level function bodies of a script
+    pub compile_threads: usize,
+    // How many lists, sets and maps are created between two cycle collections
+    pub gc_threshold: usize,
+}
+
+pub const DEFAULT_GC_THRESHOLD: usize = 10_000;
+
+impl Default for VmOptions {
+    fn default() -> VmOptions {
+        VmOptions {
+            sandbox: SandboxPolicy::default(),
+            strict: false,
+            verify_bytecode: false,
+            compile_threads: 1,
+            gc_threshold: DEFAULT_GC_THRESHOLD,
+        }
+    }
+}
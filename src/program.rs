@@ -0,0 +1,139 @@
+use crate::compiler::Compiler;
+pub use crate::compiler::Diagnostic;
+use crate::object::ObjFunction;
+use crate::options::VmOptions;
+use crate::scanner::TokenType;
+use crate::verify::verify;
+
+// A script compiled without running it, for tools building on the front end
+// like formatters, analyzers and the language server
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+    // The script first, then every function defined in it
+    functions: Vec<ObjFunction>,
+    warnings: Vec<Diagnostic>,
+}
+
+// A name the script declares, with the type it was declared with if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub declared_type: Option<String>,
+}
+
+impl Symbol {
+    fn new(name: &str, declared_type: TokenType) -> Symbol {
+        Symbol {
+            name: name.to_owned(),
+            declared_type: (declared_type != TokenType::None).then(|| declared_type.to_string()),
+        }
+    }
+}
+
+// Compiles the source as the VM would run it, every error found if it does
+// not compile
+pub fn compile(source: &str) -> Result<CompiledProgram, Vec<Diagnostic>> {
+    compile_with(source, "", &VmOptions::default())
+}
+
+// Like compile, with chunks naming the file the source was read from. Of the
+// options only strict changes what compiles, compile_threads how.
+pub fn compile_with(
+    source: &str,
+    file: &str,
+    options: &VmOptions,
+) -> Result<CompiledProgram, Vec<Diagnostic>> {
+    let mut compiler = Compiler::new();
+    compiler.set_strict(options.strict);
+    compiler.set_threads(options.compile_threads);
+    compiler.set_file(file);
+    compiler.set_print_code(false);
+    let script = compiler.compile(source.to_owned());
+    let errors = compiler.take_errors();
+    let warnings = compiler.take_warnings();
+    if script.had_error() {
+        return Err(errors);
+    }
+    Ok(CompiledProgram {
+        functions: script.with_nested(),
+        warnings,
+    })
+}
+
+impl CompiledProgram {
+    pub fn script(&self) -> &ObjFunction {
+        &self.functions[0]
+    }
+
+    // The script first, then every function defined in it. Each has its chunk
+    // with the code and constants.
+    pub fn functions(&self) -> &[ObjFunction] {
+        &self.functions
+    }
+
+    pub fn function(&self, name: &str) -> Option<&ObjFunction> {
+        self.functions[1..]
+            .iter()
+            .find(|function| function.name == name)
+    }
+
+    // Top level variables, in the order of their slots
+    pub fn globals(&self) -> Vec<Symbol> {
+        let mut exports = self.script().exports.clone();
+        exports.sort_by_key(|(_, slot, _)| *slot);
+        exports
+            .iter()
+            .map(|(name, _, declared_type)| Symbol::new(name, *declared_type))
+            .collect()
+    }
+
+    // The arguments of a function as calls to it are checked against
+    pub fn parameters(&self, name: &str) -> Option<Vec<Symbol>> {
+        let info = &self.function(name)?.function_info;
+        Some(
+            info.arg_names
+                .iter()
+                .zip(&info.arg_types)
+                .map(|(name, declared_type)| Symbol::new(name, *declared_type))
+                .collect(),
+        )
+    }
+
+    // Code that compiles but is likely a mistake
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    // Problems with the stack usage of the bytecode, which the compiler
+    // should never produce
+    pub fn verify(&self) -> Vec<String> {
+        verify(self.script())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_the_same_source_twice() {
+        let source = "f: int x {\n    print x\n}\nf(1)\n";
+        for _ in 0..2 {
+            let program = compile(source).unwrap();
+            assert!(program.function("f").is_some());
+            assert_eq!(
+                program.parameters("f"),
+                Some(vec![Symbol {
+                    name: "x".to_owned(),
+                    declared_type: Some("int".to_owned()),
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn errors_of_a_compile_are_not_kept() {
+        assert!(compile("print (").is_err());
+        assert!(compile("print 1").is_ok());
+    }
+}
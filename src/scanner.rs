@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::sync::Arc;
 
 use crate::value::Value;
 
@@ -13,12 +14,16 @@ pub enum TokenType {
     RightSquareBracket,
     Comma,
     Dot,
+    DotDot,
     Minus,
     Plus,
     Colon,
     Semicolon,
     Slash,
     Star,
+    Pipe,
+    Ampersand,
+    At,
 
     // One or two character tokens
     Bang,
@@ -35,13 +40,16 @@ pub enum TokenType {
     String,
     Integer,
     Float,
+    Decimal,
 
     // Type annotations
     TypeFloat,
     TypeInt,
+    TypeDecimal,
     TypeString,
     TypeBool,
     TypeFunction,
+    TypeChannel,
 
     // none types
     FloatNone,
@@ -57,11 +65,15 @@ pub enum TokenType {
     False,
     For,
     In,
+    Is,
+    Not,
     Break,
     Continue,
     If,
     Or,
     None,
+    Nan,
+    Inf,
     Print,
     Return,
     Super,
@@ -69,6 +81,17 @@ pub enum TokenType {
     Cls,
     True,
     While,
+    Do,
+    Loop,
+    Yield,
+    Time,
+    Defer,
+    With,
+    As,
+    Try,
+    Catch,
+    Finally,
+    Raise,
 
     Error,
     Eof,
@@ -78,37 +101,27 @@ pub enum TokenType {
 
 impl TokenType {
     pub fn is_type(&self) -> bool {
-        match self {
+        matches!(
+            self,
             TokenType::TypeFloat
-            | TokenType::TypeInt
-            | TokenType::TypeString
-            | TokenType::TypeBool => true,
-            _ => false,
-        }
+                | TokenType::TypeInt
+                | TokenType::TypeDecimal
+                | TokenType::TypeString
+                | TokenType::TypeBool
+                | TokenType::TypeFunction
+                | TokenType::TypeChannel
+        )
     }
 
     pub fn is_value_correct_type(&self, value: &Value) -> bool {
         match self {
-            TokenType::TypeFloat => match value {
-                Value::Float(_) => true,
-                _ => false,
-            },
-            TokenType::TypeInt => match value {
-                Value::Integer(_) => true,
-                _ => false,
-            },
-            TokenType::TypeString => match value {
-                Value::String(_) => true,
-                _ => false,
-            },
-            TokenType::TypeBool => match value {
-                Value::True | Value::False => true,
-                _ => false,
-            },
-            TokenType::TypeFunction => match value {
-                Value::ObjFunction(_) => true,
-                _ => false,
-            },
+            TokenType::TypeFloat => matches!(value, Value::Float(_)),
+            TokenType::TypeInt => matches!(value, Value::Integer(_)),
+            TokenType::TypeDecimal => matches!(value, Value::Decimal(_)),
+            TokenType::TypeString => matches!(value, Value::String(_)),
+            TokenType::TypeBool => matches!(value, Value::True | Value::False),
+            TokenType::TypeFunction => matches!(value, Value::ObjFunction(_)),
+            TokenType::TypeChannel => matches!(value, Value::Channel(_)),
             TokenType::None => true,
             _ => false,
         }
@@ -116,31 +129,44 @@ impl TokenType {
 
     pub fn is_token_correct_type(&self, token: &Token) -> bool {
         match self {
-            TokenType::TypeFloat => match token.r#type {
-                TokenType::Float => true,
-                _ => false,
-            },
-            TokenType::TypeInt => match token.r#type {
-                TokenType::Integer => true,
-                _ => false,
-            },
-            TokenType::TypeString => match token.r#type {
-                TokenType::String => true,
-                _ => false,
-            },
-            TokenType::TypeBool => match token.r#type {
-                TokenType::True | TokenType::False => true,
-                _ => false,
-            },
-            TokenType::TypeFunction => match token.r#type {
-                TokenType::TypeFunction => true,
-                _ => false,
-            },
+            TokenType::TypeFloat => matches!(token.r#type, TokenType::Float),
+            TokenType::TypeInt => matches!(token.r#type, TokenType::Integer),
+            TokenType::TypeDecimal => matches!(token.r#type, TokenType::Decimal),
+            TokenType::TypeString => matches!(token.r#type, TokenType::String),
+            TokenType::TypeBool => matches!(token.r#type, TokenType::True | TokenType::False),
+            TokenType::TypeFunction => matches!(token.r#type, TokenType::TypeFunction),
             TokenType::None => true,
             _ => false,
         }
     }
 
+    // A line ending with one of these tokens continues on the next line
+    pub fn continues_line(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Plus
+                | TokenType::DotDot
+                | TokenType::Minus
+                | TokenType::Star
+                | TokenType::Slash
+                | TokenType::Pipe
+                | TokenType::Ampersand
+                | TokenType::Comma
+                | TokenType::Equal
+                | TokenType::EqualEqual
+                | TokenType::BangEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::Less
+                | TokenType::LessEqual
+                | TokenType::And
+                | TokenType::Or
+                | TokenType::In
+                | TokenType::Is
+                | TokenType::Not
+        )
+    }
+
     pub fn get_none_type(&self) -> Value {
         match self {
             TokenType::TypeFloat => Value::FloatNone,
@@ -163,12 +189,16 @@ impl Display for TokenType {
             TokenType::RightSquareBracket => "]",
             TokenType::Comma => ",",
             TokenType::Dot => ".",
+            TokenType::DotDot => "..",
             TokenType::Minus => "-",
             TokenType::Plus => "+",
             TokenType::Colon => ":",
             TokenType::Semicolon => ";",
             TokenType::Slash => "/",
             TokenType::Star => "*",
+            TokenType::Pipe => "|",
+            TokenType::Ampersand => "&",
+            TokenType::At => "@",
             TokenType::Bang => "!",
             TokenType::BangEqual => "!=",
             TokenType::Equal => "=",
@@ -181,22 +211,29 @@ impl Display for TokenType {
             TokenType::String => "string",
             TokenType::Integer => "integer",
             TokenType::Float => "float",
+            TokenType::Decimal => "decimal",
             TokenType::TypeFloat => "float",
             TokenType::TypeInt => "int",
+            TokenType::TypeDecimal => "decimal",
             TokenType::TypeString => "string",
             TokenType::TypeBool => "bool",
             TokenType::TypeFunction => "function",
+            TokenType::TypeChannel => "chan",
             TokenType::And => "and",
             TokenType::Class => "class",
             TokenType::Else => "else",
             TokenType::False => "false",
             TokenType::For => "for",
             TokenType::In => "in",
+            TokenType::Is => "is",
+            TokenType::Not => "not",
             TokenType::Break => "break",
             TokenType::Continue => "continue",
             TokenType::If => "if",
             TokenType::Or => "or",
             TokenType::None => "none",
+            TokenType::Nan => "nan",
+            TokenType::Inf => "inf",
             TokenType::Print => "print",
             TokenType::Return => "return",
             TokenType::Super => "super",
@@ -204,6 +241,17 @@ impl Display for TokenType {
             TokenType::Cls => "cls",
             TokenType::True => "true",
             TokenType::While => "while",
+            TokenType::Yield => "yield",
+            TokenType::Do => "do",
+            TokenType::Loop => "loop",
+            TokenType::Time => "time",
+            TokenType::Defer => "defer",
+            TokenType::With => "with",
+            TokenType::As => "as",
+            TokenType::Try => "try",
+            TokenType::Catch => "catch",
+            TokenType::Finally => "finally",
+            TokenType::Raise => "raise",
             TokenType::Error => "error",
             TokenType::Eof => "eof",
             TokenType::Newline => "newline",
@@ -223,6 +271,8 @@ pub struct Token {
     pub r#type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    // Column of the first character of the lexeme, counting from 1
+    pub column: usize,
 }
 
 impl Token {
@@ -231,14 +281,21 @@ impl Token {
             r#type,
             lexeme: String::new(),
             line,
+            column: 0,
         }
     }
 
+    // Column just past the last character of the lexeme
+    pub fn end_column(&self) -> usize {
+        self.column + self.lexeme.chars().count()
+    }
+
     pub fn type_of(&self) -> String {
         match self.r#type {
             TokenType::True => "bool".to_owned(),
             TokenType::False => "bool".to_owned(),
             TokenType::Float => "float".to_owned(),
+            TokenType::Decimal => "decimal".to_owned(),
             TokenType::Integer => "int".to_owned(),
             TokenType::String => "string".to_owned(),
             _ => self.lexeme.clone(),
@@ -251,7 +308,13 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
-    source: String,
+    // Positions count characters rather than bytes. Shared by the copies of
+    // the scanner, e.g. of a function body compiled on another thread.
+    source: Arc<[char]>,
+    // Line breaks inside parentheses or brackets, or after an operator, do
+    // not end the statement
+    nesting: usize,
+    last_type: TokenType,
 }
 
 impl Scanner {
@@ -260,17 +323,38 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
-            source,
+            source: source.chars().collect(),
+            nesting: 0,
+            last_type: TokenType::Newline,
         }
     }
 
+    pub fn source(&self) -> String {
+        self.source.iter().collect()
+    }
+
     pub fn reset(&mut self) {
         self.start = 0;
         self.current = 0;
         self.line = 1;
+        self.nesting = 0;
+        self.last_type = TokenType::Newline;
     }
 
     pub fn scan_token(&mut self) -> Token {
+        let token = self.scan();
+        match token.r#type {
+            TokenType::LeftParen | TokenType::LeftSquareBracket => self.nesting += 1,
+            TokenType::RightParen | TokenType::RightSquareBracket => {
+                self.nesting = self.nesting.saturating_sub(1)
+            }
+            _ => (),
+        }
+        self.last_type = token.r#type;
+        token
+    }
+
+    fn scan(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
 
@@ -282,7 +366,7 @@ impl Scanner {
         if c.is_alphabetic() {
             return self.identifier();
         }
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number();
         }
         match c {
@@ -293,13 +377,23 @@ impl Scanner {
             '[' => return self.make_token(TokenType::LeftSquareBracket),
             ']' => return self.make_token(TokenType::RightSquareBracket),
             ',' => return self.make_token(TokenType::Comma),
-            '.' => return self.make_token(TokenType::Dot),
+            '.' => {
+                if self.match_char('.') {
+                    return self.make_token(TokenType::DotDot);
+                } else {
+                    return self.make_token(TokenType::Dot);
+                }
+            }
             '-' => return self.make_token(TokenType::Minus),
             '+' => return self.make_token(TokenType::Plus),
             ':' => return self.make_token(TokenType::Colon),
-            ';' => return self.make_token(TokenType::Semicolon),
+            // A semicolon ends a statement exactly like a line break does
+            ';' => return self.make_token(TokenType::Newline),
             '/' => return self.make_token(TokenType::Slash),
             '*' => return self.make_token(TokenType::Star),
+            '|' => return self.make_token(TokenType::Pipe),
+            '&' => return self.make_token(TokenType::Ampersand),
+            '@' => return self.make_token(TokenType::At),
             '\n' => {
                 self.start = self.current;
                 let token = self.make_token(TokenType::Newline);
@@ -340,7 +434,7 @@ impl Scanner {
             _ => {}
         }
 
-        return self.error_token("Unexpected character.");
+        self.error_token("Unexpected character.")
     }
 
     fn skip_whitespace(&mut self) {
@@ -349,6 +443,10 @@ impl Scanner {
                 ' ' | '\r' | '\t' => {
                     self.current += 1;
                 }
+                '\n' if self.nesting > 0 || self.last_type.continues_line() => {
+                    self.line += 1;
+                    self.current += 1;
+                }
                 '-' => {
                     if self.peek_next() == '-' {
                         while self.peek() != '\n' && !self.is_at_end() {
@@ -378,7 +476,7 @@ impl Scanner {
 
     fn advance(&mut self) -> char {
         self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        self.source.get(self.current - 1).copied().unwrap_or('\0')
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -386,7 +484,7 @@ impl Scanner {
             return false;
         }
 
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false;
         }
 
@@ -398,18 +496,18 @@ impl Scanner {
         if self.is_at_end() {
             return '\0';
         }
-        self.source.chars().nth(self.current).unwrap()
+        self.source.get(self.current).copied().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
         if self.current + 1 >= self.source.len() {
             return '\0';
         }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source.get(self.current + 1).copied().unwrap_or('\0')
     }
 
     fn string(&mut self) -> Token {
-        let quote = self.source.chars().nth(self.start).unwrap();
+        let quote = self.source.get(self.start).copied().unwrap_or('"');
         self.start += 1;
         let start_line = self.line;
         while self.peek() != quote && !self.is_at_end() {
@@ -428,24 +526,30 @@ impl Scanner {
 
         let token = self.make_token(TokenType::String);
         self.current += 1;
-        return token;
+        token
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
+        while self.peek().is_ascii_digit() {
             self.current += 1;
         }
 
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        let mut r#type = TokenType::Integer;
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.current += 1;
-            while self.peek().is_digit(10) {
+            while self.peek().is_ascii_digit() {
                 self.current += 1;
             }
-        } else {
-            return self.make_token(TokenType::Integer);
+            r#type = TokenType::Float;
         }
 
-        self.make_token(TokenType::Float)
+        // Decimal literals are written with a d suffix, as in 19.99d
+        if self.peek() == 'd' && !(self.peek_next().is_alphanumeric() || self.peek_next() == '_') {
+            self.current += 1;
+            r#type = TokenType::Decimal;
+        }
+
+        self.make_token(r#type)
     }
 
     fn identifier(&mut self) -> Token {
@@ -453,16 +557,12 @@ impl Scanner {
             self.current += 1;
         }
 
-        let token = self.make_token(self.identifier_type());
-        return token;
+        self.make_token(self.identifier_type())
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self
-            .source
-            .chars()
-            .skip(self.start)
-            .take(self.current - self.start)
+        match self.source[self.start..self.current]
+            .iter()
             .collect::<String>()
             .as_str()
         {
@@ -472,6 +572,8 @@ impl Scanner {
             "false" => TokenType::False,
             "for" => TokenType::For,
             "in" => TokenType::In,
+            "is" => TokenType::Is,
+            "not" => TokenType::Not,
             "break" => TokenType::Break,
             "continue" => TokenType::Continue,
             "if" => TokenType::If,
@@ -483,11 +585,27 @@ impl Scanner {
             "cls" => TokenType::Cls,
             "true" => TokenType::True,
             "while" => TokenType::While,
+            "do" => TokenType::Do,
+            "loop" => TokenType::Loop,
+            "yield" => TokenType::Yield,
+            "time" => TokenType::Time,
+            "defer" => TokenType::Defer,
+            "with" => TokenType::With,
+            "as" => TokenType::As,
+            "try" => TokenType::Try,
+            "catch" => TokenType::Catch,
+            "finally" => TokenType::Finally,
+            "raise" => TokenType::Raise,
             "none" => TokenType::None,
+            "nan" => TokenType::Nan,
+            "inf" => TokenType::Inf,
             "int" => TokenType::TypeInt,
+            "decimal" => TokenType::TypeDecimal,
             "float" => TokenType::TypeFloat,
             "bool" => TokenType::TypeBool,
             "string" => TokenType::TypeString,
+            "chan" => TokenType::TypeChannel,
+            "function" => TokenType::TypeFunction,
             _ => TokenType::Identifier,
         }
     }
@@ -495,13 +613,9 @@ impl Scanner {
     fn make_token(&self, r#type: TokenType) -> Token {
         Token {
             r#type,
-            lexeme: self
-                .source
-                .chars()
-                .skip(self.start)
-                .take(self.current - self.start)
-                .collect(),
+            lexeme: self.source[self.start..self.current].iter().collect(),
             line: self.line,
+            column: self.column(self.start),
         }
     }
 
@@ -510,6 +624,7 @@ impl Scanner {
             r#type: TokenType::Error,
             lexeme: message.to_string(),
             line: self.line,
+            column: self.column(self.start),
         }
     }
 
@@ -517,7 +632,16 @@ impl Scanner {
         Token {
             r#type: TokenType::Error,
             lexeme: message.to_string(),
-            line: line,
+            line,
+            column: self.column(self.start),
         }
     }
+
+    fn column(&self, index: usize) -> usize {
+        let line_start = self.source[..index]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |newline| newline + 1);
+        index - line_start + 1
+    }
 }
@@ -0,0 +1,213 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+
+use crate::chunk::{Chunk, OpCode, Span};
+use crate::datetime::{DateTime, Duration};
+use crate::decimal::Decimal;
+use crate::gc;
+use crate::object::{FunctionInfo, ObjChannel, ObjFunction};
+use crate::value::Value;
+
+// Values crossing a thread boundary are deep copied into this form since the
+// Rc backed containers cannot be shared, every thread works on its own copy.
+#[derive(Debug)]
+pub enum Sendable {
+    Float(f64),
+    FloatNone,
+    Integer(i64),
+    Decimal(Decimal),
+    DateTime(DateTime),
+    Duration(Duration),
+    IntegerNone,
+    String(String),
+    StringNone,
+    None,
+    True,
+    False,
+    BoolNone,
+    Function(Box<SendableFunction>),
+    FunctionNone,
+    List(Vec<Sendable>),
+    Set(Vec<Sendable>),
+    Map(Vec<(Sendable, Sendable)>),
+    Channel(ObjChannel),
+}
+
+#[derive(Debug)]
+pub struct SendableFunction {
+    name: String,
+    code: Vec<OpCode>,
+    lines: Vec<usize>,
+    spans: Vec<(Span, usize)>,
+    file: String,
+    constants: Vec<Sendable>,
+    // The caches start out empty on the other thread
+    call_sites: usize,
+    function_info: FunctionInfo,
+    functions_count: usize,
+    max_stack: usize,
+    is_generator: bool,
+}
+
+impl SendableFunction {
+    pub fn from_function(function: &ObjFunction) -> Result<SendableFunction, String> {
+        Ok(SendableFunction {
+            name: function.name.clone(),
+            code: function.chunk.code.clone(),
+            lines: function.chunk.lines.clone(),
+            spans: function.chunk.spans.clone(),
+            file: function.chunk.file.to_string(),
+            constants: function
+                .chunk
+                .constants
+                .iter()
+                .map(Sendable::from_value)
+                .collect::<Result<_, _>>()?,
+            call_sites: function.chunk.call_caches.borrow().len(),
+            function_info: function.function_info.as_ref().clone(),
+            functions_count: function.functions_count,
+            max_stack: function.max_stack,
+            is_generator: function.is_generator,
+        })
+    }
+
+    pub fn into_function(self) -> ObjFunction {
+        let mut chunk = Chunk::new();
+        chunk.code = self.code;
+        chunk.lines = self.lines;
+        chunk.spans = self.spans;
+        chunk.function_name = Rc::from(self.name.as_str());
+        chunk.file = Rc::from(self.file);
+        chunk.constants = self
+            .constants
+            .into_iter()
+            .map(Sendable::into_value)
+            .collect();
+        chunk.call_caches = RefCell::new((0..self.call_sites).map(|_| Weak::new()).collect());
+
+        ObjFunction {
+            name: self.name,
+            chunk,
+            function_info: Rc::new(self.function_info),
+            functions_count: self.functions_count,
+            max_stack: self.max_stack,
+            is_generator: self.is_generator,
+            // Only scripts export variables and threads run functions
+            exports: Vec::new(),
+            // The cache stays with the thread that filled it, tracing with the thread
+            // that asked for it
+            memo: None,
+            traced: false,
+            // Bound arguments travel in the slots the thread starts with
+            bound: Vec::new(),
+        }
+    }
+}
+
+impl Sendable {
+    pub fn from_value(value: &Value) -> Result<Sendable, String> {
+        Sendable::copy(value, &mut Vec::new())
+    }
+
+    // The containers holding the value are kept in copying, a container met
+    // again inside itself could not be copied in full
+    fn copy(value: &Value, copying: &mut Vec<usize>) -> Result<Sendable, String> {
+        let address = match value {
+            Value::List(l) => Some(Rc::as_ptr(l) as usize),
+            Value::Set(s) => Some(Rc::as_ptr(s) as usize),
+            Value::Map(m) => Some(Rc::as_ptr(m) as usize),
+            _ => None,
+        };
+        if let Some(address) = address {
+            if copying.contains(&address) {
+                return Err("Cyclic values cannot be sent to another thread".to_owned());
+            }
+            copying.push(address);
+        }
+        let sendable = match value {
+            Value::Float(f) => Sendable::Float(*f),
+            Value::FloatNone => Sendable::FloatNone,
+            Value::Integer(i) => Sendable::Integer(*i),
+            Value::Decimal(d) => Sendable::Decimal(**d),
+            Value::DateTime(d) => Sendable::DateTime(*d),
+            Value::Duration(d) => Sendable::Duration(*d),
+            Value::IntegerNone => Sendable::IntegerNone,
+            Value::String(s) => Sendable::String(s.to_string()),
+            Value::StringNone => Sendable::StringNone,
+            Value::None => Sendable::None,
+            Value::True => Sendable::True,
+            Value::False => Sendable::False,
+            Value::BoolNone => Sendable::BoolNone,
+            Value::ObjFunction(f) => {
+                Sendable::Function(Box::new(SendableFunction::from_function(f)?))
+            }
+            Value::ObjFunctionNone => Sendable::FunctionNone,
+            Value::List(l) => Sendable::List(
+                l.borrow()
+                    .iter()
+                    .map(|item| Sendable::copy(item, copying))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Set(s) => Sendable::Set(
+                s.borrow()
+                    .iter()
+                    .map(|element| Sendable::copy(element, copying))
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Map(m) => Sendable::Map(
+                m.borrow()
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((
+                            Sendable::copy(key, copying)?,
+                            Sendable::copy(value, copying)?,
+                        ))
+                    })
+                    .collect::<Result<_, String>>()?,
+            ),
+            Value::Channel(c) => Sendable::Channel((**c).clone()),
+            _ => {
+                return Err(format!(
+                    "Values of type {} cannot be sent to another thread",
+                    value.type_of()
+                ))
+            }
+        };
+        if address.is_some() {
+            copying.pop();
+        }
+        Ok(sendable)
+    }
+
+    pub fn into_value(self) -> Value {
+        match self {
+            Sendable::Float(f) => Value::Float(f),
+            Sendable::FloatNone => Value::FloatNone,
+            Sendable::Integer(i) => Value::Integer(i),
+            Sendable::Decimal(d) => Value::from(d),
+            Sendable::DateTime(d) => Value::DateTime(d),
+            Sendable::Duration(d) => Value::Duration(d),
+            Sendable::IntegerNone => Value::IntegerNone,
+            Sendable::String(s) => Value::from(s),
+            Sendable::StringNone => Value::StringNone,
+            Sendable::None => Value::None,
+            Sendable::True => Value::True,
+            Sendable::False => Value::False,
+            Sendable::BoolNone => Value::BoolNone,
+            Sendable::Function(f) => Value::ObjFunction(Rc::new(f.into_function())),
+            Sendable::FunctionNone => Value::ObjFunctionNone,
+            Sendable::List(l) => {
+                Value::List(gc::items(l.into_iter().map(Sendable::into_value).collect()))
+            }
+            Sendable::Set(s) => Value::Set(gc::elements(
+                s.into_iter().map(Sendable::into_value).collect(),
+            )),
+            Sendable::Map(m) => Value::Map(gc::entries(
+                m.into_iter()
+                    .map(|(key, value)| (key.into_value(), value.into_value()))
+                    .collect(),
+            )),
+            Sendable::Channel(c) => Value::Channel(Rc::new(c)),
+        }
+    }
+}
@@ -0,0 +1,97 @@
+#[cfg(feature = "os")]
+use std::fs::File;
+#[cfg(feature = "os")]
+use std::io::LineWriter;
+use std::io::Write;
+
+use crate::chunk::Chunk;
+use crate::value::Value;
+
+const DEFAULT_STACK_DEPTH: usize = 3;
+
+fn owner(chunk: &Chunk) -> &str {
+    if chunk.function_name.is_empty() {
+        "script"
+    } else {
+        &chunk.function_name
+    }
+}
+
+// Writes every executed instruction with the top of the stack, one line each:
+// `script 0011    5 OP_GET                        0            [1, "a"]`
+pub struct Trace {
+    out: Box<dyn Write>,
+    // Only these functions are traced when set, `script` is the top level
+    functions: Vec<String>,
+    stack_depth: usize,
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Write>) -> Trace {
+        Trace {
+            out,
+            functions: Vec::new(),
+            stack_depth: DEFAULT_STACK_DEPTH,
+        }
+    }
+
+    pub fn stdout() -> Trace {
+        Trace::new(Box::new(std::io::stdout()))
+    }
+
+    pub fn stderr() -> Trace {
+        Trace::new(Box::new(std::io::stderr()))
+    }
+
+    // Flushed line by line so the trace is complete even if the script exits
+    #[cfg(feature = "os")]
+    pub fn to_file(path: &str) -> Result<Trace, String> {
+        match File::create(path) {
+            Ok(file) => Ok(Trace::new(Box::new(LineWriter::new(file)))),
+            Err(e) => Err(format!("Could not create trace file {}: {}", path, e)),
+        }
+    }
+
+    pub fn filter_function(&mut self, name: &str) {
+        self.functions.push(name.to_owned());
+    }
+
+    // How many values from the top of the stack are shown per instruction
+    pub fn set_stack_depth(&mut self, stack_depth: usize) {
+        self.stack_depth = stack_depth;
+    }
+
+    fn is_traced(&self, chunk: &Chunk) -> bool {
+        self.functions.is_empty() || self.functions.iter().any(|name| name == owner(chunk))
+    }
+
+    // Returns false once the trace can no longer be written
+    pub fn instruction(&mut self, chunk: &Chunk, offset: usize, stack: &[Value]) -> bool {
+        if !self.is_traced(chunk) {
+            return true;
+        }
+        let instruction = match chunk.instruction_at(offset) {
+            Some(instruction) => instruction,
+            None => return true,
+        };
+
+        let operands: Vec<String> = instruction
+            .operands
+            .iter()
+            .map(|operand| operand.to_string())
+            .collect();
+        let top = &stack[stack.len().saturating_sub(self.stack_depth)..];
+        let values: Vec<String> = top.iter().map(|value| value.to_string()).collect();
+        writeln!(
+            self.out,
+            "{} {:04} {:4} {:30}{:12} [{}]",
+            owner(chunk),
+            offset,
+            instruction.line,
+            instruction.name,
+            operands.join(" "),
+            values.join(", ")
+        )
+        .is_ok()
+    }
+}
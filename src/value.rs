@@ -1,8 +1,24 @@
 use core::fmt::Display;
+use std::cell::RefCell;
 use std::cmp::{PartialEq, PartialOrd};
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Not, Sub};
+use std::rc::Rc;
+use std::sync::Arc;
 
-use crate::object::ObjFunction;
+use std::net::TcpListener;
+use std::thread::JoinHandle;
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::datetime::{DateTime, Duration};
+use crate::decimal::Decimal;
+use crate::gc;
+use crate::object::{
+    ObjChannel, ObjDatabase, ObjError, ObjFunction, ObjGenerator, ObjIterator, ObjProcess,
+    ObjSocket,
+};
 
 #[derive(Debug, Clone)]
 pub enum Value {
@@ -10,31 +26,70 @@ pub enum Value {
     FloatNone,
     Integer(i64),
     IntegerNone,
-    String(String),
+    Decimal(Rc<Decimal>),
+    DateTime(DateTime),
+    Duration(Duration),
+    String(Rc<String>),
     StringNone,
     None,
     True,
     False,
     BoolNone,
-    ObjFunction(ObjFunction),
+    ObjFunction(Rc<ObjFunction>),
     ObjFunctionNone,
+    List(Rc<RefCell<Vec<Value>>>),
+    Set(Rc<RefCell<IndexSet<Value>>>),
+    Map(Rc<RefCell<IndexMap<Value, Value>>>),
+    Iterator(Rc<RefCell<ObjIterator>>),
+    Generator(Rc<RefCell<ObjGenerator>>),
+    Channel(Rc<ObjChannel>),
+    Thread(Rc<RefCell<Option<JoinHandle<bool>>>>),
+    Socket(Rc<RefCell<Option<ObjSocket>>>),
+    Database(Rc<RefCell<Option<ObjDatabase>>>),
+    Process(Rc<RefCell<ObjProcess>>),
+    Listener(Rc<RefCell<Option<TcpListener>>>),
+    Error(Rc<ObjError>),
+    // Made by ok and err, or by `try value`
+    Result(Rc<Result<Value, Value>>),
 }
 
+// Every stack slot, local and constant is a Value, so anything larger than a
+// number lives behind a pointer and a Value stays two words
+const _: () = assert!(std::mem::size_of::<Value>() == 16);
+
 impl Value {
     pub fn is_truthy(&self) -> bool {
         match self {
             Value::True => true,
             Value::Integer(i) => *i != 0,
             Value::Float(i) => *i != 0.0,
+            Value::Decimal(d) => !d.is_zero(),
+            Value::DateTime(_) => true,
+            Value::Duration(d) => d.millis() != 0,
             Value::String(s) => !s.is_empty(),
+            Value::List(l) => !l.borrow().is_empty(),
+            Value::Set(s) => !s.borrow().is_empty(),
+            Value::Map(m) => !m.borrow().is_empty(),
             _ => false,
         }
     }
 
     pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Value::Float(_)
+                | Value::Integer(_)
+                | Value::Decimal(_)
+                | Value::FloatNone
+                | Value::IntegerNone
+        )
+    }
+
+    // The text of a value when built into a string, strings are not quoted
+    pub fn to_text(&self) -> String {
         match self {
-            Value::Float(_) | Value::Integer(_) | Value::FloatNone | Value::IntegerNone => true,
-            _ => false,
+            Value::String(s) => s.to_string(),
+            value => value.to_string(),
         }
     }
 
@@ -42,10 +97,26 @@ impl Value {
         match self {
             Value::Float(_) => "float".to_owned(),
             Value::Integer(_) => "int".to_owned(),
+            Value::Decimal(_) => "decimal".to_owned(),
+            Value::DateTime(_) => "datetime".to_owned(),
+            Value::Duration(_) => "duration".to_owned(),
             Value::True => "bool".to_owned(),
             Value::False => "bool".to_owned(),
             Value::String(_) => "string".to_owned(),
             Value::ObjFunction(_) => "function".to_owned(),
+            Value::List(_) => "list".to_owned(),
+            Value::Set(_) => "set".to_owned(),
+            Value::Map(_) => "map".to_owned(),
+            Value::Iterator(_) => "iterator".to_owned(),
+            Value::Generator(_) => "generator".to_owned(),
+            Value::Channel(_) => "channel".to_owned(),
+            Value::Thread(_) => "thread".to_owned(),
+            Value::Socket(_) => "socket".to_owned(),
+            Value::Database(_) => "database".to_owned(),
+            Value::Process(_) => "process".to_owned(),
+            Value::Listener(_) => "listener".to_owned(),
+            Value::Error(_) => "error".to_owned(),
+            Value::Result(_) => "result".to_owned(),
             Value::None => "none".to_owned(),
             Value::IntegerNone => "none".to_owned(),
             Value::FloatNone => "none".to_owned(),
@@ -54,14 +125,425 @@ impl Value {
             Value::ObjFunctionNone => "none".to_owned(),
         }
     }
+
+    // Only immutable values can be used as set elements or map keys.
+    pub fn is_hashable(&self) -> bool {
+        matches!(
+            self,
+            Value::Float(_)
+                | Value::Integer(_)
+                | Value::Decimal(_)
+                | Value::DateTime(_)
+                | Value::Duration(_)
+                | Value::String(_)
+                | Value::True
+                | Value::False
+                | Value::None
+        )
+    }
+
+    // Sets keep their elements in insertion order, behind a hash index
+    pub fn new_set(items: Vec<Value>) -> Result<Value, String> {
+        let mut elements = IndexSet::with_capacity(items.len());
+        for item in items {
+            if !item.is_hashable() {
+                return Err(format!("Unhashable type {} in set", item.type_of()));
+            }
+            elements.insert(item);
+        }
+        Ok(Value::Set(gc::elements(elements)))
+    }
+
+    // Maps keep their entries in insertion order, assigning an existing key
+    // again replaces its value in place.
+    pub fn new_map(entries: Vec<(Value, Value)>) -> Result<Value, String> {
+        let mut map = IndexMap::with_capacity(entries.len());
+        for (key, value) in entries {
+            if !key.is_hashable() {
+                return Err(format!("Unhashable type {} as map key", key.type_of()));
+            }
+            map.insert(key, value);
+        }
+        Ok(Value::Map(gc::entries(map)))
+    }
+
+    pub fn contains(&self, item: &Value) -> Result<bool, String> {
+        match (self, item) {
+            (Value::String(s), Value::String(sub)) => Ok(s.contains(sub.as_str())),
+            (Value::String(_), _) => Err(format!(
+                "Cannot look for type {} in a string",
+                item.type_of()
+            )),
+            (Value::List(l), _) => Ok(l.borrow().contains(item)),
+            (Value::Set(s), _) => Ok(s.borrow().contains(item)),
+            (Value::Map(m), _) => Ok(m.borrow().contains_key(item)),
+            _ => Err(format!(
+                "Membership test is not supported on type {}",
+                self.type_of()
+            )),
+        }
+    }
+
+    // Every value that can be looped over goes through an iterator, so the
+    // VM only needs to know how to advance one.
+    pub fn iter(&self) -> Result<Value, String> {
+        let iterator = match self {
+            Value::Iterator(_) | Value::Generator(_) => return Ok(self.clone()),
+            Value::String(s) => ObjIterator::Chars {
+                chars: s.chars().collect(),
+                position: 0,
+            },
+            Value::List(items) => ObjIterator::Sequence {
+                items: items.clone(),
+                position: 0,
+            },
+            Value::Set(elements) => ObjIterator::Sequence {
+                items: Rc::new(RefCell::new(elements.borrow().iter().cloned().collect())),
+                position: 0,
+            },
+            // Looping over a map goes through its keys
+            Value::Map(m) => ObjIterator::Sequence {
+                items: Rc::new(RefCell::new(m.borrow().keys().cloned().collect())),
+                position: 0,
+            },
+            _ => return Err(format!("Type {} is not iterable", self.type_of())),
+        };
+        Ok(Value::Iterator(Rc::new(RefCell::new(iterator))))
+    }
+
+    pub fn index(&self, index: Value) -> Result<Value, String> {
+        if let Value::Map(m) = self {
+            return match m.borrow().get(&index) {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("Key {} not found in map", index)),
+            };
+        }
+
+        let index = match index {
+            Value::Integer(i) => i,
+            _ => {
+                return Err(format!(
+                    "Indices must be of type int, got {} instead",
+                    index.type_of()
+                ))
+            }
+        };
+
+        match self {
+            Value::String(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                match resolve_index(index, chars.len()) {
+                    Some(i) => Ok(Value::from(chars[i].to_string())),
+                    None => Err(format!(
+                        "String index {} out of range for length {}",
+                        index,
+                        chars.len()
+                    )),
+                }
+            }
+            Value::List(l) => {
+                let items = l.borrow();
+                match resolve_index(index, items.len()) {
+                    Some(i) => Ok(items[i].clone()),
+                    None => Err(format!(
+                        "List index {} out of range for length {}",
+                        index,
+                        items.len()
+                    )),
+                }
+            }
+            _ => Err(format!("Cannot index into type {}", self.type_of())),
+        }
+    }
+
+    // Only the container itself is copied, the elements are still shared with
+    // the original.
+    // Heap values are identical when they are the same object, plain values
+    // when they have the same type and value. Every none is the same none.
+    pub fn is_identical(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Set(a), Value::Set(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::Iterator(a), Value::Iterator(b)) => Rc::ptr_eq(a, b),
+            (Value::Generator(a), Value::Generator(b)) => Rc::ptr_eq(a, b),
+            (Value::Channel(a), Value::Channel(b)) => Arc::ptr_eq(&a.receiver, &b.receiver),
+            (Value::Thread(a), Value::Thread(b)) => Rc::ptr_eq(a, b),
+            (Value::Socket(a), Value::Socket(b)) => Rc::ptr_eq(a, b),
+            (Value::Database(a), Value::Database(b)) => Rc::ptr_eq(a, b),
+            (Value::Process(a), Value::Process(b)) => Rc::ptr_eq(a, b),
+            (Value::Listener(a), Value::Listener(b)) => Rc::ptr_eq(a, b),
+            (Value::Error(a), Value::Error(b)) => Rc::ptr_eq(a, b),
+            (Value::Result(a), Value::Result(b)) => Rc::ptr_eq(a, b),
+            (Value::ObjFunction(a), Value::ObjFunction(b)) => a.name == b.name,
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Decimal(_), Value::Decimal(_)) => self == other,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::True, Value::True) | (Value::False, Value::False) => true,
+            _ => self.type_of() == "none" && other.type_of() == "none",
+        }
+    }
+
+    // Strict mode only compares numbers together or values of the same type,
+    // none can still be compared with anything.
+    pub fn is_comparable_with(&self, other: &Value) -> bool {
+        (self.is_number() && other.is_number())
+            || self.type_of() == other.type_of()
+            || self.type_of() == "none"
+            || other.type_of() == "none"
+    }
+
+    // Numbers are always orderable, even NaN which is simply never smaller or
+    // greater than anything.
+    pub fn is_orderable_with(&self, other: &Value) -> bool {
+        let numeric = |value: &Value| matches!(value, Value::Float(_) | Value::Integer(_));
+        self.partial_cmp(other).is_some() || (numeric(self) && numeric(other))
+    }
+
+    pub fn shallow_copy(&self) -> Value {
+        match self {
+            Value::List(l) => Value::List(gc::items(l.borrow().clone())),
+            Value::Set(s) => Value::Set(gc::elements(s.borrow().clone())),
+            Value::Map(m) => Value::Map(gc::entries(m.borrow().clone())),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn deep_copy(&self) -> Value {
+        self.deep_copy_with(&mut HashMap::new())
+    }
+
+    // Containers already copied are remembered by address, so a container
+    // appearing twice is copied once and cycles do not recurse forever.
+    fn deep_copy_with(&self, copies: &mut HashMap<usize, Value>) -> Value {
+        let address = match self {
+            Value::List(l) => Rc::as_ptr(l) as usize,
+            Value::Set(s) => Rc::as_ptr(s) as usize,
+            Value::Map(m) => Rc::as_ptr(m) as usize,
+            _ => return self.clone(),
+        };
+        if let Some(copy) = copies.get(&address) {
+            return copy.clone();
+        }
+
+        match self {
+            // Elements are never containers, a set is copied like a shallow copy
+            Value::Set(s) => Value::Set(gc::elements(s.borrow().clone())),
+            Value::List(l) => {
+                let items = gc::items(Vec::new());
+                let copy = Value::List(items.clone());
+                copies.insert(address, copy.clone());
+                let copied = l
+                    .borrow()
+                    .iter()
+                    .map(|item| item.deep_copy_with(copies))
+                    .collect();
+                *items.borrow_mut() = copied;
+                copy
+            }
+            Value::Map(m) => {
+                let entries = gc::entries(IndexMap::new());
+                let copy = Value::Map(entries.clone());
+                copies.insert(address, copy.clone());
+                let copied = m
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.deep_copy_with(copies)))
+                    .collect();
+                *entries.borrow_mut() = copied;
+                copy
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Containers compare by content. Pairs of containers already being
+    // compared are assumed equal, which stops cycles from recursing forever.
+    fn deep_eq(&self, other: &Value, comparing: &mut Vec<(usize, usize)>) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => {
+                if Rc::ptr_eq(a, b) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize);
+                if comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let equal = a.len() == b.len()
+                    && a.iter().zip(b.iter()).all(|(x, y)| x.deep_eq(y, comparing));
+                comparing.pop();
+                equal
+            }
+            (Value::Map(a), Value::Map(b)) => {
+                if Rc::ptr_eq(a, b) {
+                    return true;
+                }
+                let pair = (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize);
+                if comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let equal = a.len() == b.len()
+                    && a.iter()
+                        .all(|(key, x)| b.get(key).is_some_and(|y| x.deep_eq(y, comparing)));
+                comparing.pop();
+                equal
+            }
+            // Numbers follow IEEE 754 whatever their type: ints and floats
+            // compare by value and nan is not equal to anything, not even itself
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Integer(a), Value::Float(b)) => *a as f64 == *b,
+            (Value::Float(a), Value::Integer(b)) => *a == *b as f64,
+            (Value::Decimal(_), _) | (_, Value::Decimal(_)) => {
+                self.partial_cmp(other) == Some(std::cmp::Ordering::Equal)
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::True, Value::True) => true,
+            (Value::False, Value::False) => true,
+            (Value::None, Value::None) => true,
+            (Value::Set(a), Value::Set(b)) => {
+                let (a, b) = (a.borrow(), b.borrow());
+                a.len() == b.len() && a.iter().all(|item| b.contains(item))
+            }
+            (Value::Result(a), Value::Result(b)) => match (&**a, &**b) {
+                (Ok(a), Ok(b)) | (Err(a), Err(b)) => a.deep_eq(b, comparing),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn slice(&self, start: Value, stop: Value, step: Value) -> Result<Value, String> {
+        let start = slice_bound(start)?;
+        let stop = slice_bound(stop)?;
+        let step = slice_bound(step)?.unwrap_or(1);
+        if step == 0 {
+            return Err("Slice step cannot be zero".to_owned());
+        }
+
+        match self {
+            Value::String(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                let sliced: String = slice_positions(chars.len(), start, stop, step)
+                    .map(|i| chars[i])
+                    .collect();
+                Ok(Value::from(sliced))
+            }
+            Value::List(l) => {
+                let items = l.borrow();
+                let sliced = slice_positions(items.len(), start, stop, step)
+                    .map(|i| items[i].clone())
+                    .collect();
+                Ok(Value::List(gc::items(sliced)))
+            }
+            _ => Err(format!("Cannot slice type {}", self.type_of())),
+        }
+    }
+}
+
+// Decimals only mix with ints, mixing them with floats would bring back the
+// rounding errors they exist to avoid.
+fn decimal_operands(a: &Value, b: &Value) -> Option<(Decimal, Decimal)> {
+    match (a, b) {
+        (Value::Decimal(a), Value::Decimal(b)) => Some((**a, **b)),
+        (Value::Decimal(a), Value::Integer(b)) => Some((**a, Decimal::from_integer(*b))),
+        (Value::Integer(a), Value::Decimal(b)) => Some((Decimal::from_integer(*a), **b)),
+        _ => None,
+    }
+}
+
+// Negative indices count from the end of the sequence, so -1 is the last element.
+fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let len = len as i64;
+    let resolved = if index < 0 { index + len } else { index };
+    if resolved < 0 || resolved >= len {
+        None
+    } else {
+        Some(resolved as usize)
+    }
+}
+
+fn slice_bound(value: Value) -> Result<Option<i64>, String> {
+    match value {
+        Value::Integer(i) => Ok(Some(i)),
+        Value::None => Ok(None),
+        _ => Err(format!(
+            "Slice bounds must be of type int, got {} instead",
+            value.type_of()
+        )),
+    }
+}
+
+// Slice bounds never fail: like in Python, out of range bounds are clamped to the
+// sequence, so "abc"[1:100] is "bc" and "abc"[5:] is "".
+fn slice_positions(
+    len: usize,
+    start: Option<i64>,
+    stop: Option<i64>,
+    step: i64,
+) -> impl Iterator<Item = usize> {
+    let len = len as i64;
+    let clamp = |bound: i64, low: i64, high: i64| {
+        let bound = if bound < 0 { bound + len } else { bound };
+        bound.clamp(low, high)
+    };
+
+    let (start, stop) = if step > 0 {
+        (
+            start.map_or(0, |b| clamp(b, 0, len)),
+            stop.map_or(len, |b| clamp(b, 0, len)),
+        )
+    } else {
+        (
+            start.map_or(len - 1, |b| clamp(b, -1, len - 1)),
+            stop.map_or(-1, |b| clamp(b, -1, len - 1)),
+        )
+    };
+
+    let mut current = start;
+    std::iter::from_fn(move || {
+        if (step > 0 && current < stop) || (step < 0 && current > stop) {
+            let position = current as usize;
+            // A step past the end of the range ends it
+            current = current.checked_add(step).unwrap_or(stop);
+            Some(position)
+        } else {
+            None
+        }
+    })
+}
+
+// Floats print with the fewest digits that read back to the same value, and
+// whole floats keep a trailing .0 so they cannot be mistaken for ints.
+// NaN and infinities print the same way they are written in scripts.
+pub fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_owned()
+    } else if value.is_finite() && value.fract() == 0.0 && value.abs() < 1e16 {
+        format!("{:.1}", value)
+    } else {
+        format!("{}", value)
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::String(s) => write!(f, "\"{}\"", s),
-            Value::Float(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", format_float(*n)),
             Value::Integer(n) => write!(f, "{}", n),
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::DateTime(d) => write!(f, "{}", d),
+            Value::Duration(d) => write!(f, "{}", d),
             Value::True => write!(f, "true"),
             Value::False => write!(f, "false"),
             Value::ObjFunction(n) => write!(f, "{}", n),
@@ -71,22 +553,113 @@ impl Display for Value {
             Value::StringNone => write!(f, "string.none"),
             Value::BoolNone => write!(f, "bool.none"),
             Value::ObjFunctionNone => write!(f, "function.none"),
+            Value::List(l) => format_container(f, Rc::as_ptr(l) as usize, "[", "]", |f| {
+                for (i, item) in l.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            }),
+            Value::Set(s) => format_container(f, Rc::as_ptr(s) as usize, "{", "}", |f| {
+                for (i, item) in s.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            }),
+            Value::Map(m) => format_container(f, Rc::as_ptr(m) as usize, "{", "}", |f| {
+                for (i, (key, value)) in m.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                Ok(())
+            }),
+            Value::Iterator(_) => write!(f, "<iterator>"),
+            Value::Generator(g) => write!(f, "{}", g.borrow()),
+            Value::Channel(_) => write!(f, "<channel>"),
+            Value::Thread(_) => write!(f, "<thread>"),
+            Value::Socket(_) => write!(f, "<socket>"),
+            Value::Database(_) => write!(f, "<database>"),
+            Value::Process(_) => write!(f, "<process>"),
+            Value::Listener(_) => write!(f, "<listener>"),
+            Value::Error(error) => write!(f, "{}", error),
+            Value::Result(result) => match &**result {
+                Ok(value) => write!(f, "ok({})", value),
+                Err(value) => write!(f, "err({})", value),
+            },
         }
     }
 }
 
+thread_local! {
+    // Addresses of the containers being formatted, outermost first
+    static FORMATTING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// A container met again inside itself is shown as `[...]` or `{...}` instead
+// of being formatted forever
+fn format_container(
+    f: &mut std::fmt::Formatter<'_>,
+    address: usize,
+    open: &str,
+    close: &str,
+    items: impl FnOnce(&mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+) -> std::fmt::Result {
+    if FORMATTING.with(|formatting| formatting.borrow().contains(&address)) {
+        return write!(f, "{}...{}", open, close);
+    }
+    write!(f, "{}", open)?;
+    FORMATTING.with(|formatting| formatting.borrow_mut().push(address));
+    let result = items(f);
+    FORMATTING.with(|formatting| formatting.borrow_mut().pop());
+    result?;
+    write!(f, "{}", close)
+}
+
+fn integer_overflow() -> String {
+    "Integer overflow".to_owned()
+}
+
+// The longest string a repetition can build, in bytes
+const MAX_REPEAT_LENGTH: usize = 1 << 28;
+
+// A negative count gives an empty string, like repeating zero times
+fn repeat(text: &str, count: i64) -> Result<Value, String> {
+    let count = usize::try_from(count).unwrap_or(0);
+    match text.len().checked_mul(count) {
+        Some(length) if length <= MAX_REPEAT_LENGTH => Ok(Value::from(text.repeat(count))),
+        _ => Err(format!("String repeated {} times is too long", count)),
+    }
+}
+
 impl Add for Value {
     type Output = Result<Value, String>;
 
     fn add(self, other: Value) -> Result<Value, String> {
+        if let Some((a, b)) = decimal_operands(&self, &other) {
+            return a.add(&b).map(Value::from);
+        }
         let type_self = self.type_of();
         let type_other = other.type_of();
         match (self, other) {
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a + b)),
-            (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a + b)),
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_add(b)
+                .map(Value::Integer)
+                .ok_or_else(integer_overflow),
             (Value::Float(a), Value::Integer(b)) => Ok(Value::Float(a + b as f64)),
             (Value::Integer(a), Value::Float(b)) => Ok(Value::Float(a as f64 + b)),
-            (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{}{}", a, b))),
+            (Value::String(a), Value::String(b)) => Ok(Value::from(format!("{}{}", a, b))),
+            (Value::DateTime(a), Value::Duration(b)) | (Value::Duration(b), Value::DateTime(a)) => {
+                a.add_millis(b.millis()).map(Value::from)
+            }
+            (Value::Duration(a), Value::Duration(b)) => a.checked_add(b).map(Value::from),
             _ => Err(format!(
                 "Unsupported add operation on types {} and {}",
                 type_self, type_other
@@ -100,13 +673,33 @@ impl Sub for Value {
     type Output = Result<Value, String>;
 
     fn sub(self, other: Value) -> Result<Value, String> {
+        if let Some((a, b)) = decimal_operands(&self, &other) {
+            return a.sub(&b).map(Value::from);
+        }
         let type_self = self.type_of();
         let type_other = other.type_of();
         match (self, other) {
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a - b)),
-            (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a - b)),
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_sub(b)
+                .map(Value::Integer)
+                .ok_or_else(integer_overflow),
             (Value::Float(a), Value::Integer(b)) => Ok(Value::Float(a - b as f64)),
             (Value::Integer(a), Value::Float(b)) => Ok(Value::Float(a as f64 - b)),
+            (Value::Set(a), Value::Set(b)) => {
+                let b = b.borrow();
+                let difference = a.borrow().difference(&b).cloned().collect();
+                Ok(Value::Set(gc::elements(difference)))
+            }
+            (Value::DateTime(a), Value::DateTime(b)) => a.since(b).map(Value::from),
+            (Value::DateTime(a), Value::Duration(b)) => b
+                .checked_neg()
+                .and_then(|b| a.add_millis(b.millis()))
+                .map(Value::from),
+            (Value::Duration(a), Value::Duration(b)) => b
+                .checked_neg()
+                .and_then(|b| a.checked_add(b))
+                .map(Value::from),
             _ => Err(format!(
                 "Unsupported substract operation on types {} and {}",
                 type_self, type_other
@@ -120,15 +713,29 @@ impl Mul for Value {
     type Output = Result<Value, String>;
 
     fn mul(self, other: Value) -> Result<Value, String> {
+        if let Some((a, b)) = decimal_operands(&self, &other) {
+            return a.mul(&b).map(Value::from);
+        }
         let type_self = self.type_of();
         let type_other = other.type_of();
         match (self, other) {
             (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a * b)),
-            (Value::Integer(a), Value::Integer(b)) => Ok(Value::Integer(a * b)),
+            (Value::Integer(a), Value::Integer(b)) => a
+                .checked_mul(b)
+                .map(Value::Integer)
+                .ok_or_else(integer_overflow),
             (Value::Float(a), Value::Integer(b)) => Ok(Value::Float(a * b as f64)),
             (Value::Integer(a), Value::Float(b)) => Ok(Value::Float(a as f64 * b)),
-            (Value::Integer(a), Value::String(b)) => Ok(Value::String(b.repeat(a as usize))),
-            (Value::String(a), Value::Integer(b)) => Ok(Value::String(a.repeat(b as usize))),
+            (Value::Integer(count), Value::String(text))
+            | (Value::String(text), Value::Integer(count)) => repeat(&text, count),
+            (Value::Duration(d), Value::Integer(n)) | (Value::Integer(n), Value::Duration(d)) => d
+                .millis()
+                .checked_mul(n)
+                .map(|millis| Value::from(Duration::from_millis(millis)))
+                .ok_or_else(|| "Duration out of range".to_owned()),
+            (Value::Duration(d), Value::Float(n)) | (Value::Float(n), Value::Duration(d)) => {
+                Duration::from_float_millis(d.millis() as f64 * n).map(Value::from)
+            }
             _ => Err(format!(
                 "Unsupported multiply operation on types {} and {}",
                 type_self, type_other
@@ -142,6 +749,9 @@ impl Div for Value {
     type Output = Result<Value, String>;
 
     fn div(self, other: Value) -> Result<Value, String> {
+        if let Some((a, b)) = decimal_operands(&self, &other) {
+            return a.div(&b).map(Value::from);
+        }
         let type_self = self.type_of();
         let type_other = other.type_of();
         match (self, other) {
@@ -149,6 +759,18 @@ impl Div for Value {
             (Value::Integer(a), Value::Integer(b)) => Ok(Value::Float(a as f64 / b as f64)),
             (Value::Float(a), Value::Integer(b)) => Ok(Value::Float(a / b as f64)),
             (Value::Integer(a), Value::Float(b)) => Ok(Value::Float(a as f64 / b)),
+            (Value::Duration(a), Value::Duration(b)) => {
+                Ok(Value::Float(a.millis() as f64 / b.millis() as f64))
+            }
+            (Value::Duration(_), Value::Integer(0)) => {
+                Err("Cannot divide a duration by 0".to_owned())
+            }
+            (Value::Duration(d), Value::Integer(n)) => {
+                Duration::from_float_millis(d.millis() as f64 / n as f64).map(Value::from)
+            }
+            (Value::Duration(d), Value::Float(n)) => {
+                Duration::from_float_millis(d.millis() as f64 / n).map(Value::from)
+            }
             _ => Err(format!(
                 "Unsupported divide operation on types {} and {}",
                 type_self, type_other
@@ -159,13 +781,21 @@ impl Div for Value {
 }
 
 impl Neg for Value {
-    type Output = Value;
+    type Output = Result<Value, String>;
 
-    fn neg(self) -> Value {
+    fn neg(self) -> Result<Value, String> {
         match self {
-            Value::Float(a) => Value::Float(-a),
-            Value::Integer(a) => Value::Integer(-a),
-            _ => panic!("Unsupported operation"),
+            Value::Float(a) => Ok(Value::Float(-a)),
+            Value::Integer(a) => a
+                .checked_neg()
+                .map(Value::Integer)
+                .ok_or_else(integer_overflow),
+            Value::Decimal(a) => Ok(Value::from(a.neg())),
+            Value::Duration(a) => a.checked_neg().map(Value::from),
+            value => Err(format!(
+                "Unsupported negate operation on type {}",
+                value.type_of()
+            )),
         }
     }
 }
@@ -174,11 +804,17 @@ impl BitAnd for Value {
     type Output = Result<Value, String>;
 
     fn bitand(self, other: Value) -> Result<Value, String> {
+        if let (Value::Set(a), Value::Set(b)) = (&self, &other) {
+            let b = b.borrow();
+            let intersection = a.borrow().intersection(&b).cloned().collect();
+            return Ok(Value::Set(gc::elements(intersection)));
+        }
+
         let ret = self.is_truthy() && other.is_truthy();
         if ret {
-            return Ok(Value::True);
+            Ok(Value::True)
         } else {
-            return Ok(Value::False);
+            Ok(Value::False)
         }
     }
 }
@@ -187,11 +823,16 @@ impl BitOr for Value {
     type Output = Result<Value, String>;
 
     fn bitor(self, other: Value) -> Result<Value, String> {
+        if let (Value::Set(a), Value::Set(b)) = (&self, &other) {
+            let union = a.borrow().union(&b.borrow()).cloned().collect();
+            return Ok(Value::Set(gc::elements(union)));
+        }
+
         let ret = self.is_truthy() || other.is_truthy();
         if ret {
-            return Ok(Value::True);
+            Ok(Value::True)
         } else {
-            return Ok(Value::False);
+            Ok(Value::False)
         }
     }
 }
@@ -201,27 +842,49 @@ impl Not for Value {
 
     fn not(self) -> Value {
         if self.is_truthy() {
-            return Value::False;
+            Value::False
         } else {
-            return Value::True;
+            Value::True
         }
     }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Value) -> bool {
-        match (self, other) {
-            (Value::Float(a), Value::Float(b)) => a == b,
-            (Value::Integer(a), Value::Integer(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            (Value::True, Value::True) => true,
-            (Value::False, Value::False) => true,
-            (Value::None, Value::None) => true,
-            _ => false,
+        self.deep_eq(other, &mut Vec::new())
+    }
+}
+
+// Sets and maps only hold hashable values, for which == is an equivalence
+// except for nan, which is then never found again like it is never equal
+impl Eq for Value {}
+
+// Consistent with ==, so ints, floats and decimals of the same value hash the
+// same. Values that cannot be hashed are never looked up and only hash their
+// type.
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Value::Integer(i) => hash_number(*i as f64, state),
+            Value::Float(f) => hash_number(*f, state),
+            Value::Decimal(d) => match d.parts() {
+                (units, 0) => hash_number(units as f64, state),
+                parts => parts.hash(state),
+            },
+            Value::String(s) => s.hash(state),
+            Value::DateTime(d) => d.hash(state),
+            Value::Duration(d) => d.hash(state),
+            _ => std::mem::discriminant(self).hash(state),
         }
     }
 }
 
+// 0.0 and -0.0 are equal so they hash the same
+fn hash_number<H: Hasher>(number: f64, state: &mut H) {
+    let number = if number == 0.0 { 0.0 } else { number };
+    number.to_bits().hash(state);
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Value) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -229,11 +892,273 @@ impl PartialOrd for Value {
             (Value::Integer(a), Value::Integer(b)) => a.partial_cmp(b),
             (Value::Float(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
             (Value::Integer(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::DateTime(a), Value::DateTime(b)) => a.partial_cmp(b),
+            (Value::Duration(a), Value::Duration(b)) => a.partial_cmp(b),
+            (Value::Decimal(_), _) | (_, Value::Decimal(_)) => {
+                let (a, b) = decimal_operands(self, other)?;
+                a.compare(&b)
+            }
+            (Value::True | Value::False, Value::True | Value::False) => {
+                matches!(self, Value::True).partial_cmp(&matches!(other, Value::True))
+            }
             _ => None,
         }
     }
 }
 
-pub fn print_value(value: Value) {
-    print!("{}", value);
+impl From<i64> for Value {
+    fn from(value: i64) -> Value {
+        Value::Integer(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        if value {
+            Value::True
+        } else {
+            Value::False
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::String(Rc::new(value))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::String(Rc::new(value.to_owned()))
+    }
+}
+
+impl From<DateTime> for Value {
+    fn from(value: DateTime) -> Value {
+        Value::DateTime(value)
+    }
+}
+
+impl From<Duration> for Value {
+    fn from(value: Duration) -> Value {
+        Value::Duration(value)
+    }
+}
+
+impl From<Decimal> for Value {
+    fn from(value: Decimal) -> Value {
+        Value::Decimal(Rc::new(value))
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Value {
+        Value::List(gc::items(items))
+    }
+}
+
+fn conversion_error(expected: &str, value: &Value) -> String {
+    format!("Expected {}, got {} instead", expected, value.type_of())
+}
+
+impl TryFrom<Value> for i64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<i64, String> {
+        match value {
+            Value::Integer(i) => Ok(i),
+            value => Err(conversion_error("an int", &value)),
+        }
+    }
+}
+
+// Integers are widened, like they are in arithmetic with floats
+impl TryFrom<Value> for f64 {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<f64, String> {
+        match value {
+            Value::Float(f) => Ok(f),
+            Value::Integer(i) => Ok(i as f64),
+            value => Err(conversion_error("a float", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<bool, String> {
+        match value {
+            Value::True => Ok(true),
+            Value::False => Ok(false),
+            value => Err(conversion_error("a bool", &value)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<String, String> {
+        match value {
+            Value::String(s) => Ok(Rc::unwrap_or_clone(s)),
+            value => Err(conversion_error("a string", &value)),
+        }
+    }
+}
+
+// The items are copied out, the list itself may still be shared by the script
+impl TryFrom<Value> for Vec<Value> {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<Vec<Value>, String> {
+        match value {
+            Value::List(items) => Ok(items.borrow().clone()),
+            value => Err(conversion_error("a list", &value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash(value: &Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_numbers_hash_the_same() {
+        let decimal = |text| Value::from(Decimal::parse(text).unwrap());
+        assert_eq!(hash(&Value::Integer(1)), hash(&Value::Float(1.0)));
+        assert_eq!(hash(&Value::Integer(1)), hash(&decimal("1.00")));
+        assert_eq!(hash(&decimal("1.50")), hash(&decimal("1.5")));
+        assert_eq!(hash(&Value::Float(0.0)), hash(&Value::Float(-0.0)));
+    }
+
+    #[test]
+    fn sets_and_maps_find_equal_values() {
+        let set = Value::new_set(vec![Value::Integer(1), Value::Float(1.0), Value::from("a")]);
+        let set = set.unwrap();
+        assert_eq!(set.to_string(), "{1, \"a\"}");
+        assert_eq!(set.contains(&Value::Float(1.0)), Ok(true));
+        assert_eq!(set.contains(&Value::from("b")), Ok(false));
+
+        let map = Value::new_map(vec![
+            (Value::from("a"), Value::Integer(1)),
+            (Value::from("b"), Value::Integer(2)),
+            (Value::from("a"), Value::Integer(3)),
+        ])
+        .unwrap();
+        assert_eq!(map.to_string(), "{\"a\": 3, \"b\": 2}");
+        assert_eq!(map.index(Value::from("b")), Ok(Value::Integer(2)));
+        assert!(map.index(Value::from("c")).is_err());
+    }
+
+    #[test]
+    fn unhashable_values_are_rejected() {
+        let list = Value::from(vec![Value::Integer(1)]);
+        assert!(Value::new_set(vec![list.clone()]).is_err());
+        assert!(Value::new_map(vec![(list, Value::None)]).is_err());
+    }
+
+    fn list(items: &[i64]) -> Value {
+        Value::from(items.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>())
+    }
+
+    fn slice(value: &Value, start: Option<i64>, stop: Option<i64>, step: Option<i64>) -> String {
+        let bound = |bound: Option<i64>| bound.map_or(Value::None, Value::Integer);
+        match value.slice(bound(start), bound(stop), bound(step)) {
+            Ok(sliced) => sliced.to_text(),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let items = list(&[1, 2, 3]);
+        assert_eq!(items.index(Value::Integer(-1)), Ok(Value::Integer(3)));
+        assert_eq!(items.index(Value::Integer(-3)), Ok(Value::Integer(1)));
+        assert!(items.index(Value::Integer(-4)).is_err());
+        assert!(items.index(Value::Integer(3)).is_err());
+        let text = Value::from("abc");
+        assert_eq!(text.index(Value::Integer(-1)), Ok(Value::from("c")));
+        assert!(text.index(Value::Integer(i64::MIN)).is_err());
+    }
+
+    #[test]
+    fn slices_of_lists_and_strings() {
+        let items = list(&[0, 1, 2, 3, 4]);
+        let text = Value::from("abcde");
+        assert_eq!(slice(&items, Some(1), Some(3), None), "[1, 2]");
+        assert_eq!(slice(&text, Some(1), Some(3), None), "bc");
+        assert_eq!(slice(&items, Some(-2), None, None), "[3, 4]");
+        assert_eq!(slice(&text, None, Some(-2), None), "abc");
+        assert_eq!(slice(&items, None, None, Some(2)), "[0, 2, 4]");
+        assert_eq!(slice(&text, None, None, Some(-1)), "edcba");
+        assert_eq!(slice(&text, Some(3), Some(0), Some(-2)), "db");
+    }
+
+    #[test]
+    fn slice_bounds_are_clamped() {
+        let items = list(&[0, 1, 2]);
+        let text = Value::from("abc");
+        assert_eq!(slice(&text, Some(1), Some(100), None), "bc");
+        assert_eq!(slice(&text, Some(5), None, None), "");
+        assert_eq!(slice(&items, Some(-100), Some(2), None), "[0, 1]");
+        assert_eq!(
+            slice(&items, Some(i64::MIN), Some(i64::MAX), None),
+            "[0, 1, 2]"
+        );
+        assert_eq!(slice(&items, Some(100), None, Some(-1)), "[2, 1, 0]");
+        assert_eq!(slice(&text, Some(2), None, Some(i64::MAX)), "c");
+        assert_eq!(slice(&text, None, None, Some(i64::MIN)), "c");
+    }
+
+    #[test]
+    fn zero_step_is_an_error() {
+        assert_eq!(
+            slice(&list(&[1, 2]), None, None, Some(0)),
+            "Slice step cannot be zero"
+        );
+        assert_eq!(
+            slice(&Value::from("ab"), None, None, Some(0)),
+            "Slice step cannot be zero"
+        );
+    }
+
+    #[test]
+    fn containers_holding_themselves_are_printed_once() {
+        let list = list(&[1]);
+        if let Value::List(items) = &list {
+            items.borrow_mut().push(list.clone());
+        }
+        assert_eq!(list.to_string(), "[1, [...]]");
+
+        let map = Value::new_map(vec![(Value::from("a"), Value::Integer(1))]).unwrap();
+        if let Value::Map(entries) = &map {
+            entries
+                .borrow_mut()
+                .insert(Value::from("self"), map.clone());
+            entries
+                .borrow_mut()
+                .insert(Value::from("list"), list.clone());
+        }
+        assert_eq!(
+            map.to_string(),
+            "{\"a\": 1, \"self\": {...}, \"list\": [1, [...]]}"
+        );
+    }
 }
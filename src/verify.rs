@@ -0,0 +1,405 @@
+use std::collections::VecDeque;
+
+use crate::chunk::{Chunk, OpCode, TEST_COMPARISONS};
+use crate::native::NATIVES;
+use crate::object::ObjFunction;
+use crate::value::Value;
+
+// How an instruction uses the stack, found from its opcode and operands
+struct Effect {
+    pops: usize,
+    pushes: usize,
+    // Offset of the next instruction, the opcode plus its operands
+    size: usize,
+    // Where the instruction can jump to besides the next instruction
+    jump: Option<usize>,
+    // Set for instructions that never fall through to the next one
+    ends: bool,
+    // Where the finally code of a try starts, run on the stack of the try
+    finally: Option<usize>,
+}
+
+fn operand(chunk: &Chunk, ip: usize, index: usize) -> Result<usize, String> {
+    match chunk.code.get(ip + index) {
+        Some(OpCode::Number(n)) => Ok(*n),
+        Some(byte) => Err(format!("expected an operand, found {:?}", byte)),
+        None => Err("operand missing at the end of the chunk".to_owned()),
+    }
+}
+
+fn effect(chunk: &Chunk, ip: usize, depth: usize) -> Result<Effect, String> {
+    let simple = |pops, pushes| Effect {
+        pops,
+        pushes,
+        size: 1,
+        jump: None,
+        ends: false,
+        finally: None,
+    };
+    let with_operand = |pops, pushes| Effect {
+        size: 2,
+        ..simple(pops, pushes)
+    };
+    let jump_to = |target: Option<usize>| -> Result<Option<usize>, String> {
+        match target {
+            Some(target) if target <= chunk.code.len() => Ok(Some(target)),
+            _ => Err("jump target is outside of the chunk".to_owned()),
+        }
+    };
+
+    Ok(match chunk.code[ip] {
+        OpCode::OpConstant => {
+            if operand(chunk, ip, 1)? >= chunk.constants.len() {
+                return Err("constant index is out of range".to_owned());
+            }
+            with_operand(0, 1)
+        }
+        OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpEqual
+        | OpCode::OpNotEqual
+        | OpCode::OpGreater
+        | OpCode::OpGreaterEqual
+        | OpCode::OpLess
+        | OpCode::OpLessEqual
+        | OpCode::OpUnion
+        | OpCode::OpIntersection
+        | OpCode::OpIn
+        | OpCode::OpIs
+        | OpCode::OpConcat
+        | OpCode::OpIndex => simple(2, 1),
+        OpCode::OpNegate | OpCode::OpNot | OpCode::OpGetIter | OpCode::OpYield => simple(1, 1),
+        OpCode::OpTrue | OpCode::OpFalse | OpCode::OpNone | OpCode::OpCaught => simple(0, 1),
+        OpCode::OpEndTry => simple(0, 0),
+        // The catch block and the finally code start on the stack as it was
+        // at the try, each offset counts from its own operand and none is 0
+        OpCode::OpTry => {
+            let finally = operand(chunk, ip, 1)?;
+            let catch = operand(chunk, ip, 2)?;
+            Effect {
+                size: 3,
+                jump: match catch {
+                    0 => None,
+                    _ => jump_to((ip + 3).checked_add(catch))?,
+                },
+                finally: match finally {
+                    0 => None,
+                    _ => jump_to((ip + 2).checked_add(finally))?,
+                },
+                ..simple(0, 0)
+            }
+        }
+        OpCode::OpPrint | OpCode::OpPop => simple(1, 0),
+        OpCode::OpEol => simple(0, 0),
+        OpCode::OpRunDeferred => with_operand(0, 0),
+        // The deferred code runs on the stack as it was when it was deferred
+        // and goes back to where it was run from once done
+        OpCode::OpEndDefer => Effect {
+            ends: true,
+            ..simple(0, 0)
+        },
+        OpCode::OpSlice => simple(4, 1),
+        OpCode::OpReturn => Effect {
+            ends: true,
+            ..simple(1, 0)
+        },
+        OpCode::OpEof => Effect {
+            ends: true,
+            ..simple(0, 0)
+        },
+        OpCode::OpRaise => Effect {
+            ends: true,
+            ..simple(1, 0)
+        },
+        // The type a catch clause takes is a string constant, the value
+        // stays below the result
+        OpCode::OpCatches => match chunk.constants.get(operand(chunk, ip, 1)?) {
+            Some(Value::String(_)) => with_operand(0, 1),
+            _ => return Err("caught type is not a string constant".to_owned()),
+        },
+        // Globals are named by a string constant
+        OpCode::OpGetGlobal | OpCode::OpSetGlobal => {
+            match chunk.constants.get(operand(chunk, ip, 1)?) {
+                Some(Value::String(_)) => {}
+                _ => return Err("global name is not a string constant".to_owned()),
+            }
+            match chunk.code[ip] {
+                OpCode::OpSetGlobal => with_operand(1, 1),
+                _ => with_operand(0, 1),
+            }
+        }
+        // The method name is a constant, the receiver sits below the arguments
+        OpCode::OpInvokeBuiltin => {
+            match chunk.constants.get(operand(chunk, ip, 1)?) {
+                Some(Value::String(_)) => {}
+                _ => return Err("method name is not a string constant".to_owned()),
+            }
+            Effect {
+                size: 3,
+                ..simple(operand(chunk, ip, 2)? + 1, 1)
+            }
+        }
+        OpCode::OpSet | OpCode::OpGet => {
+            let slot = operand(chunk, ip, 1)?;
+            // Unresolved variables use usize::MAX and are reported by the VM
+            if slot != usize::MAX && slot >= depth {
+                return Err(format!(
+                    "slot {} is not on the stack, which holds {} values",
+                    slot, depth
+                ));
+            }
+            match chunk.code[ip] {
+                OpCode::OpSet => with_operand(1, 1),
+                _ => with_operand(0, 1),
+            }
+        }
+        OpCode::OpJumpIfTrue | OpCode::OpJumpIfFalse => Effect {
+            jump: jump_to((ip + 2).checked_add(operand(chunk, ip, 1)?))?,
+            ..with_operand(1, 1)
+        },
+        OpCode::OpJump => Effect {
+            jump: jump_to((ip + 2).checked_add(operand(chunk, ip, 1)?))?,
+            ends: true,
+            ..with_operand(0, 0)
+        },
+        OpCode::OpLoop => Effect {
+            jump: jump_to((ip + 2).checked_sub(operand(chunk, ip, 1)?))?,
+            ends: true,
+            ..with_operand(0, 0)
+        },
+        // The function stays below its arguments and the result replaces them
+        OpCode::OpCall => {
+            let arg_count = operand(chunk, ip, 1)?;
+            with_operand(arg_count + 1, 2)
+        }
+        OpCode::OpCallIndirect => {
+            if operand(chunk, ip, 2)? >= chunk.call_caches.borrow().len() {
+                return Err("call site index is out of range".to_owned());
+            }
+            Effect {
+                size: 3,
+                ..simple(operand(chunk, ip, 1)? + 1, 2)
+            }
+        }
+        OpCode::OpBuildList | OpCode::OpBuildSet => with_operand(operand(chunk, ip, 1)?, 1),
+        OpCode::OpBuildMap => with_operand(operand(chunk, ip, 1)? * 2, 1),
+        OpCode::OpCallNative => {
+            if operand(chunk, ip, 1)? >= NATIVES.len() {
+                return Err("native function index is out of range".to_owned());
+            }
+            Effect {
+                size: 3,
+                ..simple(operand(chunk, ip, 2)?, 1)
+            }
+        }
+        // Falls through into the deferred code, which is registered and
+        // jumped over
+        OpCode::OpDefer => Effect {
+            size: 3,
+            jump: jump_to((ip + 3).checked_add(operand(chunk, ip, 2)?))?,
+            ..simple(0, 0)
+        },
+        OpCode::OpForIter => {
+            let slot = operand(chunk, ip, 1)?;
+            if slot >= depth {
+                return Err(format!(
+                    "iterator slot {} is not on the stack, which holds {} values",
+                    slot, depth
+                ));
+            }
+            Effect {
+                size: 3,
+                jump: jump_to((ip + 3).checked_add(operand(chunk, ip, 2)?))?,
+                ..simple(0, 1)
+            }
+        }
+        // Compares a local with a local or a constant and jumps if false,
+        // nothing is left on the stack
+        OpCode::OpTestLocals | OpCode::OpTestLocalConstant => {
+            let left = operand(chunk, ip, 1)?;
+            let right = operand(chunk, ip, 2)?;
+            let right_is_slot = chunk.code[ip] == OpCode::OpTestLocals;
+            for slot in [Some(left), right_is_slot.then_some(right)]
+                .into_iter()
+                .flatten()
+            {
+                if slot >= depth {
+                    return Err(format!(
+                        "slot {} is not on the stack, which holds {} values",
+                        slot, depth
+                    ));
+                }
+            }
+            if !right_is_slot && right >= chunk.constants.len() {
+                return Err("constant index is out of range".to_owned());
+            }
+            if operand(chunk, ip, 3)? >= TEST_COMPARISONS.len() {
+                return Err("unknown comparison".to_owned());
+            }
+            Effect {
+                size: 5,
+                jump: jump_to((ip + 5).checked_add(operand(chunk, ip, 4)?))?,
+                ..simple(0, 0)
+            }
+        }
+        OpCode::Number(_) => return Err("operand found where an opcode was expected".to_owned()),
+    })
+}
+
+// Follows every path through the chunk, computing how many values are on the
+// stack before each instruction. Paths meeting at an instruction must agree,
+// otherwise a loop or branch leaves values behind or pops too many. Returns
+// the errors along with the most values the stack holds at once.
+fn verify_chunk(chunk: &Chunk, entry_depth: usize) -> (Vec<(usize, String)>, usize) {
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len() + 1];
+    let mut pending = VecDeque::from([(0, entry_depth)]);
+    let mut errors = Vec::new();
+
+    while let Some((ip, depth)) = pending.pop_front() {
+        match depths.get(ip) {
+            None => continue,
+            Some(Some(known)) => {
+                if *known != depth {
+                    errors.push((
+                        ip,
+                        format!(
+                            "paths reach this instruction with {} and {} values on the stack",
+                            known, depth
+                        ),
+                    ));
+                }
+                continue;
+            }
+            Some(None) => depths[ip] = Some(depth),
+        }
+        if ip == chunk.code.len() {
+            errors.push((ip, "execution runs past the end of the chunk".to_owned()));
+            continue;
+        }
+
+        let effect = match effect(chunk, ip, depth) {
+            Ok(effect) => effect,
+            Err(message) => {
+                errors.push((ip, message));
+                continue;
+            }
+        };
+        if effect.pops > depth {
+            errors.push((
+                ip,
+                format!("needs {} values but the stack holds {}", effect.pops, depth),
+            ));
+            continue;
+        }
+
+        let depth = depth - effect.pops + effect.pushes;
+        if let Some(target) = effect.jump {
+            pending.push_back((target, depth));
+        }
+        if let Some(target) = effect.finally {
+            pending.push_back((target, depth));
+        }
+        if !effect.ends {
+            pending.push_back((ip + effect.size, depth));
+        }
+    }
+
+    errors.sort_by_key(|(ip, _)| *ip);
+    errors.dedup();
+    let max_depth = depths
+        .iter()
+        .flatten()
+        .copied()
+        .max()
+        .unwrap_or(entry_depth);
+    (errors, max_depth)
+}
+
+// The script starts with an empty stack, functions with the slots holding the
+// functions followed by their arguments
+fn entry_depth(function: &ObjFunction) -> usize {
+    if function.name.is_empty() {
+        0
+    } else {
+        function.functions_count + function.function_info.arg_names.len()
+    }
+}
+
+// How many values a frame running the function holds at most. Only a hint
+// when the chunk does not verify, the stack still grows past it if needed.
+pub fn max_stack(function: &ObjFunction) -> usize {
+    verify_chunk(&function.chunk, entry_depth(function)).1
+}
+
+// Checks the script and every function it defines. Each error is reported
+// with the line and offset of the instruction it was found at.
+pub fn verify(script: &ObjFunction) -> Vec<String> {
+    let mut errors = Vec::new();
+    for function in script.with_nested() {
+        let name = if function.name.is_empty() {
+            "<script>"
+        } else {
+            &function.name
+        };
+        for (ip, message) in verify_chunk(&function.chunk, entry_depth(&function)).0 {
+            let line = if ip < function.chunk.code.len() {
+                function.chunk.get_line(ip)
+            } else {
+                function.chunk.get_line(ip - 1)
+            };
+            errors.push(format!(
+                "[line {}] {} at {:04}: {}",
+                line, name, ip, message
+            ));
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Span;
+    use crate::compiler::Compiler;
+
+    fn compile(source: &str) -> ObjFunction {
+        let mut compiler = Compiler::new();
+        compiler.set_print_code(false);
+        let script = compiler.compile(source.to_owned());
+        assert!(!script.had_error(), "{:?}", compiler.take_errors());
+        script
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        let programs = [
+            "total = 0\nfor i in range(10) {\n    if i == 2 {\n        continue\n    }\n    total = total + i\n}\ncount = 0\nwhile count < 3 {\n    count = count + 1\n}\nprint total\n",
+            "squares = [x * x for x in [1, 2, 3]]\npairs = {k: k + 1 for k in [1, 2]}\nprint squares\nprint pairs\n",
+            "for i in [1, 2] {\n    print {k: [x + i for x in [k]] for k in [1, 2] if k > 1}\n}\n",
+            "greet: string name {\n    print \"hello \" + name\n}\ngreet(\"you\")\n",
+            "try {\n    raise error(\"boom\")\n} catch e {\n    print e\n} finally {\n    print \"done\"\n}\nr = try ok(1)\nprint r\n",
+            "{\n    defer print \"later\"\n    print \"now\"\n}\ns = \"abc\"[1:]\nprint s\n",
+        ];
+        for source in programs {
+            assert_eq!(verify(&compile(source)), Vec::<String>::new(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_an_unbalanced_chunk() {
+        let mut script = ObjFunction::new();
+        let span = Span { start: 0, end: 0 };
+        for byte in [OpCode::OpPop, OpCode::OpNone, OpCode::OpReturn] {
+            script.chunk.write(byte, 1, span);
+        }
+        let errors = verify(&script);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(
+            errors[0].starts_with("[line 1] <script> at 0000"),
+            "{}",
+            errors[0]
+        );
+    }
+}
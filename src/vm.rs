@@ -1,16 +1,56 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use crate::clock::Clock;
 use crate::common::DEBUG_TRACE_EXECUTION;
-use crate::compiler::{Compiler, FunctionType};
-use crate::object::ObjFunction;
+use crate::compiler::{Compiler, Diagnostic};
+use crate::gc;
+pub use crate::gc::GcStats;
+use crate::interrupt;
+use crate::io::{Output, OutputFactory, StdOutput};
+use crate::native::{allowed_in_expression, expected_args, find_method, Rng, NATIVES};
+use crate::object::{
+    Advance, Deferred, ObjError, ObjFunction, ObjGenerator, TraceFrame, TryHandler,
+};
+use crate::options::{SandboxPolicy, VmOptions};
+use crate::scanner::TokenType;
+use crate::trace::Trace;
+use crate::verify::verify;
 use crate::{
-    chunk::OpCode,
-    value::{print_value, Value},
+    chunk::{json_string, OpCode, TEST_COMPARISONS},
+    value::Value,
 };
 
+// Malformed bytecode must not panic the interpreter, running out of values or
+// reading past the chunk is reported as an internal error instead
+macro_rules! checked {
+    ($vm:expr, $value:expr, $problem:expr) => {
+        match $value {
+            Some(value) => value,
+            None => return $vm.internal_error($problem),
+        }
+    };
+}
+
+macro_rules! pop {
+    ($vm:expr) => {
+        checked!($vm, $vm.current_frame().slots.pop(), "stack underflow")
+    };
+}
+
+macro_rules! read_operand {
+    ($vm:expr) => {
+        checked!($vm, $vm.read_operand(), "missing operand")
+    };
+}
+
 macro_rules! binary_op {
     ($vm:expr, $operator:tt) => {
         {
-            let b = $vm.current_frame().slots.pop().unwrap();
-            let a = $vm.current_frame().slots.pop().unwrap();
+            let b = pop!($vm);
+            let a = pop!($vm);
             let val = a $operator b;
             match val {
                 Ok(val) => $vm.current_frame().slots.push(val),
@@ -23,217 +63,1463 @@ macro_rules! binary_op {
     };
 }
 
+// Strict mode refuses to compare values of unrelated types instead of
+// evaluating to false
+macro_rules! comparison_op {
+    ($vm:expr, $operator:tt) => {
+        {
+            let b = pop!($vm);
+            let a = pop!($vm);
+            if $vm.options.strict && !a.is_comparable_with(&b) {
+                $vm.runtime_error(&format!(
+                    "Cannot compare {} with {} in strict mode",
+                    a.type_of(),
+                    b.type_of()
+                ));
+                return InterpretResult::RuntimeError;
+            }
+            $vm.current_frame()
+                .slots
+                .push(if a $operator b { Value::True } else { Value::False });
+        }
+    };
+}
+
+// Ordering values of types that have no order between them is an error
+macro_rules! ordering_op {
+    ($vm:expr, $operator:tt) => {
+        {
+            let b = pop!($vm);
+            let a = pop!($vm);
+            if !a.is_orderable_with(&b) {
+                $vm.runtime_error(&format!(
+                    "Cannot order {} and {}",
+                    a.type_of(),
+                    b.type_of()
+                ));
+                return InterpretResult::RuntimeError;
+            }
+            $vm.current_frame()
+                .slots
+                .push(if a $operator b { Value::True } else { Value::False });
+        }
+    };
+}
+
+pub enum Resumed {
+    Yielded(Value),
+    Returned(Value),
+}
+
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    // The script called exit with this status
+    Exit(i32),
+}
+
+// Errors are reported to stderr as they are found, this only says which kind
+// stopped the script
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterpretError {
+    Compile,
+    Runtime,
+    Exit(i32),
 }
 
 #[derive(Clone, Debug)]
 struct CallFrame {
     ip: usize,
-    function: ObjFunction,
+    // Offset of the instruction being run, ip is already past its operands
+    instruction_start: usize,
+    function: Rc<ObjFunction>,
     slots: Vec<Value>,
+    generator: Option<Rc<RefCell<ObjGenerator>>>,
+    // Where the result goes in the cache of a memoized function
+    memo_key: Option<String>,
+    deferred: Vec<Deferred>,
+    running_deferred: Vec<RunningDeferred>,
+    // Try blocks of the frame the code is in, the innermost last
+    handlers: Vec<TryHandler>,
+}
+
+// Deferred code being run, the values the frame held above where it was
+// deferred are set aside until it is done
+#[derive(Clone, Debug)]
+struct RunningDeferred {
+    // None when run by exit, which stops once the code is done
+    return_ip: Option<usize>,
+    height: usize,
+    kept: Vec<Value>,
+}
+
+// What unwinding the frames of a run got to
+enum Unwind {
+    // Deferred code to run before going on
+    Deferred,
+    // A catch block to go on from
+    Caught,
+    // Every frame of the run dropped
+    Done,
+}
+
+#[derive(Clone)]
+struct Timer {
+    id: usize,
+    // Time on the VM clock the timer is due at
+    deadline: Duration,
+    function: Rc<ObjFunction>,
+    slots: Vec<Value>,
+}
+
+// How many instructions run between two checks for Ctrl-C
+const INTERRUPT_CHECK_INTERVAL: u32 = 1024;
+
+// The kind of the errors the VM raises itself
+pub(crate) const RUNTIME_ERROR: &str = "RuntimeError";
+
+// The function registered by on_interrupt, with the slots it captured
+struct InterruptHandler {
+    function: Rc<ObjFunction>,
+    slots: Vec<Value>,
+}
+
+// A value shared by the host and the scripts it runs. The type is the one the
+// variable was declared with, TokenType::None when it was not annotated.
+#[derive(Clone)]
+struct Global {
+    value: Value,
+    type_: TokenType,
+}
+
+impl Global {
+    fn check_type(&self, name: &str, value: &Value) -> Result<(), String> {
+        if self.type_.is_value_correct_type(value) {
+            Ok(())
+        } else {
+            Err(format!(
+                "Global {} is of type {} but value is of type {}",
+                name,
+                self.type_,
+                value.type_of()
+            ))
+        }
+    }
+}
+
+// State a VM keeps between runs, taken by VM::snapshot. Values held by
+// pending timers are deep copied so later changes do not leak into it.
+#[derive(Clone)]
+pub struct VmSnapshot {
+    globals: HashMap<String, Global>,
+    timers: Vec<Timer>,
+    next_timer_id: usize,
+    clock: Clock,
+    rng: Rng,
 }
 
 pub struct VM {
     frames: Vec<CallFrame>,
+    // Slots of returned frames, cleared and kept so calls reuse them instead
+    // of allocating
+    spare_slots: Vec<Vec<Value>>,
+    timers: Vec<Timer>,
+    next_timer_id: usize,
+    interrupt_handler: Option<InterruptHandler>,
+    // Whether Ctrl-C stops the running script when no handler is registered
+    interruptible: bool,
+    // Set by exit until the host runs something else. A native that ran
+    // script code checks it to stop instead of reporting an error.
+    exit_status: Option<i32>,
+    // The error or other raised value being unwound to a catch block, until
+    // the block takes it
+    error: Option<Value>,
+    // Set when Ctrl-C stopped the script, which no try block catches
+    interrupted: bool,
+    // Instructions run since the last check for Ctrl-C
+    ticks: u32,
+    // Set while eval_expression runs, only natives computing a value can be
+    // called
+    expression_only: bool,
+    options: VmOptions,
+    dump_bytecode: bool,
+    analyze: bool,
+    trace: Option<Trace>,
+    clock: Clock,
+    rng: Rng,
+    deterministic: bool,
+    // What the script returned, taken by interpret once it is done
+    script_result: Value,
+    globals: HashMap<String, Global>,
+    script_args: Vec<String>,
+    // Answers given to the prompt natives before reading from stdin
+    answers: VecDeque<String>,
+    output: Box<dyn Output>,
+    // Where spawned threads write, stdout and stderr when not set
+    output_factory: Option<OutputFactory>,
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
-        VM { frames: Vec::new() }
+        VM::with_options(VmOptions::default())
+    }
+
+    pub fn with_options(options: VmOptions) -> VM {
+        VM {
+            frames: Vec::new(),
+            spare_slots: Vec::new(),
+            timers: Vec::new(),
+            next_timer_id: 0,
+            interrupt_handler: None,
+            interruptible: false,
+            exit_status: None,
+            error: None,
+            interrupted: false,
+            ticks: 0,
+            expression_only: false,
+            options,
+            dump_bytecode: false,
+            analyze: false,
+            trace: if DEBUG_TRACE_EXECUTION {
+                Some(Trace::stdout())
+            } else {
+                None
+            },
+            clock: Clock::real(),
+            rng: Rng::from_time(),
+            deterministic: false,
+            script_result: Value::None,
+            globals: HashMap::new(),
+            script_args: Vec::new(),
+            answers: VecDeque::new(),
+            output: Box::new(StdOutput),
+            output_factory: None,
+        }
+    }
+
+    pub fn options(&self) -> VmOptions {
+        self.options
+    }
+
+    // In sandbox mode natives touching the network, files or processes are refused
+    pub fn set_sandboxed(&mut self, sandboxed: bool) {
+        self.options.sandbox = if sandboxed {
+            SandboxPolicy::deny_all()
+        } else {
+            SandboxPolicy::allow_all()
+        };
+    }
+
+    pub fn set_sandbox_policy(&mut self, policy: SandboxPolicy) {
+        self.options.sandbox = policy;
+    }
+
+    // In strict mode conditions must be bool and values of unrelated types
+    // cannot be compared
+    pub fn set_strict(&mut self, strict: bool) {
+        self.options.strict = strict;
+    }
+
+    // Ctrl-C stops the running script with a runtime error instead of the
+    // whole process, so a REPL can go on with the next line
+    pub fn set_interruptible(&mut self, interruptible: bool) {
+        self.interruptible = interruptible;
+        if interruptible {
+            interrupt::catch_ctrl_c();
+        } else if self.interrupt_handler.is_none() {
+            interrupt::release_ctrl_c();
+        }
+    }
+
+    // Checks the stack usage of compiled code before running it, which catches
+    // compiler bugs before they turn into a panic in the middle of a script
+    pub fn set_verify_bytecode(&mut self, verify_bytecode: bool) {
+        self.options.verify_bytecode = verify_bytecode;
+    }
+
+    // Compiles the bodies of top level functions on that many threads, which
+    // speeds up compiling large scripts without changing the bytecode
+    pub fn set_compile_threads(&mut self, threads: usize) {
+        self.options.compile_threads = threads;
+    }
+
+    // Cycles between lists, sets and maps are collected once this many have
+    // been created since the last collection, 1 collects before every
+    // instruction that follows an allocation
+    pub fn set_gc_threshold(&mut self, gc_threshold: usize) {
+        self.options.gc_threshold = gc_threshold.max(1);
+    }
+
+    // Lists, sets and maps of the heap the VM runs on, shared with any other
+    // VM created on the same thread
+    pub fn gc_stats(&self) -> GcStats {
+        gc::stats()
+    }
+
+    // Collects cycles now instead of waiting for the threshold, returns how
+    // many containers were freed
+    pub fn gc_collect(&mut self) -> usize {
+        gc::collect()
+    }
+
+    // Prints the compiled bytecode as JSON instead of running it, an array
+    // holding the script followed by every function it defines
+    pub fn set_dump_bytecode(&mut self, dump_bytecode: bool) {
+        self.dump_bytecode = dump_bytecode;
+    }
+
+    // Runs the compile time checks without running the script, and prints
+    // what they found as a JSON report. The bytecode is verified as well
+    // when verification is on.
+    pub fn set_analyze(&mut self, analyze: bool) {
+        self.analyze = analyze;
+    }
+
+    // What the args native returns, the command line arguments following the script
+    pub fn set_script_args(&mut self, script_args: Vec<String>) {
+        self.script_args = script_args;
+    }
+
+    // Answers confirm, choose and password take in order instead of asking,
+    // so interactive scripts can run unattended
+    pub fn set_answers(&mut self, answers: Vec<String>) {
+        self.answers = answers.into();
+    }
+
+    // Makes runs reproducible: random numbers come from the given seed and the
+    // clock is virtual, starting at 0 and moving only when the script sleeps
+    // or waits for a timer. Maps and sets already iterate in insertion order.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.clock = Clock::Virtual(Duration::ZERO);
+        self.rng = Rng::new(seed);
+        self.deterministic = true;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub(crate) fn clock(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    pub(crate) fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub(crate) fn output(&mut self) -> &mut dyn Output {
+        self.output.as_mut()
+    }
+
+    // Only threads started by spawn use it
+    #[cfg(feature = "os")]
+    pub(crate) fn output_factory(&self) -> Option<OutputFactory> {
+        self.output_factory.clone()
+    }
+
+    pub(crate) fn script_args(&self) -> &[String] {
+        &self.script_args
+    }
+
+    pub(crate) fn next_answer(&mut self) -> Option<String> {
+        self.answers.pop_front()
+    }
+
+    // Drops what a previous run left behind, the frames of a script stopped by
+    // an error, the timers it scheduled and the globals, so the VM can run
+    // another script. Every script gets a new compiler and parser, nothing of
+    // an earlier compile is kept.
+    pub fn reset(&mut self) {
+        self.frames.clear();
+        self.timers.clear();
+        self.script_result = Value::None;
+        self.globals.clear();
+        self.error = None;
+        self.exit_status = None;
+        self.interrupted = false;
+        self.ticks = 0;
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot {
+        let timers = self
+            .timers
+            .iter()
+            .map(|timer| Timer {
+                slots: timer.slots.iter().map(Value::deep_copy).collect(),
+                ..timer.clone()
+            })
+            .collect();
+        let globals = self
+            .globals
+            .iter()
+            .map(|(name, global)| {
+                let global = Global {
+                    value: global.value.deep_copy(),
+                    type_: global.type_,
+                };
+                (name.clone(), global)
+            })
+            .collect();
+        VmSnapshot {
+            globals,
+            timers,
+            next_timer_id: self.next_timer_id,
+            clock: self.clock.clone(),
+            rng: self.rng.clone(),
+        }
+    }
+
+    // Rolls the VM back to the snapshot, after a failed script for example
+    pub fn restore(&mut self, snapshot: VmSnapshot) {
+        self.reset();
+        self.globals = snapshot.globals;
+        self.timers = snapshot.timers;
+        self.next_timer_id = snapshot.next_timer_id;
+        self.clock = snapshot.clock;
+        self.rng = snapshot.rng;
+    }
+
+    // Scripts read globals by name anywhere and assign them from their top
+    // level. Their own top level variables become globals once they are done,
+    // which is how a host reads the results of a script.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).map(|global| global.value.clone())
+    }
+
+    // Fails when the value does not match the type the global was declared with
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), String> {
+        match self.globals.get_mut(name) {
+            Some(global) => {
+                global.check_type(name, &value)?;
+                global.value = value;
+            }
+            None => {
+                let global = Global {
+                    value,
+                    type_: TokenType::None,
+                };
+                self.globals.insert(name.to_owned(), global);
+            }
+        }
+        Ok(())
+    }
+
+    // Where printed values and reported errors go, stdout and stderr by default
+    pub fn set_output(&mut self, output: Box<dyn Output>) {
+        self.output = output;
     }
 
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
+    // Like set_output for scripts spawning threads, the VM and every thread
+    // it spawns write to an output of their own made by the factory
+    pub fn set_output_factory(&mut self, factory: OutputFactory) {
+        self.output = factory();
+        self.output_factory = Some(factory);
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    // Returns the value of the expression the script ends with, none if it
+    // ends with any other statement
+    pub fn interpret(&mut self, source: String) -> Result<Value, InterpretError> {
+        self.interpret_file(source, "")
+    }
+
+    // Like interpret, with errors naming the file the source was read from
+    pub fn interpret_file(&mut self, source: String, file: &str) -> Result<Value, InterpretError> {
+        self.exit_status = None;
+        self.error = None;
+        self.interrupted = false;
         let mut compiler = Compiler::new();
+        compiler.set_strict(self.options.strict);
+        compiler.set_threads(self.options.compile_threads);
+        compiler.set_file(file);
+        compiler.set_globals(self.globals.keys().cloned().collect::<HashSet<String>>());
+        if self.dump_bytecode || self.analyze {
+            compiler.set_print_code(false);
+        }
         let function = compiler.compile(source);
+        self.output.write_out(&compiler.take_listing());
+        let warnings = compiler.take_warnings();
+        if self.analyze {
+            return self.report_analysis(&function, file, compiler.take_errors(), warnings);
+        }
+        for error in compiler.take_errors() {
+            self.output.write_err(&format!("{}\n", error));
+        }
         if function.had_error() {
-            eprintln!("Errors were found at compile time.");
-            return InterpretResult::CompileError;
+            self.output
+                .write_err("Errors were found at compile time.\n");
+            return Err(InterpretError::Compile);
+        }
+        if self.options.verify_bytecode {
+            let errors = verify(&function);
+            if !errors.is_empty() {
+                for error in errors {
+                    self.output.write_err(&format!("{}\n", error));
+                }
+                self.output.write_err("Bytecode verification failed.\n");
+                return Err(InterpretError::Compile);
+            }
+        }
+        if self.dump_bytecode {
+            let chunks: Vec<String> = function
+                .with_nested()
+                .iter()
+                .map(|function| function.chunk.to_json())
+                .collect();
+            self.output.write_out(&format!("[{}]\n", chunks.join(", ")));
+            return Ok(Value::None);
         }
 
         let frame = {
             CallFrame {
                 ip: 0,
-                function,
-                slots: Vec::new(),
+                instruction_start: 0,
+                slots: Vec::with_capacity(function.max_stack),
+                function: Rc::new(function),
+                generator: None,
+                memo_key: None,
+                deferred: Vec::new(),
+                running_deferred: Vec::new(),
+                handlers: Vec::new(),
             }
         };
 
         self.frames.push(frame);
 
-        let result = self.run();
-        return result;
+        match self.run(0) {
+            InterpretResult::Ok => Ok(std::mem::replace(&mut self.script_result, Value::None)),
+            InterpretResult::CompileError => Err(InterpretError::Compile),
+            InterpretResult::RuntimeError => Err(InterpretError::Runtime),
+            InterpretResult::Exit(status) => Err(InterpretError::Exit(status)),
+        }
+    }
+
+    // Evaluates a single expression, as a formula or configuration value,
+    // against the globals of the VM. Statements are refused at compile time and
+    // only natives that compute a value can be called while it runs.
+    pub fn eval_expression(&mut self, source: &str) -> Result<Value, InterpretError> {
+        if !self.options.sandbox.eval {
+            self.output
+                .write_err("Evaluating expressions is not allowed by the sandbox.\n");
+            return Err(InterpretError::Runtime);
+        }
+        self.exit_status = None;
+        self.error = None;
+        self.interrupted = false;
+        let mut compiler = Compiler::new();
+        compiler.set_strict(self.options.strict);
+        compiler.set_globals(self.globals.keys().cloned().collect::<HashSet<String>>());
+        compiler.set_print_code(false);
+        let function = compiler.compile_expression(source.to_string());
+        for error in compiler.take_errors() {
+            self.output.write_err(&format!("{}\n", error));
+        }
+        compiler.take_warnings();
+        if function.had_error() {
+            self.output
+                .write_err("Errors were found at compile time.\n");
+            return Err(InterpretError::Compile);
+        }
+
+        // Frames a failed script left behind stay as they were
+        let frames = std::mem::take(&mut self.frames);
+        let policy = std::mem::replace(&mut self.options.sandbox, SandboxPolicy::deny_all());
+        self.expression_only = true;
+        let result = self.run_function(Rc::new(function), Vec::new());
+        self.expression_only = false;
+        self.options.sandbox = policy;
+        self.frames = frames;
+        match result {
+            InterpretResult::Ok => Ok(std::mem::replace(&mut self.script_result, Value::None)),
+            InterpretResult::CompileError => Err(InterpretError::Compile),
+            InterpretResult::RuntimeError => Err(InterpretError::Runtime),
+            InterpretResult::Exit(status) => Err(InterpretError::Exit(status)),
+        }
+    }
+
+    // `{"file": ..., "errors": [...], "warnings": [...], "bytecode": [...]}`,
+    // the script counts as failing to compile if anything but a warning is found
+    fn report_analysis(
+        &mut self,
+        function: &ObjFunction,
+        file: &str,
+        errors: Vec<Diagnostic>,
+        warnings: Vec<Diagnostic>,
+    ) -> Result<Value, InterpretError> {
+        // Bytecode of a script that failed to compile is not worth checking
+        let bytecode = if self.options.verify_bytecode && !function.had_error() {
+            verify(function)
+        } else {
+            Vec::new()
+        };
+        let to_json = |diagnostics: &[Diagnostic]| {
+            let items: Vec<String> = diagnostics.iter().map(Diagnostic::to_json).collect();
+            format!("[{}]", items.join(", "))
+        };
+        let bytecode_items: Vec<String> = bytecode.iter().map(|error| json_string(error)).collect();
+        self.output.write_out(&format!(
+            "{{\"file\": {}, \"errors\": {}, \"warnings\": {}, \"bytecode\": [{}]}}\n",
+            json_string(file),
+            to_json(&errors),
+            to_json(&warnings),
+            bytecode_items.join(", ")
+        ));
+        if function.had_error() || !bytecode.is_empty() {
+            return Err(InterpretError::Compile);
+        }
+        Ok(Value::None)
+    }
+
+    // Used by spawned threads and timers, which start directly from a compiled function
+    pub(crate) fn run_function(
+        &mut self,
+        function: Rc<ObjFunction>,
+        slots: Vec<Value>,
+    ) -> InterpretResult {
+        let base_depth = self.frames.len();
+        self.frames.push(CallFrame {
+            ip: 0,
+            instruction_start: 0,
+            function,
+            slots,
+            generator: None,
+            memo_key: None,
+            deferred: Vec::new(),
+            running_deferred: Vec::new(),
+            handlers: Vec::new(),
+        });
+        self.run(base_depth)
+    }
+
+    // The comparison of a test instruction, with the same errors as the
+    // comparison instructions it replaces. None once the error is reported.
+    fn test(&mut self, a: &Value, b: &Value, comparison: OpCode) -> Option<bool> {
+        let ordering = !matches!(comparison, OpCode::OpEqual | OpCode::OpNotEqual);
+        if ordering && !a.is_orderable_with(b) {
+            self.runtime_error(&format!("Cannot order {} and {}", a.type_of(), b.type_of()));
+            return None;
+        }
+        if !ordering && self.options.strict && !a.is_comparable_with(b) {
+            self.runtime_error(&format!(
+                "Cannot compare {} with {} in strict mode",
+                a.type_of(),
+                b.type_of()
+            ));
+            return None;
+        }
+        Some(match comparison {
+            OpCode::OpLess => a < b,
+            OpCode::OpLessEqual => a <= b,
+            OpCode::OpGreater => a > b,
+            OpCode::OpGreaterEqual => a >= b,
+            OpCode::OpEqual => a == b,
+            _ => a != b,
+        })
+    }
+
+    fn check_condition(&mut self) -> bool {
+        let condition = match self.peek(0) {
+            Some(condition) => condition,
+            None => {
+                self.internal_error("stack underflow");
+                return false;
+            }
+        };
+        if self.options.strict && !matches!(condition, Value::True | Value::False) {
+            let message = format!(
+                "Condition must be of type bool, got {} instead",
+                condition.type_of()
+            );
+            self.runtime_error(&message);
+            return false;
+        }
+        true
+    }
+
+    pub(crate) fn add_timer(&mut self, function: Rc<ObjFunction>, delay: Duration) -> usize {
+        let id = self.next_timer_id;
+        self.next_timer_id += 1;
+        let mut slots = self.function_slots();
+        slots.extend_from_slice(&function.bound);
+        self.timers.push(Timer {
+            id,
+            deadline: self.clock.elapsed() + delay,
+            function,
+            slots,
+        });
+        id
+    }
+
+    pub(crate) fn cancel_timer(&mut self, id: usize) -> bool {
+        let count = self.timers.len();
+        self.timers.retain(|timer| timer.id != id);
+        self.timers.len() != count
+    }
+
+    // Ctrl-C runs the function instead of stopping the script, None gives
+    // Ctrl-C back its usual meaning
+    pub(crate) fn set_interrupt_handler(&mut self, function: Option<Rc<ObjFunction>>) {
+        self.interrupt_handler = function.map(|function| {
+            let mut slots = self.function_slots();
+            slots.extend_from_slice(&function.bound);
+            InterruptHandler { function, slots }
+        });
+        if self.interrupt_handler.is_some() {
+            interrupt::catch_ctrl_c();
+        } else if !self.interruptible {
+            interrupt::release_ctrl_c();
+        }
+    }
+
+    // Checks for Ctrl-C before the next instruction rather than after the
+    // usual interval, once a wait was cut short by it
+    pub(crate) fn check_interrupt_soon(&mut self) {
+        self.ticks = INTERRUPT_CHECK_INTERVAL - 1;
+    }
+
+    // Stops the script once the native calling this returns
+    pub(crate) fn request_exit(&mut self, status: i32) {
+        self.exit_status = Some(status);
+    }
+
+    fn catches_interrupts(&self) -> bool {
+        self.interrupt_handler.is_some() || self.interruptible
+    }
+
+    // Runs the registered handler once for an interrupt that came in, or stops
+    // the script without one. The handler is unregistered while it runs so a
+    // second Ctrl-C stops the script instead of starting it again.
+    fn handle_interrupt(&mut self) -> InterpretResult {
+        let handler = match self.interrupt_handler.take() {
+            Some(handler) => handler,
+            None if self.frames.is_empty() => {
+                // Waiting for timers, which are dropped along with the script
+                self.timers.clear();
+                self.output.write_err("\nInterrupted\n");
+                return InterpretResult::RuntimeError;
+            }
+            None => {
+                self.interrupted = true;
+                self.runtime_error("Interrupted");
+                return InterpretResult::RuntimeError;
+            }
+        };
+        if !self.interruptible {
+            interrupt::release_ctrl_c();
+        }
+        let in_script = !self.frames.is_empty();
+        let result = self.run_function(handler.function.clone(), handler.slots.clone());
+        if !matches!(result, InterpretResult::Ok) {
+            return result;
+        }
+        // What the handler returned is of no use to the interrupted code
+        if in_script {
+            self.current_frame().slots.pop();
+        }
+        if self.interrupt_handler.is_none() {
+            self.interrupt_handler = Some(handler);
+            interrupt::catch_ctrl_c();
+        }
+        InterpretResult::Ok
+    }
+
+    // When the next timer is due, as time elapsed on the VM clock
+    pub fn next_timer(&self) -> Option<Duration> {
+        self.timers.iter().map(|timer| timer.deadline).min()
+    }
+
+    // Runs the callbacks of every timer that is due, in deadline order. Hosts
+    // embedding the VM can call this from their own loop instead of run_event_loop.
+    pub fn run_due_timers(&mut self) -> InterpretResult {
+        self.exit_status = None;
+        self.error = None;
+        self.interrupted = false;
+        loop {
+            let now = self.clock.elapsed();
+            let due = self
+                .timers
+                .iter()
+                .enumerate()
+                .filter(|(_, timer)| timer.deadline <= now)
+                .min_by_key(|(_, timer)| (timer.deadline, timer.id))
+                .map(|(index, _)| index);
+
+            let timer = match due {
+                Some(index) => self.timers.remove(index),
+                None => return InterpretResult::Ok,
+            };
+            let result = self.run_function(timer.function, timer.slots);
+            if !matches!(result, InterpretResult::Ok) {
+                return result;
+            }
+        }
+    }
+
+    // Sleeps until each timer is due and runs it, until no timer is left
+    pub fn run_event_loop(&mut self) -> InterpretResult {
+        while let Some(deadline) = self.next_timer() {
+            self.clock.sleep_until(deadline);
+            if self.catches_interrupts() && interrupt::take() {
+                let result = self.handle_interrupt();
+                if !matches!(result, InterpretResult::Ok) {
+                    return result;
+                }
+            }
+            let result = self.run_due_timers();
+            if !matches!(result, InterpretResult::Ok) {
+                return result;
+            }
+        }
+        InterpretResult::Ok
     }
 
     fn current_frame(&mut self) -> &mut CallFrame {
         self.frames.last_mut().unwrap()
     }
 
-    fn run(&mut self) -> InterpretResult {
+    // Runs until the frame stack shrinks back to base_depth, which lets a
+    // generator be resumed from within an instruction of its caller.
+    fn run(&mut self, base_depth: usize) -> InterpretResult {
+        let mut result = self.execute(base_depth);
+        // Exiting or an error runs the code the frames of this run deferred
+        // and drops them, each nested run does the same for its own until the
+        // host gets the result. Deferred code calling exit changes the status,
+        // an error in it is reported and the unwinding goes on. An error a try
+        // block is waiting for stops at the innermost one instead, once the
+        // code deferred in the scopes it leaves has run.
         loop {
-            let instruction = self.read_byte();
-            if DEBUG_TRACE_EXECUTION {
-                let frame = self.current_frame();
-                frame
-                    .function
-                    .chunk
-                    .disassemble_instruction(&instruction, frame.ip - 1);
+            let status = match result {
+                InterpretResult::Exit(status) => Some(status),
+                InterpretResult::RuntimeError => None,
+                _ => break,
+            };
+            let catching = status.is_none() && self.error.is_some();
+            match self.unwind(base_depth, catching) {
+                Unwind::Done => {
+                    self.exit_status = status;
+                    break;
+                }
+                Unwind::Caught => result = self.execute(base_depth),
+                Unwind::Deferred => {
+                    // The deferred code can catch errors of its own
+                    let pending = self.error.take();
+                    self.exit_status = None;
+                    result = match (self.execute(base_depth), status) {
+                        (InterpretResult::Ok, Some(status)) => InterpretResult::Exit(status),
+                        (InterpretResult::Ok, None) => {
+                            self.error = pending;
+                            InterpretResult::RuntimeError
+                        }
+                        (result, _) => result,
+                    };
+                }
+            }
+        }
+        result
+    }
+
+    // Drops frames from the top until one has deferred or finally code left
+    // to run or, when catching, a try block to go on from
+    fn unwind(&mut self, base_depth: usize, catching: bool) -> Unwind {
+        while self.frames.len() > base_depth {
+            let frame = self.frames.last_mut().unwrap();
+            // Code deferred before the innermost try runs once it is left
+            let floor = frame.handlers.last().map_or(0, |handler| handler.deferred);
+            while frame.deferred.len() > floor {
+                let deferred = frame.deferred.pop().unwrap();
+                // An error can leave the stack below where the code was
+                // deferred, it cannot run then
+                if deferred.height <= frame.slots.len() {
+                    self.start_deferred(deferred, None);
+                    return Unwind::Deferred;
+                }
+            }
+            let Some(handler) = frame.handlers.pop() else {
+                let frame = self.frames.pop().unwrap();
+                // A generator left by an error cannot be resumed
+                if let Some(generator) = &frame.generator {
+                    generator.borrow_mut().done = true;
+                }
+                continue;
+            };
+            frame.running_deferred.truncate(handler.running);
+            if let Some(catch_ip) = handler.catch_ip.filter(|_| catching) {
+                frame.slots.truncate(handler.height);
+                frame.ip = catch_ip;
+                // Kept for the finally code, the end of the catch block runs it
+                frame.handlers.push(TryHandler {
+                    catch_ip: None,
+                    ..handler
+                });
+                return Unwind::Caught;
+            }
+            if let Some(ip) = handler.finally_ip {
+                let finally = Deferred {
+                    ip,
+                    height: handler.height,
+                    depth: 0,
+                };
+                if self.start_deferred(finally, None) {
+                    return Unwind::Deferred;
+                }
+            }
+        }
+        Unwind::Done
+    }
+
+    // Jumps to the deferred code with the values above its stack set aside
+    fn start_deferred(&mut self, deferred: Deferred, return_ip: Option<usize>) -> bool {
+        let frame = self.current_frame();
+        if deferred.height > frame.slots.len() {
+            return false;
+        }
+        let kept = frame.slots.split_off(deferred.height);
+        frame.running_deferred.push(RunningDeferred {
+            return_ip,
+            height: deferred.height,
+            kept,
+        });
+        frame.ip = deferred.ip;
+        true
+    }
+
+    fn execute(&mut self, base_depth: usize) -> InterpretResult {
+        loop {
+            // Between two instructions nothing is half done, so the handler
+            // can run here as if the interrupted code had called it
+            self.ticks = self.ticks.wrapping_add(1);
+            if self.ticks.is_multiple_of(INTERRUPT_CHECK_INTERVAL)
+                && self.catches_interrupts()
+                && interrupt::take()
+            {
+                let result = self.handle_interrupt();
+                if !matches!(result, InterpretResult::Ok) {
+                    return result;
+                }
+            }
+            let frame = self.current_frame();
+            frame.instruction_start = frame.ip;
+            let instruction = checked!(self, self.read_byte(), "ran past the end of the chunk");
+            // Everything the script can still reach is on the stack, in the
+            // frames, globals and timers, so this is a safe point to collect
+            if gc::pending() >= self.options.gc_threshold {
+                gc::collect();
+            }
+            if let Some(trace) = &mut self.trace {
+                let frame = self.frames.last().unwrap();
+                let chunk = &frame.function.chunk;
+                if !trace.instruction(chunk, frame.instruction_start, &frame.slots) {
+                    self.output
+                        .write_err("Could not write the trace, tracing stops here.\n");
+                    self.trace = None;
+                }
             }
 
             match instruction {
                 OpCode::OpConstant => {
-                    let constant = self.read_constant();
+                    let constant = checked!(self, self.read_constant(), "unknown constant");
                     self.current_frame().slots.push(constant);
                 }
                 OpCode::OpAdd => binary_op!(self, +),
                 OpCode::OpSubtract => binary_op!(self, -),
                 OpCode::OpMultiply => binary_op!(self, *),
                 OpCode::OpDivide => binary_op!(self, /),
-                OpCode::OpEqual => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame().slots.push(if a == b {
-                        Value::True
-                    } else {
-                        Value::False
-                    });
-                }
-                OpCode::OpNotEqual => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame().slots.push(if a != b {
-                        Value::True
-                    } else {
-                        Value::False
-                    });
-                }
-                OpCode::OpGreater => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame()
-                        .slots
-                        .push(if a > b { Value::True } else { Value::False });
-                }
-                OpCode::OpGreaterEqual => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame().slots.push(if a >= b {
-                        Value::True
-                    } else {
-                        Value::False
-                    });
-                }
-                OpCode::OpLess => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame()
-                        .slots
-                        .push(if a < b { Value::True } else { Value::False });
-                }
-                OpCode::OpLessEqual => {
-                    let b = self.current_frame().slots.pop().unwrap();
-                    let a = self.current_frame().slots.pop().unwrap();
-                    self.current_frame().slots.push(if a <= b {
-                        Value::True
-                    } else {
-                        Value::False
-                    });
-                }
+                OpCode::OpEqual => comparison_op!(self, ==),
+                OpCode::OpNotEqual => comparison_op!(self, !=),
+                OpCode::OpGreater => ordering_op!(self, >),
+                OpCode::OpGreaterEqual => ordering_op!(self, >=),
+                OpCode::OpLess => ordering_op!(self, <),
+                OpCode::OpLessEqual => ordering_op!(self, <=),
                 OpCode::OpNot => {
-                    let value = self.current_frame().slots.pop().unwrap();
+                    let value = pop!(self);
                     self.current_frame().slots.push(!value);
                 }
                 OpCode::OpTrue => self.current_frame().slots.push(Value::True),
                 OpCode::OpFalse => self.current_frame().slots.push(Value::False),
                 OpCode::OpNone => self.current_frame().slots.push(Value::None),
                 OpCode::OpPrint => {
-                    print_value(self.current_frame().slots.pop().unwrap());
-                    println!();
+                    let value = pop!(self);
+                    self.output.write_out(&format!("{}\n", value));
                 }
                 OpCode::OpNegate => {
-                    if !self.peek(0).is_number() {
+                    let operand = checked!(self, self.peek(0), "stack underflow");
+                    if !operand.is_number() && !matches!(operand, Value::Duration(_)) {
                         self.runtime_error("Operand must be a number.");
                         return InterpretResult::RuntimeError;
                     }
-                    let value = self.current_frame().slots.pop().unwrap();
-                    self.current_frame().slots.push(-value);
+                    let value = pop!(self);
+                    match -value {
+                        Ok(value) => self.current_frame().slots.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
                 }
                 OpCode::OpEof => {
                     return InterpretResult::Ok;
                 }
                 OpCode::OpEol => (),
                 OpCode::OpSet => {
-                    let slot = self.read_byte();
-                    match slot {
-                        OpCode::Number(slot) => {
-                            if slot == usize::MAX {
-                                self.runtime_error( &format!("Variable with this name already declared in the global scope.\nGlobal variables cannot be edited from a scope."));
-                                return InterpretResult::RuntimeError;
-                            }
-                            self.current_frame().slots[slot as usize] =
-                                self.current_frame().slots.last().unwrap().clone();
-                        }
-                        _ => {
-                            self.runtime_error(&format!("Unknown opcode {:?}", slot));
-                            return InterpretResult::CompileError;
+                    let slot = read_operand!(self);
+                    let value = checked!(self, self.peek(0), "stack underflow");
+                    let target = checked!(
+                        self,
+                        self.current_frame().slots.get_mut(slot),
+                        "slot out of range"
+                    );
+                    *target = value;
+                }
+                OpCode::OpGetGlobal => {
+                    let name = checked!(self, self.read_constant(), "unknown constant").to_text();
+                    let value = match self.globals.get(&name) {
+                        Some(global) => global.value.clone(),
+                        None => {
+                            self.runtime_error(&format!("Undefined global {}.", name));
+                            return InterpretResult::RuntimeError;
                         }
+                    };
+                    self.current_frame().slots.push(value);
+                }
+                OpCode::OpSetGlobal => {
+                    let name = checked!(self, self.read_constant(), "unknown constant").to_text();
+                    let value = checked!(self, self.peek(0), "stack underflow");
+                    if let Err(message) = self.set_global(&name, value) {
+                        self.runtime_error(&message);
+                        return InterpretResult::RuntimeError;
                     }
                 }
                 OpCode::OpGet => {
-                    let slot = self.read_byte();
-                    match slot {
-                        OpCode::Number(slot) => {
-                            if slot == usize::MAX {
-                                self.runtime_error(&format!("Undefined variable."));
-                                return InterpretResult::RuntimeError;
-                            }
-                            let frame = self.current_frame();
-                            frame.slots.push(frame.slots[slot as usize].clone());
-                        }
-                        _ => {
-                            self.runtime_error(&format!("Unknown opcode {:?}", slot));
-                            return InterpretResult::CompileError;
-                        }
+                    let slot = read_operand!(self);
+                    if slot == usize::MAX {
+                        self.runtime_error("Undefined variable.");
+                        return InterpretResult::RuntimeError;
                     }
+                    let frame = self.current_frame();
+                    let value = checked!(self, frame.slots.get(slot).cloned(), "slot out of range");
+                    self.current_frame().slots.push(value);
                 }
                 OpCode::OpPop => {
                     self.current_frame().slots.pop();
                 }
+                OpCode::OpTestLocals | OpCode::OpTestLocalConstant => {
+                    let left = read_operand!(self);
+                    let right = read_operand!(self);
+                    let comparison = read_operand!(self);
+                    let offset = read_operand!(self);
+                    let frame = self.current_frame();
+                    let a = checked!(self, frame.slots.get(left).cloned(), "slot out of range");
+                    let b = if instruction == OpCode::OpTestLocals {
+                        checked!(self, frame.slots.get(right).cloned(), "slot out of range")
+                    } else {
+                        checked!(
+                            self,
+                            frame.function.chunk.constants.get(right).cloned(),
+                            "unknown constant"
+                        )
+                    };
+                    let comparison = checked!(
+                        self,
+                        TEST_COMPARISONS.get(comparison).copied(),
+                        "unknown comparison"
+                    );
+                    match self.test(&a, &b, comparison) {
+                        Some(true) => {}
+                        Some(false) => self.current_frame().ip += offset,
+                        None => return InterpretResult::RuntimeError,
+                    }
+                }
                 OpCode::OpJumpIfTrue => {
-                    let offset = self.read_byte().as_number();
-                    if self.peek(0).is_truthy() {
+                    let offset = read_operand!(self);
+                    if !self.check_condition() {
+                        return InterpretResult::RuntimeError;
+                    }
+                    if checked!(self, self.peek(0), "stack underflow").is_truthy() {
                         self.current_frame().ip += offset;
                     }
                 }
                 OpCode::OpJumpIfFalse => {
-                    let offset = self.read_byte().as_number();
-                    if !self.peek(0).is_truthy() {
+                    let offset = read_operand!(self);
+                    if !self.check_condition() {
+                        return InterpretResult::RuntimeError;
+                    }
+                    if !checked!(self, self.peek(0), "stack underflow").is_truthy() {
                         self.current_frame().ip += offset;
                     }
                 }
                 OpCode::OpJump => {
-                    let offset = self.read_byte().as_number();
+                    let offset = read_operand!(self);
                     self.current_frame().ip += offset;
                 }
                 OpCode::OpLoop => {
-                    let offset = self.read_byte().as_number();
-                    self.current_frame().ip -= offset;
+                    let offset = read_operand!(self);
+                    let ip = self.current_frame().ip.checked_sub(offset);
+                    self.current_frame().ip =
+                        checked!(self, ip, "jump before the start of the chunk");
                 }
                 OpCode::OpCall => {
-                    let arg_count = self.read_byte().as_number();
+                    let arg_count = read_operand!(self);
                     if !self.call_value(arg_count) {
                         return InterpretResult::RuntimeError;
                     }
                 }
+                OpCode::OpCallIndirect => {
+                    let arg_count = read_operand!(self);
+                    let site = read_operand!(self);
+                    if !self.check_call_site(site, arg_count) || !self.call_value(arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::OpBuildList => {
+                    let count = read_operand!(self);
+                    let items = checked!(self, self.pop_many(count), "stack underflow");
+                    self.current_frame()
+                        .slots
+                        .push(Value::List(gc::items(items)));
+                }
+                OpCode::OpBuildSet => {
+                    let count = read_operand!(self);
+                    let items = checked!(self, self.pop_many(count), "stack underflow");
+                    match Value::new_set(items) {
+                        Ok(set) => self.current_frame().slots.push(set),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpBuildMap => {
+                    let count = read_operand!(self);
+                    let items = checked!(self, self.pop_many(count * 2), "stack underflow");
+                    let mut items = items.into_iter();
+                    let mut entries = Vec::with_capacity(count);
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        entries.push((key, value));
+                    }
+                    match Value::new_map(entries) {
+                        Ok(map) => self.current_frame().slots.push(map),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpUnion => binary_op!(self, |),
+                OpCode::OpIntersection => binary_op!(self, &),
+                OpCode::OpIs => {
+                    let b = pop!(self);
+                    let a = pop!(self);
+                    self.current_frame().slots.push(if a.is_identical(&b) {
+                        Value::True
+                    } else {
+                        Value::False
+                    });
+                }
+                OpCode::OpConcat => {
+                    let b = pop!(self);
+                    let a = pop!(self);
+                    let text = a.to_text() + &b.to_text();
+                    self.current_frame().slots.push(Value::from(text));
+                }
+                OpCode::OpIn => {
+                    let container = pop!(self);
+                    let item = pop!(self);
+                    match container.contains(&item) {
+                        Ok(found) => self.current_frame().slots.push(if found {
+                            Value::True
+                        } else {
+                            Value::False
+                        }),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpGetIter => {
+                    let value = pop!(self);
+                    match value.iter() {
+                        Ok(iterator) => self.current_frame().slots.push(iterator),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpForIter => {
+                    let slot = read_operand!(self);
+                    let offset = read_operand!(self);
+                    let iterator = self.current_frame().slots.get(slot).cloned();
+                    let iterator = checked!(self, iterator, "slot out of range");
+                    let next = match self.next_value(&iterator) {
+                        Ok(next) => next,
+                        Err(result) => return result,
+                    };
+                    match next {
+                        Some(value) => self.current_frame().slots.push(value),
+                        None => {
+                            // Keeps the loop variable slot filled, it is popped with the loop scope
+                            self.current_frame().slots.push(Value::None);
+                            self.current_frame().ip += offset;
+                        }
+                    }
+                }
+                OpCode::OpIndex => {
+                    let index = pop!(self);
+                    let sequence = pop!(self);
+                    match sequence.index(index) {
+                        Ok(value) => self.current_frame().slots.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpSlice => {
+                    let step = pop!(self);
+                    let stop = pop!(self);
+                    let start = pop!(self);
+                    let sequence = pop!(self);
+                    match sequence.slice(start, stop, step) {
+                        Ok(value) => self.current_frame().slots.push(value),
+                        Err(message) => {
+                            self.runtime_error(&message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::OpDefer => {
+                    let depth = read_operand!(self);
+                    let skip = read_operand!(self);
+                    let frame = self.current_frame();
+                    let deferred = Deferred {
+                        ip: frame.ip,
+                        height: frame.slots.len(),
+                        depth,
+                    };
+                    frame.deferred.push(deferred);
+                    frame.ip += skip;
+                }
+                // Runs the code deferred at the given depth or deeper, one at
+                // a time, coming back here after each
+                OpCode::OpRunDeferred => {
+                    let depth = read_operand!(self);
+                    let frame = self.current_frame();
+                    if frame.deferred.last().map(|deferred| deferred.depth) >= Some(depth) {
+                        let deferred = frame.deferred.pop().unwrap();
+                        let start = frame.instruction_start;
+                        if !self.start_deferred(deferred, Some(start)) {
+                            return self.internal_error("deferred above the stack");
+                        }
+                    }
+                }
+                OpCode::OpEndDefer => {
+                    let frame = self.current_frame();
+                    let running = checked!(
+                        self,
+                        frame.running_deferred.pop(),
+                        "end of deferred code that is not running"
+                    );
+                    frame.slots.truncate(running.height);
+                    frame.slots.extend(running.kept);
+                    match running.return_ip {
+                        Some(ip) => frame.ip = ip,
+                        None => return InterpretResult::Ok,
+                    }
+                }
+                // Each offset counts from its own operand, a try without a
+                // catch or finally block has 0 for it
+                OpCode::OpTry => {
+                    let finally = read_operand!(self);
+                    let finally_ip = self.current_frame().ip + finally;
+                    let catch = read_operand!(self);
+                    let frame = self.current_frame();
+                    let handler = TryHandler {
+                        catch_ip: (catch > 0).then_some(frame.ip + catch),
+                        finally_ip: (finally > 0).then_some(finally_ip),
+                        height: frame.slots.len(),
+                        deferred: frame.deferred.len(),
+                        running: frame.running_deferred.len(),
+                    };
+                    frame.handlers.push(handler);
+                }
+                OpCode::OpEndTry => {
+                    let handler = checked!(
+                        self,
+                        self.current_frame().handlers.pop(),
+                        "end of a try block that is not open"
+                    );
+                    if let Some(ip) = handler.finally_ip {
+                        let finally = Deferred {
+                            ip,
+                            height: handler.height,
+                            depth: 0,
+                        };
+                        let next = self.current_frame().ip;
+                        if !self.start_deferred(finally, Some(next)) {
+                            return self.internal_error("finally code above the stack");
+                        }
+                    }
+                }
+                OpCode::OpCaught => {
+                    let error = self.error.take().unwrap_or(Value::None);
+                    self.current_frame().slots.push(error);
+                }
+                OpCode::OpCatches => {
+                    let kind = match checked!(self, self.read_constant(), "unknown constant") {
+                        Value::String(kind) => kind,
+                        _ => {
+                            self.internal_error("caught type is not a string");
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    let error = checked!(self, self.peek(0), "stack underflow");
+                    // Errors are taken by their kind as well as by the error type
+                    let caught = error.type_of() == *kind
+                        || matches!(&error, Value::Error(error) if *error.kind == *kind);
+                    self.current_frame().slots.push(Value::from(caught));
+                }
+                OpCode::OpRaise => {
+                    let value = pop!(self);
+                    if self.catches_errors() {
+                        self.error = Some(value);
+                    } else {
+                        let message = match &value {
+                            Value::Error(error) if &*error.kind == RUNTIME_ERROR => {
+                                error.message.clone()
+                            }
+                            Value::Error(error) => format!("{}: {}", error.kind, error.message),
+                            value => format!("Uncaught {} {}", value.type_of(), value),
+                        };
+                        self.report_error(&message);
+                    }
+                    return InterpretResult::RuntimeError;
+                }
+                OpCode::OpYield => {
+                    let value = pop!(self);
+                    if self.frames.len() < 2 {
+                        return self.internal_error("yield without a caller");
+                    }
+                    let frame = self.frames.pop().unwrap();
+                    if let Some(generator) = frame.generator {
+                        let mut generator = generator.borrow_mut();
+                        generator.ip = frame.ip;
+                        generator.slots = frame.slots;
+                        generator.deferred = frame.deferred;
+                        generator.handlers = frame.handlers;
+                    }
+                    self.current_frame().slots.push(value);
+                    if self.frames.len() == base_depth {
+                        return InterpretResult::Ok;
+                    }
+                }
+                OpCode::OpCallNative => {
+                    let native = checked!(
+                        self,
+                        NATIVES.get(read_operand!(self)),
+                        "unknown native function"
+                    );
+                    let arg_count = read_operand!(self);
+                    if self.expression_only && !allowed_in_expression(native) {
+                        self.runtime_error(&format!(
+                            "Function {} cannot be called from an expression",
+                            native.name
+                        ));
+                        return InterpretResult::RuntimeError;
+                    }
+                    if let Some(capability) = native.capability {
+                        if !self.options.sandbox.allows(capability) {
+                            self.runtime_error(&format!(
+                                "Function {} needs {} access, which the sandbox does not allow",
+                                native.name,
+                                capability.name()
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                    let args = checked!(self, self.pop_many(arg_count), "stack underflow");
+                    self.error = None;
+                    let result = (native.function)(self, args);
+                    // An error from a native that ran script code calling exit
+                    // only says that code stopped
+                    if let Some(status) = self.exit_status {
+                        return InterpretResult::Exit(status);
+                    }
+                    match result {
+                        Ok(value) => self.current_frame().slots.push(value),
+                        Err(message) => return self.native_error(&message),
+                    }
+                }
+                OpCode::OpInvokeBuiltin => {
+                    let name = match checked!(self, self.read_constant(), "unknown constant") {
+                        Value::String(name) => name,
+                        _ => {
+                            self.internal_error("method name is not a string");
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    let arg_count = read_operand!(self);
+                    let args = checked!(self, self.pop_many(arg_count + 1), "stack underflow");
+                    let receiver = args[0].type_of();
+                    let method = match find_method(&receiver, &name) {
+                        Some(method) => method,
+                        None => {
+                            self.runtime_error(&format!(
+                                "Type {} has no method {}",
+                                receiver, name
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    if arg_count < method.min_args || arg_count > method.max_args {
+                        self.runtime_error(&format!(
+                            "Method {} of {} expects {} arguments but got {}",
+                            name,
+                            receiver,
+                            expected_args(method.min_args, method.max_args),
+                            arg_count
+                        ));
+                        return InterpretResult::RuntimeError;
+                    }
+                    self.error = None;
+                    match (method.function)(self, args) {
+                        Ok(value) => self.current_frame().slots.push(value),
+                        Err(message) => return self.native_error(&message),
+                    }
+                }
                 OpCode::OpReturn => {
-                    let result = self.current_frame().slots.pop().unwrap();
-                    self.frames.pop();
+                    let result = pop!(self);
+                    let frame = self.frames.pop().unwrap();
+                    if let Some(generator) = &frame.generator {
+                        generator.borrow_mut().done = true;
+                    }
+                    if let (Some(key), Some(memo)) = (&frame.memo_key, &frame.function.memo) {
+                        memo.borrow_mut().insert(key.clone(), result.clone());
+                    }
                     if self.frames.is_empty() {
+                        if frame.function.name.is_empty() {
+                            self.export_globals(&frame);
+                        }
+                        self.script_result = result;
                         return InterpretResult::Ok;
                     }
                     self.current_frame().slots.push(result);
+                    let mut slots = frame.slots;
+                    slots.clear();
+                    self.spare_slots.push(slots);
+                    if self.frames.len() == base_depth {
+                        return InterpretResult::Ok;
+                    }
                 }
                 _ => {
                     self.runtime_error(&format!("Unknown opcode {:?}", instruction));
@@ -243,33 +1529,120 @@ impl VM {
         }
     }
 
-    fn read_byte(&mut self) -> OpCode {
+    // The top level variables of a finished script replace the globals of the
+    // same name, along with the type they were declared with
+    fn export_globals(&mut self, frame: &CallFrame) {
+        for (name, slot, type_) in &frame.function.exports {
+            if let Some(value) = frame.slots.get(*slot) {
+                let global = Global {
+                    value: value.clone(),
+                    type_: *type_,
+                };
+                self.globals.insert(name.clone(), global);
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<OpCode> {
         let frame = self.current_frame();
+        let byte = *frame.function.chunk.code.get(frame.ip)?;
+        frame.ip += 1;
+        Some(byte)
+    }
 
-        if frame.ip >= frame.function.chunk.code.len() {
-            todo!("Handle this error");
+    fn read_operand(&mut self) -> Option<usize> {
+        match self.read_byte()? {
+            OpCode::Number(operand) => Some(operand),
+            _ => None,
         }
+    }
 
-        let byte = frame.function.chunk.code[frame.ip];
-        frame.ip += 1;
-        byte
+    fn read_constant(&mut self) -> Option<Value> {
+        let index = self.read_operand()?;
+        self.current_frame()
+            .function
+            .chunk
+            .constants
+            .get(index)
+            .cloned()
     }
 
-    fn read_constant(&mut self) -> Value {
-        let constant = self.read_byte();
-        match constant {
-            OpCode::Number(index) => self.current_frame().function.chunk.constants[index].clone(),
-            _ => panic!("Expected constant to be a number"),
+    // The values on top of the stack, in the order they were pushed
+    fn pop_many(&mut self, count: usize) -> Option<Vec<Value>> {
+        let slots = &mut self.current_frame().slots;
+        let at = slots.len().checked_sub(count)?;
+        Some(slots.split_off(at))
+    }
+
+    // The compiler checks calls to functions it knows by name, calls through a
+    // variable or an expression are checked here instead. A site calling the
+    // same function as last time skips the checks that only depend on it.
+    fn check_call_site(&mut self, site: usize, arg_count: usize) -> bool {
+        let function = match self.peek(arg_count) {
+            Some(Value::ObjFunction(function)) => function,
+            // Reported by call_value
+            Some(_) => return true,
+            None => {
+                self.internal_error("stack underflow");
+                return false;
+            }
+        };
+        let caches = &self.current_frame().function.chunk.call_caches;
+        let cached = caches.borrow().get(site).map(Weak::as_ptr);
+        let hit = match cached {
+            Some(cached) => cached == Rc::as_ptr(&function),
+            None => {
+                self.internal_error("unknown call site");
+                return false;
+            }
+        };
+        if hit {
+            return self.check_argument_types(&function, arg_count);
+        }
+        if !self.check_arguments(&function, arg_count) {
+            return false;
+        }
+        self.current_frame().function.chunk.call_caches.borrow_mut()[site] =
+            Rc::downgrade(&function);
+        true
+    }
+
+    fn check_arguments(&mut self, function: &ObjFunction, arg_count: usize) -> bool {
+        let info = &function.function_info;
+        if info.arg_names.len() != arg_count {
+            self.runtime_error(&format!(
+                "Function {} expects {} arguments but got {}",
+                info.name,
+                info.arg_names.len(),
+                arg_count
+            ));
+            return false;
+        }
+        self.check_argument_types(function, arg_count)
+    }
+
+    fn check_argument_types(&mut self, function: &ObjFunction, arg_count: usize) -> bool {
+        let slots = &self.current_frame().slots;
+        let args = &slots[slots.len() - arg_count..];
+        match function.function_info.check_argument_types(args) {
+            Ok(()) => true,
+            Err(message) => {
+                self.runtime_error(&message);
+                false
+            }
         }
     }
 
     fn call_value(&mut self, arg_count: usize) -> bool {
-        let value = self.peek(arg_count);
-        match value {
-            Value::ObjFunction(function) => {
-                self.call(function);
-                true
+        let value = match self.peek(arg_count) {
+            Some(value) => value,
+            None => {
+                self.internal_error("stack underflow");
+                return false;
             }
+        };
+        match value {
+            Value::ObjFunction(function) => self.call(function),
             _ => {
                 self.runtime_error(&format!(
                     "Can only call functions and classes. Got {:?} instead.",
@@ -280,37 +1653,272 @@ impl VM {
         }
     }
 
-    fn call(&mut self, function: ObjFunction) {
+    fn call(&mut self, function: Rc<ObjFunction>) -> bool {
         let frame = self.current_frame();
 
         let arg_count = function.function_info.arg_names.len();
-        let at = frame.slots.len() - arg_count;
+        let functions_count = frame.function.functions_count;
+        // The called value sits below the arguments, it is replaced by the result
+        let at = match frame.slots.len().checked_sub(arg_count) {
+            Some(at) if at > 0 => at,
+            _ => {
+                self.internal_error("stack underflow");
+                return false;
+            }
+        };
+
+        if function.traced {
+            let args: Vec<String> = function
+                .bound
+                .iter()
+                .chain(&frame.slots[at..])
+                .map(Value::to_string)
+                .collect();
+            let call = format!("trace: {}({})\n", function.name, args.join(", "));
+            self.output.write_err(&call);
+        }
+
+        let frame = self.current_frame();
+        let memo_key = function
+            .memo
+            .as_ref()
+            .and_then(|_| memo_key(function.bound.iter().chain(&frame.slots[at..])));
+        if let (Some(key), Some(memo)) = (&memo_key, &function.memo) {
+            if let Some(result) = memo.borrow().get(key) {
+                frame.slots.truncate(at - 1);
+                frame.slots.push(result.clone());
+                return true;
+            }
+        }
+
+        let mut new_slots = self.spare_slots.pop().unwrap_or_default();
+        new_slots.reserve(function.max_stack);
+        let frame = self.current_frame();
 
-        let mut new_slots = frame.slots[0..frame.function.functions_count].to_vec();
-        new_slots.extend(frame.slots.split_off(at));
+        // Functions defined further down the script have no slot yet
+        new_slots.extend_from_slice(&frame.slots[0..functions_count.min(at - 1)]);
+        new_slots.resize(functions_count, Value::None);
+        new_slots.extend_from_slice(&function.bound);
+        new_slots.extend(frame.slots.drain(at..));
+        frame.slots.pop();
+
+        if function.is_generator {
+            let generator = ObjGenerator::new(function, new_slots);
+            frame
+                .slots
+                .push(Value::Generator(Rc::new(RefCell::new(generator))));
+            return true;
+        }
 
         let new_frame = CallFrame {
             ip: 0,
+            instruction_start: 0,
             function,
             slots: new_slots,
+            generator: None,
+            memo_key,
+            deferred: Vec::new(),
+            running_deferred: Vec::new(),
+            handlers: Vec::new(),
         };
         self.frames.push(new_frame);
+        true
+    }
+
+    // The slots holding the functions, which every new frame starts with
+    pub(crate) fn function_slots(&mut self) -> Vec<Value> {
+        match self.frames.last() {
+            Some(frame) => frame
+                .slots
+                .get(0..frame.function.functions_count)
+                .unwrap_or(&[])
+                .to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    // The next value of an iterator or generator, None once it is done
+    fn next_value(&mut self, iterator: &Value) -> Result<Option<Value>, InterpretResult> {
+        let advance = match iterator {
+            Value::Iterator(iterator) => iterator.borrow_mut().advance(),
+            Value::Generator(generator) => {
+                if generator.borrow().done {
+                    return Ok(None);
+                }
+                return match self.resume(generator.clone(), Value::None)? {
+                    Resumed::Yielded(value) => Ok(Some(value)),
+                    Resumed::Returned(_) => Ok(None),
+                };
+            }
+            _ => return Ok(None),
+        };
+        match advance {
+            Advance::Value(value) => Ok(value),
+            Advance::Enumerate(source, index) => Ok(self
+                .next_value(&source)?
+                .map(|value| Value::from(vec![Value::Integer(index), value]))),
+            Advance::Zip(sources) => {
+                let mut values = Vec::with_capacity(sources.len());
+                for source in &sources {
+                    match self.next_value(source)? {
+                        Some(value) => values.push(value),
+                        None => return Ok(None),
+                    }
+                }
+                Ok(Some(Value::from(values)))
+            }
+        }
+    }
+
+    // Runs the generator up to its next yield or its end. The sent value is
+    // the result of the yield the generator was suspended on.
+    pub(crate) fn resume(
+        &mut self,
+        generator: Rc<RefCell<ObjGenerator>>,
+        sent: Value,
+    ) -> Result<Resumed, InterpretResult> {
+        let frame = {
+            let mut suspended = generator.borrow_mut();
+            if suspended.started || suspended.awaiting_argument {
+                suspended.slots.push(sent);
+            }
+            suspended.started = true;
+            suspended.awaiting_argument = false;
+
+            CallFrame {
+                ip: suspended.ip,
+                instruction_start: suspended.ip,
+                function: suspended.function.clone(),
+                slots: std::mem::take(&mut suspended.slots),
+                generator: Some(generator.clone()),
+                memo_key: None,
+                deferred: std::mem::take(&mut suspended.deferred),
+                running_deferred: Vec::new(),
+                handlers: std::mem::take(&mut suspended.handlers),
+            }
+        };
+
+        let base_depth = self.frames.len();
+        self.frames.push(frame);
+        let result = self.run(base_depth);
+        if !matches!(result, InterpretResult::Ok) {
+            self.frames.truncate(base_depth);
+            return Err(result);
+        }
+
+        let value = match self.current_frame().slots.pop() {
+            Some(value) => value,
+            None => return Err(self.internal_error("stack underflow")),
+        };
+        if generator.borrow().done {
+            Ok(Resumed::Returned(value))
+        } else {
+            Ok(Resumed::Yielded(value))
+        }
+    }
+
+    fn peek(&mut self, distance: usize) -> Option<Value> {
+        let slots = &self.current_frame().slots;
+        let index = slots.len().checked_sub(distance + 1)?;
+        Some(slots[index].clone())
     }
 
-    fn peek(&mut self, distance: usize) -> Value {
+    // Reports a problem with the bytecode itself rather than with the script,
+    // with the function and offset of the instruction that ran into it
+    fn internal_error(&mut self, problem: &str) -> InterpretResult {
         let frame = self.current_frame();
-        frame.slots[frame.slots.len() - distance - 1].clone()
+        let message = format!(
+            "Internal VM error: {} at offset {:04} of {}",
+            problem,
+            frame.instruction_start,
+            frame.function.chunk.location()
+        );
+        self.runtime_error(&message);
+        InterpretResult::RuntimeError
+    }
+
+    // Script code a native ran can already have raised the error, which a try
+    // block is now waiting for
+    fn native_error(&mut self, message: &str) -> InterpretResult {
+        if self.error.is_none() {
+            self.runtime_error(message);
+        }
+        InterpretResult::RuntimeError
+    }
+
+    // The error as a catch block gets it, located at the instruction each
+    // frame is running
+    pub(crate) fn error_value(&self, kind: &str, message: &str) -> ObjError {
+        let trace: Vec<TraceFrame> = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let chunk = &frame.function.chunk;
+                let offset = frame
+                    .instruction_start
+                    .min(chunk.code.len().saturating_sub(1));
+                TraceFrame {
+                    function: if chunk.function_name.is_empty() {
+                        Rc::from("<script>")
+                    } else {
+                        chunk.function_name.clone()
+                    },
+                    file: chunk.file.clone(),
+                    line: chunk.get_line(offset),
+                }
+            })
+            .collect();
+        let raised = trace.last().unwrap().clone();
+        ObjError {
+            kind: Rc::from(kind),
+            message: message.to_owned(),
+            line: raised.line,
+            function: raised.function,
+            file: raised.file,
+            trace,
+        }
+    }
+
+    // Whether an error raised now goes to a try block of the running frames
+    // instead of being reported
+    fn catches_errors(&self) -> bool {
+        !self.interrupted
+            && self.frames.iter().any(|frame| {
+                frame
+                    .handlers
+                    .iter()
+                    .any(|handler| handler.catch_ip.is_some())
+            })
     }
 
     fn runtime_error(&mut self, message: &str) {
+        if self.catches_errors() {
+            let error = self.error_value(RUNTIME_ERROR, message);
+            self.error = Some(Value::Error(Rc::new(error)));
+        } else {
+            self.report_error(message);
+        }
+    }
+
+    fn report_error(&mut self, message: &str) {
         let frame = self.current_frame();
+        let chunk = &frame.function.chunk;
+        let offset = frame
+            .instruction_start
+            .min(chunk.code.len().saturating_sub(1));
 
-        eprintln!();
-        eprintln!("{}", message);
-        eprintln!(
-            "[line {}] in script",
-            frame.function.chunk.get_line(frame.ip - 1)
-        );
+        let location = match chunk.get_span(offset) {
+            Some(span) => format!(
+                "[line {}, columns {}-{}] in {}",
+                chunk.get_line(offset),
+                span.start,
+                span.end.saturating_sub(1).max(span.start),
+                chunk.location()
+            ),
+            None => format!("[line {}] in {}", chunk.get_line(offset), chunk.location()),
+        };
+        let report = format!("\n{}\n{}\n", message, location);
+        self.output.write_err(&report);
 
         // for i in (0..self.frames.len()).rev() {
         //     let frame = &self.frames[i];
@@ -325,3 +1933,295 @@ impl VM {
         // }
     }
 }
+
+// Calls with arguments that can change, like lists, are not cached. The type
+// is part of the key since 1, 1.0 and "1" can give different results.
+fn memo_key<'a>(args: impl Iterator<Item = &'a Value>) -> Option<String> {
+    let mut key = String::new();
+    for arg in args {
+        if !arg.is_hashable() {
+            return None;
+        }
+        key.push_str(&format!("{}:{},", arg.type_of(), arg));
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Span;
+    use crate::io::CapturedOutput;
+    use crate::options::Capability;
+    use crate::thread::Sendable;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn runs_many_scripts_after_reset() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(CapturedOutput::new()));
+        let source = "answer {\n    print 42\n}\nanswer()\n6 * 7\n";
+        let snapshot = vm.snapshot();
+        for _ in 0..3 {
+            assert!(matches!(
+                vm.interpret("print (".to_owned()),
+                Err(InterpretError::Compile)
+            ));
+            vm.reset();
+            assert!(matches!(
+                vm.interpret("x = [][1]".to_owned()),
+                Err(InterpretError::Runtime)
+            ));
+            vm.restore(snapshot.clone());
+            assert!(matches!(
+                vm.interpret(source.to_owned()),
+                Ok(Value::Integer(42))
+            ));
+            vm.reset();
+        }
+    }
+
+    // Inputs found to crash the interpreter, each must end in a runtime error
+    #[test]
+    fn hostile_inputs_are_runtime_errors() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(CapturedOutput::new()));
+        for source in [
+            "\"x\" * 9223372036854775807",
+            "\"ab\" * 1000000000",
+            "format(1.5, \".99999999f\")",
+            "format(1.5, \".4000e\")",
+            "format(1.5, \"99999999999\")",
+            "format(1.5, \".99999999999999999999999f\")",
+            "format_number(1.5, false, 1000000000000)",
+            "format_number(15, true, 9223372036854775807)",
+        ] {
+            let result = vm.interpret(source.to_owned());
+            assert!(matches!(result, Err(InterpretError::Runtime)), "{}", source);
+            vm.reset();
+        }
+    }
+
+    #[test]
+    fn loop_before_the_start_of_the_chunk_is_a_runtime_error() {
+        let mut function = ObjFunction::new();
+        let span = Span { start: 0, end: 0 };
+        function.chunk.write(OpCode::OpLoop, 1, span);
+        function.chunk.write(OpCode::Number(100), 1, span);
+        let mut vm = VM::new();
+        vm.set_output(Box::new(CapturedOutput::new()));
+        vm.frames.push(CallFrame {
+            ip: 0,
+            instruction_start: 0,
+            slots: Vec::new(),
+            function: Rc::new(function),
+            generator: None,
+            memo_key: None,
+            deferred: Vec::new(),
+            running_deferred: Vec::new(),
+            handlers: Vec::new(),
+        });
+        assert!(matches!(vm.run(0), InterpretResult::RuntimeError));
+    }
+
+    #[test]
+    fn expressions_only_call_natives_computing_a_value() {
+        let mut vm = VM::new();
+        vm.set_output(Box::new(CapturedOutput::new()));
+        for source in [
+            "sleep(1)",
+            "exit(0)",
+            "confirm(\"Sure?\")",
+            "set_timeout(1, 1)",
+        ] {
+            let result = vm.eval_expression(source);
+            assert!(matches!(result, Err(InterpretError::Runtime)), "{}", source);
+        }
+        let result = vm.eval_expression("format(1.25, \".1f\")");
+        assert!(matches!(result, Ok(Value::String(text)) if &*text == "1.2"));
+    }
+
+    // Shared between the threads of a script, unlike CapturedOutput
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<String>>);
+
+    impl Output for SharedOutput {
+        fn write_out(&mut self, text: &str) {
+            self.0.lock().unwrap().push_str(text);
+        }
+
+        fn write_err(&mut self, text: &str) {
+            self.0.lock().unwrap().push_str(text);
+        }
+    }
+
+    #[test]
+    fn functions_compiled_on_other_threads_run() {
+        let output = SharedOutput::default();
+        let mut vm = VM::new();
+        vm.set_output(Box::new(output.clone()));
+        vm.set_compile_threads(3);
+        let source = "double: int n {\n    print n * 2\n    half(20)\n}\nhalf: int n {\n    print n - 15\n}\nsquare: int n {\n    print n * n\n}\ndouble(20)\nsquare(3)\n";
+        assert!(vm.interpret(source.to_owned()).is_ok());
+        let output = output.0.lock().unwrap();
+        let printed: Vec<&str> = output
+            .lines()
+            .filter(|line| !line.starts_with(['0', '=', ' ']))
+            .collect();
+        assert_eq!(printed, ["40", "5", "9"]);
+    }
+
+    // The lines a script prints, without the listing of its code or blank lines
+    fn printed(source: &str) -> (Result<Value, InterpretError>, Vec<String>) {
+        let mut vm = VM::new();
+        let output = CapturedOutput::new();
+        vm.set_output(Box::new(output.clone()));
+        let result = vm.interpret(source.to_owned());
+        let lines = output
+            .take()
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(['0', '=', ' ']))
+            .map(str::to_owned)
+            .collect();
+        (result, lines)
+    }
+
+    #[test]
+    fn lists_maps_and_strings_are_indexed_and_sliced() {
+        let source = "l = [1, 2, 3, 4]\nm = {\"a\": 1, \"b\": 2}\nprint l[1:3]\nprint l[-1]\nprint l[:2]\nprint l[2:]\nprint \"hello\"[1:3]\nprint m[\"b\"]\nprint [[1, 2], []]\nprint {}\n";
+        let (result, lines) = printed(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            lines,
+            [
+                "[2, 3]",
+                "4",
+                "[1, 2]",
+                "[3, 4]",
+                "\"el\"",
+                "2",
+                "[[1, 2], []]",
+                "{}"
+            ]
+        );
+
+        let (result, lines) = printed("l = [1, 2, 3]\nprint l[5]\n");
+        assert!(matches!(result, Err(InterpretError::Runtime)));
+        assert_eq!(lines[0], "List index 5 out of range for length 3");
+        let (result, lines) = printed("m = {\"a\": 1}\nprint m[\"b\"]\n");
+        assert!(matches!(result, Err(InterpretError::Runtime)));
+        assert_eq!(lines[0], "Key \"b\" not found in map");
+    }
+
+    #[test]
+    fn comprehensions_filter_nest_and_build_maps_and_sets() {
+        let source = "l = [1, 2, 3]\nm = {\"a\": 1, \"b\": 2}\nprint [x * 2 for x in l if x > 1]\nprint {k: m[k] * 10 for k in m}\nprint [[y for y in range(0, x)] for x in l]\nprint {x for x in [1, 1, 2]}\n";
+        let (result, lines) = printed(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            lines,
+            [
+                "[4, 6]",
+                "{\"a\": 10, \"b\": 20}",
+                "[[0], [0, 1], [0, 1, 2]]",
+                "{1, 2}"
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_leave_only_their_result_on_the_stack() {
+        let source = "f {\n    print 3\n}\nf()\ng {\n    print [x for x in [1, 2]]\n    f()\n}\ng()\ng()\nprint [y * 2 for y in [1, 2, 3] if y > 1]\n";
+        let (result, lines) = printed(source);
+        assert!(result.is_ok());
+        assert_eq!(lines, ["3", "[1, 2]", "3", "[1, 2]", "3", "[4, 6]"]);
+    }
+
+    #[test]
+    fn deferred_code_and_finally_blocks_run_on_raise() {
+        let source = "f {\n    defer print 1\n    defer print 2\n    print 3\n}\nf()\ng {\n    defer print \"cleanup\"\n    raise \"boom\"\n}\ntry {\n    g()\n} catch e {\n    print \"caught \" .. e\n} finally {\n    print \"done\"\n}\n";
+        let (result, lines) = printed(source);
+        assert!(result.is_ok());
+        assert_eq!(
+            lines,
+            ["3", "2", "1", "\"cleanup\"", "\"caught boom\"", "\"done\""]
+        );
+
+        let (result, lines) =
+            printed("try {\n    raise \"x\"\n} finally {\n    print \"finally\"\n}\n");
+        assert!(matches!(result, Err(InterpretError::Runtime)));
+        assert_eq!(lines[0], "Uncaught string \"x\"");
+        assert_eq!(lines.last().unwrap(), "\"finally\"");
+    }
+
+    #[cfg(feature = "os")]
+    #[test]
+    fn spawned_threads_write_to_the_output_of_the_host() {
+        let output = SharedOutput::default();
+        let factory = output.clone();
+        let mut vm = VM::new();
+        vm.set_output_factory(Arc::new(move || Box::new(factory.clone())));
+
+        let source = "worker: int n {\n    print n * 2\n}\nt = spawn(worker, 21)\njoin(t)\n";
+        assert!(vm.interpret(source.to_owned()).is_ok());
+        assert!(output.0.lock().unwrap().lines().any(|line| line == "42"));
+
+        vm.reset();
+        let source = "worker: int n {\n    print n\n}\nt = spawn(worker, \"x\")\n";
+        assert!(matches!(
+            vm.interpret(source.to_owned()),
+            Err(InterpretError::Runtime)
+        ));
+        assert!(output
+            .0
+            .lock()
+            .unwrap()
+            .contains("Argument n of function worker must be of type int, got string instead"));
+    }
+
+    #[test]
+    fn cyclic_values_are_not_sent() {
+        let mut vm = VM::new();
+        let output = CapturedOutput::new();
+        vm.set_output(Box::new(output.clone()));
+        let source = "c = channel()\nl = [1]\nl.push(l)\nsend(c, l)\n";
+        assert!(matches!(
+            vm.interpret(source.to_owned()),
+            Err(InterpretError::Runtime)
+        ));
+        assert!(output
+            .take()
+            .contains("Cyclic values cannot be sent to another thread"));
+
+        let shared = Value::from(vec![Value::Integer(1)]);
+        let list = Value::from(vec![shared.clone(), shared]);
+        assert!(Sendable::from_value(&list).is_ok());
+    }
+
+    #[test]
+    fn sandbox_gates_the_environment_and_eval() {
+        let mut vm = VM::new();
+        let output = CapturedOutput::new();
+        vm.set_output(Box::new(output.clone()));
+        let mut policy = SandboxPolicy::deny_all();
+        vm.set_sandbox_policy(policy);
+        assert!(matches!(
+            vm.interpret("format_number(1234.5)\n".to_owned()),
+            Err(InterpretError::Runtime)
+        ));
+        assert!(output
+            .take()
+            .contains("Function format_number needs env access, which the sandbox does not allow"));
+        assert!(matches!(
+            vm.eval_expression("1 + 2"),
+            Err(InterpretError::Runtime)
+        ));
+
+        vm.reset();
+        policy.allow(Capability::Env);
+        policy.allow(Capability::Eval);
+        vm.set_sandbox_policy(policy);
+        assert!(vm.interpret("format_number(1234.5)\n".to_owned()).is_ok());
+        assert!(matches!(vm.eval_expression("1 + 2"), Ok(Value::Integer(3))));
+    }
+}